@@ -1,29 +1,444 @@
 //! Various constraints as required for production environments
 
+use crate::curve::SwapCurve;
 use crate::error::SwapError;
 use crate::fees::Fees;
+use crate::state::BPS_DENOMINATOR;
 
-use solana_program::program_error::ProgramError;
+use solana_program::{program_error::ProgramError, pubkey::Pubkey};
+use spl_math::uint::U256;
 
-/// Validate the given supply on initialization. This is useful for curves
-/// that allow zero supply on one or both sides, since the standard constant
-/// product curve must have a non-zero supply on both sides.
-pub fn validate_supply(token_a_amount: u64, token_b_amount: u64) -> Result<(), SwapError> {
-    if token_a_amount == 0 {
-        return Err(SwapError::EmptySupply);
+/// Constraints a controlled deployment applies when initializing pools
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SwapConstraints<'a> {
+    /// The only owner allowed for new pools
+    pub owner_key: &'a str,
+    /// Largest trade fee, in basis points
+    pub max_trade_fee_basis_points: u64,
+    /// Largest owner trade fee, in basis points
+    pub max_owner_fee_basis_points: u64,
+}
+
+impl SwapConstraints<'_> {
+    /// Checks that the pool owner is the approved one
+    pub fn validate_owner(&self, owner: &Pubkey) -> Result<(), SwapError> {
+        if owner.to_string() == self.owner_key {
+            Ok(())
+        } else {
+            Err(SwapError::InvalidOwner)
+        }
     }
-    if token_b_amount == 0 {
-        return Err(SwapError::EmptySupply);
+
+    /// Checks that both trade fees stay within the approved maxima
+    pub fn validate_fees(&self, fees: &Fees) -> Result<(), SwapError> {
+        if within_basis_points(
+            fees.trade_fee_numerator,
+            fees.trade_fee_denominator,
+            self.max_trade_fee_basis_points,
+        ) && within_basis_points(
+            fees.owner_trade_fee_numerator,
+            fees.owner_trade_fee_denominator,
+            self.max_owner_fee_basis_points,
+        ) {
+            Ok(())
+        } else {
+            Err(SwapError::InvalidFee)
+        }
     }
-    Ok(())
 }
 
+/// Owner approved for production deployments, set at build time
+#[cfg(feature = "production")]
+const OWNER_KEY: &str = env!("SWAP_PROGRAM_OWNER_FEE_ADDRESS");
+
+/// Constraints enforced at initialization by production builds
+#[cfg(feature = "production")]
+pub const SWAP_CONSTRAINTS: Option<SwapConstraints> = Some(SwapConstraints {
+    owner_key: OWNER_KEY,
+    max_trade_fee_basis_points: 30,
+    max_owner_fee_basis_points: 5,
+});
+
+/// Constraints enforced at initialization, none outside production builds
+#[cfg(not(feature = "production"))]
+pub const SWAP_CONSTRAINTS: Option<SwapConstraints> = None;
+
+/// Validate the given supply on initialization. This is useful for curves
+/// that allow zero supply on one or both sides, since the standard constant
+/// product curve must have a non-zero supply on both sides. Which sides may
+/// be empty is decided by the curve.
+pub fn validate_supply(
+    curve: &dyn SwapCurve,
+    token_a_amount: u64,
+    token_b_amount: u64,
+) -> Result<(), SwapError> {
+    curve.validate_supply(token_a_amount, token_b_amount)
+}
+
+/// Smallest trade fee numerator accepted at initialization, so that every
+/// pool charges a trade fee
+pub const MIN_TRADE_FEE_NUMERATOR: u64 = 1;
+
+/// Largest trade fee accepted at initialization, in basis points
+pub const MAX_TRADE_FEE_BASIS_POINTS: u64 = 3_333;
+
+/// Largest owner trade fee accepted at initialization, in basis points
+pub const MAX_OWNER_FEE_BASIS_POINTS: u64 = 1_000;
+
 /// Checks that the provided curve is valid for the given constraints
 pub fn validate_fees(fees: &Fees) -> Result<(), ProgramError> {
-    // fee should be smaller than 33% and non-zero
-    if fees.trade_fee_denominator > fees.trade_fee_numerator * 3 {
+    if fees.trade_fee_numerator >= MIN_TRADE_FEE_NUMERATOR
+        && within_basis_points(
+            fees.trade_fee_numerator,
+            fees.trade_fee_denominator,
+            MAX_TRADE_FEE_BASIS_POINTS,
+        )
+        && within_basis_points(
+            fees.owner_trade_fee_numerator,
+            fees.owner_trade_fee_denominator,
+            MAX_OWNER_FEE_BASIS_POINTS,
+        )
+    {
         Ok(())
     } else {
         Err(SwapError::InvalidFee.into())
     }
 }
+
+fn within_basis_points(numerator: u64, denominator: u64, max_basis_points: u64) -> bool {
+    u128::from(numerator) * BPS_DENOMINATOR
+        <= u128::from(max_basis_points) * u128::from(denominator)
+}
+
+/// Checks that the reward cap is either disabled (zero denominator) or a
+/// non-zero share of at most the whole fee account balance
+pub fn validate_reward_cap(numerator: u64, denominator: u64) -> Result<(), SwapError> {
+    if denominator == 0 || (numerator != 0 && numerator <= denominator) {
+        Ok(())
+    } else {
+        Err(SwapError::InvalidInput)
+    }
+}
+
+/// Largest pool token amount a pool may be initialized with, leaving room
+/// for deposits in the u64 pool token supply
+pub const MAX_INITIAL_POOL_AMOUNT: u64 = 1_000_000_000_000_000_000;
+
+/// Checks that the initial pool token amount is non-zero and leaves room for
+/// deposits
+pub fn validate_initial_pool_amount(initial_pool_amount: u64) -> Result<(), SwapError> {
+    if initial_pool_amount != 0 && initial_pool_amount <= MAX_INITIAL_POOL_AMOUNT {
+        Ok(())
+    } else {
+        Err(SwapError::InvalidInput)
+    }
+}
+
+/// Largest reserve a deposit may leave in the pool, keeping the product of
+/// both reserves within a quarter of `u128::MAX` for the curve math
+pub const MAX_RESERVE: u64 = u64::MAX >> 1;
+
+/// Checks that neither reserve exceeds `MAX_RESERVE` after a deposit
+pub fn validate_reserves(reserve_a: u128, reserve_b: u128) -> Result<(), SwapError> {
+    let max_reserve = u128::from(MAX_RESERVE);
+    if reserve_a <= max_reserve && reserve_b <= max_reserve {
+        Ok(())
+    } else {
+        Err(SwapError::ReserveLimitExceeded)
+    }
+}
+
+/// Largest deviation of a deposit from its expected token ratio, in basis
+/// points of the expected ratio
+pub const DEPOSIT_RATIO_TOLERANCE_BPS: u64 = 100;
+
+/// Checks that the deposited whole token A per whole token B, after scaling
+/// each raw amount by its mint's decimals, is within
+/// `DEPOSIT_RATIO_TOLERANCE_BPS` of the expected ratio, unchecked when the
+/// expected denominator is zero
+pub fn validate_deposit_ratio(
+    token_a_amount: u64,
+    token_b_amount: u64,
+    token_a_decimals: u8,
+    token_b_decimals: u8,
+    expected_ratio_numerator: u64,
+    expected_ratio_denominator: u64,
+) -> Result<(), SwapError> {
+    if expected_ratio_denominator == 0 {
+        return Ok(());
+    }
+    // (a / 10^da) / (b / 10^db) against n / d, multiplied through so that
+    // only the amount with fewer decimals is scaled up
+    let scaled = |amount: u64, ratio_term: u64, decimals: u8, other_decimals: u8| {
+        U256::from(10u8)
+            .checked_pow(U256::from(other_decimals.saturating_sub(decimals)))
+            .and_then(|scale| scale.checked_mul(U256::from(amount)))
+            .and_then(|amount| amount.checked_mul(U256::from(ratio_term)))
+            .ok_or(SwapError::CalculationFailure)
+    };
+    // |a / b - n / d| <= tolerance * n / d, without dividing
+    let actual = scaled(
+        token_a_amount,
+        expected_ratio_denominator,
+        token_a_decimals,
+        token_b_decimals,
+    )?;
+    let expected = scaled(
+        token_b_amount,
+        expected_ratio_numerator,
+        token_b_decimals,
+        token_a_decimals,
+    )?;
+    let deviation = actual
+        .max(expected)
+        .checked_sub(actual.min(expected))
+        .and_then(|deviation| deviation.checked_mul(U256::from(BPS_DENOMINATOR)))
+        .ok_or(SwapError::CalculationFailure)?;
+    let tolerance = expected
+        .checked_mul(U256::from(DEPOSIT_RATIO_TOLERANCE_BPS))
+        .ok_or(SwapError::CalculationFailure)?;
+    if deviation <= tolerance {
+        Ok(())
+    } else {
+        Err(SwapError::ExceededSlippage)
+    }
+}
+
+/// Checks that the price band, in basis points, leaves a non-negative
+/// minimum output
+pub fn validate_price_band(price_band_bps: u16) -> Result<(), SwapError> {
+    if u128::from(price_band_bps) <= BPS_DENOMINATOR {
+        Ok(())
+    } else {
+        Err(SwapError::InvalidInput)
+    }
+}
+
+/// Checks that the pool price moved from `price_before` to `price_after` by
+/// at most `max_price_impact_bps` basis points of `price_before`
+pub fn validate_price_impact(
+    price_before: u128,
+    price_after: u128,
+    max_price_impact_bps: u16,
+) -> Result<(), SwapError> {
+    if U256::from(price_before.abs_diff(price_after)) * U256::from(BPS_DENOMINATOR)
+        <= U256::from(max_price_impact_bps) * U256::from(price_before)
+    {
+        Ok(())
+    } else {
+        Err(SwapError::ExcessivePriceImpact)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curve::{ConstantPriceCurve, ConstantProductCurve, OffsetCurve, StableCurve};
+
+    #[test]
+    fn swap_constraints_owner_and_fees() {
+        let owner = Pubkey::new_unique();
+        let owner_key = owner.to_string();
+        let constraints = SwapConstraints {
+            owner_key: &owner_key,
+            max_trade_fee_basis_points: 30,
+            max_owner_fee_basis_points: 5,
+        };
+        assert_eq!(constraints.validate_owner(&owner), Ok(()));
+        assert_eq!(
+            constraints.validate_owner(&Pubkey::new_unique()),
+            Err(SwapError::InvalidOwner)
+        );
+
+        let fees = Fees {
+            trade_fee_numerator: 30,
+            trade_fee_denominator: 10_000,
+            owner_trade_fee_numerator: 5,
+            owner_trade_fee_denominator: 10_000,
+            ..Fees::default()
+        };
+        assert_eq!(constraints.validate_fees(&fees), Ok(()));
+        assert_eq!(
+            constraints.validate_fees(&Fees {
+                trade_fee_numerator: 31,
+                ..fees.clone()
+            }),
+            Err(SwapError::InvalidFee)
+        );
+        assert_eq!(
+            constraints.validate_fees(&Fees {
+                owner_trade_fee_numerator: 6,
+                ..fees
+            }),
+            Err(SwapError::InvalidFee)
+        );
+    }
+
+    #[test]
+    fn validate_fees_bounds() {
+        let fees = |trade_fee_numerator, owner_trade_fee_numerator| Fees {
+            trade_fee_numerator,
+            trade_fee_denominator: 10_000,
+            owner_trade_fee_numerator,
+            owner_trade_fee_denominator: 10_000,
+            ..Fees::default()
+        };
+        assert_eq!(validate_fees(&fees(MIN_TRADE_FEE_NUMERATOR, 0)), Ok(()));
+        assert_eq!(
+            validate_fees(&fees(MIN_TRADE_FEE_NUMERATOR - 1, 0)),
+            Err(SwapError::InvalidFee.into())
+        );
+        assert_eq!(validate_fees(&fees(MAX_TRADE_FEE_BASIS_POINTS, 0)), Ok(()));
+        assert_eq!(
+            validate_fees(&fees(MAX_TRADE_FEE_BASIS_POINTS + 1, 0)),
+            Err(SwapError::InvalidFee.into())
+        );
+        assert_eq!(validate_fees(&fees(1, MAX_OWNER_FEE_BASIS_POINTS)), Ok(()));
+        assert_eq!(
+            validate_fees(&fees(1, MAX_OWNER_FEE_BASIS_POINTS + 1)),
+            Err(SwapError::InvalidFee.into())
+        );
+
+        // a disabled owner fee is accepted, a trade fee cannot be disabled
+        let no_owner_fee = Fees {
+            owner_trade_fee_denominator: 0,
+            ..fees(1, 0)
+        };
+        assert_eq!(validate_fees(&no_owner_fee), Ok(()));
+        let no_trade_fee = Fees {
+            trade_fee_denominator: 0,
+            ..fees(1, 0)
+        };
+        assert_eq!(
+            validate_fees(&no_trade_fee),
+            Err(SwapError::InvalidFee.into())
+        );
+    }
+
+    #[test]
+    fn validate_reserves_limit() {
+        let max_reserve = u128::from(MAX_RESERVE);
+        assert_eq!(validate_reserves(max_reserve, max_reserve), Ok(()));
+        assert!(max_reserve * max_reserve <= u128::MAX / 4);
+        assert_eq!(
+            validate_reserves(max_reserve + 1, 1),
+            Err(SwapError::ReserveLimitExceeded)
+        );
+        assert_eq!(
+            validate_reserves(1, max_reserve + 1),
+            Err(SwapError::ReserveLimitExceeded)
+        );
+    }
+
+    #[test]
+    fn validate_deposit_ratio_tolerance() {
+        // no expected ratio
+        assert_eq!(validate_deposit_ratio(100, 300, 2, 2, 0, 0), Ok(()));
+        assert_eq!(validate_deposit_ratio(100, 200, 2, 2, 1, 2), Ok(()));
+        // 1% off either way
+        assert_eq!(validate_deposit_ratio(101, 200, 2, 2, 1, 2), Ok(()));
+        assert_eq!(validate_deposit_ratio(99, 200, 2, 2, 1, 2), Ok(()));
+        assert_eq!(
+            validate_deposit_ratio(102, 200, 2, 2, 1, 2),
+            Err(SwapError::ExceededSlippage)
+        );
+        assert_eq!(
+            validate_deposit_ratio(98, 200, 2, 2, 1, 2),
+            Err(SwapError::ExceededSlippage)
+        );
+        assert_eq!(
+            validate_deposit_ratio(u64::MAX, u64::MAX, 2, 2, u64::MAX, u64::MAX),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn validate_deposit_ratio_decimals() {
+        // 1 token A at 6 decimals for 3 token B at 9 decimals is 1 / 3
+        assert_eq!(
+            validate_deposit_ratio(1_000_000, 3_000_000_000, 6, 9, 1, 3),
+            Ok(())
+        );
+        assert_eq!(
+            validate_deposit_ratio(3_000_000_000, 1_000_000, 9, 6, 3, 1),
+            Ok(())
+        );
+        // the raw ratio is a thousand times off
+        assert_eq!(
+            validate_deposit_ratio(1_000_000, 3_000_000_000, 6, 9, 1, 3_000),
+            Err(SwapError::ExceededSlippage)
+        );
+        assert_eq!(
+            validate_deposit_ratio(1_020_000, 3_000_000_000, 6, 9, 1, 3),
+            Err(SwapError::ExceededSlippage)
+        );
+        assert_eq!(
+            validate_deposit_ratio(1, 1, 0, u8::MAX, 1, 1),
+            Err(SwapError::CalculationFailure)
+        );
+    }
+
+    #[test]
+    fn validate_price_impact_tolerance() {
+        // 3% either way
+        assert_eq!(validate_price_impact(1000, 1030, 300), Ok(()));
+        assert_eq!(validate_price_impact(1000, 970, 300), Ok(()));
+        assert_eq!(
+            validate_price_impact(1000, 1031, 300),
+            Err(SwapError::ExcessivePriceImpact)
+        );
+        assert_eq!(
+            validate_price_impact(1000, 969, 300),
+            Err(SwapError::ExcessivePriceImpact)
+        );
+        assert_eq!(validate_price_impact(u128::MAX, 0, 10_000), Ok(()));
+    }
+
+    #[test]
+    fn validate_supply_per_curve() {
+        let constant_product = ConstantProductCurve;
+        assert_eq!(validate_supply(&constant_product, 1, 1), Ok(()));
+        assert_eq!(
+            validate_supply(&constant_product, 0, 1),
+            Err(SwapError::EmptySupply)
+        );
+        assert_eq!(
+            validate_supply(&constant_product, 1, 0),
+            Err(SwapError::EmptySupply)
+        );
+        assert_eq!(
+            validate_supply(&constant_product, 0, 0),
+            Err(SwapError::EmptySupply)
+        );
+
+        let offset = OffsetCurve {
+            token_b_offset: 1_000,
+        };
+        assert_eq!(validate_supply(&offset, 1, 1), Ok(()));
+        assert_eq!(validate_supply(&offset, 1, 0), Ok(()));
+        assert_eq!(validate_supply(&offset, 0, 1), Err(SwapError::EmptySupply));
+        assert_eq!(validate_supply(&offset, 0, 0), Err(SwapError::EmptySupply));
+
+        let constant_price = ConstantPriceCurve {
+            token_a_price: 2,
+            token_a_decimals: 6,
+            token_b_decimals: 6,
+        };
+        assert_eq!(validate_supply(&constant_price, 1, 1), Ok(()));
+        assert_eq!(validate_supply(&constant_price, 1, 0), Ok(()));
+        assert_eq!(
+            validate_supply(&constant_price, 0, 1),
+            Err(SwapError::EmptySupply)
+        );
+        assert_eq!(
+            validate_supply(&constant_price, 0, 0),
+            Err(SwapError::EmptySupply)
+        );
+
+        let stable = StableCurve { amp: 100 };
+        assert_eq!(validate_supply(&stable, 1, 1), Ok(()));
+        assert_eq!(validate_supply(&stable, 0, 1), Err(SwapError::EmptySupply));
+        assert_eq!(validate_supply(&stable, 1, 0), Err(SwapError::EmptySupply));
+        assert_eq!(validate_supply(&stable, 0, 0), Err(SwapError::EmptySupply));
+    }
+}
@@ -0,0 +1,765 @@
+//! Swap curves, calculating the amounts exchanged for a given invariant
+
+use crate::{
+    error::SwapError,
+    fees::{FeeRounding, Fees},
+    state::PRICE_SCALE,
+};
+use num_enum::TryFromPrimitive;
+use spl_math::precise_number::PreciseNumber;
+use std::fmt::Debug;
+
+pub mod stable;
+
+pub use stable::StableCurve;
+
+/// Curve types supported by the token-swap program.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, TryFromPrimitive)]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CurveType {
+    /// Uniswap-style constant product curve, invariant = token_a_amount * token_b_amount
+    ConstantProduct = 0,
+    /// Offset curve, like Uniswap, but the token B side has a faked offset
+    Offset = 1,
+    /// Constant price curve, always trading at a fixed price set in whole
+    /// tokens
+    ConstantPrice = 2,
+    /// StableSwap curve for correlated assets, amplified around balanced
+    /// reserves
+    Stable = 3,
+}
+
+// `#[default]` would make `TryFromPrimitive` accept any unknown value
+#[allow(clippy::derivable_impls)]
+impl Default for CurveType {
+    fn default() -> Self {
+        CurveType::ConstantProduct
+    }
+}
+
+/// The direction of a trade, since curves can be specialized to treat each
+/// token differently (by adding offsets or weights)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TradeDirection {
+    /// Input token A, output token B
+    AtoB,
+    /// Input token B, output token A
+    BtoA,
+}
+
+/// Outcome of a swap through a curve, with the fees charged on the source
+/// amount. Both fees stay in the pool, the trade fee for liquidity providers
+/// and the owner fee owed to the owner as pool tokens.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SwapResult {
+    /// Source token reserve after the swap
+    pub new_source_amount: u128,
+    /// Destination token reserve after the swap
+    pub new_destination_amount: u128,
+    /// Source token taken from the user, fees included
+    pub source_amount_swapped: u128,
+    /// Destination token paid out to the user
+    pub destination_amount_swapped: u128,
+    /// Trading fee charged on the source amount, kept in the pool
+    pub trade_fee: u128,
+    /// Owner fee charged on the source amount, kept in the pool
+    pub owner_fee: u128,
+}
+
+impl SwapResult {
+    /// Source token traded through the curve, net of all fees
+    pub fn source_amount_traded(&self) -> Option<u128> {
+        self.source_amount_swapped
+            .checked_sub(self.trade_fee)?
+            .checked_sub(self.owner_fee)
+    }
+}
+
+/// Trait for the calculations behind a swap curve
+pub trait SwapCurve: Debug {
+    /// Swap `source_amount` against the given reserves, charging the trade
+    /// and owner fees on the source amount, rounded as chosen by the curve,
+    /// before the rest goes through the curve. The fees are added to the
+    /// source reserve without being traded. A larger `source_amount` never
+    /// yields a smaller destination amount: the fees are rounded once on
+    /// their sum, with the owner fee taking what remains after the trade
+    /// fee, and the curves round their output consistently.
+    fn swap(
+        &self,
+        source_amount: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        trade_direction: TradeDirection,
+        fees: &Fees,
+    ) -> Option<SwapResult> {
+        let trade_fee = fees
+            .trading_fee_rounded(source_amount, self.fee_rounding())
+            .unwrap_or(0u128);
+        let total_fee = fees
+            .total_trading_fee_rounded(source_amount, self.fee_rounding())
+            .unwrap_or(trade_fee);
+        let owner_fee = total_fee.saturating_sub(trade_fee);
+        let amount_in = source_amount
+            .checked_sub(trade_fee)?
+            .checked_sub(owner_fee)?;
+        let destination_amount_swapped = self.swap_without_fees(
+            amount_in,
+            swap_source_amount,
+            swap_destination_amount,
+            trade_direction,
+        )?;
+        Some(SwapResult {
+            new_source_amount: swap_source_amount.checked_add(source_amount)?,
+            new_destination_amount: swap_destination_amount
+                .checked_sub(destination_amount_swapped)?,
+            source_amount_swapped: source_amount,
+            destination_amount_swapped,
+            trade_fee,
+            owner_fee,
+        })
+    }
+
+    /// Calculate how much destination token will be provided given an amount
+    /// of source token, without fees
+    fn swap_without_fees(
+        &self,
+        source_amount: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<u128>;
+
+    /// Marginal price of raw token A in raw token B at the given reserves,
+    /// scaled by `PRICE_SCALE` and rounded down, so pools on different
+    /// curves can be ranked on one scale. `None` if the price is undefined
+    /// at these reserves or overflows.
+    fn normalized_value(
+        &self,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+    ) -> Option<u128>;
+
+    /// Pool tokens to burn for withdrawing exactly `destination_amount` of
+    /// the destination token alone, rounded up. The withdrawal is treated as
+    /// a proportional withdrawal of both tokens followed by a swap of the
+    /// source side in `trade_direction`, with the trading fee charged on
+    /// half the destination amount as the swapped portion. `None` if the
+    /// curve does not support single-sided withdrawals.
+    fn withdraw_single_token_type(
+        &self,
+        destination_amount: u128,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+        pool_supply: u128,
+        trade_direction: TradeDirection,
+        fees: &Fees,
+    ) -> Option<u128> {
+        let half_destination_amount = std::cmp::max(1, destination_amount.checked_div(2)?);
+        let trade_fee = fees.trading_fee_rounded(half_destination_amount, self.fee_rounding())?;
+        self.withdraw_single_token_type_without_fees(
+            destination_amount.checked_add(trade_fee)?,
+            swap_token_a_amount,
+            swap_token_b_amount,
+            pool_supply,
+            trade_direction,
+        )
+    }
+
+    /// Pool tokens to burn for withdrawing `destination_amount` of the
+    /// destination token alone, without fees, unsupported by default
+    fn withdraw_single_token_type_without_fees(
+        &self,
+        _destination_amount: u128,
+        _swap_token_a_amount: u128,
+        _swap_token_b_amount: u128,
+        _pool_supply: u128,
+        _trade_direction: TradeDirection,
+    ) -> Option<u128> {
+        None
+    }
+
+    /// Pool tokens worth an owner fee of `owner_fee` source token, out of
+    /// `pool_supply`, given the source reserve with the fee included. The
+    /// source reserve is valued as half the pool and the result rounded
+    /// down, which never pays out more than a single-sided constant product
+    /// deposit would mint.
+    fn owner_fee_pool_tokens(
+        &self,
+        owner_fee: u128,
+        swap_source_amount: u128,
+        pool_supply: u128,
+    ) -> Option<u128> {
+        owner_fee
+            .checked_mul(pool_supply)?
+            .checked_div(swap_source_amount.checked_mul(2)?)
+    }
+
+    /// Rounding of the trading fee charged by swaps on this curve, up by
+    /// default so a non-zero fee is never waived
+    fn fee_rounding(&self) -> FeeRounding {
+        FeeRounding::Ceil
+    }
+
+    /// Validate the curve parameters
+    fn validate(&self) -> Result<(), SwapError> {
+        Ok(())
+    }
+
+    /// Validate the given supply on initialization, requiring a non-zero
+    /// supply on both sides by default
+    fn validate_supply(&self, token_a_amount: u64, token_b_amount: u64) -> Result<(), SwapError> {
+        if token_a_amount == 0 {
+            return Err(SwapError::EmptySupply);
+        }
+        if token_b_amount == 0 {
+            return Err(SwapError::EmptySupply);
+        }
+        Ok(())
+    }
+}
+
+/// Builds the curve of the given type from its stored parameter and the
+/// decimals of both mints, rejecting invalid combinations
+pub fn build_swap_curve(
+    curve_type: CurveType,
+    curve_parameter: u64,
+    token_a_decimals: u8,
+    token_b_decimals: u8,
+) -> Result<Box<dyn SwapCurve>, SwapError> {
+    let curve: Box<dyn SwapCurve> = match curve_type {
+        CurveType::ConstantProduct => {
+            if curve_parameter != 0 {
+                return Err(SwapError::InvalidCurve);
+            }
+            Box::new(ConstantProductCurve)
+        }
+        CurveType::Offset => Box::new(OffsetCurve {
+            token_b_offset: curve_parameter,
+        }),
+        CurveType::ConstantPrice => Box::new(ConstantPriceCurve {
+            token_a_price: curve_parameter,
+            token_a_decimals,
+            token_b_decimals,
+        }),
+        CurveType::Stable => Box::new(StableCurve {
+            amp: curve_parameter,
+        }),
+    };
+    curve.validate()?;
+    Ok(curve)
+}
+
+/// Output of a constant product swap, rounded down
+fn constant_product_swap(
+    source_amount: u128,
+    swap_source_amount: u128,
+    swap_destination_amount: u128,
+) -> Option<u128> {
+    // x * y = k
+    // (x + amount_in) * (y - amount_out) = k
+    // amount_out = y - k / (x + amount_in)
+    //             = y - x * y / (x + amount_in)
+    // with the new destination reserve rounded up so k never decreases
+    let invariant = swap_source_amount.checked_mul(swap_destination_amount)?;
+    let new_swap_source_amount = swap_source_amount.checked_add(source_amount)?;
+    let mut new_swap_destination_amount = invariant.checked_div(new_swap_source_amount)?;
+    if new_swap_destination_amount.checked_mul(new_swap_source_amount)? < invariant {
+        new_swap_destination_amount = new_swap_destination_amount.checked_add(1)?;
+    }
+    swap_destination_amount.checked_sub(new_swap_destination_amount)
+}
+
+/// Constant product curve
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConstantProductCurve;
+
+impl SwapCurve for ConstantProductCurve {
+    fn swap_without_fees(
+        &self,
+        source_amount: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        _trade_direction: TradeDirection,
+    ) -> Option<u128> {
+        constant_product_swap(source_amount, swap_source_amount, swap_destination_amount)
+    }
+
+    /// `y / x`
+    fn normalized_value(
+        &self,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+    ) -> Option<u128> {
+        swap_token_b_amount
+            .checked_mul(PRICE_SCALE)?
+            .checked_div(swap_token_a_amount)
+    }
+
+    fn withdraw_single_token_type_without_fees(
+        &self,
+        destination_amount: u128,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+        pool_supply: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<u128> {
+        // the pool share s leaving with both tokens, once the source side is
+        // swapped in, delivers D = R * (1 - (1 - s)^2) of the destination
+        // reserve R, so s = 1 - sqrt(1 - D / R)
+        let swap_destination_amount = match trade_direction {
+            TradeDirection::AtoB => swap_token_b_amount,
+            TradeDirection::BtoA => swap_token_a_amount,
+        };
+        let one = PreciseNumber::new(1)?;
+        let ratio = PreciseNumber::new(destination_amount)?
+            .checked_div(&PreciseNumber::new(swap_destination_amount)?)?;
+        let share = one.checked_sub(&one.checked_sub(&ratio)?.sqrt()?)?;
+        PreciseNumber::new(pool_supply)?
+            .checked_mul(&share)?
+            .ceiling()?
+            .to_imprecise()
+    }
+}
+
+/// Offset curve, where token B behaves as if it held `token_b_offset` more
+/// tokens, so the invariant is `x * (y + offset) = k`. Allows launching a
+/// pool with no real token B.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OffsetCurve {
+    /// Amount to offset the token B liquidity account
+    pub token_b_offset: u64,
+}
+
+impl SwapCurve for OffsetCurve {
+    fn swap_without_fees(
+        &self,
+        source_amount: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<u128> {
+        let token_b_offset = u128::from(self.token_b_offset);
+        match trade_direction {
+            TradeDirection::AtoB => {
+                let amount_out = constant_product_swap(
+                    source_amount,
+                    swap_source_amount,
+                    swap_destination_amount.checked_add(token_b_offset)?,
+                )?;
+                // only the real token B can leave the pool
+                if amount_out > swap_destination_amount {
+                    None
+                } else {
+                    Some(amount_out)
+                }
+            }
+            TradeDirection::BtoA => constant_product_swap(
+                source_amount,
+                swap_source_amount.checked_add(token_b_offset)?,
+                swap_destination_amount,
+            ),
+        }
+    }
+
+    /// `(y + offset) / x`, token B priced with its offset
+    fn normalized_value(
+        &self,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+    ) -> Option<u128> {
+        swap_token_b_amount
+            .checked_add(u128::from(self.token_b_offset))?
+            .checked_mul(PRICE_SCALE)?
+            .checked_div(swap_token_a_amount)
+    }
+
+    fn validate(&self) -> Result<(), SwapError> {
+        if self.token_b_offset == 0 {
+            Err(SwapError::InvalidCurve)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The offset fakes the token B supply, so only token A must be provided
+    fn validate_supply(&self, token_a_amount: u64, _token_b_amount: u64) -> Result<(), SwapError> {
+        if token_a_amount == 0 {
+            return Err(SwapError::EmptySupply);
+        }
+        Ok(())
+    }
+}
+
+/// Constant price curve, where one whole token A always trades for
+/// `token_a_price` whole token B. The decimals of both mints scale the price
+/// to raw token amounts.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConstantPriceCurve {
+    /// Amount of whole token B one whole token A is worth
+    pub token_a_price: u64,
+    /// Decimals of the token A mint
+    pub token_a_decimals: u8,
+    /// Decimals of the token B mint
+    pub token_b_decimals: u8,
+}
+
+impl ConstantPriceCurve {
+    /// Raw token B per raw token A, as a numerator and denominator
+    fn raw_price(&self) -> Option<(u128, u128)> {
+        let numerator = u128::from(self.token_a_price)
+            .checked_mul(10u128.checked_pow(u32::from(self.token_b_decimals))?)?;
+        let denominator = 10u128.checked_pow(u32::from(self.token_a_decimals))?;
+        Some((numerator, denominator))
+    }
+}
+
+impl SwapCurve for ConstantPriceCurve {
+    fn swap_without_fees(
+        &self,
+        source_amount: u128,
+        _swap_source_amount: u128,
+        swap_destination_amount: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<u128> {
+        let (price_numerator, price_denominator) = self.raw_price()?;
+        let amount_out = match trade_direction {
+            TradeDirection::AtoB => source_amount
+                .checked_mul(price_numerator)?
+                .checked_div(price_denominator)?,
+            TradeDirection::BtoA => source_amount
+                .checked_mul(price_denominator)?
+                .checked_div(price_numerator)?,
+        };
+        // the price never moves, so the pool can run dry
+        if amount_out > swap_destination_amount {
+            None
+        } else {
+            Some(amount_out)
+        }
+    }
+
+    /// The fixed price, whatever the reserves
+    fn normalized_value(
+        &self,
+        _swap_token_a_amount: u128,
+        _swap_token_b_amount: u128,
+    ) -> Option<u128> {
+        let (price_numerator, price_denominator) = self.raw_price()?;
+        price_numerator
+            .checked_mul(PRICE_SCALE)?
+            .checked_div(price_denominator)
+    }
+
+    fn validate(&self) -> Result<(), SwapError> {
+        if self.token_a_price == 0 || self.raw_price().is_none() {
+            Err(SwapError::InvalidCurve)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The price is fixed rather than set by the reserves, so a launch may
+    /// provide only token A
+    fn validate_supply(&self, token_a_amount: u64, _token_b_amount: u64) -> Result<(), SwapError> {
+        if token_a_amount == 0 {
+            return Err(SwapError::EmptySupply);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_product_swap_rounds_down() {
+        let curve = ConstantProductCurve;
+        // 2000 - 1000 * 2000 / 1010 = 2000 - 1980.2, the pool keeping 1981
+        assert_eq!(
+            curve.swap_without_fees(10, 1000, 2000, TradeDirection::AtoB),
+            Some(19)
+        );
+        assert_eq!(
+            curve.swap_without_fees(10, 1000, 2000, TradeDirection::BtoA),
+            Some(19)
+        );
+    }
+
+    #[test]
+    fn constant_product_swap_result() {
+        let curve = ConstantProductCurve;
+        let fees = Fees {
+            trade_fee_numerator: 1,
+            trade_fee_denominator: 100,
+            owner_trade_fee_numerator: 1,
+            owner_trade_fee_denominator: 50,
+            ..Fees::default()
+        };
+        // 3.3 token total fee rounds up to 4, of which the 1.1 token trade
+        // fee rounded up takes 2 and the owner fee the other 2. 106 token A
+        // traded for 2000 - 1000 * 2000 / 1106 = 2000 - 1808.32 token B out,
+        // rounded to 191, with both fees kept in the pool
+        let result = curve
+            .swap(110, 1000, 2000, TradeDirection::AtoB, &fees)
+            .unwrap();
+        assert_eq!(
+            result,
+            SwapResult {
+                new_source_amount: 1110,
+                new_destination_amount: 1809,
+                source_amount_swapped: 110,
+                destination_amount_swapped: 191,
+                trade_fee: 2,
+                owner_fee: 2,
+            }
+        );
+        assert_eq!(result.source_amount_traded(), Some(106));
+        // 2 / 2220 of the pool
+        assert_eq!(curve.owner_fee_pool_tokens(2, 1110, 1_000_000), Some(900));
+
+        // the 0.06 token total fee rounds up to a single token, all of it
+        // trade fee, and the other token is traded for 2000 - 1998.002
+        // token B rounded down to 1
+        let result = curve
+            .swap(2, 1000, 2000, TradeDirection::AtoB, &fees)
+            .unwrap();
+        assert_eq!(result.trade_fee, 1);
+        assert_eq!(result.owner_fee, 0);
+        assert_eq!(result.destination_amount_swapped, 1);
+    }
+
+    #[test]
+    fn constant_product_withdraw_single_token_type() {
+        let curve = ConstantProductCurve;
+        let fees = Fees {
+            trade_fee_numerator: 1,
+            trade_fee_denominator: 100,
+            ..Fees::default()
+        };
+
+        // 100 token B out, with a fee of 1 on the 50 swapped from A:
+        // 1_000_000 * (1 - sqrt(1 - 101 / 2000)) = 25_577.09
+        assert_eq!(
+            curve.withdraw_single_token_type(
+                100,
+                1000,
+                2000,
+                1_000_000,
+                TradeDirection::AtoB,
+                &fees
+            ),
+            Some(25_578)
+        );
+        // 50 token A out, with a fee of 1 on the 25 swapped from B:
+        // 1_000_000 * (1 - sqrt(1 - 51 / 1000)) = 25_833.69
+        assert_eq!(
+            curve.withdraw_single_token_type(
+                50,
+                1000,
+                2000,
+                1_000_000,
+                TradeDirection::BtoA,
+                &fees
+            ),
+            Some(25_834)
+        );
+        // without fees: 1_000_000 * (1 - sqrt(1 - 100 / 2000)) = 25_320.57
+        assert_eq!(
+            curve.withdraw_single_token_type(
+                100,
+                1000,
+                2000,
+                1_000_000,
+                TradeDirection::AtoB,
+                &Fees::default()
+            ),
+            Some(25_321)
+        );
+
+        // more than the whole reserve
+        assert_eq!(
+            curve.withdraw_single_token_type(
+                2001,
+                1000,
+                2000,
+                1_000_000,
+                TradeDirection::AtoB,
+                &Fees::default()
+            ),
+            None
+        );
+        // unsupported by other curves
+        let offset = OffsetCurve {
+            token_b_offset: 1_000,
+        };
+        assert_eq!(
+            offset.withdraw_single_token_type(
+                100,
+                1000,
+                2000,
+                1_000_000,
+                TradeDirection::AtoB,
+                &fees
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn offset_swap_shifts_price() {
+        let curve = OffsetCurve {
+            token_b_offset: 1_000_000,
+        };
+        // no real token B, selling it into the pool prices against the offset
+        // 1000 - 1000 * 1_000_000 / 1_010_000 = 1000 - 990.1
+        assert_eq!(
+            curve.swap_without_fees(10_000, 0, 1000, TradeDirection::BtoA),
+            Some(9)
+        );
+        // token A in cannot take out more than the real token B
+        assert_eq!(
+            curve.swap_without_fees(10, 1000, 0, TradeDirection::AtoB),
+            None
+        );
+        // 1_002_000 - 1000 * 1_002_000 / 1100 exceeds the 2_000 real token B
+        assert_eq!(
+            curve.swap_without_fees(100, 1000, 2_000, TradeDirection::AtoB),
+            None
+        );
+        assert_eq!(
+            curve.swap_without_fees(100, 1000, 100_000, TradeDirection::AtoB),
+            Some(100_000)
+        );
+    }
+
+    #[test]
+    fn constant_price_swap_normalizes_decimals() {
+        // 1 A = 100 B, A with 6 decimals and B with 9
+        let curve = ConstantPriceCurve {
+            token_a_price: 100,
+            token_a_decimals: 6,
+            token_b_decimals: 9,
+        };
+        // 1.5 A for 150 B
+        assert_eq!(
+            curve.swap_without_fees(1_500_000, 0, 1_000_000_000_000, TradeDirection::AtoB),
+            Some(150_000_000_000)
+        );
+        // 250 B for 2.5 A
+        assert_eq!(
+            curve.swap_without_fees(250_000_000_000, 0, 10_000_000, TradeDirection::BtoA),
+            Some(2_500_000)
+        );
+        // less than a raw token A worth of B rounds down to nothing
+        assert_eq!(
+            curve.swap_without_fees(99_999, 0, 10_000_000, TradeDirection::BtoA),
+            Some(0)
+        );
+        // the pool cannot give more than it holds
+        assert_eq!(
+            curve.swap_without_fees(1_500_000, 0, 100_000_000_000, TradeDirection::AtoB),
+            None
+        );
+
+        // the same human price, A with 9 decimals and B with 6
+        let curve = ConstantPriceCurve {
+            token_a_price: 100,
+            token_a_decimals: 9,
+            token_b_decimals: 6,
+        };
+        assert_eq!(
+            curve.swap_without_fees(1_500_000_000, 0, 1_000_000_000, TradeDirection::AtoB),
+            Some(150_000_000)
+        );
+        assert_eq!(
+            curve.swap_without_fees(250_000_000, 0, 10_000_000_000, TradeDirection::BtoA),
+            Some(2_500_000_000)
+        );
+    }
+
+    #[test]
+    fn normalized_value_compares_curves() {
+        let price = |curve: &dyn SwapCurve, reserve_a, reserve_b| {
+            curve.normalized_value(reserve_a, reserve_b).unwrap()
+        };
+        let constant_product = ConstantProductCurve;
+
+        // 1 A = 2 B, priced by reserves, by an offset or fixed
+        let two = 2 * PRICE_SCALE;
+        assert_eq!(price(&constant_product, 1000, 2000), two);
+        let offset = OffsetCurve {
+            token_b_offset: 1000,
+        };
+        assert_eq!(price(&offset, 1000, 1000), two);
+        assert_eq!(price(&offset, 1000, 0), PRICE_SCALE);
+        let constant_price = ConstantPriceCurve {
+            token_a_price: 2,
+            token_a_decimals: 6,
+            token_b_decimals: 6,
+        };
+        assert_eq!(price(&constant_price, 1000, 2000), two);
+        assert_eq!(price(&constant_price, 0, 0), two);
+
+        // raw amounts are compared, so decimals scale the fixed price
+        let constant_price = ConstantPriceCurve {
+            token_a_price: 2,
+            token_a_decimals: 6,
+            token_b_decimals: 9,
+        };
+        assert_eq!(
+            price(&constant_price, 0, 0),
+            price(&constant_product, 1_000_000, 2_000_000_000)
+        );
+
+        // balanced stable reserves trade one for one
+        let stable = StableCurve { amp: 100 };
+        assert_eq!(price(&stable, 1_000_000, 1_000_000), PRICE_SCALE);
+        assert_eq!(price(&constant_product, 1_000_000, 1_000_000), PRICE_SCALE);
+
+        // with token A plentiful, the stable price falls far less
+        let stable_price = price(&stable, 3_000_000, 1_000_000);
+        assert!(stable_price < PRICE_SCALE);
+        assert!(stable_price > price(&constant_product, 3_000_000, 1_000_000));
+
+        // no price without token A
+        assert_eq!(constant_product.normalized_value(0, 1000), None);
+        assert_eq!(offset.normalized_value(0, 1000), None);
+        assert_eq!(stable.normalized_value(0, 1000), None);
+    }
+
+    #[test]
+    fn build_swap_curve_validates_parameter() {
+        assert!(build_swap_curve(CurveType::ConstantProduct, 0, 6, 9).is_ok());
+        assert_eq!(
+            build_swap_curve(CurveType::ConstantProduct, 1, 6, 9).unwrap_err(),
+            SwapError::InvalidCurve
+        );
+        assert!(build_swap_curve(CurveType::Offset, 1, 6, 9).is_ok());
+        assert_eq!(
+            build_swap_curve(CurveType::Offset, 0, 6, 9).unwrap_err(),
+            SwapError::InvalidCurve
+        );
+        assert!(build_swap_curve(CurveType::ConstantPrice, 100, 6, 9).is_ok());
+        assert_eq!(
+            build_swap_curve(CurveType::ConstantPrice, 0, 6, 9).unwrap_err(),
+            SwapError::InvalidCurve
+        );
+        // the decimals scale overflows
+        assert_eq!(
+            build_swap_curve(CurveType::ConstantPrice, 100, 6, 39).unwrap_err(),
+            SwapError::InvalidCurve
+        );
+        assert!(build_swap_curve(CurveType::Stable, 100, 6, 9).is_ok());
+        assert_eq!(
+            build_swap_curve(CurveType::Stable, 0, 6, 9).unwrap_err(),
+            SwapError::InvalidCurve
+        );
+        assert!(CurveType::try_from_primitive(4).is_err());
+    }
+}
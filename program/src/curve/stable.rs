@@ -0,0 +1,265 @@
+//! StableSwap curve for correlated assets, trading close to one for one
+//! around balanced reserves and like constant product when unbalanced
+
+use crate::{
+    curve::{SwapCurve, TradeDirection},
+    error::SwapError,
+    state::PRICE_SCALE,
+};
+use spl_math::precise_number::PreciseNumber;
+
+/// Largest amplification coefficient accepted
+pub const MAX_AMP: u64 = 1_000_000;
+
+/// Newton iterations allowed before giving up on an invariant or a reserve
+const MAX_ITERATIONS: usize = 64;
+
+/// Number of tokens in the pool
+const N_COINS: u128 = 2;
+
+/// StableSwap curve, with the invariant
+/// `A * n^n * (x + y) + D = A * n^n * D + D^(n + 1) / (n^n * x * y)`
+/// for an amplification coefficient `A`. Solved with `u128` math, so very
+/// large reserves under a high amplification fail to swap rather than
+/// overflow.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StableCurve {
+    /// Amplification coefficient, higher values flatten the curve around
+    /// balanced reserves
+    pub amp: u64,
+}
+
+impl StableCurve {
+    /// `A * n^n`
+    fn leverage(&self) -> Result<u128, SwapError> {
+        u128::from(self.amp)
+            .checked_mul(N_COINS * N_COINS)
+            .ok_or(SwapError::CalculationFailure)
+    }
+
+    /// Invariant `D` of the given reserves, found by Newton's method
+    pub fn compute_d(&self, amount_a: u128, amount_b: u128) -> Result<u128, SwapError> {
+        let sum = amount_a
+            .checked_add(amount_b)
+            .ok_or(SwapError::CalculationFailure)?;
+        if sum == 0 {
+            return Ok(0);
+        }
+        let leverage = self.leverage()?;
+        let mut d = sum;
+        for _ in 0..MAX_ITERATIONS {
+            // d_p = D^(n + 1) / (n^n * x * y)
+            let d_p = d
+                .checked_mul(d)
+                .and_then(|d_p| d_p.checked_div(amount_a.checked_mul(N_COINS)?))
+                .and_then(|d_p| d_p.checked_mul(d))
+                .and_then(|d_p| d_p.checked_div(amount_b.checked_mul(N_COINS)?))
+                .ok_or(SwapError::CalculationFailure)?;
+            let previous_d = d;
+            // D = (Ann * S + n * d_p) * D / ((Ann - 1) * D + (n + 1) * d_p)
+            let numerator = leverage
+                .checked_mul(sum)
+                .and_then(|numerator| numerator.checked_add(d_p.checked_mul(N_COINS)?))
+                .and_then(|numerator| numerator.checked_mul(d))
+                .ok_or(SwapError::CalculationFailure)?;
+            let denominator = leverage
+                .checked_sub(1)
+                .and_then(|denominator| denominator.checked_mul(d))
+                .and_then(|denominator| {
+                    denominator.checked_add(d_p.checked_mul(N_COINS.checked_add(1)?)?)
+                })
+                .ok_or(SwapError::CalculationFailure)?;
+            d = numerator
+                .checked_div(denominator)
+                .ok_or(SwapError::CalculationFailure)?;
+            if d.abs_diff(previous_d) <= 1 {
+                return Ok(d);
+            }
+        }
+        Err(SwapError::CalculationFailure)
+    }
+
+    /// Reserve of the other token keeping the invariant `d` once one token's
+    /// reserve is `new_amount`, found by Newton's method
+    pub fn compute_y(&self, new_amount: u128, d: u128) -> Result<u128, SwapError> {
+        let leverage = self.leverage()?;
+        // c = D^(n + 1) / (n^n * x * Ann)
+        let c = d
+            .checked_mul(d)
+            .and_then(|c| c.checked_div(new_amount.checked_mul(N_COINS)?))
+            .and_then(|c| c.checked_mul(d))
+            .and_then(|c| c.checked_div(leverage.checked_mul(N_COINS)?))
+            .ok_or(SwapError::CalculationFailure)?;
+        // b = x + D / Ann
+        let b = d
+            .checked_div(leverage)
+            .and_then(|b| b.checked_add(new_amount))
+            .ok_or(SwapError::CalculationFailure)?;
+        let mut y = d;
+        for _ in 0..MAX_ITERATIONS {
+            let previous_y = y;
+            // y = (y^2 + c) / (2y + b - D)
+            let numerator = y
+                .checked_mul(y)
+                .and_then(|numerator| numerator.checked_add(c))
+                .ok_or(SwapError::CalculationFailure)?;
+            let denominator = y
+                .checked_mul(2)
+                .and_then(|denominator| denominator.checked_add(b))
+                .and_then(|denominator| denominator.checked_sub(d))
+                .ok_or(SwapError::CalculationFailure)?;
+            y = numerator
+                .checked_div(denominator)
+                .ok_or(SwapError::CalculationFailure)?;
+            if y.abs_diff(previous_y) <= 1 {
+                return Ok(y);
+            }
+        }
+        Err(SwapError::CalculationFailure)
+    }
+}
+
+impl SwapCurve for StableCurve {
+    fn swap_without_fees(
+        &self,
+        source_amount: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        _trade_direction: TradeDirection,
+    ) -> Option<u128> {
+        let d = self
+            .compute_d(swap_source_amount, swap_destination_amount)
+            .ok()?;
+        let new_swap_source_amount = swap_source_amount.checked_add(source_amount)?;
+        let new_swap_destination_amount = self.compute_y(new_swap_source_amount, d).ok()?;
+        // the solution is only within one token, keep it in the pool
+        swap_destination_amount
+            .checked_sub(new_swap_destination_amount)?
+            .checked_sub(1)
+    }
+
+    /// Ratio of the invariant's partial derivatives in x and y,
+    /// `(Ann + D^3 / (4 * x^2 * y)) / (Ann + D^3 / (4 * x * y^2))`
+    fn normalized_value(
+        &self,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+    ) -> Option<u128> {
+        if swap_token_a_amount == 0 || swap_token_b_amount == 0 {
+            return None;
+        }
+        let d = PreciseNumber::new(
+            self.compute_d(swap_token_a_amount, swap_token_b_amount)
+                .ok()?,
+        )?;
+        let leverage = PreciseNumber::new(self.leverage().ok()?)?;
+        // n^n
+        let four = PreciseNumber::new(N_COINS * N_COINS)?;
+        // D / x and D / y keep the cubes within range
+        let d_over_a = d.checked_div(&PreciseNumber::new(swap_token_a_amount)?)?;
+        let d_over_b = d.checked_div(&PreciseNumber::new(swap_token_b_amount)?)?;
+        let numerator = d_over_a
+            .checked_mul(&d_over_a)?
+            .checked_mul(&d_over_b)?
+            .checked_div(&four)?
+            .checked_add(&leverage)?;
+        let denominator = d_over_a
+            .checked_mul(&d_over_b)?
+            .checked_mul(&d_over_b)?
+            .checked_div(&four)?
+            .checked_add(&leverage)?;
+        numerator
+            .checked_mul(&PreciseNumber::new(PRICE_SCALE)?)?
+            .checked_div(&denominator)?
+            .floor()?
+            .to_imprecise()
+    }
+
+    fn validate(&self) -> Result<(), SwapError> {
+        if self.amp == 0 || self.amp > MAX_AMP {
+            Err(SwapError::InvalidCurve)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curve::ConstantProductCurve;
+
+    #[test]
+    fn compute_d_of_balanced_reserves_is_their_sum() {
+        let curve = StableCurve { amp: 100 };
+        assert_eq!(curve.compute_d(1_000_000, 1_000_000), Ok(2_000_000));
+        assert_eq!(curve.compute_d(0, 0), Ok(0));
+        assert_eq!(
+            curve.compute_d(u128::from(u64::MAX), u128::from(u64::MAX)),
+            Err(SwapError::CalculationFailure)
+        );
+    }
+
+    #[test]
+    fn stable_swap_slips_less_than_constant_product() {
+        let stable = StableCurve { amp: 100 };
+        let constant_product = ConstantProductCurve;
+        let reserve = 1_000_000_000;
+        let amount_in = 100_000_000;
+
+        let stable_out = stable
+            .swap_without_fees(amount_in, reserve, reserve, TradeDirection::AtoB)
+            .unwrap();
+        let constant_product_out = constant_product
+            .swap_without_fees(amount_in, reserve, reserve, TradeDirection::AtoB)
+            .unwrap();
+        assert_eq!(constant_product_out, 90_909_090);
+        assert!(stable_out > constant_product_out);
+        assert!(stable_out < amount_in);
+        // about 0.05% below one for one, against 9% for constant product
+        assert_eq!(stable_out, 99_949_776);
+
+        // both directions trade the same on a balanced pool
+        assert_eq!(
+            stable.swap_without_fees(amount_in, reserve, reserve, TradeDirection::BtoA),
+            Some(stable_out)
+        );
+    }
+
+    #[test]
+    fn stable_swap_keeps_invariant() {
+        let curve = StableCurve { amp: 85 };
+        let (reserve_a, reserve_b) = (3_000_000, 1_000_000);
+        let amount_in = 500_000;
+        let amount_out = curve
+            .swap_without_fees(amount_in, reserve_a, reserve_b, TradeDirection::AtoB)
+            .unwrap();
+        let d = curve.compute_d(reserve_a, reserve_b).unwrap();
+        let new_d = curve
+            .compute_d(reserve_a + amount_in, reserve_b - amount_out)
+            .unwrap();
+        assert!(new_d >= d);
+        // the unbalanced side is worth less than one for one
+        assert!(amount_out < amount_in);
+
+        // the pool cannot be drained
+        let amount_out = curve
+            .swap_without_fees(1_000_000_000, reserve_a, reserve_b, TradeDirection::AtoB)
+            .unwrap();
+        assert!(amount_out < reserve_b);
+    }
+
+    #[test]
+    fn stable_curve_validates_amp() {
+        assert_eq!(StableCurve { amp: 1 }.validate(), Ok(()));
+        assert_eq!(StableCurve { amp: MAX_AMP }.validate(), Ok(()));
+        assert_eq!(
+            StableCurve { amp: 0 }.validate(),
+            Err(SwapError::InvalidCurve)
+        );
+        assert_eq!(
+            StableCurve { amp: MAX_AMP + 1 }.validate(),
+            Err(SwapError::InvalidCurve)
+        );
+    }
+}
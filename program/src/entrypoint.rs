@@ -1,9 +1,14 @@
 //! Program entrypoint definitions
 
 use crate::{error::SwapError, processor::Processor};
+use num_traits::FromPrimitive;
 use solana_program::{
-    account_info::AccountInfo, entrypoint, entrypoint::ProgramResult,
-    program_error::PrintProgramError, pubkey::Pubkey,
+    account_info::AccountInfo,
+    entrypoint,
+    entrypoint::ProgramResult,
+    msg,
+    program_error::{PrintProgramError, ProgramError},
+    pubkey::Pubkey,
 };
 
 entrypoint!(process_instruction);
@@ -15,6 +20,11 @@ fn process_instruction(
     if let Err(error) = Processor::process(program_id, accounts, instruction_data) {
         // catch the error so we can print it
         error.print::<SwapError>();
+        if let ProgramError::Custom(code) = error {
+            if let Some(swap_error) = SwapError::from_u32(code) {
+                msg!("SwapError::{}", swap_error.name());
+            }
+        }
         return Err(error);
     }
     Ok(())
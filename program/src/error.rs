@@ -73,7 +73,7 @@ pub enum SwapError {
     /// The provided token account has a close authority.
     #[error("Token account has a close authority")]
     InvalidCloseAuthority,
-    /// The pool token mint has a freeze authority.
+    /// The pool token mint has a freeze authority other than the pool owner.
     #[error("Pool token mint has a freeze authority")]
     InvalidFreezeAuthority,
     /// The pool fee token account is incorrect
@@ -107,7 +107,115 @@ pub enum SwapError {
     /// The operation cannot be performed on the given curve
     #[error("The operation cannot be performed on the given curve")]
     UnsupportedCurveOperation,
+    /// The pool still has outstanding pool tokens or token balances
+    #[error("The pool still has outstanding pool tokens or token balances")]
+    NonEmptyPool,
+    /// The swap output exceeds the share of the fee account allowed by the reward cap
+    #[error("Swap output exceeds the reward budget")]
+    RewardBudgetExceeded,
+
+    // 30.
+    /// The swap would execute too far from the last swap price
+    #[error("Swap price deviates too far from the last swap price")]
+    PriceDeviationTooHigh,
+    /// The provided mint does not match the mint of the swap token account
+    #[error("Address of the provided token mint is incorrect")]
+    IncorrectMint,
+    /// The flash loan reserve was not restored with the flash loan fee
+    #[error("Flash loan was not repaid with its fee")]
+    FlashLoanNotRepaid,
+    /// The operation is not allowed while a flash loan is outstanding
+    #[error("A flash loan is outstanding on the pool")]
+    FlashLoanOutstanding,
+    /// The pool fee token account cannot receive fees
+    #[error("Pool fee token account cannot receive fees")]
+    InvalidFeeAccount,
+
+    // 35.
+    /// The swap token account is frozen
+    #[error("Swap token account is frozen")]
+    FrozenAccount,
+    /// The operation is not allowed in the current state of the pool
+    #[error("The operation is not allowed in the current state of the pool")]
+    OperationNotAllowed,
+    /// The withdrawal destination accounts have different owners
+    #[error("Withdrawal destination accounts have different owners")]
+    DestinationOwnerMismatch,
+    /// The deposit would push a reserve above the reserve limit
+    #[error("Deposit would push a reserve above the reserve limit")]
+    ReserveLimitExceeded,
+    /// The source account holds less than the amount to swap
+    #[error("Source account holds less than the amount to swap")]
+    InsufficientFunds,
+
+    // 40.
+    /// The swap landed after its deadline slot
+    #[error("Swap deadline slot exceeded")]
+    DeadlineExceeded,
+    /// A reserve or fee account holds too few lamports to be rent exempt
+    #[error("Swap token account is not rent exempt")]
+    NotRentExempt,
+    /// The swap moves the pool price further than its price impact bound
+    #[error("Swap moves the pool price too far")]
+    ExcessivePriceImpact,
+    /// A rebalance takes more token B out than the owner has put in
+    #[error("Rebalance removes more than the owner added")]
+    RebalanceExceedsOwnerFunds,
+}
+
+impl SwapError {
+    /// Name of the error variant, for logs that would otherwise only show
+    /// the numeric code
+    pub fn name(&self) -> &'static str {
+        match self {
+            SwapError::AlreadyInUse => "AlreadyInUse",
+            SwapError::InvalidProgramAddress => "InvalidProgramAddress",
+            SwapError::InvalidOwner => "InvalidOwner",
+            SwapError::InvalidOutputOwner => "InvalidOutputOwner",
+            SwapError::ExpectedMint => "ExpectedMint",
+            SwapError::ExpectedAccount => "ExpectedAccount",
+            SwapError::EmptySupply => "EmptySupply",
+            SwapError::InvalidSupply => "InvalidSupply",
+            SwapError::InvalidDelegate => "InvalidDelegate",
+            SwapError::InvalidInput => "InvalidInput",
+            SwapError::IncorrectSwapAccount => "IncorrectSwapAccount",
+            SwapError::IncorrectPoolMint => "IncorrectPoolMint",
+            SwapError::InvalidOutput => "InvalidOutput",
+            SwapError::CalculationFailure => "CalculationFailure",
+            SwapError::InvalidInstruction => "InvalidInstruction",
+            SwapError::RepeatedMint => "RepeatedMint",
+            SwapError::ExceededSlippage => "ExceededSlippage",
+            SwapError::InvalidCloseAuthority => "InvalidCloseAuthority",
+            SwapError::InvalidFreezeAuthority => "InvalidFreezeAuthority",
+            SwapError::IncorrectFeeAccount => "IncorrectFeeAccount",
+            SwapError::ZeroTradingTokens => "ZeroTradingTokens",
+            SwapError::FeeCalculationFailure => "FeeCalculationFailure",
+            SwapError::ConversionFailure => "ConversionFailure",
+            SwapError::InvalidFee => "InvalidFee",
+            SwapError::IncorrectTokenProgramId => "IncorrectTokenProgramId",
+            SwapError::UnsupportedCurveType => "UnsupportedCurveType",
+            SwapError::InvalidCurve => "InvalidCurve",
+            SwapError::UnsupportedCurveOperation => "UnsupportedCurveOperation",
+            SwapError::NonEmptyPool => "NonEmptyPool",
+            SwapError::RewardBudgetExceeded => "RewardBudgetExceeded",
+            SwapError::PriceDeviationTooHigh => "PriceDeviationTooHigh",
+            SwapError::IncorrectMint => "IncorrectMint",
+            SwapError::FlashLoanNotRepaid => "FlashLoanNotRepaid",
+            SwapError::FlashLoanOutstanding => "FlashLoanOutstanding",
+            SwapError::InvalidFeeAccount => "InvalidFeeAccount",
+            SwapError::FrozenAccount => "FrozenAccount",
+            SwapError::OperationNotAllowed => "OperationNotAllowed",
+            SwapError::DestinationOwnerMismatch => "DestinationOwnerMismatch",
+            SwapError::ReserveLimitExceeded => "ReserveLimitExceeded",
+            SwapError::InsufficientFunds => "InsufficientFunds",
+            SwapError::DeadlineExceeded => "DeadlineExceeded",
+            SwapError::NotRentExempt => "NotRentExempt",
+            SwapError::ExcessivePriceImpact => "ExcessivePriceImpact",
+            SwapError::RebalanceExceedsOwnerFunds => "RebalanceExceedsOwnerFunds",
+        }
+    }
 }
+
 impl From<SwapError> for ProgramError {
     fn from(e: SwapError) -> Self {
         ProgramError::Custom(e as u32)
@@ -183,6 +291,57 @@ impl PrintProgramError for SwapError {
             SwapError::UnsupportedCurveOperation => {
                 msg!("Error: The operation cannot be performed on the given curve")
             }
+            SwapError::NonEmptyPool => {
+                msg!("Error: The pool still has outstanding pool tokens or token balances")
+            }
+            SwapError::RewardBudgetExceeded => msg!("Error: Swap output exceeds the reward budget"),
+            SwapError::PriceDeviationTooHigh => {
+                msg!("Error: Swap price deviates too far from the last swap price")
+            }
+            SwapError::IncorrectMint => {
+                msg!("Error: Address of the provided token mint is incorrect")
+            }
+            SwapError::FlashLoanNotRepaid => msg!("Error: Flash loan was not repaid with its fee"),
+            SwapError::FlashLoanOutstanding => {
+                msg!("Error: A flash loan is outstanding on the pool")
+            }
+            SwapError::InvalidFeeAccount => {
+                msg!("Error: Pool fee token account cannot receive fees")
+            }
+            SwapError::FrozenAccount => msg!("Error: Swap token account is frozen"),
+            SwapError::OperationNotAllowed => {
+                msg!("Error: The operation is not allowed in the current state of the pool")
+            }
+            SwapError::DestinationOwnerMismatch => {
+                msg!("Error: Withdrawal destination accounts have different owners")
+            }
+            SwapError::ReserveLimitExceeded => {
+                msg!("Error: Deposit would push a reserve above the reserve limit")
+            }
+            SwapError::InsufficientFunds => {
+                msg!("Error: Source account holds less than the amount to swap")
+            }
+            SwapError::DeadlineExceeded => msg!("Error: Swap deadline slot exceeded"),
+            SwapError::NotRentExempt => msg!("Error: Swap token account is not rent exempt"),
+            SwapError::ExcessivePriceImpact => msg!("Error: Swap moves the pool price too far"),
+            SwapError::RebalanceExceedsOwnerFunds => {
+                msg!("Error: Rebalance removes more than the owner added")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_matches_variant() {
+        assert_eq!(SwapError::ExceededSlippage.name(), "ExceededSlippage");
+        let errors = (0..).map_while(SwapError::from_u32).collect::<Vec<_>>();
+        assert_eq!(errors.len(), 44);
+        for error in errors {
+            assert_eq!(error.name(), format!("{:?}", error));
         }
     }
 }
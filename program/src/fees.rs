@@ -1,15 +1,18 @@
 //! All fee information, to be used for validation currently
 
-use crate::error::SwapError;
+use crate::{error::SwapError, state::BPS_DENOMINATOR};
 use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
 use solana_program::{
     program_error::ProgramError,
     program_pack::{IsInitialized, Pack, Sealed},
 };
+use spl_math::uint::U256;
 use std::convert::TryFrom;
 
 /// Encapsulates all fee information and calculations for swap operations
 #[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "borsh", derive(borsh::BorshSerialize, borsh::BorshDeserialize))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Fees {
     /// Trade fees are extra token amounts that are held inside the token
     /// accounts during a trade, making the value of liquidity tokens rise.
@@ -17,9 +20,34 @@ pub struct Fees {
     pub trade_fee_numerator: u64,
     /// Trade fee denominator
     pub trade_fee_denominator: u64,
+
+    /// Owner trading fees are extra token amounts that are held inside the
+    /// token accounts during a trade, with the equivalent in pool tokens
+    /// going to the owner of the program.
+    /// Owner trade fee numerator
+    pub owner_trade_fee_numerator: u64,
+    /// Owner trade fee denominator
+    pub owner_trade_fee_denominator: u64,
+
+    /// Flash loan fees are charged on the borrowed amount and must be repaid
+    /// along with it, staying in the pool like trade fees.
+    /// Flash loan fee numerator
+    pub flash_loan_fee_numerator: u64,
+    /// Flash loan fee denominator
+    pub flash_loan_fee_denominator: u64,
+}
+
+/// How a fee worth a fraction of a token is rounded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FeeRounding {
+    /// Round up, never undercharging, see `calculate_fee_ceil`
+    Ceil,
+    /// Round down, never overcharging, see `calculate_fee_floor`
+    Floor,
 }
 
-/// Helper function for calculating swap fee
+/// Helper function for calculating swap fee, charging a minimum fee of one
+/// token whenever a non-zero fee rounds down to zero
 pub fn calculate_fee(
     token_amount: u128,
     fee_numerator: u128,
@@ -39,6 +67,40 @@ pub fn calculate_fee(
     }
 }
 
+/// Fee rounded up, so any non-zero fee is at least one token
+pub fn calculate_fee_ceil(
+    token_amount: u128,
+    fee_numerator: u128,
+    fee_denominator: u128,
+) -> Option<u128> {
+    if fee_numerator == 0 || token_amount == 0 {
+        Some(0)
+    } else {
+        let numerator = token_amount.checked_mul(fee_numerator)?;
+        let fee = numerator.checked_div(fee_denominator)?;
+        if numerator % fee_denominator == 0 {
+            Some(fee)
+        } else {
+            fee.checked_add(1)
+        }
+    }
+}
+
+/// Fee rounded down, so a fee worth less than one token is waived
+pub fn calculate_fee_floor(
+    token_amount: u128,
+    fee_numerator: u128,
+    fee_denominator: u128,
+) -> Option<u128> {
+    if fee_numerator == 0 || token_amount == 0 {
+        Some(0)
+    } else {
+        token_amount
+            .checked_mul(fee_numerator)?
+            .checked_div(fee_denominator)
+    }
+}
+
 fn validate_fraction(numerator: u64, denominator: u64) -> Result<(), SwapError> {
     if denominator == 0 && numerator == 0 {
         Ok(())
@@ -59,9 +121,114 @@ impl Fees {
         )
     }
 
+    /// Calculate the trading fee in trading tokens with the given rounding,
+    /// as chosen by the swap curve
+    pub fn trading_fee_rounded(&self, trading_tokens: u128, rounding: FeeRounding) -> Option<u128> {
+        let calculate = match rounding {
+            FeeRounding::Ceil => calculate_fee_ceil,
+            FeeRounding::Floor => calculate_fee_floor,
+        };
+        calculate(
+            trading_tokens,
+            u128::from(self.trade_fee_numerator),
+            u128::from(self.trade_fee_denominator),
+        )
+    }
+
+    /// Calculate the owner trading fee in trading tokens with the given
+    /// rounding, as chosen by the swap curve
+    pub fn owner_trading_fee_rounded(
+        &self,
+        trading_tokens: u128,
+        rounding: FeeRounding,
+    ) -> Option<u128> {
+        let calculate = match rounding {
+            FeeRounding::Ceil => calculate_fee_ceil,
+            FeeRounding::Floor => calculate_fee_floor,
+        };
+        calculate(
+            trading_tokens,
+            u128::from(self.owner_trade_fee_numerator),
+            u128::from(self.owner_trade_fee_denominator),
+        )
+    }
+
+    /// Calculate the fee owed on a flash loan of the given amount
+    pub fn flash_loan_fee(&self, loan_amount: u128) -> Option<u128> {
+        calculate_fee(
+            loan_amount,
+            u128::from(self.flash_loan_fee_numerator),
+            u128::from(self.flash_loan_fee_denominator),
+        )
+    }
+
+    /// Calculate the trade and owner fees together with the given rounding.
+    /// Rounding their sum once, rather than each fee on its own, keeps the
+    /// amount left after fees from shrinking as the trading tokens grow.
+    pub fn total_trading_fee_rounded(
+        &self,
+        trading_tokens: u128,
+        rounding: FeeRounding,
+    ) -> Option<u128> {
+        let (numerator, denominator) = self.total_trading_fee_fraction()?;
+        let numerator = U256::from(trading_tokens).checked_mul(U256::from(numerator))?;
+        let denominator = U256::from(denominator);
+        let fee = numerator / denominator;
+        let fee = match rounding {
+            FeeRounding::Ceil if !(numerator % denominator).is_zero() => fee + 1,
+            _ => fee,
+        };
+        if fee > U256::from(u128::MAX) {
+            None
+        } else {
+            Some(fee.as_u128())
+        }
+    }
+
+    /// Combined trade and owner fee in basis points, rounded to the nearest
+    /// basis point. Returns `None` if a fee has a zero denominator with a
+    /// non-zero numerator, or if the total does not fit in a `u16`.
+    pub fn total_fee_basis_points(&self) -> Option<u16> {
+        let (numerator, denominator) = self.total_trading_fee_fraction()?;
+        let numerator = numerator.checked_mul(BPS_DENOMINATOR)?;
+        let basis_points = numerator.checked_add(denominator / 2)? / denominator;
+        u16::try_from(basis_points).ok()
+    }
+
+    /// Trade and owner fee summed into a single fraction, `None` if a fee has
+    /// a zero denominator with a non-zero numerator
+    fn total_trading_fee_fraction(&self) -> Option<(u128, u128)> {
+        let fraction = |numerator: u64, denominator: u64| match (numerator, denominator) {
+            (0, _) => Some((0u128, 1u128)),
+            (_, 0) => None,
+            _ => Some((u128::from(numerator), u128::from(denominator))),
+        };
+        let (trade_numerator, trade_denominator) =
+            fraction(self.trade_fee_numerator, self.trade_fee_denominator)?;
+        let (owner_numerator, owner_denominator) = fraction(
+            self.owner_trade_fee_numerator,
+            self.owner_trade_fee_denominator,
+        )?;
+
+        // trade + owner = (tn * od + on * td) / (td * od)
+        let numerator = trade_numerator
+            .checked_mul(owner_denominator)?
+            .checked_add(owner_numerator.checked_mul(trade_denominator)?)?;
+        let denominator = trade_denominator.checked_mul(owner_denominator)?;
+        Some((numerator, denominator))
+    }
+
     /// Validate that the fees are reasonable
     pub fn validate(&self) -> Result<(), SwapError> {
         validate_fraction(self.trade_fee_numerator, self.trade_fee_denominator)?;
+        validate_fraction(
+            self.owner_trade_fee_numerator,
+            self.owner_trade_fee_denominator,
+        )?;
+        validate_fraction(
+            self.flash_loan_fee_numerator,
+            self.flash_loan_fee_denominator,
+        )?;
         Ok(())
     }
 }
@@ -74,28 +241,48 @@ impl IsInitialized for Fees {
 }
 
 impl Sealed for Fees {}
+
+// six u64 fields, a new fee field must grow the packed length
+const _: () = assert!(6 * 8 == Fees::LEN);
+
 impl Pack for Fees {
-    const LEN: usize = 16;
+    const LEN: usize = 48;
     fn pack_into_slice(&self, output: &mut [u8]) {
-        let output = array_mut_ref![output, 0, 16];
+        let output = array_mut_ref![output, 0, Fees::LEN];
         let (
             trade_fee_numerator,
             trade_fee_denominator,
-        ) = mut_array_refs![output, 8, 8];
+            owner_trade_fee_numerator,
+            owner_trade_fee_denominator,
+            flash_loan_fee_numerator,
+            flash_loan_fee_denominator,
+        ) = mut_array_refs![output, 8, 8, 8, 8, 8, 8];
         *trade_fee_numerator = self.trade_fee_numerator.to_le_bytes();
         *trade_fee_denominator = self.trade_fee_denominator.to_le_bytes();
+        *owner_trade_fee_numerator = self.owner_trade_fee_numerator.to_le_bytes();
+        *owner_trade_fee_denominator = self.owner_trade_fee_denominator.to_le_bytes();
+        *flash_loan_fee_numerator = self.flash_loan_fee_numerator.to_le_bytes();
+        *flash_loan_fee_denominator = self.flash_loan_fee_denominator.to_le_bytes();
     }
 
     fn unpack_from_slice(input: &[u8]) -> Result<Fees, ProgramError> {
-        let input = array_ref![input, 0, 16];
+        let input = array_ref![input, 0, Fees::LEN];
         #[allow(clippy::ptr_offset_with_cast)]
         let (
             trade_fee_numerator,
             trade_fee_denominator,
-        ) = array_refs![input, 8, 8];
+            owner_trade_fee_numerator,
+            owner_trade_fee_denominator,
+            flash_loan_fee_numerator,
+            flash_loan_fee_denominator,
+        ) = array_refs![input, 8, 8, 8, 8, 8, 8];
         Ok(Self {
             trade_fee_numerator: u64::from_le_bytes(*trade_fee_numerator),
             trade_fee_denominator: u64::from_le_bytes(*trade_fee_denominator),
+            owner_trade_fee_numerator: u64::from_le_bytes(*owner_trade_fee_numerator),
+            owner_trade_fee_denominator: u64::from_le_bytes(*owner_trade_fee_denominator),
+            flash_loan_fee_numerator: u64::from_le_bytes(*flash_loan_fee_numerator),
+            flash_loan_fee_denominator: u64::from_le_bytes(*flash_loan_fee_denominator),
         })
     }
 }
@@ -108,9 +295,17 @@ mod tests {
     fn pack_fees() {
         let trade_fee_numerator = 1;
         let trade_fee_denominator = 4;
+        let owner_trade_fee_numerator = 2;
+        let owner_trade_fee_denominator = 5;
+        let flash_loan_fee_numerator = 3;
+        let flash_loan_fee_denominator = 1000;
         let fees = Fees {
             trade_fee_numerator,
             trade_fee_denominator,
+            owner_trade_fee_numerator,
+            owner_trade_fee_denominator,
+            flash_loan_fee_numerator,
+            flash_loan_fee_denominator,
         };
 
         let mut packed = [0u8; Fees::LEN];
@@ -121,7 +316,116 @@ mod tests {
         let mut packed = vec![];
         packed.extend_from_slice(&trade_fee_numerator.to_le_bytes());
         packed.extend_from_slice(&trade_fee_denominator.to_le_bytes());
+        packed.extend_from_slice(&owner_trade_fee_numerator.to_le_bytes());
+        packed.extend_from_slice(&owner_trade_fee_denominator.to_le_bytes());
+        packed.extend_from_slice(&flash_loan_fee_numerator.to_le_bytes());
+        packed.extend_from_slice(&flash_loan_fee_denominator.to_le_bytes());
         let unpacked = Fees::unpack_from_slice(&packed).unwrap();
         assert_eq!(fees, unpacked);
+
+        // every byte of every field survives the round trip
+        let fees = Fees {
+            trade_fee_numerator: u64::MAX,
+            trade_fee_denominator: u64::MAX - 1,
+            owner_trade_fee_numerator: u64::MAX - 2,
+            owner_trade_fee_denominator: u64::MAX - 3,
+            flash_loan_fee_numerator: u64::MAX - 4,
+            flash_loan_fee_denominator: u64::MAX - 5,
+        };
+        let mut packed = [0u8; Fees::LEN];
+        Pack::pack_into_slice(&fees, &mut packed[..]);
+        assert_eq!(Fees::unpack_from_slice(&packed).unwrap(), fees);
+    }
+
+    #[test]
+    fn fee_rounding() {
+        let fees = Fees {
+            trade_fee_numerator: 1,
+            trade_fee_denominator: 400,
+            ..Fees::default()
+        };
+
+        // 100 / 400 is a quarter token
+        assert_eq!(calculate_fee(100, 1, 400), Some(1));
+        assert_eq!(calculate_fee_ceil(100, 1, 400), Some(1));
+        assert_eq!(calculate_fee_floor(100, 1, 400), Some(0));
+        assert_eq!(fees.trading_fee_rounded(100, FeeRounding::Ceil), Some(1));
+        assert_eq!(fees.trading_fee_rounded(100, FeeRounding::Floor), Some(0));
+
+        // 1000 / 400 is two and a half tokens
+        assert_eq!(calculate_fee(1000, 1, 400), Some(2));
+        assert_eq!(calculate_fee_ceil(1000, 1, 400), Some(3));
+        assert_eq!(calculate_fee_floor(1000, 1, 400), Some(2));
+
+        // exact fees are the same under every rounding
+        assert_eq!(calculate_fee_ceil(800, 1, 400), Some(2));
+        assert_eq!(calculate_fee_floor(800, 1, 400), Some(2));
+        assert_eq!(calculate_fee_ceil(0, 1, 400), Some(0));
+        assert_eq!(calculate_fee_floor(100, 0, 0), Some(0));
+    }
+
+    #[test]
+    fn total_trading_fee_rounded() {
+        let fees = Fees {
+            trade_fee_numerator: 1,
+            trade_fee_denominator: 4,
+            owner_trade_fee_numerator: 1,
+            owner_trade_fee_denominator: 4,
+            ..Fees::default()
+        };
+        // 1.25 + 1.25 rounds once to 3, not to 2 + 2
+        assert_eq!(
+            fees.total_trading_fee_rounded(5, FeeRounding::Ceil),
+            Some(3)
+        );
+        assert_eq!(
+            fees.total_trading_fee_rounded(5, FeeRounding::Floor),
+            Some(2)
+        );
+        assert_eq!(
+            fees.total_trading_fee_rounded(u128::from(u64::MAX), FeeRounding::Ceil),
+            Some(u128::from(u64::MAX / 2 + 1))
+        );
+        assert_eq!(
+            Fees::default().total_trading_fee_rounded(100, FeeRounding::Ceil),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn total_fee_basis_points() {
+        let fees = Fees {
+            trade_fee_numerator: 25,
+            trade_fee_denominator: 100,
+            owner_trade_fee_numerator: 5,
+            owner_trade_fee_denominator: 100,
+            ..Fees::default()
+        };
+        assert_eq!(fees.total_fee_basis_points(), Some(3000));
+
+        // a disabled owner fee adds nothing
+        let fees = Fees {
+            trade_fee_numerator: 1,
+            trade_fee_denominator: 3,
+            ..Fees::default()
+        };
+        assert_eq!(fees.total_fee_basis_points(), Some(3333));
+
+        // 1/3 + 1/3 is 6666.67 basis points
+        let fees = Fees {
+            owner_trade_fee_numerator: 1,
+            owner_trade_fee_denominator: 3,
+            ..fees
+        };
+        assert_eq!(fees.total_fee_basis_points(), Some(6667));
+
+        assert_eq!(Fees::default().total_fee_basis_points(), Some(0));
+
+        let fees = Fees {
+            trade_fee_numerator: 1,
+            trade_fee_denominator: 0,
+            ..Fees::default()
+        };
+        assert_eq!(fees.total_fee_basis_points(), None);
     }
 }
@@ -2,23 +2,69 @@
 
 #![allow(clippy::too_many_arguments)]
 
+use crate::curve::CurveType;
 use crate::error::SwapError;
 use crate::fees::Fees;
-use solana_program::{program_error::ProgramError, program_pack::Pack};
-use std::convert::TryInto;
+use crate::quote::MAX_QUOTE_AMOUNTS;
+use num_enum::TryFromPrimitive;
+use solana_program::{
+    instruction::AccountMeta,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::{Pubkey, PUBKEY_BYTES},
+};
+use std::convert::{TryFrom, TryInto};
 use std::mem::size_of;
 
 /// Initialize instruction data
 #[repr(C)]
 #[derive(Debug, PartialEq)]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Initialize {
     /// all swap fees
     pub fees: Fees,
+    /// owner of the pool, allowed to perform privileged operations
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_pubkey"))]
+    pub owner: Pubkey,
+    /// curve used to calculate swaps
+    pub curve_type: CurveType,
+    /// curve-specific parameter, the token B offset for the offset curve or
+    /// the whole token B per whole token A for the constant price curve
+    pub curve_parameter: u64,
+    /// numerator of the share of the destination fee account balance a
+    /// single swap may output
+    pub reward_cap_numerator: u64,
+    /// denominator of the reward cap, zero to leave swaps uncapped
+    pub reward_cap_denominator: u64,
+    /// largest deviation from the last swap price allowed for a swap, in
+    /// basis points, zero to leave swaps unbounded
+    pub price_band_bps: u16,
+    /// pool token amount minted to the initializer, zero for the default
+    /// of 1_000_000_000
+    pub initial_pool_amount: u64,
+    /// minimum pool token amount to mint to the initializer, prevents
+    /// initializing with other terms than expected
+    pub minimum_pool_token_amount: u64,
+    /// round deposits up and withdrawals down in favor of the liquidity
+    /// providers, omitted by older clients and then set
+    pub round_up_deposits: bool,
+    /// largest move of the pool price a single swap may cause, in basis
+    /// points, zero to leave swaps unbounded, omitted by older clients
+    pub max_price_impact_bps: u16,
 }
 
 /// DepositTokens instruction data
 #[repr(C)]
 #[derive(Debug, PartialEq)]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DepositTokens {
     /// Pool token amount to mint. token_a and token_b amount are set by
     /// the current exchange rate and size of the pool
@@ -27,11 +73,43 @@ pub struct DepositTokens {
     pub maximum_token_a_amount: u64,
     /// Maximum token B amount to deposit, prevents excessive slippage
     pub maximum_token_b_amount: u64,
+    /// Expected whole token A per whole token B of the deposit, numerator.
+    /// Raw amounts are scaled by the decimals of their mints first.
+    pub expected_ratio_numerator: u64,
+    /// Expected whole token A per whole token B of the deposit, denominator,
+    /// zero when the deposit ratio is not checked
+    pub expected_ratio_denominator: u64,
+}
+
+/// DepositAllTokenTypes instruction data, as named by the canonical swap
+/// program. Token amounts are rounded up in the pool's favor.
+pub type DepositAllTokenTypes = DepositTokens;
+
+/// DepositTokensExactAmounts instruction data
+#[repr(C)]
+#[derive(Debug, PartialEq)]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DepositTokensExactAmounts {
+    /// Token A amount to deposit, transferred in full
+    pub token_a_amount: u64,
+    /// Token B amount to deposit, transferred in full
+    pub token_b_amount: u64,
+    /// Minimum pool token amount to mint, prevents excessive slippage
+    pub minimum_pool_token_amount: u64,
 }
 
 /// WithdrawTokens instruction data
 #[repr(C)]
 #[derive(Debug, PartialEq)]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct WithdrawTokens {
     /// Pool token amount to burn. User receives an output of token a
     /// and b based on the percentage of the pool tokens that are returned
@@ -40,43 +118,212 @@ pub struct WithdrawTokens {
     pub minimum_token_a_amount: u64,
     /// Minimum token B amount to receive, prevents excessive slippage
     pub minimum_token_b_amount: u64,
+    /// Mint of token A or B to receive the whole withdrawal in, the other
+    /// side being swapped into it within the pool
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_pubkey::option"))]
+    pub preferred_mint: Option<Pubkey>,
+    /// Minimum amount of the preferred mint to receive, prevents excessive
+    /// slippage. Ignored without a preferred mint.
+    pub minimum_preferred_amount: u64,
+}
+
+/// WithdrawAllTokenTypes instruction data
+#[repr(C)]
+#[derive(Debug, PartialEq)]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WithdrawAllTokenTypes {
+    /// Minimum token A amount to receive, prevents excessive slippage
+    pub minimum_token_a_amount: u64,
+    /// Minimum token B amount to receive, prevents excessive slippage
+    pub minimum_token_b_amount: u64,
 }
 
 /// Swap instruction data
 #[repr(C)]
 #[derive(Debug, PartialEq)]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Swap {
     /// SOURCE amount to transfer, output to DESTINATION is based on the exchange rate
     pub amount_in: u64,
     /// Minimum amount of DESTINATION token to output, prevents excessive slippage
     pub minimum_amount_out: u64,
+    /// Last slot the swap may land in, so a stuck transaction cannot execute
+    /// much later at a bad price. Zero disables the check.
+    pub deadline_slot: u64,
+}
+
+/// SwapWithSlippageBps instruction data
+#[repr(C)]
+#[derive(Debug, PartialEq)]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SwapWithSlippageBps {
+    /// SOURCE amount to transfer, output to DESTINATION is based on the exchange rate
+    pub amount_in: u64,
+    /// Tolerated shortfall of the output against the spot price output, in
+    /// basis points
+    pub slippage_bps: u16,
+}
+
+/// SwapExactOutOrPartial instruction data
+#[repr(C)]
+#[derive(Debug, PartialEq)]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SwapExactOutOrPartial {
+    /// Amount of DESTINATION token to receive
+    pub amount_out: u64,
+    /// Most SOURCE token to spend, fees included
+    pub maximum_amount_in: u64,
+    /// Whether to swap all of `maximum_amount_in` for less than
+    /// `amount_out` instead of failing when it cannot buy `amount_out`
+    pub allow_partial: bool,
+}
+
+/// RouteSwap instruction data
+#[repr(C)]
+#[derive(Debug, PartialEq)]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RouteSwap {
+    /// SOURCE amount to swap in the first pool
+    pub amount_in: u64,
+    /// Minimum amount of DESTINATION token to output from the second pool,
+    /// prevents excessive slippage over the whole route
+    pub minimum_amount_out: u64,
+}
+
+/// BatchQuote instruction data
+#[repr(C)]
+#[derive(Debug, PartialEq)]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BatchQuote {
+    /// Source token amounts to quote, at most `MAX_QUOTE_AMOUNTS`
+    pub amounts_in: Vec<u64>,
+}
+
+/// FlashLoan instruction data
+#[repr(C)]
+#[derive(Debug, PartialEq)]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FlashLoan {
+    /// Amount to borrow, to be repaid with the flash loan fee
+    pub amount: u64,
+    /// Mint of token A or B to borrow
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_pubkey"))]
+    pub token: Pubkey,
+}
+
+/// Rebalance instruction data
+#[repr(C)]
+#[derive(Debug, PartialEq)]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rebalance {
+    /// Token B reserve per token A reserve to reach, scaled by `PRICE_SCALE`
+    pub target_ratio: u64,
+    /// Maximum token B amount moved in or out of the pool, prevents moving
+    /// more than expected if the reserves changed
+    pub maximum_amount: u64,
 }
 
+/// SetCurveParameter instruction data
+#[repr(C)]
+#[derive(Debug, PartialEq)]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SetCurveParameter {
+    /// New curve parameter, interpreted according to the pool's curve type
+    pub parameter: u64,
+}
+
+/// InitializeWithDeposit instruction data
+#[repr(C)]
+#[derive(Debug, PartialEq)]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InitializeWithDeposit {
+    /// terms of the new swap, as for `Initialize`
+    pub initialize: Initialize,
+    /// token A amount moved from the user into the token_a reserve
+    pub token_a_amount: u64,
+    /// token B amount moved from the user into the token_b reserve
+    pub token_b_amount: u64,
+}
+
+/// Packed length of the `Initialize` instruction data after the tag
+const INITIALIZE_LEN: usize = Fees::LEN + PUBKEY_BYTES + 46;
+
 /// Instructions supported by the token swap program
 #[repr(C)]
 #[derive(Debug, PartialEq)]
 pub enum SwapInstruction {
-    /// Initialize a new swap
+    /// Initialize a new swap. The token and fee accounts must be rent exempt.
     ///
     /// 0. `[writable, signer]` New Token-swap to create.
     /// 1. `[]` swap authority derived from `create_program_address(&[Token-swap account])`
     /// 2. `[]` token_a Account. Must be non zero, owned by swap authority.
     /// 3. `[]` token_b Account. Must be non zero, owned by swap authority.
     /// 4. `[writable]` Pool Token Mint. Must be empty, owned by swap authority.
+    ///    Only the pool owner may hold its freeze authority.
     /// 5. `[]` token_a Account to deposit trading fees. Must be empty, not
     /// owned by swap authority.
     /// 6. `[]` token_b Account to deposit trading fees. Must be empty, not
     /// owned by swap authority.
     /// 7. `[writable]` Pool Token Account to deposit the initial pool token
     /// supply. Must be empty, not owned by swap authority.
-    /// 8. `[]` Token program id
+    /// 8. `[]` Token program id, spl-token or Token-2022
+    /// 9. `[]` Pool token program id, spl-token or Token-2022
+    /// 10. `[]` token_a Mint, its decimals are stored for the curve
+    /// 11. `[]` token_b Mint, its decimals are stored for the curve
     Initialize(Initialize),
 
     ///   Deposit both types of tokens into the pool.  The output is a "pool"
     ///   token representing ownership in the pool. Inputs are converted to
-    ///   the current ratio.
+    ///   the current ratio. If the pool token supply is zero, the requested
+    ///   pool token amount is ignored: the pool's initial amount is minted in
+    ///   exchange for the whole of both reserves, within the given maxima.
+    ///   Given an expected ratio, the token amounts must be within
+    ///   `DEPOSIT_RATIO_TOLERANCE_BPS` of it. Wrapped SOL reserves are synced
+    ///   with their lamports first. Neither reserve may exceed `MAX_RESERVE`
+    ///   afterwards. The amounts moved are returned through the return data,
+    ///   see `LiquidityDeltas::from_return_data`.
     ///
-    ///   0. `[]` Token-swap
+    ///   0. `[writable]` Token-swap
     ///   1. `[]` swap authority
     ///   2. `[signer]` user transfer authority
     ///   3. `[writable]` token_a user transfer authority can transfer amount,
@@ -86,13 +333,18 @@ pub enum SwapInstruction {
     ///   7. `[writable]` Pool MINT account, swap authority is the owner.
     ///   8. `[writable]` Pool Account to deposit the generated tokens, user is the owner.
     ///   9. `[]` Token program id
+    ///   10. `[]` Pool token program id
     DepositTokens(DepositTokens),
 
     ///   Withdraw both types of tokens from the pool at the current ratio, given
     ///   pool tokens.  The pool tokens are burned in exchange for an equivalent
-    ///   amount of token A and B.
+    ///   amount of token A and B. If a preferred mint is given, the other
+    ///   token is swapped into it and only the preferred token is credited.
+    ///   That swap pays the owner fee and must pass the price band and price
+    ///   impact bound like a `Swap`. The amounts moved are returned through the return data, see
+    ///   `LiquidityDeltas::from_return_data`.
     ///
-    ///   0. `[]` Token-swap
+    ///   0. `[writable]` Token-swap
     ///   1. `[]` swap authority
     ///   2. `[signer]` user transfer authority
     ///   3. `[writable]` Pool mint account, swap authority is the owner
@@ -100,67 +352,553 @@ pub enum SwapInstruction {
     ///   5. `[writable]` token_a Swap Account to withdraw FROM.
     ///   6. `[writable]` token_b Swap Account to withdraw FROM.
     ///   7. `[writable]` token_a user Account to credit.
-    ///   8. `[writable]` token_b user Account to credit.
+    ///   8. `[writable]` token_b user Account to credit, with the same owner
+    ///      as the token_a Account.
     ///   9. `[]` Token program id
+    ///   10. `[]` Pool token program id
     WithdrawTokens(WithdrawTokens),
 
     ///   Swap the tokens in the pool.
     ///
-    ///   0. `[]` Token-swap
+    ///   0. `[writable]` Token-swap, recording the last swap price
     ///   1. `[]` swap authority
     ///   2. `[signer]` user transfer authority
     ///   3. `[writable]` token_(A|B) SOURCE Account, amount is transferable by user transfer authority,
     ///   4. `[writable]` token_(A|B) Base Account to swap INTO.  Must be the SOURCE token.
     ///   5. `[writable]` token_(A|B) Base Account to swap FROM.  Must be the DESTINATION token.
     ///   6. `[writable]` token_(A|B) DESTINATION Account assigned to USER as the owner.
-    ///   7. `[writable]` Fee account, to receive trading fees
+    ///   7. `[]` Fee account of the SOURCE token. Owner fees stay in the
+    ///      pool until collected with `CollectOwnerFees`.
     ///   8. `[]` Token program id
+    ///   9. `[]` Fee account of the DESTINATION token, whose balance bounds
+    ///      the amount out. Only required if the pool has a reward cap.
     Swap(Swap),
+
+    ///   Close an empty pool, reclaiming the rent held by the swap account
+    ///   and its token A and B accounts. The pool token supply and both
+    ///   token accounts must be empty.
+    ///
+    ///   0. `[writable]` Token-swap
+    ///   1. `[]` swap authority
+    ///   2. `[signer]` Pool owner
+    ///   3. `[writable]` token_a Swap Account to close.
+    ///   4. `[writable]` token_b Swap Account to close.
+    ///   5. `[]` Pool mint account
+    ///   6. `[writable]` Account to receive the reclaimed lamports.
+    ///   7. `[]` Token program id
+    ///   8. `[]` Pool token program id
+    ClosePool,
+
+    ///   Quote the output of swapping each of the given amounts against the
+    ///   current reserves, without swapping. The amounts out are returned as
+    ///   little-endian u64s through the return data, amounts that cannot be
+    ///   swapped being quoted as `FAILED_QUOTE`.
+    ///
+    ///   0. `[]` Token-swap
+    ///   1. `[]` token_(A|B) Base Account to swap INTO.  Must be the SOURCE token.
+    ///   2. `[]` token_(A|B) Base Account to swap FROM.  Must be the DESTINATION token.
+    BatchQuote(BatchQuote),
+
+    ///   Lend tokens from a reserve for the rest of the transaction. A
+    ///   `FlashLoanRepay` for the same pool must follow in the transaction,
+    ///   after the borrower transferred the amount and the flash loan fee
+    ///   back into the reserve. The pool cannot be traded with until then.
+    ///
+    ///   0. `[writable]` Token-swap, recording the outstanding loan
+    ///   1. `[]` swap authority
+    ///   2. `[writable]` token_(A|B) Base Account to borrow FROM.
+    ///   3. `[writable]` token_(A|B) Account to receive the loan.
+    ///   4. `[]` Token program id
+    ///   5. `[]` Instructions sysvar
+    FlashLoan(FlashLoan),
+
+    ///   Settle the outstanding flash loan, checking that its reserve holds
+    ///   at least its balance before the loan plus the flash loan fee.
+    ///
+    ///   0. `[writable]` Token-swap
+    ///   1. `[]` token_(A|B) Base Account the loan was taken FROM.
+    FlashLoanRepay,
+
+    ///   Move the pool's token B reserve to the target ratio with the token A
+    ///   reserve, transferring token B between the owner and the pool. No
+    ///   pool tokens are minted or burned, the owner bears the difference.
+    ///   The owner may only take back the token B added by earlier
+    ///   rebalances and not yet removed.
+    ///
+    ///   0. `[writable]` Token-swap, recording the target as last swap price
+    ///   1. `[]` swap authority
+    ///   2. `[signer]` Pool owner
+    ///   3. `[]` token_a Swap Account
+    ///   4. `[writable]` token_b Swap Account to rebalance.
+    ///   5. `[writable]` token_b owner Account to move tokens FROM or TO.
+    ///   6. `[]` Token program id
+    Rebalance(Rebalance),
+
+    ///   Deposit the given amounts of both tokens into the pool. The pool
+    ///   tokens minted are set by the side worth the fewest pool tokens at
+    ///   the current ratio, the excess of the other side is deposited
+    ///   without being refunded. Wrapped SOL reserves are synced with their
+    ///   lamports first. Neither reserve may exceed `MAX_RESERVE` afterwards.
+    ///   The amounts moved are returned through the return data, see
+    ///   `LiquidityDeltas::from_return_data`.
+    ///
+    ///   0. `[writable]` Token-swap
+    ///   1. `[]` swap authority
+    ///   2. `[signer]` user transfer authority
+    ///   3. `[writable]` token_a user transfer authority can transfer amount,
+    ///   4. `[writable]` token_b user transfer authority can transfer amount,
+    ///   5. `[writable]` token_a Base Account to deposit into.
+    ///   6. `[writable]` token_b Base Account to deposit into.
+    ///   7. `[writable]` Pool MINT account, swap authority is the owner.
+    ///   8. `[writable]` Pool Account to deposit the generated tokens, user is the owner.
+    ///   9. `[]` Token program id
+    ///   10. `[]` Pool token program id
+    DepositTokensExactAmounts(DepositTokensExactAmounts),
+
+    ///   Update the curve parameter of the pool, such as the offset or the
+    ///   constant price, while the owner holds every pool token, so no other
+    ///   liquidity provider is exposed to the change. Not allowed while a
+    ///   flash loan is outstanding. The new curve is validated against the
+    ///   reserves.
+    ///
+    ///   0. `[writable]` Token-swap
+    ///   1. `[signer]` Pool owner
+    ///   2. `[]` token_a Swap Account
+    ///   3. `[]` token_b Swap Account
+    ///   4. `[]` Pool mint account
+    ///   5. `[]` Pool token account of the owner, holding the whole supply
+    SetCurveParameter(SetCurveParameter),
+
+    ///   Return the token A and B reserves and the pool token supply as
+    ///   little-endian u64s through the return data, see
+    ///   `PoolReserves::from_return_data`.
+    ///
+    ///   0. `[]` Token-swap
+    ///   1. `[]` token_a Swap Account
+    ///   2. `[]` token_b Swap Account
+    ///   3. `[]` Pool mint account
+    QueryReserves,
+
+    ///   Mint the pool tokens owed to the owner for the owner fees kept in
+    ///   the reserves since the last collection, and reset the amount owed.
+    ///
+    ///   0. `[writable]` Token-swap
+    ///   1. `[]` swap authority
+    ///   2. `[signer]` Pool owner
+    ///   3. `[writable]` Pool mint account
+    ///   4. `[writable]` Pool Account to receive the owed pool tokens
+    ///   5. `[]` Pool token program id
+    CollectOwnerFees,
+
+    ///   Bring a pool created under the original 274-byte layout to the
+    ///   current layout, once. The owner becomes the program's upgrade
+    ///   authority and the curve constant product, with the other new fields
+    ///   disabled or read from the mints.
+    ///
+    ///   0. `[writable]` Token-swap, already funded for the new length
+    ///   1. `[signer]` Upgrade authority of the swap program
+    ///   2. `[]` Program data account of the swap program
+    ///   3. `[]` Pool mint account
+    ///   4. `[]` token_a Mint
+    ///   5. `[]` token_b Mint
+    ///   6. `[]` Rent sysvar
+    Migrate,
+
+    ///   Withdraw both types of tokens from the pool at the current ratio for
+    ///   the whole balance of the source pool account, read when the
+    ///   instruction runs. Otherwise the same as `WithdrawTokens` without a
+    ///   preferred mint, taking the same accounts.
+    ///
+    ///   0. `[writable]` Token-swap
+    ///   1. `[]` swap authority
+    ///   2. `[signer]` user transfer authority
+    ///   3. `[writable]` Pool mint account, swap authority is the owner
+    ///   4. `[writable]` SOURCE Pool account, its whole balance is
+    ///      transferable by user transfer authority.
+    ///   5. `[writable]` token_a Swap Account to withdraw FROM.
+    ///   6. `[writable]` token_b Swap Account to withdraw FROM.
+    ///   7. `[writable]` token_a user Account to credit.
+    ///   8. `[writable]` token_b user Account to credit, with the same owner
+    ///      as the token_a Account.
+    ///   9. `[]` Token program id
+    ///   10. `[]` Pool token program id
+    WithdrawAllTokenTypes(WithdrawAllTokenTypes),
+
+    ///   Redirect the owner fees to new token A and token B fee accounts,
+    ///   for instance when the current ones are compromised. The new
+    ///   accounts must hold the trading mints and not be owned by the swap
+    ///   authority.
+    ///
+    ///   0. `[writable]` Token-swap
+    ///   1. `[]` swap authority
+    ///   2. `[signer]` Pool owner
+    ///   3. `[]` New token_a fee Account
+    ///   4. `[]` New token_b fee Account
+    SetFeeAccounts,
+
+    ///   Swap the tokens in the pool, with the minimum amount out computed
+    ///   from the ideal output for `amount_in` and the slippage tolerance.
+    ///   The ideal output is `amount_in` after fees at the spot price of the
+    ///   reserves when the instruction runs, so the tolerance bounds the
+    ///   trade's own price impact. Pair it with a price band to guard
+    ///   against moves before the transaction lands. Otherwise the same as
+    ///   `Swap` without a deadline, taking the same accounts.
+    ///
+    ///   0. `[writable]` Token-swap, recording the last swap price
+    ///   1. `[]` swap authority
+    ///   2. `[signer]` user transfer authority
+    ///   3. `[writable]` token_(A|B) SOURCE Account, amount is transferable by user transfer authority,
+    ///   4. `[writable]` token_(A|B) Base Account to swap INTO.  Must be the SOURCE token.
+    ///   5. `[writable]` token_(A|B) Base Account to swap FROM.  Must be the DESTINATION token.
+    ///   6. `[writable]` token_(A|B) DESTINATION Account assigned to USER as the owner.
+    ///   7. `[]` Fee account of the SOURCE token.
+    ///   8. `[]` Token program id
+    ///   9. `[]` Fee account of the DESTINATION token. Only required if the
+    ///      pool has a reward cap.
+    SwapWithSlippageBps(SwapWithSlippageBps),
+
+    ///   Swap through two pools in one instruction, SOURCE into INTERMEDIATE
+    ///   in the first pool and the whole INTERMEDIATE amount received into
+    ///   DESTINATION in the second. Each hop is checked as a `Swap`.
+    ///
+    ///   0. `[signer]` user transfer authority, able to transfer from the
+    ///      SOURCE and INTERMEDIATE Accounts
+    ///   1. `[writable]` SOURCE Account of the user
+    ///   2. `[writable]` INTERMEDIATE Account of the user, credited by the
+    ///      first pool and debited by the second
+    ///   3. `[writable]` DESTINATION Account of the user
+    ///   4. `[]` Token program id
+    ///   5. `[writable]` Token-swap of the first pool
+    ///   6. `[]` swap authority of the first pool
+    ///   7. `[writable]` Base Account of the first pool to swap INTO
+    ///   8. `[writable]` Base Account of the first pool to swap FROM
+    ///   9. `[]` Fee account of the first pool for the SOURCE token
+    ///   10. `[]` Fee account of the first pool for the INTERMEDIATE token,
+    ///       only read if the pool has a reward cap
+    ///   11. `[writable]` Token-swap of the second pool
+    ///   12. `[]` swap authority of the second pool
+    ///   13. `[writable]` Base Account of the second pool to swap INTO
+    ///   14. `[writable]` Base Account of the second pool to swap FROM
+    ///   15. `[]` Fee account of the second pool for the INTERMEDIATE token
+    ///   16. `[]` Fee account of the second pool for the DESTINATION token,
+    ///       only read if the pool has a reward cap
+    RouteSwap(RouteSwap),
+
+    ///   Initialize a new swap, first moving the initial token A and B
+    ///   amounts from the user into the reserves, so the reserves need not be
+    ///   funded in an earlier transaction. Otherwise the same as
+    ///   `Initialize`, with `minimum_pool_token_amount` bounding the pool
+    ///   tokens minted for the deposit.
+    ///
+    ///   0-11. The accounts of `Initialize`, with the token_a and token_b
+    ///   Accounts writable and possibly empty beforehand
+    ///   12. `[signer]` user transfer authority
+    ///   13. `[writable]` token_a user Account, user transfer authority can
+    ///       transfer token_a_amount
+    ///   14. `[writable]` token_b user Account, user transfer authority can
+    ///       transfer token_b_amount
+    InitializeWithDeposit(InitializeWithDeposit),
+
+    ///   Check that the supplied authority is the one derived from the swap
+    ///   account and its stored bump seed, returning a single byte through
+    ///   the return data, 1 if it is and 0 if not, so clients caching the
+    ///   authority can detect a stale key or corrupted state.
+    ///
+    ///   0. `[]` Token-swap
+    ///   1. `[]` swap authority to verify
+    VerifyAuthority,
+
+    ///   Run every check of `Initialize` against the same data and accounts
+    ///   without minting pool tokens or writing the swap state, failing with
+    ///   the first error `Initialize` would return, so deployers can check
+    ///   their accounts before spending the initialization.
+    ///
+    ///   0-11. The accounts of `Initialize`, none written
+    ValidateInitialize(Initialize),
+
+    ///   Swap the least SOURCE token, at most `maximum_amount_in`, that
+    ///   outputs at least `amount_out`. If `maximum_amount_in` cannot buy
+    ///   `amount_out`, for instance because the pool lacks the liquidity,
+    ///   either fail with `ExceededSlippage` or, if `allow_partial`, swap
+    ///   all of `maximum_amount_in` for whatever it outputs. The amount of
+    ///   DESTINATION token received is returned as a little-endian u64
+    ///   through the return data. Otherwise the same as `Swap` without a
+    ///   deadline, taking the same accounts.
+    ///
+    ///   0. `[writable]` Token-swap, recording the last swap price
+    ///   1. `[]` swap authority
+    ///   2. `[signer]` user transfer authority
+    ///   3. `[writable]` token_(A|B) SOURCE Account, amount is transferable by user transfer authority,
+    ///   4. `[writable]` token_(A|B) Base Account to swap INTO.  Must be the SOURCE token.
+    ///   5. `[writable]` token_(A|B) Base Account to swap FROM.  Must be the DESTINATION token.
+    ///   6. `[writable]` token_(A|B) DESTINATION Account assigned to USER as the owner.
+    ///   7. `[]` Fee account of the SOURCE token.
+    ///   8. `[]` Token program id
+    ///   9. `[]` Fee account of the DESTINATION token. Only required if the
+    ///      pool has a reward cap.
+    SwapExactOutOrPartial(SwapExactOutOrPartial),
+}
+
+/// Tags of the instructions, the first byte of their packed data, so
+/// off-chain decoders can share them with `pack` and `unpack`
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, TryFromPrimitive)]
+pub enum SwapInstructionTag {
+    /// Tag of `Initialize`
+    Initialize = 0,
+    /// Tag of `DepositTokens`
+    DepositTokens = 1,
+    /// Tag of `WithdrawTokens`
+    WithdrawTokens = 2,
+    /// Tag of `Swap`
+    Swap = 3,
+    /// Tag of `ClosePool`
+    ClosePool = 4,
+    /// Tag of `BatchQuote`
+    BatchQuote = 5,
+    /// Tag of `FlashLoan`
+    FlashLoan = 6,
+    /// Tag of `FlashLoanRepay`
+    FlashLoanRepay = 7,
+    /// Tag of `Rebalance`
+    Rebalance = 8,
+    /// Tag of `DepositTokensExactAmounts`
+    DepositTokensExactAmounts = 9,
+    /// Tag of `SetCurveParameter`
+    SetCurveParameter = 10,
+    /// Tag of `QueryReserves`
+    QueryReserves = 11,
+    /// Tag of `CollectOwnerFees`
+    CollectOwnerFees = 12,
+    /// Tag of `Migrate`
+    Migrate = 13,
+    /// Tag of `WithdrawAllTokenTypes`
+    WithdrawAllTokenTypes = 14,
+    /// Tag of `SetFeeAccounts`
+    SetFeeAccounts = 15,
+    /// Tag of `SwapWithSlippageBps`
+    SwapWithSlippageBps = 16,
+    /// Tag of `RouteSwap`
+    RouteSwap = 17,
+    /// Tag of `InitializeWithDeposit`
+    InitializeWithDeposit = 18,
+    /// Tag of `VerifyAuthority`
+    VerifyAuthority = 19,
+    /// Tag of `ValidateInitialize`
+    ValidateInitialize = 20,
+    /// Tag of `SwapExactOutOrPartial`
+    SwapExactOutOrPartial = 21,
 }
 
 impl SwapInstruction {
-    /// Unpacks a byte buffer into a [SwapInstruction](enum.SwapInstruction.html).
+    /// Unpacks a byte buffer into a [SwapInstruction](enum.SwapInstruction.html),
+    /// rejecting any bytes left over after the instruction's fields.
     pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
         let (&tag, rest) = input.split_first().ok_or(SwapError::InvalidInstruction)?;
-        Ok(match tag {
-            0 => {
-                if rest.len() == Fees::LEN {
-                    let fees = Fees::unpack_unchecked(rest)?;
-                    Self::Initialize(Initialize { fees })
-                } else {
+        let tag = SwapInstructionTag::try_from(tag).or(Err(SwapError::InvalidInstruction))?;
+        let (instruction, rest) = match tag {
+            SwapInstructionTag::Initialize => {
+                // data from before the trailing fields were added gets their
+                // defaults: rounding in favor of the pool, no impact bound
+                let mut rest = rest.to_vec();
+                if rest.len() == INITIALIZE_LEN - 3 {
+                    rest.push(1);
+                }
+                if rest.len() == INITIALIZE_LEN - 2 {
+                    rest.extend_from_slice(&0u16.to_le_bytes());
+                }
+                if rest.len() != INITIALIZE_LEN {
                     return Err(SwapError::InvalidInstruction.into());
                 }
+                (Self::Initialize(Self::unpack_initialize(&rest)?.0), &[][..])
             }
-            1 => {
+            SwapInstructionTag::DepositTokens => {
                 let (pool_token_amount, rest) = Self::unpack_u64(rest)?;
                 let (maximum_token_a_amount, rest) = Self::unpack_u64(rest)?;
-                let (maximum_token_b_amount, _rest) = Self::unpack_u64(rest)?;
-                Self::DepositTokens(DepositTokens {
-                    pool_token_amount,
-                    maximum_token_a_amount,
-                    maximum_token_b_amount,
-                })
+                let (maximum_token_b_amount, rest) = Self::unpack_u64(rest)?;
+                let (expected_ratio_numerator, expected_ratio_denominator, rest) =
+                    if rest.is_empty() {
+                        (0, 0, rest)
+                    } else {
+                        let (expected_ratio_numerator, rest) = Self::unpack_u64(rest)?;
+                        let (expected_ratio_denominator, rest) = Self::unpack_u64(rest)?;
+                        (expected_ratio_numerator, expected_ratio_denominator, rest)
+                    };
+                (
+                    Self::DepositTokens(DepositTokens {
+                        pool_token_amount,
+                        maximum_token_a_amount,
+                        maximum_token_b_amount,
+                        expected_ratio_numerator,
+                        expected_ratio_denominator,
+                    }),
+                    rest,
+                )
             }
-            2 => {
+            SwapInstructionTag::WithdrawTokens => {
                 let (pool_token_amount, rest) = Self::unpack_u64(rest)?;
                 let (minimum_token_a_amount, rest) = Self::unpack_u64(rest)?;
-                let (minimum_token_b_amount, _rest) = Self::unpack_u64(rest)?;
-                Self::WithdrawTokens(WithdrawTokens {
-                    pool_token_amount,
-                    minimum_token_a_amount,
-                    minimum_token_b_amount,
-                })
+                let (minimum_token_b_amount, rest) = Self::unpack_u64(rest)?;
+                let (preferred_mint, minimum_preferred_amount, rest) = if rest.is_empty() {
+                    (None, 0, rest)
+                } else {
+                    let (preferred_mint, rest) = Self::unpack_pubkey(rest)?;
+                    let (minimum_preferred_amount, rest) = Self::unpack_u64(rest)?;
+                    (Some(preferred_mint), minimum_preferred_amount, rest)
+                };
+                (
+                    Self::WithdrawTokens(WithdrawTokens {
+                        pool_token_amount,
+                        minimum_token_a_amount,
+                        minimum_token_b_amount,
+                        preferred_mint,
+                        minimum_preferred_amount,
+                    }),
+                    rest,
+                )
             }
-            3 => {
+            SwapInstructionTag::Swap => {
                 let (amount_in, rest) = Self::unpack_u64(rest)?;
-                let (minimum_amount_out, _rest) = Self::unpack_u64(rest)?;
-                Self::Swap(Swap {
-                    amount_in,
-                    minimum_amount_out,
-                })
+                let (minimum_amount_out, rest) = Self::unpack_u64(rest)?;
+                let (deadline_slot, rest) = if rest.is_empty() {
+                    (0, rest)
+                } else {
+                    Self::unpack_u64(rest)?
+                };
+                (
+                    Self::Swap(Swap {
+                        amount_in,
+                        minimum_amount_out,
+                        deadline_slot,
+                    }),
+                    rest,
+                )
             }
-            _ => return Err(SwapError::InvalidInstruction.into()),
-        })
+            SwapInstructionTag::ClosePool => (Self::ClosePool, rest),
+            SwapInstructionTag::BatchQuote => {
+                let (&count, mut rest) = rest.split_first().ok_or(SwapError::InvalidInstruction)?;
+                if usize::from(count) > MAX_QUOTE_AMOUNTS {
+                    return Err(SwapError::InvalidInstruction.into());
+                }
+                let mut amounts_in = Vec::with_capacity(usize::from(count));
+                for _ in 0..count {
+                    let (amount_in, next) = Self::unpack_u64(rest)?;
+                    amounts_in.push(amount_in);
+                    rest = next;
+                }
+                (Self::BatchQuote(BatchQuote { amounts_in }), rest)
+            }
+            SwapInstructionTag::FlashLoan => {
+                let (amount, rest) = Self::unpack_u64(rest)?;
+                let (token, rest) = Self::unpack_pubkey(rest)?;
+                (Self::FlashLoan(FlashLoan { amount, token }), rest)
+            }
+            SwapInstructionTag::FlashLoanRepay => (Self::FlashLoanRepay, rest),
+            SwapInstructionTag::Rebalance => {
+                let (target_ratio, rest) = Self::unpack_u64(rest)?;
+                let (maximum_amount, rest) = Self::unpack_u64(rest)?;
+                (
+                    Self::Rebalance(Rebalance {
+                        target_ratio,
+                        maximum_amount,
+                    }),
+                    rest,
+                )
+            }
+            SwapInstructionTag::DepositTokensExactAmounts => {
+                let (token_a_amount, rest) = Self::unpack_u64(rest)?;
+                let (token_b_amount, rest) = Self::unpack_u64(rest)?;
+                let (minimum_pool_token_amount, rest) = Self::unpack_u64(rest)?;
+                (
+                    Self::DepositTokensExactAmounts(DepositTokensExactAmounts {
+                        token_a_amount,
+                        token_b_amount,
+                        minimum_pool_token_amount,
+                    }),
+                    rest,
+                )
+            }
+            SwapInstructionTag::SetCurveParameter => {
+                let (parameter, rest) = Self::unpack_u64(rest)?;
+                (
+                    Self::SetCurveParameter(SetCurveParameter { parameter }),
+                    rest,
+                )
+            }
+            SwapInstructionTag::QueryReserves => (Self::QueryReserves, rest),
+            SwapInstructionTag::CollectOwnerFees => (Self::CollectOwnerFees, rest),
+            SwapInstructionTag::Migrate => (Self::Migrate, rest),
+            SwapInstructionTag::WithdrawAllTokenTypes => {
+                let (minimum_token_a_amount, rest) = Self::unpack_u64(rest)?;
+                let (minimum_token_b_amount, rest) = Self::unpack_u64(rest)?;
+                (
+                    Self::WithdrawAllTokenTypes(WithdrawAllTokenTypes {
+                        minimum_token_a_amount,
+                        minimum_token_b_amount,
+                    }),
+                    rest,
+                )
+            }
+            SwapInstructionTag::SetFeeAccounts => (Self::SetFeeAccounts, rest),
+            SwapInstructionTag::SwapWithSlippageBps => {
+                let (amount_in, rest) = Self::unpack_u64(rest)?;
+                let (slippage_bps, rest) = Self::unpack_u16(rest)?;
+                (
+                    Self::SwapWithSlippageBps(SwapWithSlippageBps {
+                        amount_in,
+                        slippage_bps,
+                    }),
+                    rest,
+                )
+            }
+            SwapInstructionTag::RouteSwap => {
+                let (amount_in, rest) = Self::unpack_u64(rest)?;
+                let (minimum_amount_out, rest) = Self::unpack_u64(rest)?;
+                (
+                    Self::RouteSwap(RouteSwap {
+                        amount_in,
+                        minimum_amount_out,
+                    }),
+                    rest,
+                )
+            }
+            SwapInstructionTag::InitializeWithDeposit => {
+                let (initialize, rest) = Self::unpack_initialize(rest)?;
+                let (token_a_amount, rest) = Self::unpack_u64(rest)?;
+                let (token_b_amount, rest) = Self::unpack_u64(rest)?;
+                (
+                    Self::InitializeWithDeposit(InitializeWithDeposit {
+                        initialize,
+                        token_a_amount,
+                        token_b_amount,
+                    }),
+                    rest,
+                )
+            }
+            SwapInstructionTag::VerifyAuthority => (Self::VerifyAuthority, rest),
+            SwapInstructionTag::ValidateInitialize => {
+                let (initialize, rest) = Self::unpack_initialize(rest)?;
+                (Self::ValidateInitialize(initialize), rest)
+            }
+            SwapInstructionTag::SwapExactOutOrPartial => {
+                let (amount_out, rest) = Self::unpack_u64(rest)?;
+                let (maximum_amount_in, rest) = Self::unpack_u64(rest)?;
+                let (allow_partial, rest) = Self::unpack_bool(rest)?;
+                (
+                    Self::SwapExactOutOrPartial(SwapExactOutOrPartial {
+                        amount_out,
+                        maximum_amount_in,
+                        allow_partial,
+                    }),
+                    rest,
+                )
+            }
+        };
+        // extra bytes mean the data was built for another layout
+        if !rest.is_empty() {
+            return Err(SwapError::InvalidInstruction.into());
+        }
+        Ok(instruction)
     }
 
     fn unpack_u64(input: &[u8]) -> Result<(u64, &[u8]), ProgramError> {
@@ -177,69 +915,652 @@ impl SwapInstruction {
         }
     }
 
+    fn unpack_initialize(input: &[u8]) -> Result<(Initialize, &[u8]), ProgramError> {
+        if input.len() < Fees::LEN {
+            return Err(SwapError::InvalidInstruction.into());
+        }
+        let (fees, rest) = input.split_at(Fees::LEN);
+        let fees = Fees::unpack_unchecked(fees)?;
+        let (owner, rest) = Self::unpack_pubkey(rest)?;
+        let (&curve_type, rest) = rest.split_first().ok_or(SwapError::InvalidInstruction)?;
+        let curve_type =
+            CurveType::try_from_primitive(curve_type).or(Err(SwapError::UnsupportedCurveType))?;
+        let (curve_parameter, rest) = Self::unpack_u64(rest)?;
+        let (reward_cap_numerator, rest) = Self::unpack_u64(rest)?;
+        let (reward_cap_denominator, rest) = Self::unpack_u64(rest)?;
+        let (price_band_bps, rest) = Self::unpack_u16(rest)?;
+        let (initial_pool_amount, rest) = Self::unpack_u64(rest)?;
+        let (minimum_pool_token_amount, rest) = Self::unpack_u64(rest)?;
+        let (round_up_deposits, rest) = Self::unpack_bool(rest)?;
+        let (max_price_impact_bps, rest) = Self::unpack_u16(rest)?;
+        Ok((
+            Initialize {
+                fees,
+                owner,
+                curve_type,
+                curve_parameter,
+                reward_cap_numerator,
+                reward_cap_denominator,
+                price_band_bps,
+                initial_pool_amount,
+                minimum_pool_token_amount,
+                round_up_deposits,
+                max_price_impact_bps,
+            },
+            rest,
+        ))
+    }
+
+    fn pack_initialize(initialize: &Initialize, buf: &mut Vec<u8>) {
+        let mut fees_slice = [0u8; Fees::LEN];
+        Pack::pack_into_slice(&initialize.fees, &mut fees_slice[..]);
+        buf.extend_from_slice(&fees_slice);
+        buf.extend_from_slice(initialize.owner.as_ref());
+        buf.push(initialize.curve_type as u8);
+        buf.extend_from_slice(&initialize.curve_parameter.to_le_bytes());
+        buf.extend_from_slice(&initialize.reward_cap_numerator.to_le_bytes());
+        buf.extend_from_slice(&initialize.reward_cap_denominator.to_le_bytes());
+        buf.extend_from_slice(&initialize.price_band_bps.to_le_bytes());
+        buf.extend_from_slice(&initialize.initial_pool_amount.to_le_bytes());
+        buf.extend_from_slice(&initialize.minimum_pool_token_amount.to_le_bytes());
+        buf.push(initialize.round_up_deposits as u8);
+        buf.extend_from_slice(&initialize.max_price_impact_bps.to_le_bytes());
+    }
+
+    fn unpack_bool(input: &[u8]) -> Result<(bool, &[u8]), ProgramError> {
+        match input.split_first() {
+            Some((0, rest)) => Ok((false, rest)),
+            Some((1, rest)) => Ok((true, rest)),
+            _ => Err(SwapError::InvalidInstruction.into()),
+        }
+    }
+
+    fn unpack_u16(input: &[u8]) -> Result<(u16, &[u8]), ProgramError> {
+        if input.len() >= 2 {
+            let (amount, rest) = input.split_at(2);
+            let amount = amount
+                .get(..2)
+                .and_then(|slice| slice.try_into().ok())
+                .map(u16::from_le_bytes)
+                .ok_or(SwapError::InvalidInstruction)?;
+            Ok((amount, rest))
+        } else {
+            Err(SwapError::InvalidInstruction.into())
+        }
+    }
+
+    fn unpack_pubkey(input: &[u8]) -> Result<(Pubkey, &[u8]), ProgramError> {
+        if input.len() >= PUBKEY_BYTES {
+            let (key, rest) = input.split_at(PUBKEY_BYTES);
+            let key = key
+                .try_into()
+                .map(Pubkey::new_from_array)
+                .map_err(|_| SwapError::InvalidInstruction)?;
+            Ok((key, rest))
+        } else {
+            Err(SwapError::InvalidInstruction.into())
+        }
+    }
+
     /// Packs a [SwapInstruction](enum.SwapInstruction.html) into a byte buffer.
     pub fn pack(&self) -> Vec<u8> {
         let mut buf = Vec::with_capacity(size_of::<Self>());
+        buf.push(self.tag() as u8);
         match &*self {
-            Self::Initialize(Initialize { fees }) => {
-                buf.push(0);
-                let mut fees_slice = [0u8; Fees::LEN];
-                Pack::pack_into_slice(fees, &mut fees_slice[..]);
-                buf.extend_from_slice(&fees_slice);
+            Self::Initialize(initialize) => {
+                Self::pack_initialize(initialize, &mut buf);
             }
             Self::DepositTokens(DepositTokens {
                 pool_token_amount,
                 maximum_token_a_amount,
                 maximum_token_b_amount,
+                expected_ratio_numerator,
+                expected_ratio_denominator,
             }) => {
-                buf.push(1);
                 buf.extend_from_slice(&pool_token_amount.to_le_bytes());
                 buf.extend_from_slice(&maximum_token_a_amount.to_le_bytes());
                 buf.extend_from_slice(&maximum_token_b_amount.to_le_bytes());
+                if *expected_ratio_numerator != 0 || *expected_ratio_denominator != 0 {
+                    buf.extend_from_slice(&expected_ratio_numerator.to_le_bytes());
+                    buf.extend_from_slice(&expected_ratio_denominator.to_le_bytes());
+                }
             }
             Self::WithdrawTokens(WithdrawTokens {
                 pool_token_amount,
                 minimum_token_a_amount,
                 minimum_token_b_amount,
+                preferred_mint,
+                minimum_preferred_amount,
             }) => {
-                buf.push(2);
                 buf.extend_from_slice(&pool_token_amount.to_le_bytes());
                 buf.extend_from_slice(&minimum_token_a_amount.to_le_bytes());
                 buf.extend_from_slice(&minimum_token_b_amount.to_le_bytes());
+                if let Some(preferred_mint) = preferred_mint {
+                    buf.extend_from_slice(preferred_mint.as_ref());
+                    buf.extend_from_slice(&minimum_preferred_amount.to_le_bytes());
+                }
             }
             Self::Swap(Swap {
                 amount_in,
                 minimum_amount_out,
+                deadline_slot,
             }) => {
-                buf.push(3);
                 buf.extend_from_slice(&amount_in.to_le_bytes());
                 buf.extend_from_slice(&minimum_amount_out.to_le_bytes());
+                // left off when disabled, keeping the original layout
+                if *deadline_slot != 0 {
+                    buf.extend_from_slice(&deadline_slot.to_le_bytes());
+                }
+            }
+            Self::BatchQuote(BatchQuote { amounts_in }) => {
+                buf.push(amounts_in.len() as u8);
+                for amount_in in amounts_in {
+                    buf.extend_from_slice(&amount_in.to_le_bytes());
+                }
+            }
+            Self::FlashLoan(FlashLoan { amount, token }) => {
+                buf.extend_from_slice(&amount.to_le_bytes());
+                buf.extend_from_slice(token.as_ref());
             }
+            Self::Rebalance(Rebalance {
+                target_ratio,
+                maximum_amount,
+            }) => {
+                buf.extend_from_slice(&target_ratio.to_le_bytes());
+                buf.extend_from_slice(&maximum_amount.to_le_bytes());
+            }
+            Self::DepositTokensExactAmounts(DepositTokensExactAmounts {
+                token_a_amount,
+                token_b_amount,
+                minimum_pool_token_amount,
+            }) => {
+                buf.extend_from_slice(&token_a_amount.to_le_bytes());
+                buf.extend_from_slice(&token_b_amount.to_le_bytes());
+                buf.extend_from_slice(&minimum_pool_token_amount.to_le_bytes());
+            }
+            Self::SetCurveParameter(SetCurveParameter { parameter }) => {
+                buf.extend_from_slice(&parameter.to_le_bytes());
+            }
+            Self::WithdrawAllTokenTypes(WithdrawAllTokenTypes {
+                minimum_token_a_amount,
+                minimum_token_b_amount,
+            }) => {
+                buf.extend_from_slice(&minimum_token_a_amount.to_le_bytes());
+                buf.extend_from_slice(&minimum_token_b_amount.to_le_bytes());
+            }
+            Self::SwapWithSlippageBps(SwapWithSlippageBps {
+                amount_in,
+                slippage_bps,
+            }) => {
+                buf.extend_from_slice(&amount_in.to_le_bytes());
+                buf.extend_from_slice(&slippage_bps.to_le_bytes());
+            }
+            Self::RouteSwap(RouteSwap {
+                amount_in,
+                minimum_amount_out,
+            }) => {
+                buf.extend_from_slice(&amount_in.to_le_bytes());
+                buf.extend_from_slice(&minimum_amount_out.to_le_bytes());
+            }
+            Self::InitializeWithDeposit(InitializeWithDeposit {
+                initialize,
+                token_a_amount,
+                token_b_amount,
+            }) => {
+                Self::pack_initialize(initialize, &mut buf);
+                buf.extend_from_slice(&token_a_amount.to_le_bytes());
+                buf.extend_from_slice(&token_b_amount.to_le_bytes());
+            }
+            Self::ValidateInitialize(initialize) => {
+                Self::pack_initialize(initialize, &mut buf);
+            }
+            Self::SwapExactOutOrPartial(SwapExactOutOrPartial {
+                amount_out,
+                maximum_amount_in,
+                allow_partial,
+            }) => {
+                buf.extend_from_slice(&amount_out.to_le_bytes());
+                buf.extend_from_slice(&maximum_amount_in.to_le_bytes());
+                buf.push(*allow_partial as u8);
+            }
+            Self::ClosePool
+            | Self::FlashLoanRepay
+            | Self::QueryReserves
+            | Self::CollectOwnerFees
+            | Self::Migrate
+            | Self::SetFeeAccounts
+            | Self::VerifyAuthority => {}
         }
         buf
     }
+
+    /// Tag of the instruction, the first byte of its packed data
+    pub fn tag(&self) -> SwapInstructionTag {
+        match self {
+            Self::Initialize(_) => SwapInstructionTag::Initialize,
+            Self::DepositTokens(_) => SwapInstructionTag::DepositTokens,
+            Self::WithdrawTokens(_) => SwapInstructionTag::WithdrawTokens,
+            Self::Swap(_) => SwapInstructionTag::Swap,
+            Self::ClosePool => SwapInstructionTag::ClosePool,
+            Self::BatchQuote(_) => SwapInstructionTag::BatchQuote,
+            Self::FlashLoan(_) => SwapInstructionTag::FlashLoan,
+            Self::FlashLoanRepay => SwapInstructionTag::FlashLoanRepay,
+            Self::Rebalance(_) => SwapInstructionTag::Rebalance,
+            Self::DepositTokensExactAmounts(_) => SwapInstructionTag::DepositTokensExactAmounts,
+            Self::SetCurveParameter(_) => SwapInstructionTag::SetCurveParameter,
+            Self::QueryReserves => SwapInstructionTag::QueryReserves,
+            Self::CollectOwnerFees => SwapInstructionTag::CollectOwnerFees,
+            Self::Migrate => SwapInstructionTag::Migrate,
+            Self::WithdrawAllTokenTypes(_) => SwapInstructionTag::WithdrawAllTokenTypes,
+            Self::SetFeeAccounts => SwapInstructionTag::SetFeeAccounts,
+            Self::SwapWithSlippageBps(_) => SwapInstructionTag::SwapWithSlippageBps,
+            Self::RouteSwap(_) => SwapInstructionTag::RouteSwap,
+            Self::InitializeWithDeposit(_) => SwapInstructionTag::InitializeWithDeposit,
+            Self::VerifyAuthority => SwapInstructionTag::VerifyAuthority,
+            Self::ValidateInitialize(_) => SwapInstructionTag::ValidateInitialize,
+            Self::SwapExactOutOrPartial(_) => SwapInstructionTag::SwapExactOutOrPartial,
+        }
+    }
+}
+
+/// Accounts of an `Initialize` instruction, in the order and with the
+/// signer and writable flags the program expects
+pub fn initialize_accounts(
+    swap_pubkey: &Pubkey,
+    authority_pubkey: &Pubkey,
+    token_a_pubkey: &Pubkey,
+    token_b_pubkey: &Pubkey,
+    pool_mint_pubkey: &Pubkey,
+    token_a_fee_pubkey: &Pubkey,
+    token_b_fee_pubkey: &Pubkey,
+    destination_pubkey: &Pubkey,
+    token_program_id: &Pubkey,
+    pool_token_program_id: &Pubkey,
+    token_a_mint_pubkey: &Pubkey,
+    token_b_mint_pubkey: &Pubkey,
+) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new(*swap_pubkey, true),
+        AccountMeta::new_readonly(*authority_pubkey, false),
+        AccountMeta::new_readonly(*token_a_pubkey, false),
+        AccountMeta::new_readonly(*token_b_pubkey, false),
+        AccountMeta::new(*pool_mint_pubkey, false),
+        AccountMeta::new_readonly(*token_a_fee_pubkey, false),
+        AccountMeta::new_readonly(*token_b_fee_pubkey, false),
+        AccountMeta::new(*destination_pubkey, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(*pool_token_program_id, false),
+        AccountMeta::new_readonly(*token_a_mint_pubkey, false),
+        AccountMeta::new_readonly(*token_b_mint_pubkey, false),
+    ]
 }
 
 mod tests {
     #[allow(unused_imports)]
     use super::*;
 
+    #[test]
+    fn initialize_accounts_order() {
+        let keys = (0..12u8)
+            .map(|i| Pubkey::new_from_array([i; 32]))
+            .collect::<Vec<_>>();
+        let accounts = initialize_accounts(
+            &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5], &keys[6], &keys[7],
+            &keys[8], &keys[9], &keys[10], &keys[11],
+        );
+        assert_eq!(accounts.len(), 12);
+        assert_eq!(
+            accounts.iter().map(|meta| meta.pubkey).collect::<Vec<_>>(),
+            keys
+        );
+        // only the new swap account signs, and it is writable
+        let signers = accounts
+            .iter()
+            .filter(|meta| meta.is_signer)
+            .collect::<Vec<_>>();
+        assert_eq!(signers, vec![&AccountMeta::new(keys[0], true)]);
+        let writable = accounts
+            .iter()
+            .filter(|meta| meta.is_writable)
+            .map(|meta| meta.pubkey)
+            .collect::<Vec<_>>();
+        assert_eq!(writable, vec![keys[0], keys[4], keys[7]]);
+    }
+
+    /// One instruction of every kind, with optional fields set and unset
+    #[cfg(test)]
+    fn sample_instructions() -> Vec<SwapInstruction> {
+        let initialize = || Initialize {
+            fees: Fees::default(),
+            owner: Pubkey::new_from_array([1u8; 32]),
+            curve_type: CurveType::ConstantProduct,
+            curve_parameter: 0,
+            reward_cap_numerator: 0,
+            reward_cap_denominator: 0,
+            price_band_bps: 0,
+            initial_pool_amount: 0,
+            minimum_pool_token_amount: 0,
+            round_up_deposits: true,
+            max_price_impact_bps: 0,
+        };
+        vec![
+            SwapInstruction::Initialize(initialize()),
+            SwapInstruction::DepositTokens(DepositTokens {
+                pool_token_amount: 1,
+                maximum_token_a_amount: 2,
+                maximum_token_b_amount: 3,
+                expected_ratio_numerator: 0,
+                expected_ratio_denominator: 0,
+            }),
+            SwapInstruction::DepositTokens(DepositTokens {
+                pool_token_amount: 1,
+                maximum_token_a_amount: 2,
+                maximum_token_b_amount: 3,
+                expected_ratio_numerator: 4,
+                expected_ratio_denominator: 5,
+            }),
+            SwapInstruction::WithdrawTokens(WithdrawTokens {
+                pool_token_amount: 1,
+                minimum_token_a_amount: 2,
+                minimum_token_b_amount: 3,
+                preferred_mint: None,
+                minimum_preferred_amount: 0,
+            }),
+            SwapInstruction::WithdrawTokens(WithdrawTokens {
+                pool_token_amount: 1,
+                minimum_token_a_amount: 2,
+                minimum_token_b_amount: 3,
+                preferred_mint: Some(Pubkey::new_from_array([2u8; 32])),
+                minimum_preferred_amount: 4,
+            }),
+            SwapInstruction::Swap(Swap {
+                amount_in: 1,
+                minimum_amount_out: 2,
+                deadline_slot: 0,
+            }),
+            SwapInstruction::Swap(Swap {
+                amount_in: 1,
+                minimum_amount_out: 2,
+                deadline_slot: 3,
+            }),
+            SwapInstruction::ClosePool,
+            SwapInstruction::BatchQuote(BatchQuote {
+                amounts_in: vec![1, 2],
+            }),
+            SwapInstruction::FlashLoan(FlashLoan {
+                amount: 1,
+                token: Pubkey::new_from_array([2u8; 32]),
+            }),
+            SwapInstruction::FlashLoanRepay,
+            SwapInstruction::Rebalance(Rebalance {
+                target_ratio: 1,
+                maximum_amount: 2,
+            }),
+            SwapInstruction::DepositTokensExactAmounts(DepositTokensExactAmounts {
+                token_a_amount: 1,
+                token_b_amount: 2,
+                minimum_pool_token_amount: 3,
+            }),
+            SwapInstruction::SetCurveParameter(SetCurveParameter { parameter: 1 }),
+            SwapInstruction::QueryReserves,
+            SwapInstruction::CollectOwnerFees,
+            SwapInstruction::Migrate,
+            SwapInstruction::WithdrawAllTokenTypes(WithdrawAllTokenTypes {
+                minimum_token_a_amount: 1,
+                minimum_token_b_amount: 2,
+            }),
+            SwapInstruction::SetFeeAccounts,
+            SwapInstruction::SwapWithSlippageBps(SwapWithSlippageBps {
+                amount_in: 1,
+                slippage_bps: 2,
+            }),
+            SwapInstruction::RouteSwap(RouteSwap {
+                amount_in: 1,
+                minimum_amount_out: 2,
+            }),
+            SwapInstruction::InitializeWithDeposit(InitializeWithDeposit {
+                initialize: initialize(),
+                token_a_amount: 1,
+                token_b_amount: 2,
+            }),
+            SwapInstruction::VerifyAuthority,
+            SwapInstruction::ValidateInitialize(initialize()),
+            SwapInstruction::SwapExactOutOrPartial(SwapExactOutOrPartial {
+                amount_out: 1,
+                maximum_amount_in: 2,
+                allow_partial: true,
+            }),
+        ]
+    }
+
+    #[test]
+    fn tag_matches_packed_byte() {
+        for instruction in sample_instructions() {
+            let tag = instruction.tag();
+            assert_eq!(instruction.pack()[0], tag as u8, "{:?}", instruction);
+            assert_eq!(SwapInstructionTag::try_from(tag as u8), Ok(tag));
+        }
+        let tags = (0..=u8::MAX)
+            .filter(|&tag| SwapInstructionTag::try_from(tag).is_ok())
+            .count();
+        assert_eq!(tags, 22);
+    }
+
+    #[test]
+    fn unpack_rejects_trailing_bytes() {
+        for instruction in sample_instructions() {
+            let mut packed = instruction.pack();
+            assert_eq!(SwapInstruction::unpack(&packed).unwrap(), instruction);
+            packed.push(0);
+            assert_eq!(
+                SwapInstruction::unpack(&packed),
+                Err(SwapError::InvalidInstruction.into()),
+                "{:?}",
+                instruction
+            );
+        }
+    }
+
     #[test]
     fn pack_initialize() {
         let trade_fee_numerator: u64 = 1;
         let trade_fee_denominator: u64 = 4;
+        let owner_trade_fee_numerator: u64 = 2;
+        let owner_trade_fee_denominator: u64 = 5;
+        let flash_loan_fee_numerator: u64 = 3;
+        let flash_loan_fee_denominator: u64 = 1000;
         let fees = Fees {
             trade_fee_numerator,
             trade_fee_denominator,
+            owner_trade_fee_numerator,
+            owner_trade_fee_denominator,
+            flash_loan_fee_numerator,
+            flash_loan_fee_denominator,
         };
-        let check = SwapInstruction::Initialize(Initialize { fees });
+        let owner = Pubkey::new_from_array([1u8; 32]);
+        let curve_type = CurveType::Offset;
+        let curve_parameter: u64 = 1_000;
+        let reward_cap_numerator: u64 = 1;
+        let reward_cap_denominator: u64 = 10;
+        let price_band_bps: u16 = 500;
+        let initial_pool_amount: u64 = 1_000_000;
+        let minimum_pool_token_amount: u64 = 900_000;
+        let max_price_impact_bps: u16 = 250;
+        let check = SwapInstruction::Initialize(Initialize {
+            fees: fees.clone(),
+            owner,
+            curve_type,
+            curve_parameter,
+            reward_cap_numerator,
+            reward_cap_denominator,
+            price_band_bps,
+            initial_pool_amount,
+            minimum_pool_token_amount,
+            round_up_deposits: false,
+            max_price_impact_bps,
+        });
         let packed = check.pack();
         let mut expect = vec![0u8];
         expect.extend_from_slice(&trade_fee_numerator.to_le_bytes());
         expect.extend_from_slice(&trade_fee_denominator.to_le_bytes());
+        expect.extend_from_slice(&owner_trade_fee_numerator.to_le_bytes());
+        expect.extend_from_slice(&owner_trade_fee_denominator.to_le_bytes());
+        expect.extend_from_slice(&flash_loan_fee_numerator.to_le_bytes());
+        expect.extend_from_slice(&flash_loan_fee_denominator.to_le_bytes());
+        expect.extend_from_slice(owner.as_ref());
+        expect.push(curve_type as u8);
+        expect.extend_from_slice(&curve_parameter.to_le_bytes());
+        expect.extend_from_slice(&reward_cap_numerator.to_le_bytes());
+        expect.extend_from_slice(&reward_cap_denominator.to_le_bytes());
+        expect.extend_from_slice(&price_band_bps.to_le_bytes());
+        expect.extend_from_slice(&initial_pool_amount.to_le_bytes());
+        expect.extend_from_slice(&minimum_pool_token_amount.to_le_bytes());
+        expect.push(0);
+        expect.extend_from_slice(&max_price_impact_bps.to_le_bytes());
         assert_eq!(packed, expect);
         let unpacked = SwapInstruction::unpack(&expect).unwrap();
         assert_eq!(unpacked, check);
+
+        // data without the trailing fields favors the pool and leaves swaps
+        // unbounded
+        let legacy_initialize = |round_up_deposits, max_price_impact_bps| {
+            SwapInstruction::Initialize(Initialize {
+                fees: fees.clone(),
+                owner,
+                curve_type,
+                curve_parameter,
+                reward_cap_numerator,
+                reward_cap_denominator,
+                price_band_bps,
+                initial_pool_amount,
+                minimum_pool_token_amount,
+                round_up_deposits,
+                max_price_impact_bps,
+            })
+        };
+        assert_eq!(
+            SwapInstruction::unpack(&expect[..expect.len() - 2]).unwrap(),
+            legacy_initialize(false, 0)
+        );
+        assert_eq!(
+            SwapInstruction::unpack(&expect[..expect.len() - 3]).unwrap(),
+            legacy_initialize(true, 0)
+        );
+        assert_eq!(
+            SwapInstruction::unpack(&expect[..expect.len() - 1]),
+            Err(SwapError::InvalidInstruction.into())
+        );
+        let mut invalid_flag = expect.clone();
+        invalid_flag[expect.len() - 3] = 2;
+        assert_eq!(
+            SwapInstruction::unpack(&invalid_flag),
+            Err(SwapError::InvalidInstruction.into())
+        );
+
+        // every curve round trips with its parameter
+        for (curve_type, curve_parameter) in [
+            (CurveType::ConstantProduct, 0),
+            (CurveType::Offset, 1_000),
+            (CurveType::ConstantPrice, 25),
+            (CurveType::Stable, 100),
+        ] {
+            let check = SwapInstruction::Initialize(Initialize {
+                fees: fees.clone(),
+                owner,
+                curve_type,
+                curve_parameter,
+                reward_cap_numerator,
+                reward_cap_denominator,
+                price_band_bps,
+                initial_pool_amount,
+                minimum_pool_token_amount,
+                round_up_deposits: true,
+                max_price_impact_bps,
+            });
+            let packed = check.pack();
+            assert_eq!(packed[1 + Fees::LEN + PUBKEY_BYTES], curve_type as u8);
+            assert_eq!(SwapInstruction::unpack(&packed).unwrap(), check);
+        }
+
+        expect[1 + Fees::LEN + PUBKEY_BYTES] = 4;
+        assert_eq!(
+            SwapInstruction::unpack(&expect),
+            Err(SwapError::UnsupportedCurveType.into())
+        );
+    }
+
+    #[test]
+    fn pack_validate_initialize() {
+        let initialize = Initialize {
+            fees: Fees {
+                trade_fee_numerator: 1,
+                trade_fee_denominator: 4,
+                ..Fees::default()
+            },
+            owner: Pubkey::new_from_array([1u8; 32]),
+            curve_type: CurveType::ConstantProduct,
+            curve_parameter: 0,
+            reward_cap_numerator: 0,
+            reward_cap_denominator: 0,
+            price_band_bps: 0,
+            initial_pool_amount: 1_000_000,
+            minimum_pool_token_amount: 900_000,
+            round_up_deposits: true,
+            max_price_impact_bps: 0,
+        };
+        let mut expect = SwapInstruction::Initialize(Initialize {
+            fees: initialize.fees.clone(),
+            ..initialize
+        })
+        .pack();
+        expect[0] = 20;
+        let check = SwapInstruction::ValidateInitialize(initialize);
+        assert_eq!(check.pack(), expect);
+        assert_eq!(SwapInstruction::unpack(&expect).unwrap(), check);
+    }
+
+    #[test]
+    fn pack_initialize_with_deposit() {
+        let initialize = Initialize {
+            fees: Fees {
+                trade_fee_numerator: 1,
+                trade_fee_denominator: 4,
+                ..Fees::default()
+            },
+            owner: Pubkey::new_from_array([1u8; 32]),
+            curve_type: CurveType::ConstantProduct,
+            curve_parameter: 0,
+            reward_cap_numerator: 0,
+            reward_cap_denominator: 0,
+            price_band_bps: 0,
+            initial_pool_amount: 1_000_000,
+            minimum_pool_token_amount: 900_000,
+            round_up_deposits: true,
+            max_price_impact_bps: 0,
+        };
+        let token_a_amount: u64 = 1_000;
+        let token_b_amount: u64 = 2_000;
+        let mut expect = SwapInstruction::Initialize(Initialize {
+            fees: initialize.fees.clone(),
+            ..initialize
+        })
+        .pack();
+        expect[0] = 18;
+        expect.extend_from_slice(&token_a_amount.to_le_bytes());
+        expect.extend_from_slice(&token_b_amount.to_le_bytes());
+        let check = SwapInstruction::InitializeWithDeposit(InitializeWithDeposit {
+            initialize,
+            token_a_amount,
+            token_b_amount,
+        });
+        assert_eq!(check.pack(), expect);
+        assert_eq!(SwapInstruction::unpack(&expect).unwrap(), check);
+
+        expect.pop();
+        assert_eq!(
+            SwapInstruction::unpack(&expect),
+            Err(SwapError::InvalidInstruction.into())
+        );
     }
 
     #[test]
@@ -251,6 +1572,8 @@ mod tests {
             pool_token_amount,
             maximum_token_a_amount,
             maximum_token_b_amount,
+            expected_ratio_numerator: 0,
+            expected_ratio_denominator: 0,
         });
         let packed = check.pack();
         let mut expect = vec![1];
@@ -260,6 +1583,42 @@ mod tests {
         assert_eq!(packed, expect);
         let unpacked = SwapInstruction::unpack(&expect).unwrap();
         assert_eq!(unpacked, check);
+
+        let expected_ratio_numerator: u64 = 1;
+        let expected_ratio_denominator: u64 = 2;
+        let check = SwapInstruction::DepositTokens(DepositTokens {
+            pool_token_amount,
+            maximum_token_a_amount,
+            maximum_token_b_amount,
+            expected_ratio_numerator,
+            expected_ratio_denominator,
+        });
+        let packed = check.pack();
+        expect.extend_from_slice(&expected_ratio_numerator.to_le_bytes());
+        expect.extend_from_slice(&expected_ratio_denominator.to_le_bytes());
+        assert_eq!(packed, expect);
+        let unpacked = SwapInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+    }
+
+    #[test]
+    fn pack_deposit_exact_amounts() {
+        let token_a_amount: u64 = 10;
+        let token_b_amount: u64 = 20;
+        let minimum_pool_token_amount: u64 = 5;
+        let check = SwapInstruction::DepositTokensExactAmounts(DepositTokensExactAmounts {
+            token_a_amount,
+            token_b_amount,
+            minimum_pool_token_amount,
+        });
+        let packed = check.pack();
+        let mut expect = vec![9];
+        expect.extend_from_slice(&token_a_amount.to_le_bytes());
+        expect.extend_from_slice(&token_b_amount.to_le_bytes());
+        expect.extend_from_slice(&minimum_pool_token_amount.to_le_bytes());
+        assert_eq!(packed, expect);
+        let unpacked = SwapInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
     }
 
     #[test]
@@ -271,6 +1630,8 @@ mod tests {
             pool_token_amount,
             minimum_token_a_amount,
             minimum_token_b_amount,
+            preferred_mint: None,
+            minimum_preferred_amount: 0,
         });
         let packed = check.pack();
         let mut expect = vec![2];
@@ -280,6 +1641,22 @@ mod tests {
         assert_eq!(packed, expect);
         let unpacked = SwapInstruction::unpack(&expect).unwrap();
         assert_eq!(unpacked, check);
+
+        let preferred_mint = Pubkey::new_from_array([3u8; 32]);
+        let minimum_preferred_amount: u64 = 1_000;
+        let check = SwapInstruction::WithdrawTokens(WithdrawTokens {
+            pool_token_amount,
+            minimum_token_a_amount,
+            minimum_token_b_amount,
+            preferred_mint: Some(preferred_mint),
+            minimum_preferred_amount,
+        });
+        let packed = check.pack();
+        expect.extend_from_slice(preferred_mint.as_ref());
+        expect.extend_from_slice(&minimum_preferred_amount.to_le_bytes());
+        assert_eq!(packed, expect);
+        let unpacked = SwapInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
     }
 
     #[test]
@@ -289,6 +1666,7 @@ mod tests {
         let check = SwapInstruction::Swap(Swap {
             amount_in,
             minimum_amount_out,
+            deadline_slot: 0,
         });
         let packed = check.pack();
         let mut expect = vec![3];
@@ -297,5 +1675,229 @@ mod tests {
         assert_eq!(packed, expect);
         let unpacked = SwapInstruction::unpack(&expect).unwrap();
         assert_eq!(unpacked, check);
+
+        let deadline_slot: u64 = 42;
+        let check = SwapInstruction::Swap(Swap {
+            amount_in,
+            minimum_amount_out,
+            deadline_slot,
+        });
+        let packed = check.pack();
+        expect.extend_from_slice(&deadline_slot.to_le_bytes());
+        assert_eq!(packed, expect);
+        let unpacked = SwapInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+    }
+
+    #[test]
+    fn pack_flash_loan() {
+        let amount: u64 = 1_000;
+        let token = Pubkey::new_from_array([1u8; 32]);
+        let check = SwapInstruction::FlashLoan(FlashLoan { amount, token });
+        let packed = check.pack();
+        let mut expect = vec![6];
+        expect.extend_from_slice(&amount.to_le_bytes());
+        expect.extend_from_slice(token.as_ref());
+        assert_eq!(packed, expect);
+        let unpacked = SwapInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+
+        let check = SwapInstruction::FlashLoanRepay;
+        let packed = check.pack();
+        let expect = vec![7];
+        assert_eq!(packed, expect);
+        let unpacked = SwapInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+    }
+
+    #[test]
+    fn pack_rebalance() {
+        let target_ratio: u64 = 2_000_000_000;
+        let maximum_amount: u64 = 500;
+        let check = SwapInstruction::Rebalance(Rebalance {
+            target_ratio,
+            maximum_amount,
+        });
+        let packed = check.pack();
+        let mut expect = vec![8];
+        expect.extend_from_slice(&target_ratio.to_le_bytes());
+        expect.extend_from_slice(&maximum_amount.to_le_bytes());
+        assert_eq!(packed, expect);
+        let unpacked = SwapInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+    }
+
+    #[test]
+    fn pack_set_curve_parameter() {
+        let parameter: u64 = 1_000_000;
+        let check = SwapInstruction::SetCurveParameter(SetCurveParameter { parameter });
+        let packed = check.pack();
+        let mut expect = vec![10];
+        expect.extend_from_slice(&parameter.to_le_bytes());
+        assert_eq!(packed, expect);
+        let unpacked = SwapInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+    }
+
+    #[test]
+    fn pack_query_reserves() {
+        let check = SwapInstruction::QueryReserves;
+        let packed = check.pack();
+        let expect = vec![11];
+        assert_eq!(packed, expect);
+        let unpacked = SwapInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+    }
+
+    #[test]
+    fn pack_verify_authority() {
+        let check = SwapInstruction::VerifyAuthority;
+        let packed = check.pack();
+        let expect = vec![19];
+        assert_eq!(packed, expect);
+        let unpacked = SwapInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+    }
+
+    #[test]
+    fn pack_collect_owner_fees() {
+        let check = SwapInstruction::CollectOwnerFees;
+        let packed = check.pack();
+        let expect = vec![12];
+        assert_eq!(packed, expect);
+        let unpacked = SwapInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+    }
+
+    #[test]
+    fn pack_migrate() {
+        let check = SwapInstruction::Migrate;
+        let packed = check.pack();
+        let expect = vec![13];
+        assert_eq!(packed, expect);
+        let unpacked = SwapInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+    }
+
+    #[test]
+    fn pack_withdraw_all_token_types() {
+        let minimum_token_a_amount: u64 = 10;
+        let minimum_token_b_amount: u64 = 20;
+        let check = SwapInstruction::WithdrawAllTokenTypes(WithdrawAllTokenTypes {
+            minimum_token_a_amount,
+            minimum_token_b_amount,
+        });
+        let packed = check.pack();
+        let mut expect = vec![14];
+        expect.extend_from_slice(&minimum_token_a_amount.to_le_bytes());
+        expect.extend_from_slice(&minimum_token_b_amount.to_le_bytes());
+        assert_eq!(packed, expect);
+        let unpacked = SwapInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+    }
+
+    #[test]
+    fn pack_set_fee_accounts() {
+        let check = SwapInstruction::SetFeeAccounts;
+        let packed = check.pack();
+        let expect = vec![15];
+        assert_eq!(packed, expect);
+        let unpacked = SwapInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+    }
+
+    #[test]
+    fn pack_swap_with_slippage_bps() {
+        let amount_in: u64 = 2;
+        let slippage_bps: u16 = 100;
+        let check = SwapInstruction::SwapWithSlippageBps(SwapWithSlippageBps {
+            amount_in,
+            slippage_bps,
+        });
+        let packed = check.pack();
+        let mut expect = vec![16];
+        expect.extend_from_slice(&amount_in.to_le_bytes());
+        expect.extend_from_slice(&slippage_bps.to_le_bytes());
+        assert_eq!(packed, expect);
+        let unpacked = SwapInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+    }
+
+    #[test]
+    fn pack_swap_exact_out_or_partial() {
+        let amount_out: u64 = 10;
+        let maximum_amount_in: u64 = 20;
+        let check = SwapInstruction::SwapExactOutOrPartial(SwapExactOutOrPartial {
+            amount_out,
+            maximum_amount_in,
+            allow_partial: true,
+        });
+        let packed = check.pack();
+        let mut expect = vec![21];
+        expect.extend_from_slice(&amount_out.to_le_bytes());
+        expect.extend_from_slice(&maximum_amount_in.to_le_bytes());
+        expect.push(1);
+        assert_eq!(packed, expect);
+        let unpacked = SwapInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+
+        // only 0 and 1 are flags
+        *expect.last_mut().unwrap() = 2;
+        assert_eq!(
+            SwapInstruction::unpack(&expect),
+            Err(SwapError::InvalidInstruction.into())
+        );
+    }
+
+    #[test]
+    fn pack_route_swap() {
+        let amount_in: u64 = 2;
+        let minimum_amount_out: u64 = 10;
+        let check = SwapInstruction::RouteSwap(RouteSwap {
+            amount_in,
+            minimum_amount_out,
+        });
+        let packed = check.pack();
+        let mut expect = vec![17];
+        expect.extend_from_slice(&amount_in.to_le_bytes());
+        expect.extend_from_slice(&minimum_amount_out.to_le_bytes());
+        assert_eq!(packed, expect);
+        let unpacked = SwapInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+    }
+
+    #[test]
+    fn pack_close_pool() {
+        let check = SwapInstruction::ClosePool;
+        let packed = check.pack();
+        let expect = vec![4];
+        assert_eq!(packed, expect);
+        let unpacked = SwapInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+    }
+
+    #[test]
+    fn pack_batch_quote() {
+        let amounts_in = vec![1, 10, 100];
+        let check = SwapInstruction::BatchQuote(BatchQuote {
+            amounts_in: amounts_in.clone(),
+        });
+        let packed = check.pack();
+        let mut expect = vec![5, 3];
+        for amount_in in amounts_in {
+            expect.extend_from_slice(&amount_in.to_le_bytes());
+        }
+        assert_eq!(packed, expect);
+        let unpacked = SwapInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+
+        let mut expect = vec![5, MAX_QUOTE_AMOUNTS as u8 + 1];
+        for _ in 0..=MAX_QUOTE_AMOUNTS {
+            expect.extend_from_slice(&1u64.to_le_bytes());
+        }
+        assert_eq!(
+            SwapInstruction::unpack(&expect),
+            Err(SwapError::InvalidInstruction.into())
+        );
     }
 }
@@ -3,15 +3,22 @@
 //! An Uniswap-like program for the Solana blockchain.
 
 pub mod constraints;
+pub mod curve;
 pub mod error;
 pub mod instruction;
+pub mod pda;
 pub mod processor;
+pub mod quote;
+#[cfg(feature = "serde")]
+pub mod serde_pubkey;
 pub mod state;
 pub mod fees;
 
 #[cfg(not(feature = "no-entrypoint"))]
 mod entrypoint;
 
+pub use pda::swap_authority;
+
 // Export current sdk types for downstream users building with a different sdk version
 pub use solana_program;
 
@@ -0,0 +1,155 @@
+//! Program derived addresses expected when initializing a pool
+
+use solana_program::{pubkey, pubkey::Pubkey};
+
+/// Associated token account program, used to derive the reserve accounts
+pub const ASSOCIATED_TOKEN_PROGRAM_ID: Pubkey =
+    pubkey!("ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL");
+
+/// All addresses a deployer must derive before creating a pool's accounts
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InitPdas {
+    /// Swap authority, owner of the reserves and the pool mint
+    pub authority: Pubkey,
+    /// Bump seed of the swap authority
+    pub authority_bump_seed: u8,
+    /// Associated token A account of the swap authority, usable as reserve
+    pub token_a_reserve: Pubkey,
+    /// Bump seed of the token A reserve
+    pub token_a_reserve_bump_seed: u8,
+    /// Associated token B account of the swap authority, usable as reserve
+    pub token_b_reserve: Pubkey,
+    /// Bump seed of the token B reserve
+    pub token_b_reserve_bump_seed: u8,
+}
+
+/// Swap authority of a pool and its canonical bump seed, as derived and
+/// stored by `Initialize`
+pub fn swap_authority(program_id: &Pubkey, swap_key: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[&swap_key.to_bytes()], program_id)
+}
+
+/// Seed prefix of the deterministic pool address
+pub const POOL_SEED: &[u8] = b"pool";
+
+/// Deterministic address of the pool between two mints and its bump seed,
+/// the same whichever order the mints are given in, so clients can find a
+/// pool without an index
+pub fn pool_address(program_id: &Pubkey, mint_a: &Pubkey, mint_b: &Pubkey) -> (Pubkey, u8) {
+    let (first, second) = if mint_a <= mint_b {
+        (mint_a, mint_b)
+    } else {
+        (mint_b, mint_a)
+    };
+    Pubkey::find_program_address(&[POOL_SEED, first.as_ref(), second.as_ref()], program_id)
+}
+
+/// Derives the swap authority and the authority's associated token accounts
+/// for both mints, as expected by `Initialize`
+pub fn derive_init_pdas(
+    program_id: &Pubkey,
+    swap_key: &Pubkey,
+    mint_a: &Pubkey,
+    mint_b: &Pubkey,
+) -> InitPdas {
+    let (authority, authority_bump_seed) = swap_authority(program_id, swap_key);
+    let (token_a_reserve, token_a_reserve_bump_seed) =
+        find_associated_token_address(&authority, mint_a);
+    let (token_b_reserve, token_b_reserve_bump_seed) =
+        find_associated_token_address(&authority, mint_b);
+    InitPdas {
+        authority,
+        authority_bump_seed,
+        token_a_reserve,
+        token_a_reserve_bump_seed,
+        token_b_reserve,
+        token_b_reserve_bump_seed,
+    }
+}
+
+fn find_associated_token_address(wallet: &Pubkey, mint: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[wallet.as_ref(), spl_token::id().as_ref(), mint.as_ref()],
+        &ASSOCIATED_TOKEN_PROGRAM_ID,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::Processor;
+
+    #[test]
+    fn pool_address_ignores_mint_order() {
+        let program_id = crate::id();
+        let mint_a = Pubkey::new_unique();
+        let mint_b = Pubkey::new_unique();
+        let (address, bump_seed) = pool_address(&program_id, &mint_a, &mint_b);
+        assert_eq!(
+            pool_address(&program_id, &mint_b, &mint_a),
+            (address, bump_seed)
+        );
+        assert_eq!(
+            address,
+            Pubkey::create_program_address(
+                &[POOL_SEED, mint_a.as_ref(), mint_b.as_ref(), &[bump_seed]],
+                &program_id,
+            )
+            .unwrap()
+        );
+        assert_ne!(
+            address,
+            pool_address(&program_id, &mint_a, &Pubkey::new_unique()).0
+        );
+    }
+
+    #[test]
+    fn derive_init_pdas_matches_individual_derivations() {
+        let program_id = crate::id();
+        let swap_key = Pubkey::new_unique();
+        let mint_a = Pubkey::new_unique();
+        let mint_b = Pubkey::new_unique();
+        let pdas = derive_init_pdas(&program_id, &swap_key, &mint_a, &mint_b);
+
+        assert_eq!(
+            pdas.authority,
+            Processor::authority_id(&program_id, &swap_key, pdas.authority_bump_seed).unwrap()
+        );
+        assert_eq!(
+            (pdas.authority, pdas.authority_bump_seed),
+            Pubkey::find_program_address(&[&swap_key.to_bytes()], &program_id)
+        );
+
+        let (token_a_reserve, token_a_reserve_bump_seed) =
+            find_associated_token_address(&pdas.authority, &mint_a);
+        assert_eq!(pdas.token_a_reserve, token_a_reserve);
+        assert_eq!(pdas.token_a_reserve_bump_seed, token_a_reserve_bump_seed);
+        assert_eq!(
+            pdas.token_a_reserve,
+            Pubkey::create_program_address(
+                &[
+                    pdas.authority.as_ref(),
+                    spl_token::id().as_ref(),
+                    mint_a.as_ref(),
+                    &[pdas.token_a_reserve_bump_seed],
+                ],
+                &ASSOCIATED_TOKEN_PROGRAM_ID,
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            pdas.token_b_reserve,
+            Pubkey::create_program_address(
+                &[
+                    pdas.authority.as_ref(),
+                    spl_token::id().as_ref(),
+                    mint_b.as_ref(),
+                    &[pdas.token_b_reserve_bump_seed],
+                ],
+                &ASSOCIATED_TOKEN_PROGRAM_ID,
+            )
+            .unwrap()
+        );
+        assert_ne!(pdas.token_a_reserve, pdas.token_b_reserve);
+    }
+}
@@ -1,26 +1,58 @@
 //! Program state processor
 
-use crate::constraints::{validate_fees, validate_supply};
+use crate::constraints::{
+    validate_deposit_ratio, validate_fees, validate_initial_pool_amount, validate_price_band,
+    validate_price_impact, validate_reserves, validate_reward_cap, validate_supply,
+    SwapConstraints, SWAP_CONSTRAINTS,
+};
+use crate::curve::{build_swap_curve, CurveType, SwapCurve, SwapResult, TradeDirection};
 use crate::{
     error::SwapError,
     fees::Fees,
-    instruction::{DepositTokens, Initialize, Swap, SwapInstruction, WithdrawTokens},
-    state::SwapState,
+    instruction::{
+        BatchQuote, DepositTokens, DepositTokensExactAmounts, FlashLoan, Initialize,
+        InitializeWithDeposit, Rebalance, RouteSwap, SetCurveParameter, Swap,
+        SwapExactOutOrPartial, SwapInstruction, SwapWithSlippageBps, WithdrawAllTokenTypes,
+        WithdrawTokens,
+    },
+    pda::swap_authority,
+    quote::{
+        minimum_out_with_slippage, spot_amount_out, swap_amount_in, swap_amount_out,
+        LiquidityDeltas, PoolReserves, FAILED_QUOTE,
+    },
+    state::{authority_signer_seeds, swap_price, SwapState, SwapStateV1, PRICE_SCALE},
 };
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
+    bpf_loader_upgradeable,
+    clock::Clock,
     entrypoint::ProgramResult,
+    instruction::Instruction,
     msg,
-    program::invoke_signed,
+    program::{invoke, invoke_signed, set_return_data},
     program_error::ProgramError,
     program_option::COption,
     program_pack::Pack,
     pubkey::Pubkey,
+    rent::Rent,
+    sysvar::{
+        instructions::{load_current_index_checked, load_instruction_at_checked},
+        Sysvar,
+    },
 };
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 
 const INITIAL_SWAP_POOL_AMOUNT: u128 = 1_000_000_000;
 
+/// Program id of Token-2022, which shares the spl-token account layout
+pub const TOKEN_2022_PROGRAM_ID: Pubkey =
+    solana_program::pubkey!("TokenzQdBNbLqP5VEhdkAS6EPFLC1PKQgYdBAR6xNiZ");
+
+/// Whether the pool may keep its tokens under the given program
+fn is_supported_token_program(program_id: &Pubkey) -> bool {
+    *program_id == spl_token::id() || *program_id == TOKEN_2022_PROGRAM_ID
+}
+
 /// Program state handler.
 pub struct Processor {}
 
@@ -38,6 +70,18 @@ impl Processor {
         }
     }
 
+    /// Returns the amounts moved by a deposit or withdrawal to the caller
+    fn set_liquidity_return_data(pool_token_delta: u64, token_a_delta: u64, token_b_delta: u64) {
+        let mut data = [0u8; LiquidityDeltas::LEN];
+        LiquidityDeltas {
+            pool_token_delta,
+            token_a_delta,
+            token_b_delta,
+        }
+        .pack_into_slice(&mut data);
+        set_return_data(&data);
+    }
+
     /// Unpacks a spl_token `Mint`.
     pub fn unpack_mint(
         account_info: &AccountInfo,
@@ -51,16 +95,47 @@ impl Processor {
         }
     }
 
+    /// Pool token supply read from the pool mint, re-syncing the copy cached
+    /// in the swap state, which falls behind when holders burn pool tokens
+    /// through the token program directly. The same unpack checks that the
+    /// pool mint still has the decimals stored on initialization.
+    fn sync_pool_token_supply(
+        swap_state: &mut SwapState,
+        pool_mint_info: &AccountInfo,
+    ) -> Result<u64, ProgramError> {
+        let pool_mint = Self::unpack_mint(pool_mint_info, swap_state.pool_token_program_id())?;
+        if pool_mint.decimals != swap_state.pool_token_decimals {
+            return Err(SwapError::IncorrectPoolMint.into());
+        }
+        swap_state.pool_token_supply = pool_mint.supply;
+        Ok(pool_mint.supply)
+    }
+
     /// Calculates the authority id by generating a program address.
     pub fn authority_id(
         program_id: &Pubkey,
         swap_info: &Pubkey,
         bump_seed: u8,
     ) -> Result<Pubkey, SwapError> {
-        Pubkey::create_program_address(&[&swap_info.to_bytes()[..32], &[bump_seed]], program_id)
+        Pubkey::create_program_address(&authority_signer_seeds(swap_info, &bump_seed), program_id)
             .or(Err(SwapError::InvalidProgramAddress))
     }
 
+    /// Checks that the authority is derived from the swap account with its
+    /// canonical bump seed
+    fn check_authority(
+        swap_state: &SwapState,
+        program_id: &Pubkey,
+        swap_key: &Pubkey,
+        authority_key: &Pubkey,
+    ) -> Result<(), SwapError> {
+        swap_state.validate_bump_seed(program_id, swap_key)?;
+        if *authority_key != Self::authority_id(program_id, swap_key, swap_state.bump_seed())? {
+            return Err(SwapError::InvalidProgramAddress);
+        }
+        Ok(())
+    }
+
     /// Issue a spl_token `Burn` instruction.
     pub fn token_burn<'a>(
         token_program: AccountInfo<'a>, // should be pool token program address
@@ -69,14 +144,17 @@ impl Processor {
         authority_id: AccountInfo<'a>,
         amount: u64,
     ) -> Result<(), ProgramError> {
-        let ix = spl_token::instruction::burn(
+        let ix = token_instruction(
+            spl_token::instruction::burn(
+                &spl_token::id(),
+                burn_account.key,
+                mint.key,
+                authority_id.key,
+                &[],
+                amount,
+            )?,
             token_program.key,
-            burn_account.key,
-            mint.key,
-            authority_id.key,
-            &[],
-            amount,
-        )?;
+        );
 
         invoke_signed(&ix, &[burn_account, mint, authority_id, token_program], &[])
     }
@@ -91,21 +169,24 @@ impl Processor {
         bump_seed: u8,
         amount: u64,
     ) -> Result<(), ProgramError> {
-        let authority_signature_seeds = [&swap_info.to_bytes()[..32], &[bump_seed]];
-        let signers = &[&authority_signature_seeds[..]];
-        let ix = spl_token::instruction::mint_to(
+        let ix = token_instruction(
+            spl_token::instruction::mint_to(
+                &spl_token::id(),
+                mint.key,
+                destination.key,
+                authority_id.key,
+                &[],
+                amount,
+            )?,
             token_program.key,
-            mint.key,
-            destination.key,
-            authority_id.key,
-            &[],
-            amount,
-        )?;
+        );
 
-        invoke_signed(
+        Self::invoke_signed_by_authority(
+            "MintTo",
             &ix,
             &[mint, destination, authority_id, token_program],
-            signers,
+            swap_info,
+            bump_seed,
         )
     }
 
@@ -119,24 +200,108 @@ impl Processor {
         bump_seed: u8, // put this, only when the token is withdrawn from the program's token address
         amount: u64,
     ) -> Result<(), ProgramError> {
-        let authority_signature_seeds = [&swap_info.to_bytes()[..32], &[bump_seed]];
-        let signers = &[&authority_signature_seeds[..]];
-
-        let ix = spl_token::instruction::transfer(
+        let ix = token_instruction(
+            spl_token::instruction::transfer(
+                &spl_token::id(),
+                source.key,
+                destination.key,
+                authority_id.key,
+                &[],
+                amount,
+            )?,
             token_program.key,
-            source.key,
-            destination.key,
-            authority_id.key,
-            &[],
-            amount,
-        )?;
-        invoke_signed(
+        );
+        Self::invoke_signed_by_authority(
+            "Transfer",
             &ix,
             &[source, destination, authority_id, token_program],
-            signers,
+            swap_info,
+            bump_seed,
+        )
+    }
+
+    /// Issue a spl_token `CloseAccount` instruction.
+    pub fn token_close_account<'a>(
+        swap_info: &Pubkey,
+        token_program: AccountInfo<'a>,
+        account: AccountInfo<'a>, // Should be token A or token B token address owned by authority_id
+        destination: AccountInfo<'a>,
+        authority_id: AccountInfo<'a>,
+        bump_seed: u8,
+    ) -> Result<(), ProgramError> {
+        let ix = token_instruction(
+            spl_token::instruction::close_account(
+                &spl_token::id(),
+                account.key,
+                destination.key,
+                authority_id.key,
+                &[],
+            )?,
+            token_program.key,
+        );
+        Self::invoke_signed_by_authority(
+            "CloseAccount",
+            &ix,
+            &[account, destination, authority_id, token_program],
+            swap_info,
+            bump_seed,
         )
     }
 
+    /// Issue a spl_token `SyncNative` instruction, bringing the token amount
+    /// of a wrapped SOL account up to its lamports.
+    pub fn token_sync_native<'a>(
+        token_program: AccountInfo<'a>,
+        account: AccountInfo<'a>,
+    ) -> Result<(), ProgramError> {
+        let ix = token_instruction(
+            spl_token::instruction::sync_native(&spl_token::id(), account.key)?,
+            token_program.key,
+        );
+        invoke(&ix, &[account, token_program])
+    }
+
+    /// Syncs the reserves holding wrapped SOL, so lamports sent to them
+    /// directly count before they are priced
+    fn sync_native_reserves<'a>(
+        swap_state: &SwapState,
+        token_program_info: &AccountInfo<'a>,
+        token_a_info: &AccountInfo<'a>,
+        token_b_info: &AccountInfo<'a>,
+    ) -> ProgramResult {
+        if *swap_state.token_a_mint() == spl_token::native_mint::id() {
+            Self::token_sync_native(token_program_info.clone(), token_a_info.clone())?;
+        }
+        if *swap_state.token_b_mint() == spl_token::native_mint::id() {
+            Self::token_sync_native(token_program_info.clone(), token_b_info.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Invoke an instruction signed by the swap authority. On failure, logs
+    /// the operation and the authority seeds, which are all public, to help
+    /// track down seed mismatches, and returns the original error.
+    fn invoke_signed_by_authority(
+        operation: &str,
+        instruction: &Instruction,
+        account_infos: &[AccountInfo],
+        swap_info: &Pubkey,
+        bump_seed: u8,
+    ) -> ProgramResult {
+        let authority_signature_seeds = authority_signer_seeds(swap_info, &bump_seed);
+        let signers = &[&authority_signature_seeds[..]];
+        invoke_signed(instruction, account_infos, signers).map_err(|err| {
+            msg!(
+                "{} failed: {}, authority seeds: swap {}, bump seed {}",
+                operation,
+                err,
+                swap_info,
+                bump_seed
+            );
+            err
+        })
+    }
+
     #[allow(clippy::too_many_arguments)]
     fn check_accounts(
         swap_state: &SwapState,
@@ -147,6 +312,7 @@ impl Processor {
         token_b_info: &AccountInfo,
         pool_mint_info: &AccountInfo,
         token_program_info: &AccountInfo,
+        pool_token_program_info: &AccountInfo,
         user_token_a_info: Option<&AccountInfo>,
         user_token_b_info: Option<&AccountInfo>,
         token_a_fee_account_info: Option<&AccountInfo>,
@@ -155,11 +321,10 @@ impl Processor {
         if swap_info.owner != program_id {
             return Err(ProgramError::IncorrectProgramId);
         }
-        if *authority_info.key
-            != Self::authority_id(program_id, swap_info.key, swap_state.bump_seed())?
-        {
-            return Err(SwapError::InvalidProgramAddress.into());
+        if swap_state.has_flash_loan() {
+            return Err(SwapError::FlashLoanOutstanding.into());
         }
+        Self::check_authority(swap_state, program_id, swap_info.key, authority_info.key)?;
         if *token_a_info.key != *swap_state.token_a_account() {
             return Err(SwapError::IncorrectSwapAccount.into());
         }
@@ -172,6 +337,9 @@ impl Processor {
         if *token_program_info.key != *swap_state.token_program_id() {
             return Err(SwapError::IncorrectTokenProgramId.into());
         }
+        if *pool_token_program_info.key != *swap_state.pool_token_program_id() {
+            return Err(SwapError::IncorrectTokenProgramId.into());
+        }
         if let Some(user_token_a_info) = user_token_a_info {
             if token_a_info.key == user_token_a_info.key {
                 return Err(SwapError::InvalidInput.into());
@@ -195,12 +363,101 @@ impl Processor {
         Ok(())
     }
 
+    /// Checks that the withdrawal destinations hold the pool's mints and
+    /// belong to the same owner
+    fn check_withdraw_destinations(
+        swap_state: &SwapState,
+        dest_token_a_info: &AccountInfo,
+        dest_token_b_info: &AccountInfo,
+    ) -> Result<(), SwapError> {
+        let dest_token_a =
+            Self::unpack_token_account(dest_token_a_info, swap_state.token_program_id())?;
+        let dest_token_b =
+            Self::unpack_token_account(dest_token_b_info, swap_state.token_program_id())?;
+        if dest_token_a.mint != *swap_state.token_a_mint()
+            || dest_token_b.mint != *swap_state.token_b_mint()
+        {
+            return Err(SwapError::IncorrectSwapAccount);
+        }
+        if dest_token_a.owner != dest_token_b.owner {
+            return Err(SwapError::DestinationOwnerMismatch);
+        }
+        Ok(())
+    }
+
     /// Processes an [Initialize](enum.Instruction.html).
+    #[allow(clippy::too_many_arguments)]
     pub fn process_initialize(
         program_id: &Pubkey,
         fees: Fees,
+        owner: Pubkey,
+        curve_type: CurveType,
+        curve_parameter: u64,
+        reward_cap_numerator: u64,
+        reward_cap_denominator: u64,
+        price_band_bps: u16,
+        initial_pool_amount: u64,
+        minimum_pool_token_amount: u64,
+        round_up_deposits: bool,
+        max_price_impact_bps: u16,
         accounts: &[AccountInfo],
+        swap_constraints: &Option<SwapConstraints>,
     ) -> ProgramResult {
+        let swap_state = Self::validate_initialize(
+            program_id,
+            fees,
+            owner,
+            curve_type,
+            curve_parameter,
+            reward_cap_numerator,
+            reward_cap_denominator,
+            price_band_bps,
+            initial_pool_amount,
+            minimum_pool_token_amount,
+            round_up_deposits,
+            max_price_impact_bps,
+            accounts,
+            swap_constraints,
+        )?;
+        // the accounts were all present for the checks
+        let swap_info = &accounts[0];
+        let authority_info = &accounts[1];
+        let pool_mint_info = &accounts[4];
+        let destination_info = &accounts[7];
+        let pool_token_program_info = &accounts[9];
+
+        Self::token_mint_to(
+            swap_info.key,
+            pool_token_program_info.clone(),
+            pool_mint_info.clone(),
+            destination_info.clone(),
+            authority_info.clone(),
+            swap_state.bump_seed,
+            swap_state.pool_token_supply,
+        )?;
+        SwapState::pack(swap_state, &mut swap_info.data.borrow_mut())?;
+        Ok(())
+    }
+
+    /// Runs every check of an [Initialize](enum.Instruction.html) without
+    /// minting or writing anything, returning the swap state it would store
+    #[allow(clippy::too_many_arguments)]
+    fn validate_initialize(
+        program_id: &Pubkey,
+        fees: Fees,
+        owner: Pubkey,
+        curve_type: CurveType,
+        curve_parameter: u64,
+        reward_cap_numerator: u64,
+        reward_cap_denominator: u64,
+        price_band_bps: u16,
+        initial_pool_amount: u64,
+        minimum_pool_token_amount: u64,
+        round_up_deposits: bool,
+        max_price_impact_bps: u16,
+        accounts: &[AccountInfo],
+        swap_constraints: &Option<SwapConstraints>,
+    ) -> Result<SwapState, ProgramError> {
         let account_info_iter = &mut accounts.iter();
         let swap_info = next_account_info(account_info_iter)?;
         let authority_info = next_account_info(account_info_iter)?;
@@ -211,8 +468,12 @@ impl Processor {
         let token_b_fee_account_info = next_account_info(account_info_iter)?;
         let destination_info = next_account_info(account_info_iter)?;
         let token_program_info = next_account_info(account_info_iter)?;
+        let pool_token_program_info = next_account_info(account_info_iter)?;
+        let token_a_mint_info = next_account_info(account_info_iter)?;
+        let token_b_mint_info = next_account_info(account_info_iter)?;
 
         let token_program_id = *token_program_info.key;
+        let pool_token_program_id = *pool_token_program_info.key;
 
         // check the swap_info already in use
         if match SwapState::unpack(&swap_info.data.borrow()) {
@@ -222,19 +483,32 @@ impl Processor {
             return Err(SwapError::AlreadyInUse.into());
         }
 
-        let (swap_authority, bump_seed) =
-            Pubkey::find_program_address(&[&swap_info.key.to_bytes()], program_id);
+        let (swap_authority, bump_seed) = swap_authority(program_id, swap_info.key);
         if *authority_info.key != swap_authority {
             return Err(SwapError::InvalidProgramAddress.into());
         }
+        // the stored ids are trusted from here on, so a fake token program
+        // could later vouch for any account
+        if !is_supported_token_program(&token_program_id)
+            || !is_supported_token_program(&pool_token_program_id)
+        {
+            return Err(SwapError::IncorrectTokenProgramId.into());
+        }
         let token_a = Self::unpack_token_account(token_a_info, &token_program_id)?;
         let token_b = Self::unpack_token_account(token_b_info, &token_program_id)?;
+        // a pool token shared with a trading token would mix up the accounting
+        if token_a.mint == token_b.mint
+            || *pool_mint_info.key == token_a.mint
+            || *pool_mint_info.key == token_b.mint
+        {
+            return Err(SwapError::RepeatedMint.into());
+        }
         let token_a_fee_account =
             Self::unpack_token_account(token_a_fee_account_info, &token_program_id)?;
         let token_b_fee_account =
             Self::unpack_token_account(token_b_fee_account_info, &token_program_id)?;
-        let destination = Self::unpack_token_account(destination_info, &token_program_id)?;
-        let pool_mint = Self::unpack_mint(pool_mint_info, &token_program_id)?;
+        let destination = Self::unpack_token_account(destination_info, &pool_token_program_id)?;
+        let pool_mint = Self::unpack_mint(pool_mint_info, &pool_token_program_id)?;
         if *authority_info.key != token_a.owner {
             return Err(SwapError::InvalidOwner.into());
         }
@@ -244,6 +518,9 @@ impl Processor {
         if *authority_info.key == destination.owner {
             return Err(SwapError::InvalidOutputOwner.into());
         }
+        if destination.mint != *pool_mint_info.key {
+            return Err(SwapError::IncorrectPoolMint.into());
+        }
         if *authority_info.key == token_a_fee_account.owner {
             return Err(SwapError::InvalidOutputOwner.into());
         }
@@ -254,12 +531,23 @@ impl Processor {
             return Err(SwapError::InvalidOwner.into());
         }
 
-        if token_a.mint == token_b.mint {
-            return Err(SwapError::RepeatedMint.into());
+        if *token_a_mint_info.key != token_a.mint {
+            return Err(SwapError::IncorrectMint.into());
         }
+        if *token_b_mint_info.key != token_b.mint {
+            return Err(SwapError::IncorrectMint.into());
+        }
+        let token_a_mint = Self::unpack_mint(token_a_mint_info, &token_program_id)?;
+        let token_b_mint = Self::unpack_mint(token_b_mint_info, &token_program_id)?;
 
-        // Both of the token amount should be non-zero
-        validate_supply(token_a.amount, token_b.amount)?;
+        // the curve decides which token amounts may be zero
+        let swap_curve = build_swap_curve(
+            curve_type,
+            curve_parameter,
+            token_a_mint.decimals,
+            token_b_mint.decimals,
+        )?;
+        validate_supply(swap_curve.as_ref(), token_a.amount, token_b.amount)?;
 
         if token_a.delegate.is_some() {
             return Err(SwapError::InvalidDelegate.into());
@@ -273,39 +561,66 @@ impl Processor {
         if token_b.close_authority.is_some() {
             return Err(SwapError::InvalidCloseAuthority.into());
         }
+        if token_a.is_frozen() || token_b.is_frozen() {
+            return Err(SwapError::FrozenAccount.into());
+        }
+        if token_a_fee_account.is_frozen() || token_b_fee_account.is_frozen() {
+            return Err(SwapError::InvalidFeeAccount.into());
+        }
         if token_a.mint != token_a_fee_account.mint {
             return Err(SwapError::IncorrectFeeAccount.into());
         }
         if token_b.mint != token_b_fee_account.mint {
             return Err(SwapError::IncorrectFeeAccount.into());
         }
+        // an account below the rent exemption could be garbage collected
+        let rent = Rent::get()?;
+        for account_info in [
+            token_a_info,
+            token_b_info,
+            token_a_fee_account_info,
+            token_b_fee_account_info,
+        ] {
+            if !rent.is_exempt(account_info.lamports(), account_info.data_len()) {
+                return Err(SwapError::NotRentExempt.into());
+            }
+        }
 
         if pool_mint.supply != 0 {
             return Err(SwapError::InvalidSupply.into());
         }
-        if pool_mint.freeze_authority.is_some() {
+        // the owner may keep pool tokens freezable, nobody else
+        if pool_mint.freeze_authority.is_some()
+            && pool_mint.freeze_authority != COption::Some(owner)
+        {
             return Err(SwapError::InvalidFreezeAuthority.into());
         }
 
         fees.validate()?;
         validate_fees(&fees)?;
+        if let Some(swap_constraints) = swap_constraints {
+            swap_constraints.validate_owner(&owner)?;
+            swap_constraints.validate_fees(&fees)?;
+        }
+        validate_reward_cap(reward_cap_numerator, reward_cap_denominator)?;
+        validate_price_band(price_band_bps)?;
 
-        let initial_amount = INITIAL_SWAP_POOL_AMOUNT;
-
-        Self::token_mint_to(
-            swap_info.key,
-            token_program_info.clone(),
-            pool_mint_info.clone(),
-            destination_info.clone(),
-            authority_info.clone(),
-            bump_seed,
-            to_u64(initial_amount)?,
-        )?;
+        let initial_amount = if initial_pool_amount == 0 {
+            to_u64(INITIAL_SWAP_POOL_AMOUNT)?
+        } else {
+            initial_pool_amount
+        };
+        validate_initial_pool_amount(initial_amount)?;
+        if initial_amount < minimum_pool_token_amount {
+            return Err(SwapError::ExceededSlippage.into());
+        }
 
-        let swap_state = SwapState {
+        Ok(SwapState {
             is_initialized: true,
             bump_seed,
+            owner,
             token_program_id,
+            pool_token_program_id,
             token_a: *token_a_info.key,
             token_b: *token_b_info.key,
             pool_mint: *pool_mint_info.key,
@@ -314,17 +629,121 @@ impl Processor {
             token_a_fee_account: *token_a_fee_account_info.key,
             token_b_fee_account: *token_b_fee_account_info.key,
             fees,
-        };
-        SwapState::pack(swap_state, &mut swap_info.data.borrow_mut())?;
+            curve_type,
+            curve_parameter,
+            reward_cap_numerator,
+            reward_cap_denominator,
+            last_swap_price: 0,
+            price_band_bps,
+            pool_token_supply: initial_amount,
+            token_a_decimals: token_a_mint.decimals,
+            token_b_decimals: token_b_mint.decimals,
+            pool_token_decimals: pool_mint.decimals,
+            flash_loan_reserve: Pubkey::default(),
+            flash_loan_balance: 0,
+            initial_pool_amount: initial_amount,
+            owner_fees_owed: 0,
+            round_up_deposits,
+            max_price_impact_bps,
+            owner_rebalance_amount: 0,
+        })
+    }
+
+    /// Processes a [ValidateInitialize](enum.Instruction.html).
+    pub fn process_validate_initialize(
+        program_id: &Pubkey,
+        initialize: Initialize,
+        accounts: &[AccountInfo],
+        swap_constraints: &Option<SwapConstraints>,
+    ) -> ProgramResult {
+        Self::validate_initialize(
+            program_id,
+            initialize.fees,
+            initialize.owner,
+            initialize.curve_type,
+            initialize.curve_parameter,
+            initialize.reward_cap_numerator,
+            initialize.reward_cap_denominator,
+            initialize.price_band_bps,
+            initialize.initial_pool_amount,
+            initialize.minimum_pool_token_amount,
+            initialize.round_up_deposits,
+            initialize.max_price_impact_bps,
+            accounts,
+            swap_constraints,
+        )?;
         Ok(())
     }
 
+    /// Processes an [InitializeWithDeposit](enum.Instruction.html).
+    pub fn process_initialize_with_deposit(
+        program_id: &Pubkey,
+        initialize: Initialize,
+        token_a_amount: u64,
+        token_b_amount: u64,
+        accounts: &[AccountInfo],
+        swap_constraints: &Option<SwapConstraints>,
+    ) -> ProgramResult {
+        if accounts.len() < 12 {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let (initialize_infos, deposit_infos) = accounts.split_at(12);
+        let swap_info = &initialize_infos[0];
+        let token_a_info = &initialize_infos[2];
+        let token_b_info = &initialize_infos[3];
+        let token_program_info = &initialize_infos[8];
+        let account_info_iter = &mut deposit_infos.iter();
+        let user_transfer_authority_info = next_account_info(account_info_iter)?;
+        let source_a_info = next_account_info(account_info_iter)?;
+        let source_b_info = next_account_info(account_info_iter)?;
+
+        // the reserves are checked by the initialization right after
+        let (_, bump_seed) = swap_authority(program_id, swap_info.key);
+        Self::token_transfer(
+            swap_info.key,
+            token_program_info.clone(),
+            source_a_info.clone(),
+            token_a_info.clone(),
+            user_transfer_authority_info.clone(),
+            bump_seed,
+            token_a_amount,
+        )?;
+        Self::token_transfer(
+            swap_info.key,
+            token_program_info.clone(),
+            source_b_info.clone(),
+            token_b_info.clone(),
+            user_transfer_authority_info.clone(),
+            bump_seed,
+            token_b_amount,
+        )?;
+
+        Self::process_initialize(
+            program_id,
+            initialize.fees,
+            initialize.owner,
+            initialize.curve_type,
+            initialize.curve_parameter,
+            initialize.reward_cap_numerator,
+            initialize.reward_cap_denominator,
+            initialize.price_band_bps,
+            initialize.initial_pool_amount,
+            initialize.minimum_pool_token_amount,
+            initialize.round_up_deposits,
+            initialize.max_price_impact_bps,
+            initialize_infos,
+            swap_constraints,
+        )
+    }
+
     /// Processes an [DepositTokens](enum.Instruction.html).
     pub fn process_deposit_tokens(
         program_id: &Pubkey,
         pool_token_amount: u64,
         maximum_token_a_amount: u64,
         maximum_token_b_amount: u64,
+        expected_ratio_numerator: u64,
+        expected_ratio_denominator: u64,
         accounts: &[AccountInfo],
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
@@ -338,8 +757,9 @@ impl Processor {
         let pool_mint_info = next_account_info(account_info_iter)?;
         let dest_info = next_account_info(account_info_iter)?;
         let token_program_info = next_account_info(account_info_iter)?;
+        let pool_token_program_info = next_account_info(account_info_iter)?;
 
-        let swap_state = SwapState::unpack(&swap_info.data.borrow())?;
+        let mut swap_state = SwapState::unpack(&swap_info.data.borrow())?;
         Self::check_accounts(
             &swap_state,
             program_id,
@@ -349,41 +769,55 @@ impl Processor {
             token_b_info,
             pool_mint_info,
             token_program_info,
+            pool_token_program_info,
             Some(source_a_info),
             Some(source_b_info),
             None,
             None,
         )?;
 
-        let token_a = Self::unpack_token_account(token_a_info, swap_state.token_program_id())?;
-        let token_b = Self::unpack_token_account(token_b_info, swap_state.token_program_id())?;
-        let pool_mint = Self::unpack_mint(pool_mint_info, swap_state.token_program_id())?;
-        let current_pool_mint_supply = to_u128(pool_mint.supply)?;
+        Self::sync_native_reserves(&swap_state, token_program_info, token_a_info, token_b_info)?;
+        let (reserve_a, reserve_b) =
+            swap_state.reserves_from_accounts(token_a_info, token_b_info)?;
+        let current_pool_mint_supply = to_u128(Self::sync_pool_token_supply(
+            &mut swap_state,
+            pool_mint_info,
+        )?)?;
         let (pool_token_amount, pool_mint_supply) = if current_pool_mint_supply > 0 {
             (to_u128(pool_token_amount)?, current_pool_mint_supply)
         } else {
-            (INITIAL_SWAP_POOL_AMOUNT, INITIAL_SWAP_POOL_AMOUNT)
+            // an emptied pool starts over: the requested amount is ignored,
+            // the initial amount is minted for the whole of both reserves,
+            // and the maxima below still bound what the depositor pays
+            let initial_amount = u128::from(swap_state.initial_pool_amount);
+            (initial_amount, initial_amount)
         };
 
-        // let token_a_amount = token_a.amount * pool_token_amount / pool_token_supply
-        // let token_b_amount = token_b.amount * pool_token_amount / pool_token_supply
-        let token_a_amount = to_u128(token_a.amount)? * pool_token_amount / pool_mint_supply;
-        let token_b_amount = to_u128(token_b.amount)? * pool_token_amount / pool_mint_supply;
-
-        let token_a_amount = to_u64(token_a_amount)?;
+        // let token_a_amount = reserve_a * pool_token_amount / pool_token_supply
+        // let token_b_amount = reserve_b * pool_token_amount / pool_token_supply
+        let round_up = swap_state.round_up_deposits;
+        let token_a_amount =
+            deposit_token_amount(reserve_a, pool_token_amount, pool_mint_supply, round_up)?;
         if token_a_amount > maximum_token_a_amount {
             return Err(SwapError::ExceededSlippage.into());
         }
-        if token_a_amount == 0 {
-            return Err(SwapError::ZeroTradingTokens.into());
-        }
-        let token_b_amount = to_u64(token_b_amount)?;
+        let token_b_amount =
+            deposit_token_amount(reserve_b, pool_token_amount, pool_mint_supply, round_up)?;
         if token_b_amount > maximum_token_b_amount {
             return Err(SwapError::ExceededSlippage.into());
         }
-        if token_b_amount == 0 {
-            return Err(SwapError::ZeroTradingTokens.into());
-        }
+        validate_deposit_ratio(
+            token_a_amount,
+            token_b_amount,
+            swap_state.token_a_decimals,
+            swap_state.token_b_decimals,
+            expected_ratio_numerator,
+            expected_ratio_denominator,
+        )?;
+        validate_reserves(
+            u128::from(reserve_a) + u128::from(token_a_amount),
+            u128::from(reserve_b) + u128::from(token_b_amount),
+        )?;
 
         let pool_token_amount = to_u64(pool_token_amount)?;
 
@@ -406,8 +840,112 @@ impl Processor {
             token_b_amount,
         )?;
         Self::token_mint_to(
+            swap_info.key,
+            pool_token_program_info.clone(),
+            pool_mint_info.clone(),
+            dest_info.clone(),
+            authority_info.clone(),
+            swap_state.bump_seed(),
+            pool_token_amount,
+        )?;
+
+        swap_state.pool_token_supply = swap_state
+            .pool_token_supply
+            .checked_add(pool_token_amount)
+            .ok_or(SwapError::CalculationFailure)?;
+        SwapState::pack(swap_state, &mut swap_info.data.borrow_mut())?;
+        Self::set_liquidity_return_data(pool_token_amount, token_a_amount, token_b_amount);
+
+        Ok(())
+    }
+
+    /// Processes an [DepositTokensExactAmounts](enum.Instruction.html).
+    pub fn process_deposit_tokens_exact_amounts(
+        program_id: &Pubkey,
+        token_a_amount: u64,
+        token_b_amount: u64,
+        minimum_pool_token_amount: u64,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let swap_info = next_account_info(account_info_iter)?;
+        let authority_info = next_account_info(account_info_iter)?;
+        let user_transfer_authority_info = next_account_info(account_info_iter)?;
+        let source_a_info = next_account_info(account_info_iter)?;
+        let source_b_info = next_account_info(account_info_iter)?;
+        let token_a_info = next_account_info(account_info_iter)?;
+        let token_b_info = next_account_info(account_info_iter)?;
+        let pool_mint_info = next_account_info(account_info_iter)?;
+        let dest_info = next_account_info(account_info_iter)?;
+        let token_program_info = next_account_info(account_info_iter)?;
+        let pool_token_program_info = next_account_info(account_info_iter)?;
+
+        let mut swap_state = SwapState::unpack(&swap_info.data.borrow())?;
+        Self::check_accounts(
+            &swap_state,
+            program_id,
+            swap_info,
+            authority_info,
+            token_a_info,
+            token_b_info,
+            pool_mint_info,
+            token_program_info,
+            pool_token_program_info,
+            Some(source_a_info),
+            Some(source_b_info),
+            None,
+            None,
+        )?;
+
+        Self::sync_native_reserves(&swap_state, token_program_info, token_a_info, token_b_info)?;
+        let (reserve_a, reserve_b) =
+            swap_state.reserves_from_accounts(token_a_info, token_b_info)?;
+        let pool_mint_supply = to_u128(Self::sync_pool_token_supply(
+            &mut swap_state,
+            pool_mint_info,
+        )?)?;
+        let pool_token_amount = if pool_mint_supply > 0 {
+            // the side worth the fewest pool tokens limits the mint
+            std::cmp::min(
+                deposit_pool_token_amount(token_a_amount, reserve_a, pool_mint_supply)?,
+                deposit_pool_token_amount(token_b_amount, reserve_b, pool_mint_supply)?,
+            )
+        } else {
+            u128::from(swap_state.initial_pool_amount)
+        };
+        if pool_token_amount == 0 {
+            return Err(SwapError::ZeroTradingTokens.into());
+        }
+        let pool_token_amount = to_u64_for("pool token amount", pool_token_amount)?;
+        if pool_token_amount < minimum_pool_token_amount {
+            return Err(SwapError::ExceededSlippage.into());
+        }
+        validate_reserves(
+            u128::from(reserve_a) + u128::from(token_a_amount),
+            u128::from(reserve_b) + u128::from(token_b_amount),
+        )?;
+
+        Self::token_transfer(
+            swap_info.key,
+            token_program_info.clone(),
+            source_a_info.clone(),
+            token_a_info.clone(),
+            user_transfer_authority_info.clone(),
+            swap_state.bump_seed(),
+            token_a_amount,
+        )?;
+        Self::token_transfer(
             swap_info.key,
             token_program_info.clone(),
+            source_b_info.clone(),
+            token_b_info.clone(),
+            user_transfer_authority_info.clone(),
+            swap_state.bump_seed(),
+            token_b_amount,
+        )?;
+        Self::token_mint_to(
+            swap_info.key,
+            pool_token_program_info.clone(),
             pool_mint_info.clone(),
             dest_info.clone(),
             authority_info.clone(),
@@ -415,15 +953,25 @@ impl Processor {
             pool_token_amount,
         )?;
 
+        swap_state.pool_token_supply = swap_state
+            .pool_token_supply
+            .checked_add(pool_token_amount)
+            .ok_or(SwapError::CalculationFailure)?;
+        SwapState::pack(swap_state, &mut swap_info.data.borrow_mut())?;
+        Self::set_liquidity_return_data(pool_token_amount, token_a_amount, token_b_amount);
+
         Ok(())
     }
 
     /// Processes an [WithdrawTokens](enum.Instruction.html).
+    #[allow(clippy::too_many_arguments)]
     pub fn process_withdraw_tokens(
         program_id: &Pubkey,
         pool_token_amount: u64,
         minimum_token_a_amount: u64,
         minimum_token_b_amount: u64,
+        preferred_mint: Option<Pubkey>,
+        minimum_preferred_amount: u64,
         accounts: &[AccountInfo],
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
@@ -437,8 +985,9 @@ impl Processor {
         let dest_token_a_info = next_account_info(account_info_iter)?;
         let dest_token_b_info = next_account_info(account_info_iter)?;
         let token_program_info = next_account_info(account_info_iter)?;
+        let pool_token_program_info = next_account_info(account_info_iter)?;
 
-        let swap_state = SwapState::unpack(&swap_info.data.borrow())?;
+        let mut swap_state = SwapState::unpack(&swap_info.data.borrow())?;
         Self::check_accounts(
             &swap_state,
             program_id,
@@ -448,43 +997,122 @@ impl Processor {
             token_b_info,
             pool_mint_info,
             token_program_info,
+            pool_token_program_info,
             Some(dest_token_a_info),
             Some(dest_token_b_info),
             None,
             None,
         )?;
+        Self::check_withdraw_destinations(&swap_state, dest_token_a_info, dest_token_b_info)?;
 
-        let token_a = Self::unpack_token_account(token_a_info, swap_state.token_program_id())?;
-        let token_b = Self::unpack_token_account(token_b_info, swap_state.token_program_id())?;
-        let pool_mint = Self::unpack_mint(pool_mint_info, swap_state.token_program_id())?;
+        let (reserve_a, reserve_b) =
+            swap_state.reserves_from_accounts(token_a_info, token_b_info)?;
 
         let pool_token_amount = to_u128(pool_token_amount)?;
-        let pool_mint_supply = to_u128(pool_mint.supply)?;
-
-        // let token_a_amount = token_a.amount * pool_token_amount / pool_token_supply
-        // let token_b_amount = token_b.amount * pool_token_amount / pool_token_supply
-        let token_a_amount = to_u128(token_a.amount)? * pool_token_amount / pool_mint_supply;
-        let token_b_amount = to_u128(token_b.amount)? * pool_token_amount / pool_mint_supply;
+        let pool_mint_supply = to_u128(Self::sync_pool_token_supply(
+            &mut swap_state,
+            pool_mint_info,
+        )?)?;
+        if pool_mint_supply == 0 {
+            return Err(SwapError::ZeroTradingTokens.into());
+        }
+
+        // let token_a_amount = reserve_a * pool_token_amount / pool_token_supply
+        // let token_b_amount = reserve_b * pool_token_amount / pool_token_supply
+        let round_up = !swap_state.round_up_deposits;
+        let token_a_amount =
+            withdraw_token_amount(reserve_a, pool_token_amount, pool_mint_supply, round_up)?;
+        let token_b_amount =
+            withdraw_token_amount(reserve_b, pool_token_amount, pool_mint_supply, round_up)?;
 
-        let token_a_amount = to_u64(token_a_amount)?;
-        let token_a_amount = std::cmp::min(token_a.amount, token_a_amount);
+        let token_a_amount = to_u64_for("token A amount", token_a_amount)?;
+        let token_a_amount = std::cmp::min(reserve_a, token_a_amount);
         if token_a_amount < minimum_token_a_amount {
             return Err(SwapError::ExceededSlippage.into());
         }
-        if token_a_amount == 0 && token_a.amount != 0 {
+        if token_a_amount == 0 && reserve_a != 0 {
             return Err(SwapError::ZeroTradingTokens.into());
         }
-        let token_b_amount = to_u64(token_b_amount)?;
-        let token_b_amount = std::cmp::min(token_b.amount, token_b_amount);
+        let token_b_amount = to_u64_for("token B amount", token_b_amount)?;
+        let token_b_amount = std::cmp::min(reserve_b, token_b_amount);
         if token_b_amount < minimum_token_b_amount {
             return Err(SwapError::ExceededSlippage.into());
         }
-        if token_b_amount == 0 && token_b.amount != 0 {
+        if token_b_amount == 0 && reserve_b != 0 {
             return Err(SwapError::ZeroTradingTokens.into());
         }
 
+        // swap the other side into the preferred mint against the reserves
+        // left after the proportional withdrawal, the trading fee staying in
+        // the pool and the owner fee owed as for any swap
+        let (token_a_amount, token_b_amount) = match preferred_mint {
+            None => (token_a_amount, token_b_amount),
+            Some(preferred_mint) => {
+                let trade_direction = if preferred_mint == *swap_state.token_a_mint() {
+                    TradeDirection::BtoA
+                } else if preferred_mint == *swap_state.token_b_mint() {
+                    TradeDirection::AtoB
+                } else {
+                    return Err(SwapError::InvalidInput.into());
+                };
+                let (swapped_amount, swap_source_amount, swap_destination_amount) =
+                    match trade_direction {
+                        TradeDirection::AtoB => (
+                            token_a_amount,
+                            reserve_a.checked_sub(token_a_amount),
+                            reserve_b.checked_sub(token_b_amount),
+                        ),
+                        TradeDirection::BtoA => (
+                            token_b_amount,
+                            reserve_b.checked_sub(token_b_amount),
+                            reserve_a.checked_sub(token_a_amount),
+                        ),
+                    };
+                let swap_source_amount =
+                    to_u128(swap_source_amount.ok_or(SwapError::CalculationFailure)?)?;
+                let swap_destination_amount =
+                    to_u128(swap_destination_amount.ok_or(SwapError::CalculationFailure)?)?;
+                let swap_curve = swap_state.swap_curve()?;
+                let swap_result = swap_curve
+                    .swap(
+                        to_u128(swapped_amount)?,
+                        swap_source_amount,
+                        swap_destination_amount,
+                        trade_direction,
+                        swap_state.fees(),
+                    )
+                    .ok_or(SwapError::ZeroTradingTokens)?;
+                // the same guards and owner fee as a swap, priced against
+                // the supply left after the burn
+                Self::settle_swap(
+                    &mut swap_state,
+                    swap_curve.as_ref(),
+                    &swap_result,
+                    swap_source_amount,
+                    swap_destination_amount,
+                    trade_direction,
+                    pool_mint_supply
+                        .checked_sub(pool_token_amount)
+                        .ok_or(SwapError::CalculationFailure)?,
+                )?;
+                let amount_out = to_u64_for("amount out", swap_result.destination_amount_swapped)?;
+                let preferred_amount = match trade_direction {
+                    TradeDirection::AtoB => token_b_amount.checked_add(amount_out),
+                    TradeDirection::BtoA => token_a_amount.checked_add(amount_out),
+                }
+                .ok_or(SwapError::CalculationFailure)?;
+                if preferred_amount < minimum_preferred_amount {
+                    return Err(SwapError::ExceededSlippage.into());
+                }
+                match trade_direction {
+                    TradeDirection::AtoB => (0, preferred_amount),
+                    TradeDirection::BtoA => (preferred_amount, 0),
+                }
+            }
+        };
+
         Self::token_burn(
-            token_program_info.clone(),
+            pool_token_program_info.clone(),
             source_info.clone(),
             pool_mint_info.clone(),
             user_transfer_authority_info.clone(),
@@ -513,14 +1141,57 @@ impl Processor {
                 token_b_amount,
             )?;
         }
+
+        let pool_token_amount = to_u64(pool_token_amount)?;
+        swap_state.pool_token_supply = swap_state
+            .pool_token_supply
+            .checked_sub(pool_token_amount)
+            .ok_or(SwapError::CalculationFailure)?;
+        SwapState::pack(swap_state, &mut swap_info.data.borrow_mut())?;
+        Self::set_liquidity_return_data(pool_token_amount, token_a_amount, token_b_amount);
+
         Ok(())
     }
 
+    /// Processes a [WithdrawAllTokenTypes](enum.Instruction.html).
+    pub fn process_withdraw_all_token_types(
+        program_id: &Pubkey,
+        minimum_token_a_amount: u64,
+        minimum_token_b_amount: u64,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let swap_info = next_account_info(account_info_iter)?;
+        let _authority_info = next_account_info(account_info_iter)?;
+        let _user_transfer_authority_info = next_account_info(account_info_iter)?;
+        let _pool_mint_info = next_account_info(account_info_iter)?;
+        let source_info = next_account_info(account_info_iter)?;
+
+        if swap_info.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let swap_state = SwapState::unpack(&swap_info.data.borrow())?;
+        // read here rather than by the client, so the balance cannot change
+        // in between
+        let pool_token_amount =
+            Self::unpack_token_account(source_info, swap_state.pool_token_program_id())?.amount;
+        Self::process_withdraw_tokens(
+            program_id,
+            pool_token_amount,
+            minimum_token_a_amount,
+            minimum_token_b_amount,
+            None,
+            0,
+            accounts,
+        )
+    }
+
     /// Processes an [Swap](enum.Instruction.html).
     pub fn process_swap(
         program_id: &Pubkey,
         amount_in: u64,
         minimum_amount_out: u64,
+        deadline_slot: u64,
         accounts: &[AccountInfo],
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
@@ -537,13 +1208,18 @@ impl Processor {
         if swap_info.owner != program_id {
             return Err(ProgramError::IncorrectProgramId);
         }
-        let swap_state = SwapState::unpack(&swap_info.data.borrow())?;
-
-        if *authority_info.key
-            != Self::authority_id(program_id, swap_info.key, swap_state.bump_seed())?
-        {
-            return Err(SwapError::InvalidProgramAddress.into());
+        if amount_in == 0 {
+            return Err(SwapError::ZeroTradingTokens.into());
         }
+        if deadline_slot != 0 && Clock::get()?.slot > deadline_slot {
+            return Err(SwapError::DeadlineExceeded.into());
+        }
+        let mut swap_state = SwapState::unpack(&swap_info.data.borrow())?;
+        if swap_state.has_flash_loan() {
+            return Err(SwapError::FlashLoanOutstanding.into());
+        }
+
+        Self::check_authority(&swap_state, program_id, swap_info.key, authority_info.key)?;
         if !(*swap_source_info.key == *swap_state.token_a_account()
             || *swap_source_info.key == *swap_state.token_b_account())
         {
@@ -557,6 +1233,11 @@ impl Processor {
         if *swap_source_info.key == *swap_destination_info.key {
             return Err(SwapError::InvalidInput.into());
         }
+        let trade_direction = if *swap_source_info.key == *swap_state.token_a_account() {
+            TradeDirection::AtoB
+        } else {
+            TradeDirection::BtoA
+        };
         if swap_source_info.key == source_info.key {
             // source_info should be user's not program one
             return Err(SwapError::InvalidInput.into());
@@ -565,46 +1246,123 @@ impl Processor {
             // destination_info should be user's not program one
             return Err(SwapError::InvalidInput.into());
         }
-        if *fee_account_info.key != *swap_state.token_a_fee_account()
-            && *fee_account_info.key != *swap_state.token_b_fee_account()
+        if fee_account_info.key == swap_source_info.key
+            || fee_account_info.key == swap_destination_info.key
         {
+            // fees paid into a reserve would inflate the invariant
+            return Err(SwapError::InvalidFeeAccount.into());
+        }
+        let source_fee_account = match trade_direction {
+            TradeDirection::AtoB => swap_state.token_a_fee_account(),
+            TradeDirection::BtoA => swap_state.token_b_fee_account(),
+        };
+        if *fee_account_info.key != *source_fee_account {
             return Err(SwapError::IncorrectFeeAccount.into());
         }
-        if *token_program_info.key != *swap_state.token_program_id() {
+        let token_program_id = *swap_state.token_program_id();
+        if *token_program_info.key != token_program_id {
             return Err(SwapError::IncorrectTokenProgramId.into());
         }
 
-        let source_account =
-            Self::unpack_token_account(swap_source_info, swap_state.token_program_id())?;
-        let dest_account =
-            Self::unpack_token_account(swap_destination_info, swap_state.token_program_id())?;
-        let fee_amount =
-            Self::unpack_token_account(fee_account_info, swap_state.token_program_id())?;
+        // each account is unpacked once, its amounts widened once
+        let (swap_source, swap_destination, source_mint, destination_mint) = match trade_direction {
+            TradeDirection::AtoB => {
+                let (reserve_a, reserve_b) =
+                    swap_state.reserve_accounts(swap_source_info, swap_destination_info)?;
+                (
+                    reserve_a,
+                    reserve_b,
+                    swap_state.token_a_mint(),
+                    swap_state.token_b_mint(),
+                )
+            }
+            TradeDirection::BtoA => {
+                let (reserve_a, reserve_b) =
+                    swap_state.reserve_accounts(swap_destination_info, swap_source_info)?;
+                (
+                    reserve_b,
+                    reserve_a,
+                    swap_state.token_b_mint(),
+                    swap_state.token_a_mint(),
+                )
+            }
+        };
+        let fee_account = Self::unpack_token_account(fee_account_info, &token_program_id)?;
 
-        if fee_amount.mint != source_account.mint {
+        if fee_account.mint != *source_mint {
             return Err(SwapError::IncorrectFeeAccount.into());
         }
+        // catch mismatched user accounts and an underfunded source before
+        // the transfers fail inside the token program
+        let source_account = Self::unpack_token_account(source_info, &token_program_id)?;
+        if source_account.mint != *source_mint
+            || Self::unpack_token_account(destination_info, &token_program_id)?.mint
+                != *destination_mint
+        {
+            return Err(SwapError::IncorrectSwapAccount.into());
+        }
+        if source_account.amount < amount_in {
+            return Err(SwapError::InsufficientFunds.into());
+        }
+        // a reserve that gained a close authority since initialization could
+        // be closed out from under the pool
+        if swap_source.close_authority.is_some() || swap_destination.close_authority.is_some() {
+            return Err(SwapError::InvalidCloseAuthority.into());
+        }
 
-        // charge trading fees
-        let amount_in = to_u128(amount_in)?;
-        let trading_fees = swap_state.fees().trading_fee(amount_in).unwrap_or(0u128);
-        let amount_in = amount_in - trading_fees;
-
-        let swap_token_source_amount = to_u128(source_account.amount)?;
-        let swap_token_dest_amount = to_u128(dest_account.amount)?;
+        let swap_token_source_amount = u128::from(swap_source.amount);
+        let swap_token_dest_amount = u128::from(swap_destination.amount);
 
-        // x * y = k
-        // (x + amount_in) * (y - amount_out) = k
-        // amount_out = y - k / (x + amount_in)
-        //             = y - x * y / (x + amount_in)
-        let amount_out = swap_token_dest_amount
-            - swap_token_source_amount * swap_token_dest_amount
-                / (swap_token_source_amount + amount_in);
-        if amount_out < to_u128(minimum_amount_out)? {
+        let swap_curve = swap_state.swap_curve()?;
+        let swap_result = swap_curve
+            .swap(
+                u128::from(amount_in),
+                swap_token_source_amount,
+                swap_token_dest_amount,
+                trade_direction,
+                swap_state.fees(),
+            )
+            .ok_or(SwapError::ZeroTradingTokens)?;
+        let amount_out = swap_result.destination_amount_swapped;
+        // the input and fees would be taken for nothing
+        if amount_out == 0 {
+            return Err(SwapError::ZeroTradingTokens.into());
+        }
+        if amount_out < u128::from(minimum_amount_out) {
             return Err(SwapError::ExceededSlippage.into());
         }
+        let pool_token_supply = u128::from(swap_state.pool_token_supply);
+        Self::settle_swap(
+            &mut swap_state,
+            swap_curve.as_ref(),
+            &swap_result,
+            swap_token_source_amount,
+            swap_token_dest_amount,
+            trade_direction,
+            pool_token_supply,
+        )?;
+
+        // bound the amount out by a share of the destination fee account
+        if swap_state.has_reward_cap() {
+            let reward_account_info = next_account_info(account_info_iter)?;
+            let reward_fee_account = match trade_direction {
+                TradeDirection::AtoB => swap_state.token_b_fee_account(),
+                TradeDirection::BtoA => swap_state.token_a_fee_account(),
+            };
+            if *reward_account_info.key != *reward_fee_account {
+                return Err(SwapError::IncorrectFeeAccount.into());
+            }
+            let reward_account =
+                Self::unpack_token_account(reward_account_info, &token_program_id)?;
+            let budget = swap_state
+                .reward_budget(reward_account.amount)
+                .ok_or(SwapError::CalculationFailure)?;
+            if amount_out > budget {
+                return Err(SwapError::RewardBudgetExceeded.into());
+            }
+        }
 
-        // transfer source token from user to program
+        // transfer source token from user to program, fees included
         Self::token_transfer(
             swap_info.key,
             token_program_info.clone(),
@@ -612,7 +1370,7 @@ impl Processor {
             swap_source_info.clone(),
             user_transfer_authority_info.clone(),
             swap_state.bump_seed(),
-            to_u64(amount_in)?,
+            to_u64_for("source amount", swap_result.source_amount_swapped)?,
         )?;
 
         // transfer dest token from program to user
@@ -623,74 +1381,7708 @@ impl Processor {
             destination_info.clone(),
             authority_info.clone(),
             swap_state.bump_seed(),
-            to_u64(amount_out)?,
+            to_u64_for("amount out", amount_out)?,
         )?;
 
-        // transfer trading fees
-        Self::token_transfer(
+        #[cfg(feature = "invariant-check")]
+        if swap_state.curve_type == CurveType::ConstantProduct {
+            let source_account = Self::unpack_token_account(swap_source_info, &token_program_id)?;
+            let dest_account =
+                Self::unpack_token_account(swap_destination_info, &token_program_id)?;
+            check_swap_invariant(
+                swap_token_source_amount,
+                swap_token_dest_amount,
+                u128::from(source_account.amount),
+                u128::from(dest_account.amount),
+            );
+        }
+
+        SwapState::pack(swap_state, &mut swap_info.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    /// Checks a swap priced by the curve against the pool's price band and
+    /// price impact bound, then owes the owner the pool tokens its fee is
+    /// worth and records the swap price. Shared by `Swap` and the internal
+    /// swap of a single-mint withdrawal, so neither gets around the guards.
+    fn settle_swap(
+        swap_state: &mut SwapState,
+        swap_curve: &dyn SwapCurve,
+        swap_result: &SwapResult,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        trade_direction: TradeDirection,
+        pool_token_supply: u128,
+    ) -> ProgramResult {
+        let amount_in = swap_result
+            .source_amount_traded()
+            .ok_or(SwapError::FeeCalculationFailure)?;
+        let amount_out = swap_result.destination_amount_swapped;
+
+        // keep the execution price close to the last swap price
+        if swap_state.has_price_band() {
+            let price_band_minimum_amount_out = swap_state
+                .price_band_minimum_amount_out(amount_in, trade_direction)
+                .ok_or(SwapError::CalculationFailure)?;
+            if amount_out < price_band_minimum_amount_out {
+                return Err(SwapError::PriceDeviationTooHigh.into());
+            }
+        }
+
+        // bound how far the swap moves the pool price along the curve
+        if swap_state.max_price_impact_bps != 0 {
+            let (reserves_before, reserves_after) = match trade_direction {
+                TradeDirection::AtoB => (
+                    (swap_source_amount, swap_destination_amount),
+                    (
+                        swap_result.new_source_amount,
+                        swap_result.new_destination_amount,
+                    ),
+                ),
+                TradeDirection::BtoA => (
+                    (swap_destination_amount, swap_source_amount),
+                    (
+                        swap_result.new_destination_amount,
+                        swap_result.new_source_amount,
+                    ),
+                ),
+            };
+            let price_before = swap_curve
+                .normalized_value(reserves_before.0, reserves_before.1)
+                .ok_or(SwapError::CalculationFailure)?;
+            let price_after = swap_curve
+                .normalized_value(reserves_after.0, reserves_after.1)
+                .ok_or(SwapError::CalculationFailure)?;
+            validate_price_impact(price_before, price_after, swap_state.max_price_impact_bps)?;
+        }
+
+        // owe the owner the pool tokens the owner fee is worth
+        if swap_result.owner_fee > 0 {
+            let owner_fee_pool_tokens = swap_curve
+                .owner_fee_pool_tokens(
+                    swap_result.owner_fee,
+                    swap_result.new_source_amount,
+                    pool_token_supply,
+                )
+                .ok_or(SwapError::FeeCalculationFailure)?;
+            swap_state.owner_fees_owed = swap_state
+                .owner_fees_owed
+                .checked_add(to_u64_for("owner fee", owner_fee_pool_tokens)?)
+                .ok_or(SwapError::CalculationFailure)?;
+        }
+
+        if let Some(price) = swap_price(amount_in, amount_out, trade_direction) {
+            swap_state.last_swap_price = price;
+        }
+        Ok(())
+    }
+
+    /// Processes a [ClosePool](enum.Instruction.html).
+    pub fn process_close_pool(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let swap_info = next_account_info(account_info_iter)?;
+        let authority_info = next_account_info(account_info_iter)?;
+        let owner_info = next_account_info(account_info_iter)?;
+        let token_a_info = next_account_info(account_info_iter)?;
+        let token_b_info = next_account_info(account_info_iter)?;
+        let pool_mint_info = next_account_info(account_info_iter)?;
+        let destination_info = next_account_info(account_info_iter)?;
+        let token_program_info = next_account_info(account_info_iter)?;
+        let pool_token_program_info = next_account_info(account_info_iter)?;
+
+        let swap_state = SwapState::unpack(&swap_info.data.borrow())?;
+        Self::check_accounts(
+            &swap_state,
+            program_id,
+            swap_info,
+            authority_info,
+            token_a_info,
+            token_b_info,
+            pool_mint_info,
+            token_program_info,
+            pool_token_program_info,
+            None,
+            None,
+            None,
+            None,
+        )?;
+        if *owner_info.key != *swap_state.owner() {
+            return Err(SwapError::InvalidOwner.into());
+        }
+        if !owner_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let token_a = Self::unpack_token_account(token_a_info, swap_state.token_program_id())?;
+        let token_b = Self::unpack_token_account(token_b_info, swap_state.token_program_id())?;
+        let pool_mint = Self::unpack_mint(pool_mint_info, swap_state.pool_token_program_id())?;
+        if pool_mint.supply != 0 || token_a.amount != 0 || token_b.amount != 0 {
+            return Err(SwapError::NonEmptyPool.into());
+        }
+
+        Self::token_close_account(
             swap_info.key,
             token_program_info.clone(),
-            source_info.clone(),
-            fee_account_info.clone(),
-            user_transfer_authority_info.clone(),
+            token_a_info.clone(),
+            destination_info.clone(),
+            authority_info.clone(),
+            swap_state.bump_seed(),
+        )?;
+        Self::token_close_account(
+            swap_info.key,
+            token_program_info.clone(),
+            token_b_info.clone(),
+            destination_info.clone(),
+            authority_info.clone(),
             swap_state.bump_seed(),
-            to_u64(trading_fees)?,
         )?;
 
+        let destination_lamports = destination_info
+            .lamports()
+            .checked_add(swap_info.lamports())
+            .ok_or(SwapError::CalculationFailure)?;
+        **destination_info.lamports.borrow_mut() = destination_lamports;
+        **swap_info.lamports.borrow_mut() = 0;
+        swap_info.data.borrow_mut().fill(0);
+
         Ok(())
     }
 
-    /// Processes an [Instruction](enum.Instruction.html).
-    pub fn process(program_id: &Pubkey, accounts: &[AccountInfo], input: &[u8]) -> ProgramResult {
-        let instruction = SwapInstruction::unpack(input)?;
-        match instruction {
-            SwapInstruction::Initialize(Initialize { fees }) => {
-                msg!("Instruction: Init");
-                Self::process_initialize(program_id, fees, accounts)
-            }
-            SwapInstruction::DepositTokens(DepositTokens {
-                pool_token_amount,
-                maximum_token_a_amount,
-                maximum_token_b_amount,
-            }) => {
-                msg!("Instruction: DepositTokens");
-                Self::process_deposit_tokens(
-                    program_id,
-                    pool_token_amount,
-                    maximum_token_a_amount,
-                    maximum_token_b_amount,
-                    accounts,
-                )
-            }
-            SwapInstruction::WithdrawTokens(WithdrawTokens {
-                pool_token_amount,
-                minimum_token_a_amount,
-                minimum_token_b_amount,
-            }) => {
-                msg!("Instruction: WithdrawTokens");
-                Self::process_withdraw_tokens(
-                    program_id,
-                    pool_token_amount,
-                    minimum_token_a_amount,
-                    minimum_token_b_amount,
-                    accounts,
-                )
-            }
-            SwapInstruction::Swap(Swap {
-                amount_in,
-                minimum_amount_out,
-            }) => {
-                msg!("Instruction: Swap");
-                Self::process_swap(program_id, amount_in, minimum_amount_out, accounts)
+    /// Processes a [SwapWithSlippageBps](enum.Instruction.html).
+    pub fn process_swap_with_slippage_bps(
+        program_id: &Pubkey,
+        amount_in: u64,
+        slippage_bps: u16,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let swap_info = next_account_info(account_info_iter)?;
+        let _authority_info = next_account_info(account_info_iter)?;
+        let _user_transfer_authority_info = next_account_info(account_info_iter)?;
+        let _source_info = next_account_info(account_info_iter)?;
+        let swap_source_info = next_account_info(account_info_iter)?;
+        let swap_destination_info = next_account_info(account_info_iter)?;
+
+        if swap_info.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let swap_state = SwapState::unpack(&swap_info.data.borrow())?;
+        let (trade_direction, swap_source_amount, swap_destination_amount) =
+            if *swap_source_info.key == *swap_state.token_a_account() {
+                let (reserve_a, reserve_b) =
+                    swap_state.reserves_from_accounts(swap_source_info, swap_destination_info)?;
+                (TradeDirection::AtoB, reserve_a, reserve_b)
+            } else {
+                let (reserve_a, reserve_b) =
+                    swap_state.reserves_from_accounts(swap_destination_info, swap_source_info)?;
+                (TradeDirection::BtoA, reserve_b, reserve_a)
+            };
+        // measured against the spot price, so the tolerance bounds the price
+        // impact of the trade itself, not only moves before it lands
+        let ideal_amount_out = spot_amount_out(
+            &swap_state,
+            amount_in,
+            swap_source_amount,
+            swap_destination_amount,
+            trade_direction,
+        )?;
+        let minimum_amount_out = minimum_out_with_slippage(ideal_amount_out, slippage_bps);
+
+        // the swap checks all accounts, including the reserves quoted above
+        Self::process_swap(program_id, amount_in, minimum_amount_out, 0, accounts)
+    }
+
+    /// Processes a [SwapExactOutOrPartial](enum.Instruction.html).
+    pub fn process_swap_exact_out_or_partial(
+        program_id: &Pubkey,
+        amount_out: u64,
+        maximum_amount_in: u64,
+        allow_partial: bool,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let swap_info = next_account_info(account_info_iter)?;
+        let _authority_info = next_account_info(account_info_iter)?;
+        let _user_transfer_authority_info = next_account_info(account_info_iter)?;
+        let _source_info = next_account_info(account_info_iter)?;
+        let swap_source_info = next_account_info(account_info_iter)?;
+        let swap_destination_info = next_account_info(account_info_iter)?;
+
+        if swap_info.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        if amount_out == 0 {
+            return Err(SwapError::ZeroTradingTokens.into());
+        }
+        let swap_state = SwapState::unpack(&swap_info.data.borrow())?;
+        let (trade_direction, swap_source_amount, swap_destination_amount) =
+            if *swap_source_info.key == *swap_state.token_a_account() {
+                let (reserve_a, reserve_b) =
+                    swap_state.reserves_from_accounts(swap_source_info, swap_destination_info)?;
+                (TradeDirection::AtoB, reserve_a, reserve_b)
+            } else {
+                let (reserve_a, reserve_b) =
+                    swap_state.reserves_from_accounts(swap_destination_info, swap_source_info)?;
+                (TradeDirection::BtoA, reserve_b, reserve_a)
+            };
+        let amount_in = match swap_amount_in(
+            &swap_state,
+            amount_out,
+            maximum_amount_in,
+            swap_source_amount,
+            swap_destination_amount,
+            trade_direction,
+        ) {
+            Some(amount_in) => amount_in,
+            None if allow_partial => maximum_amount_in,
+            None => return Err(SwapError::ExceededSlippage.into()),
+        };
+        let filled_amount_out = swap_amount_out(
+            &swap_state,
+            amount_in,
+            swap_source_amount,
+            swap_destination_amount,
+            trade_direction,
+        )?;
+
+        // the swap checks all accounts, including the reserves quoted above
+        Self::process_swap(program_id, amount_in, filled_amount_out, 0, accounts)?;
+        set_return_data(&filled_amount_out.to_le_bytes());
+        Ok(())
+    }
+
+    /// Processes a [RouteSwap](enum.Instruction.html).
+    pub fn process_route_swap(
+        program_id: &Pubkey,
+        amount_in: u64,
+        minimum_amount_out: u64,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let user_transfer_authority_info = next_account_info(account_info_iter)?;
+        let source_info = next_account_info(account_info_iter)?;
+        let intermediate_info = next_account_info(account_info_iter)?;
+        let destination_info = next_account_info(account_info_iter)?;
+        let token_program_info = next_account_info(account_info_iter)?;
+        let first_pool_infos = accounts
+            .get(5..11)
+            .ok_or(ProgramError::NotEnoughAccountKeys)?;
+        let second_pool_infos = accounts
+            .get(11..17)
+            .ok_or(ProgramError::NotEnoughAccountKeys)?;
+
+        let intermediate_amount = || {
+            Self::unpack_token_account(intermediate_info, token_program_info.key)
+                .map(|account| account.amount)
+        };
+        let intermediate_before = intermediate_amount()?;
+        // the first hop is bounded by the final minimum only
+        Self::process_swap(
+            program_id,
+            amount_in,
+            0,
+            0,
+            &Self::route_hop_accounts(
+                first_pool_infos,
+                user_transfer_authority_info,
+                source_info,
+                intermediate_info,
+                token_program_info,
+            )?,
+        )?;
+        let intermediate_amount_in = intermediate_amount()?
+            .checked_sub(intermediate_before)
+            .ok_or(SwapError::CalculationFailure)?;
+
+        Self::process_swap(
+            program_id,
+            intermediate_amount_in,
+            minimum_amount_out,
+            0,
+            &Self::route_hop_accounts(
+                second_pool_infos,
+                user_transfer_authority_info,
+                intermediate_info,
+                destination_info,
+                token_program_info,
+            )?,
+        )
+    }
+
+    /// Accounts of one `RouteSwap` hop, in the order of a `Swap`
+    fn route_hop_accounts<'a>(
+        pool_infos: &[AccountInfo<'a>],
+        user_transfer_authority_info: &AccountInfo<'a>,
+        source_info: &AccountInfo<'a>,
+        destination_info: &AccountInfo<'a>,
+        token_program_info: &AccountInfo<'a>,
+    ) -> Result<Vec<AccountInfo<'a>>, ProgramError> {
+        match pool_infos {
+            [swap_info, authority_info, swap_source_info, swap_destination_info, fee_account_info, reward_account_info] => {
+                Ok(vec![
+                    swap_info.clone(),
+                    authority_info.clone(),
+                    user_transfer_authority_info.clone(),
+                    source_info.clone(),
+                    swap_source_info.clone(),
+                    swap_destination_info.clone(),
+                    destination_info.clone(),
+                    fee_account_info.clone(),
+                    token_program_info.clone(),
+                    reward_account_info.clone(),
+                ])
             }
+            _ => Err(ProgramError::NotEnoughAccountKeys),
         }
     }
-}
 
-fn to_u128(val: u64) -> Result<u128, SwapError> {
-    val.try_into().map_err(|_| SwapError::ConversionFailure)
-}
+    /// Processes a [BatchQuote](enum.Instruction.html).
+    pub fn process_batch_quote(
+        program_id: &Pubkey,
+        amounts_in: &[u64],
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let swap_info = next_account_info(account_info_iter)?;
+        let swap_source_info = next_account_info(account_info_iter)?;
+        let swap_destination_info = next_account_info(account_info_iter)?;
 
-fn to_u64(val: u128) -> Result<u64, SwapError> {
-    val.try_into().map_err(|_| SwapError::ConversionFailure)
+        if swap_info.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let swap_state = SwapState::unpack(&swap_info.data.borrow())?;
+        let trade_direction = if *swap_source_info.key == *swap_state.token_a_account()
+            && *swap_destination_info.key == *swap_state.token_b_account()
+        {
+            TradeDirection::AtoB
+        } else if *swap_source_info.key == *swap_state.token_b_account()
+            && *swap_destination_info.key == *swap_state.token_a_account()
+        {
+            TradeDirection::BtoA
+        } else {
+            return Err(SwapError::IncorrectSwapAccount.into());
+        };
+
+        let source_account =
+            Self::unpack_token_account(swap_source_info, swap_state.token_program_id())?;
+        let dest_account =
+            Self::unpack_token_account(swap_destination_info, swap_state.token_program_id())?;
+
+        let mut quotes = Vec::with_capacity(amounts_in.len() * 8);
+        for (index, amount_in) in amounts_in.iter().enumerate() {
+            let amount_out = swap_amount_out(
+                &swap_state,
+                *amount_in,
+                source_account.amount,
+                dest_account.amount,
+                trade_direction,
+            )
+            .unwrap_or_else(|error| {
+                msg!("Quote {} of {} failed: {}", index, amount_in, error);
+                FAILED_QUOTE
+            });
+            quotes.extend_from_slice(&amount_out.to_le_bytes());
+        }
+        set_return_data(&quotes);
+
+        Ok(())
+    }
+
+    /// Processes a [QueryReserves](enum.Instruction.html).
+    pub fn process_query_reserves(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let swap_info = next_account_info(account_info_iter)?;
+        let token_a_info = next_account_info(account_info_iter)?;
+        let token_b_info = next_account_info(account_info_iter)?;
+        let pool_mint_info = next_account_info(account_info_iter)?;
+
+        if swap_info.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        // read only, the synced supply is not written back
+        let mut swap_state = SwapState::unpack(&swap_info.data.borrow())?;
+        if *token_a_info.key != *swap_state.token_a_account() {
+            return Err(SwapError::IncorrectSwapAccount.into());
+        }
+        if *token_b_info.key != *swap_state.token_b_account() {
+            return Err(SwapError::IncorrectSwapAccount.into());
+        }
+        if *pool_mint_info.key != *swap_state.pool_mint() {
+            return Err(SwapError::IncorrectPoolMint.into());
+        }
+
+        let token_a = Self::unpack_token_account(token_a_info, swap_state.token_program_id())?;
+        let token_b = Self::unpack_token_account(token_b_info, swap_state.token_program_id())?;
+        let reserves = PoolReserves {
+            reserve_a: token_a.amount,
+            reserve_b: token_b.amount,
+            pool_supply: Self::sync_pool_token_supply(&mut swap_state, pool_mint_info)?,
+        };
+        let mut data = [0u8; PoolReserves::LEN];
+        reserves.pack_into_slice(&mut data);
+        set_return_data(&data);
+
+        Ok(())
+    }
+
+    /// Processes a [VerifyAuthority](enum.Instruction.html).
+    pub fn process_verify_authority(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let swap_info = next_account_info(account_info_iter)?;
+        let authority_info = next_account_info(account_info_iter)?;
+
+        if swap_info.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let swap_state = SwapState::unpack(&swap_info.data.borrow())?;
+        let matches =
+            Self::check_authority(&swap_state, program_id, swap_info.key, authority_info.key)
+                .is_ok();
+        set_return_data(&[matches as u8]);
+        Ok(())
+    }
+
+    /// Processes a [FlashLoan](enum.Instruction.html).
+    pub fn process_flash_loan(
+        program_id: &Pubkey,
+        amount: u64,
+        token: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let swap_info = next_account_info(account_info_iter)?;
+        let authority_info = next_account_info(account_info_iter)?;
+        let reserve_info = next_account_info(account_info_iter)?;
+        let destination_info = next_account_info(account_info_iter)?;
+        let token_program_info = next_account_info(account_info_iter)?;
+        let instructions_sysvar_info = next_account_info(account_info_iter)?;
+
+        if swap_info.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let mut swap_state = SwapState::unpack(&swap_info.data.borrow())?;
+        if swap_state.has_flash_loan() {
+            return Err(SwapError::FlashLoanOutstanding.into());
+        }
+        Self::check_authority(&swap_state, program_id, swap_info.key, authority_info.key)?;
+        let reserve_key = if *token == *swap_state.token_a_mint() {
+            swap_state.token_a_account()
+        } else if *token == *swap_state.token_b_mint() {
+            swap_state.token_b_account()
+        } else {
+            return Err(SwapError::InvalidInput.into());
+        };
+        if *reserve_info.key != *reserve_key {
+            return Err(SwapError::IncorrectSwapAccount.into());
+        }
+        if *token_program_info.key != *swap_state.token_program_id() {
+            return Err(SwapError::IncorrectTokenProgramId.into());
+        }
+        if amount == 0 {
+            return Err(SwapError::InvalidInput.into());
+        }
+
+        let reserve = Self::unpack_token_account(reserve_info, swap_state.token_program_id())?;
+        let flash_loan_fee = swap_state
+            .fees()
+            .flash_loan_fee(to_u128(amount)?)
+            .ok_or(SwapError::FeeCalculationFailure)?;
+        let flash_loan_balance = to_u128(reserve.amount)?
+            .checked_add(flash_loan_fee)
+            .ok_or(SwapError::CalculationFailure)?;
+        Self::check_flash_loan_repay(program_id, swap_info.key, instructions_sysvar_info)?;
+
+        Self::token_transfer(
+            swap_info.key,
+            token_program_info.clone(),
+            reserve_info.clone(),
+            destination_info.clone(),
+            authority_info.clone(),
+            swap_state.bump_seed(),
+            amount,
+        )?;
+
+        swap_state.flash_loan_reserve = *reserve_info.key;
+        swap_state.flash_loan_balance = to_u64_for("flash loan balance", flash_loan_balance)?;
+        SwapState::pack(swap_state, &mut swap_info.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    /// Checks that a `FlashLoanRepay` for the same pool follows the current
+    /// instruction in the transaction
+    fn check_flash_loan_repay(
+        program_id: &Pubkey,
+        swap_key: &Pubkey,
+        instructions_sysvar_info: &AccountInfo,
+    ) -> ProgramResult {
+        let current_index = load_current_index_checked(instructions_sysvar_info)?;
+        for index in usize::from(current_index) + 1.. {
+            let instruction = match load_instruction_at_checked(index, instructions_sysvar_info) {
+                Ok(instruction) => instruction,
+                // past the last instruction of the transaction
+                Err(ProgramError::InvalidArgument) => break,
+                Err(err) => return Err(err),
+            };
+            if instruction.program_id == *program_id
+                && instruction.accounts.first().map(|meta| meta.pubkey) == Some(*swap_key)
+                && SwapInstruction::unpack(&instruction.data) == Ok(SwapInstruction::FlashLoanRepay)
+            {
+                return Ok(());
+            }
+        }
+        Err(SwapError::FlashLoanNotRepaid.into())
+    }
+
+    /// Processes a [FlashLoanRepay](enum.Instruction.html).
+    pub fn process_flash_loan_repay(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let swap_info = next_account_info(account_info_iter)?;
+        let reserve_info = next_account_info(account_info_iter)?;
+
+        if swap_info.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let mut swap_state = SwapState::unpack(&swap_info.data.borrow())?;
+        if !swap_state.has_flash_loan() {
+            return Err(SwapError::InvalidInput.into());
+        }
+        if *reserve_info.key != swap_state.flash_loan_reserve {
+            return Err(SwapError::IncorrectSwapAccount.into());
+        }
+
+        let reserve = Self::unpack_token_account(reserve_info, swap_state.token_program_id())?;
+        if reserve.amount < swap_state.flash_loan_balance {
+            return Err(SwapError::FlashLoanNotRepaid.into());
+        }
+
+        swap_state.flash_loan_reserve = Pubkey::default();
+        swap_state.flash_loan_balance = 0;
+        SwapState::pack(swap_state, &mut swap_info.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    /// Processes a [Rebalance](enum.Instruction.html).
+    pub fn process_rebalance(
+        program_id: &Pubkey,
+        target_ratio: u64,
+        maximum_amount: u64,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let swap_info = next_account_info(account_info_iter)?;
+        let authority_info = next_account_info(account_info_iter)?;
+        let owner_info = next_account_info(account_info_iter)?;
+        let token_a_info = next_account_info(account_info_iter)?;
+        let token_b_info = next_account_info(account_info_iter)?;
+        let owner_token_b_info = next_account_info(account_info_iter)?;
+        let token_program_info = next_account_info(account_info_iter)?;
+
+        if swap_info.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let mut swap_state = SwapState::unpack(&swap_info.data.borrow())?;
+        if swap_state.has_flash_loan() {
+            return Err(SwapError::FlashLoanOutstanding.into());
+        }
+        Self::check_authority(&swap_state, program_id, swap_info.key, authority_info.key)?;
+        if *token_a_info.key != *swap_state.token_a_account() {
+            return Err(SwapError::IncorrectSwapAccount.into());
+        }
+        if *token_b_info.key != *swap_state.token_b_account() {
+            return Err(SwapError::IncorrectSwapAccount.into());
+        }
+        if token_b_info.key == owner_token_b_info.key {
+            return Err(SwapError::InvalidInput.into());
+        }
+        if *token_program_info.key != *swap_state.token_program_id() {
+            return Err(SwapError::IncorrectTokenProgramId.into());
+        }
+        if *owner_info.key != *swap_state.owner() {
+            return Err(SwapError::InvalidOwner.into());
+        }
+        if !owner_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if target_ratio == 0 {
+            return Err(SwapError::InvalidInput.into());
+        }
+
+        let token_a = Self::unpack_token_account(token_a_info, swap_state.token_program_id())?;
+        let token_b = Self::unpack_token_account(token_b_info, swap_state.token_program_id())?;
+        // let target_token_b_amount = token_a.amount * target_ratio / PRICE_SCALE
+        let target_token_b_amount = to_u64_for(
+            "target token B amount",
+            to_u128(token_a.amount)?
+                .checked_mul(u128::from(target_ratio))
+                .ok_or(SwapError::CalculationFailure)?
+                / PRICE_SCALE,
+        )?;
+        validate_supply(
+            swap_state.swap_curve()?.as_ref(),
+            token_a.amount,
+            target_token_b_amount,
+        )?;
+
+        if target_token_b_amount >= token_b.amount {
+            let amount = target_token_b_amount - token_b.amount;
+            if amount > maximum_amount {
+                return Err(SwapError::ExceededSlippage.into());
+            }
+            Self::token_transfer(
+                swap_info.key,
+                token_program_info.clone(),
+                owner_token_b_info.clone(),
+                token_b_info.clone(),
+                owner_info.clone(),
+                swap_state.bump_seed(),
+                amount,
+            )?;
+            swap_state.owner_rebalance_amount = swap_state
+                .owner_rebalance_amount
+                .checked_add(amount)
+                .ok_or(SwapError::CalculationFailure)?;
+        } else {
+            let amount = token_b.amount - target_token_b_amount;
+            if amount > maximum_amount {
+                return Err(SwapError::ExceededSlippage.into());
+            }
+            // only tokens the owner added may leave, the rest belongs to the
+            // liquidity providers
+            swap_state.owner_rebalance_amount = swap_state
+                .owner_rebalance_amount
+                .checked_sub(amount)
+                .ok_or(SwapError::RebalanceExceedsOwnerFunds)?;
+            Self::token_transfer(
+                swap_info.key,
+                token_program_info.clone(),
+                token_b_info.clone(),
+                owner_token_b_info.clone(),
+                authority_info.clone(),
+                swap_state.bump_seed(),
+                amount,
+            )?;
+        }
+
+        // the price band follows the rebalanced price
+        swap_state.last_swap_price = u128::from(target_ratio);
+        SwapState::pack(swap_state, &mut swap_info.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    /// Processes a [SetCurveParameter](enum.Instruction.html).
+    pub fn process_set_curve_parameter(
+        program_id: &Pubkey,
+        parameter: u64,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let swap_info = next_account_info(account_info_iter)?;
+        let owner_info = next_account_info(account_info_iter)?;
+        let token_a_info = next_account_info(account_info_iter)?;
+        let token_b_info = next_account_info(account_info_iter)?;
+        let pool_mint_info = next_account_info(account_info_iter)?;
+        let owner_pool_info = next_account_info(account_info_iter)?;
+
+        if swap_info.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let mut swap_state = SwapState::unpack(&swap_info.data.borrow())?;
+        if swap_state.has_flash_loan() {
+            return Err(SwapError::FlashLoanOutstanding.into());
+        }
+        if *token_a_info.key != *swap_state.token_a_account() {
+            return Err(SwapError::IncorrectSwapAccount.into());
+        }
+        if *token_b_info.key != *swap_state.token_b_account() {
+            return Err(SwapError::IncorrectSwapAccount.into());
+        }
+        if *pool_mint_info.key != *swap_state.pool_mint() {
+            return Err(SwapError::IncorrectPoolMint.into());
+        }
+        if *owner_info.key != *swap_state.owner() {
+            return Err(SwapError::InvalidOwner.into());
+        }
+        if !owner_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // the owner holding every pool token proves no other liquidity
+        // provider has a stake priced at the launch parameters, whatever
+        // deposits and withdrawals brought the supply to its current size
+        let owner_pool =
+            Self::unpack_token_account(owner_pool_info, swap_state.pool_token_program_id())?;
+        if owner_pool.mint != *swap_state.pool_mint() {
+            return Err(SwapError::IncorrectPoolMint.into());
+        }
+        if owner_pool.owner != *swap_state.owner() {
+            return Err(SwapError::InvalidOwner.into());
+        }
+        if owner_pool.amount != Self::sync_pool_token_supply(&mut swap_state, pool_mint_info)? {
+            return Err(SwapError::OperationNotAllowed.into());
+        }
+
+        swap_state.curve_parameter = parameter;
+        let token_a = Self::unpack_token_account(token_a_info, swap_state.token_program_id())?;
+        let token_b = Self::unpack_token_account(token_b_info, swap_state.token_program_id())?;
+        validate_supply(
+            swap_state.swap_curve()?.as_ref(),
+            token_a.amount,
+            token_b.amount,
+        )?;
+        SwapState::pack(swap_state, &mut swap_info.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    /// Processes a [CollectOwnerFees](enum.Instruction.html).
+    pub fn process_collect_owner_fees(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let swap_info = next_account_info(account_info_iter)?;
+        let authority_info = next_account_info(account_info_iter)?;
+        let owner_info = next_account_info(account_info_iter)?;
+        let pool_mint_info = next_account_info(account_info_iter)?;
+        let destination_info = next_account_info(account_info_iter)?;
+        let pool_token_program_info = next_account_info(account_info_iter)?;
+
+        if swap_info.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let mut swap_state = SwapState::unpack(&swap_info.data.borrow())?;
+        Self::check_authority(&swap_state, program_id, swap_info.key, authority_info.key)?;
+        if *pool_mint_info.key != *swap_state.pool_mint() {
+            return Err(SwapError::IncorrectPoolMint.into());
+        }
+        if *pool_token_program_info.key != *swap_state.pool_token_program_id() {
+            return Err(SwapError::IncorrectTokenProgramId.into());
+        }
+        if *owner_info.key != *swap_state.owner() {
+            return Err(SwapError::InvalidOwner.into());
+        }
+        if !owner_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let owner_fees_owed = swap_state.owner_fees_owed;
+        if owner_fees_owed == 0 {
+            return Ok(());
+        }
+        Self::token_mint_to(
+            swap_info.key,
+            pool_token_program_info.clone(),
+            pool_mint_info.clone(),
+            destination_info.clone(),
+            authority_info.clone(),
+            swap_state.bump_seed(),
+            owner_fees_owed,
+        )?;
+
+        swap_state.pool_token_supply = swap_state
+            .pool_token_supply
+            .checked_add(owner_fees_owed)
+            .ok_or(SwapError::CalculationFailure)?;
+        swap_state.owner_fees_owed = 0;
+        SwapState::pack(swap_state, &mut swap_info.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    /// Processes a [SetFeeAccounts](enum.Instruction.html).
+    pub fn process_set_fee_accounts(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let swap_info = next_account_info(account_info_iter)?;
+        let authority_info = next_account_info(account_info_iter)?;
+        let owner_info = next_account_info(account_info_iter)?;
+        let token_a_fee_account_info = next_account_info(account_info_iter)?;
+        let token_b_fee_account_info = next_account_info(account_info_iter)?;
+
+        if swap_info.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let mut swap_state = SwapState::unpack(&swap_info.data.borrow())?;
+        Self::check_authority(&swap_state, program_id, swap_info.key, authority_info.key)?;
+        if *owner_info.key != *swap_state.owner() {
+            return Err(SwapError::InvalidOwner.into());
+        }
+        if !owner_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let token_a_fee_account =
+            Self::unpack_token_account(token_a_fee_account_info, swap_state.token_program_id())?;
+        let token_b_fee_account =
+            Self::unpack_token_account(token_b_fee_account_info, swap_state.token_program_id())?;
+        if token_a_fee_account.mint != *swap_state.token_a_mint() {
+            return Err(SwapError::IncorrectFeeAccount.into());
+        }
+        if token_b_fee_account.mint != *swap_state.token_b_mint() {
+            return Err(SwapError::IncorrectFeeAccount.into());
+        }
+        if *authority_info.key == token_a_fee_account.owner {
+            return Err(SwapError::InvalidOutputOwner.into());
+        }
+        if *authority_info.key == token_b_fee_account.owner {
+            return Err(SwapError::InvalidOutputOwner.into());
+        }
+        if token_a_fee_account.is_frozen() || token_b_fee_account.is_frozen() {
+            return Err(SwapError::InvalidFeeAccount.into());
+        }
+
+        swap_state.token_a_fee_account = *token_a_fee_account_info.key;
+        swap_state.token_b_fee_account = *token_b_fee_account_info.key;
+        SwapState::pack(swap_state, &mut swap_info.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    /// Processes a [Migrate](enum.Instruction.html).
+    pub fn process_migrate(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let swap_info = next_account_info(account_info_iter)?;
+        let upgrade_authority_info = next_account_info(account_info_iter)?;
+        let program_data_info = next_account_info(account_info_iter)?;
+        let pool_mint_info = next_account_info(account_info_iter)?;
+        let token_a_mint_info = next_account_info(account_info_iter)?;
+        let token_b_mint_info = next_account_info(account_info_iter)?;
+        let rent_info = next_account_info(account_info_iter)?;
+
+        if swap_info.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        // only the old layout has this length, so a pool migrates once
+        if swap_info.data_len() != SwapStateV1::LEN {
+            return Err(SwapError::AlreadyInUse.into());
+        }
+        let swap_state_v1 = SwapStateV1::unpack(&swap_info.data.borrow())?;
+
+        let (program_data_key, _) =
+            Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+        if *program_data_info.key != program_data_key
+            || *program_data_info.owner != bpf_loader_upgradeable::id()
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if upgrade_authority(&program_data_info.data.borrow()) != Some(*upgrade_authority_info.key)
+        {
+            return Err(SwapError::InvalidOwner.into());
+        }
+        if !upgrade_authority_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if *pool_mint_info.key != swap_state_v1.pool_mint {
+            return Err(SwapError::IncorrectPoolMint.into());
+        }
+        if *token_a_mint_info.key != swap_state_v1.token_a_mint
+            || *token_b_mint_info.key != swap_state_v1.token_b_mint
+        {
+            return Err(SwapError::IncorrectMint.into());
+        }
+        let token_program_id = swap_state_v1.token_program_id;
+        let pool_mint = Self::unpack_mint(pool_mint_info, &token_program_id)?;
+        let token_a_mint = Self::unpack_mint(token_a_mint_info, &token_program_id)?;
+        let token_b_mint = Self::unpack_mint(token_b_mint_info, &token_program_id)?;
+
+        // the added space must already be paid for
+        let rent = Rent::from_account_info(rent_info)?;
+        if !rent.is_exempt(swap_info.lamports(), SwapState::LEN) {
+            return Err(ProgramError::AccountNotRentExempt);
+        }
+
+        let swap_state = SwapState {
+            is_initialized: swap_state_v1.is_initialized,
+            bump_seed: swap_state_v1.bump_seed,
+            owner: *upgrade_authority_info.key,
+            token_program_id,
+            pool_token_program_id: token_program_id,
+            token_a: swap_state_v1.token_a,
+            token_b: swap_state_v1.token_b,
+            pool_mint: swap_state_v1.pool_mint,
+            token_a_mint: swap_state_v1.token_a_mint,
+            token_b_mint: swap_state_v1.token_b_mint,
+            token_a_fee_account: swap_state_v1.token_a_fee_account,
+            token_b_fee_account: swap_state_v1.token_b_fee_account,
+            fees: Fees {
+                trade_fee_numerator: swap_state_v1.trade_fee_numerator,
+                trade_fee_denominator: swap_state_v1.trade_fee_denominator,
+                ..Fees::default()
+            },
+            curve_type: CurveType::ConstantProduct,
+            curve_parameter: 0,
+            reward_cap_numerator: 0,
+            reward_cap_denominator: 0,
+            last_swap_price: 0,
+            price_band_bps: 0,
+            pool_token_supply: pool_mint.supply,
+            token_a_decimals: token_a_mint.decimals,
+            token_b_decimals: token_b_mint.decimals,
+            pool_token_decimals: pool_mint.decimals,
+            flash_loan_reserve: Pubkey::default(),
+            flash_loan_balance: 0,
+            initial_pool_amount: to_u64(INITIAL_SWAP_POOL_AMOUNT)?,
+            owner_fees_owed: 0,
+            round_up_deposits: true,
+            max_price_impact_bps: 0,
+            owner_rebalance_amount: 0,
+        };
+        swap_info.realloc(SwapState::LEN, false)?;
+        SwapState::pack(swap_state, &mut swap_info.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    /// Processes an [Instruction](enum.Instruction.html).
+    pub fn process(program_id: &Pubkey, accounts: &[AccountInfo], input: &[u8]) -> ProgramResult {
+        Self::process_with_constraints(program_id, accounts, input, &SWAP_CONSTRAINTS)
+    }
+
+    /// Processes an [Instruction](enum.Instruction.html), initializing pools
+    /// under the given constraints rather than the build's.
+    pub fn process_with_constraints(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        input: &[u8],
+        swap_constraints: &Option<SwapConstraints>,
+    ) -> ProgramResult {
+        let instruction = SwapInstruction::unpack(input)?;
+        match instruction {
+            SwapInstruction::Initialize(Initialize {
+                fees,
+                owner,
+                curve_type,
+                curve_parameter,
+                reward_cap_numerator,
+                reward_cap_denominator,
+                price_band_bps,
+                initial_pool_amount,
+                minimum_pool_token_amount,
+                round_up_deposits,
+                max_price_impact_bps,
+            }) => {
+                msg!("Instruction: Init");
+                Self::process_initialize(
+                    program_id,
+                    fees,
+                    owner,
+                    curve_type,
+                    curve_parameter,
+                    reward_cap_numerator,
+                    reward_cap_denominator,
+                    price_band_bps,
+                    initial_pool_amount,
+                    minimum_pool_token_amount,
+                    round_up_deposits,
+                    max_price_impact_bps,
+                    accounts,
+                    swap_constraints,
+                )
+            }
+            SwapInstruction::DepositTokens(DepositTokens {
+                pool_token_amount,
+                maximum_token_a_amount,
+                maximum_token_b_amount,
+                expected_ratio_numerator,
+                expected_ratio_denominator,
+            }) => {
+                msg!("Instruction: DepositTokens");
+                Self::process_deposit_tokens(
+                    program_id,
+                    pool_token_amount,
+                    maximum_token_a_amount,
+                    maximum_token_b_amount,
+                    expected_ratio_numerator,
+                    expected_ratio_denominator,
+                    accounts,
+                )
+            }
+            SwapInstruction::WithdrawTokens(WithdrawTokens {
+                pool_token_amount,
+                minimum_token_a_amount,
+                minimum_token_b_amount,
+                preferred_mint,
+                minimum_preferred_amount,
+            }) => {
+                msg!("Instruction: WithdrawTokens");
+                Self::process_withdraw_tokens(
+                    program_id,
+                    pool_token_amount,
+                    minimum_token_a_amount,
+                    minimum_token_b_amount,
+                    preferred_mint,
+                    minimum_preferred_amount,
+                    accounts,
+                )
+            }
+            SwapInstruction::Swap(Swap {
+                amount_in,
+                minimum_amount_out,
+                deadline_slot,
+            }) => {
+                msg!("Instruction: Swap");
+                Self::process_swap(
+                    program_id,
+                    amount_in,
+                    minimum_amount_out,
+                    deadline_slot,
+                    accounts,
+                )
+            }
+            SwapInstruction::BatchQuote(BatchQuote { amounts_in }) => {
+                msg!("Instruction: BatchQuote");
+                Self::process_batch_quote(program_id, &amounts_in, accounts)
+            }
+            SwapInstruction::ClosePool => {
+                msg!("Instruction: ClosePool");
+                Self::process_close_pool(program_id, accounts)
+            }
+            SwapInstruction::FlashLoan(FlashLoan { amount, token }) => {
+                msg!("Instruction: FlashLoan");
+                Self::process_flash_loan(program_id, amount, &token, accounts)
+            }
+            SwapInstruction::FlashLoanRepay => {
+                msg!("Instruction: FlashLoanRepay");
+                Self::process_flash_loan_repay(program_id, accounts)
+            }
+            SwapInstruction::Rebalance(Rebalance {
+                target_ratio,
+                maximum_amount,
+            }) => {
+                msg!("Instruction: Rebalance");
+                Self::process_rebalance(program_id, target_ratio, maximum_amount, accounts)
+            }
+            SwapInstruction::DepositTokensExactAmounts(DepositTokensExactAmounts {
+                token_a_amount,
+                token_b_amount,
+                minimum_pool_token_amount,
+            }) => {
+                msg!("Instruction: DepositTokensExactAmounts");
+                Self::process_deposit_tokens_exact_amounts(
+                    program_id,
+                    token_a_amount,
+                    token_b_amount,
+                    minimum_pool_token_amount,
+                    accounts,
+                )
+            }
+            SwapInstruction::SetCurveParameter(SetCurveParameter { parameter }) => {
+                msg!("Instruction: SetCurveParameter");
+                Self::process_set_curve_parameter(program_id, parameter, accounts)
+            }
+            SwapInstruction::QueryReserves => {
+                msg!("Instruction: QueryReserves");
+                Self::process_query_reserves(program_id, accounts)
+            }
+            SwapInstruction::CollectOwnerFees => {
+                msg!("Instruction: CollectOwnerFees");
+                Self::process_collect_owner_fees(program_id, accounts)
+            }
+            SwapInstruction::Migrate => {
+                msg!("Instruction: Migrate");
+                Self::process_migrate(program_id, accounts)
+            }
+            SwapInstruction::WithdrawAllTokenTypes(WithdrawAllTokenTypes {
+                minimum_token_a_amount,
+                minimum_token_b_amount,
+            }) => {
+                msg!("Instruction: WithdrawAllTokenTypes");
+                Self::process_withdraw_all_token_types(
+                    program_id,
+                    minimum_token_a_amount,
+                    minimum_token_b_amount,
+                    accounts,
+                )
+            }
+            SwapInstruction::SetFeeAccounts => {
+                msg!("Instruction: SetFeeAccounts");
+                Self::process_set_fee_accounts(program_id, accounts)
+            }
+            SwapInstruction::SwapWithSlippageBps(SwapWithSlippageBps {
+                amount_in,
+                slippage_bps,
+            }) => {
+                msg!("Instruction: SwapWithSlippageBps");
+                Self::process_swap_with_slippage_bps(program_id, amount_in, slippage_bps, accounts)
+            }
+            SwapInstruction::RouteSwap(RouteSwap {
+                amount_in,
+                minimum_amount_out,
+            }) => {
+                msg!("Instruction: RouteSwap");
+                Self::process_route_swap(program_id, amount_in, minimum_amount_out, accounts)
+            }
+            SwapInstruction::InitializeWithDeposit(InitializeWithDeposit {
+                initialize,
+                token_a_amount,
+                token_b_amount,
+            }) => {
+                msg!("Instruction: InitializeWithDeposit");
+                Self::process_initialize_with_deposit(
+                    program_id,
+                    initialize,
+                    token_a_amount,
+                    token_b_amount,
+                    accounts,
+                    swap_constraints,
+                )
+            }
+            SwapInstruction::VerifyAuthority => {
+                msg!("Instruction: VerifyAuthority");
+                Self::process_verify_authority(program_id, accounts)
+            }
+            SwapInstruction::ValidateInitialize(initialize) => {
+                msg!("Instruction: ValidateInitialize");
+                Self::process_validate_initialize(
+                    program_id,
+                    initialize,
+                    accounts,
+                    swap_constraints,
+                )
+            }
+            SwapInstruction::SwapExactOutOrPartial(SwapExactOutOrPartial {
+                amount_out,
+                maximum_amount_in,
+                allow_partial,
+            }) => {
+                msg!("Instruction: SwapExactOutOrPartial");
+                Self::process_swap_exact_out_or_partial(
+                    program_id,
+                    amount_out,
+                    maximum_amount_in,
+                    allow_partial,
+                    accounts,
+                )
+            }
+        }
+    }
+}
+
+/// spl-token's instruction builders only accept its own program id, but
+/// other token programs such as Token-2022 share the same instruction layout,
+/// so build with spl-token and address the program actually in use.
+fn token_instruction(mut instruction: Instruction, token_program_id: &Pubkey) -> Instruction {
+    instruction.program_id = *token_program_id;
+    instruction
+}
+
+/// Reserve tokens required to mint `pool_token_amount` out of
+/// `pool_mint_supply`, kept in u128 and rounded up so existing liquidity
+/// providers are never diluted, or down when `round_up` is unset. A share
+/// worth less than one token is rejected rather than minted for free or
+/// charged a whole token, and one worth more than a token account can hold
+/// is rejected as well.
+fn deposit_token_amount(
+    reserve_amount: u64,
+    pool_token_amount: u128,
+    pool_mint_supply: u128,
+    round_up: bool,
+) -> Result<u64, SwapError> {
+    let numerator = to_u128(reserve_amount)?
+        .checked_mul(pool_token_amount)
+        .ok_or(SwapError::CalculationFailure)?;
+    let token_amount = numerator
+        .checked_div(pool_mint_supply)
+        .ok_or(SwapError::CalculationFailure)?;
+    if token_amount == 0 {
+        return Err(SwapError::ZeroTradingTokens);
+    }
+    let token_amount = if !round_up || numerator % pool_mint_supply == 0 {
+        token_amount
+    } else {
+        token_amount
+            .checked_add(1)
+            .ok_or(SwapError::CalculationFailure)?
+    };
+    u64::try_from(token_amount).map_err(|_| {
+        msg!(
+            "Deposit of {} pool tokens requires {} reserve tokens, more than the reserves support",
+            pool_token_amount,
+            token_amount
+        );
+        SwapError::CalculationFailure
+    })
+}
+
+/// Upgrade authority recorded in the upgradeable loader's program data
+/// account, if the program is still upgradeable
+fn upgrade_authority(program_data: &[u8]) -> Option<Pubkey> {
+    // bincode layout: u32 variant, u64 slot, then an optional pubkey
+    const PROGRAM_DATA_VARIANT: u32 = 3;
+    let metadata = program_data
+        .get(..bpf_loader_upgradeable::UpgradeableLoaderState::size_of_programdata_metadata())?;
+    let (variant, rest) = metadata.split_at(4);
+    if u32::from_le_bytes(variant.try_into().ok()?) != PROGRAM_DATA_VARIANT {
+        return None;
+    }
+    match rest[8..] {
+        [1, ref authority @ ..] => Some(Pubkey::new(authority)),
+        _ => None,
+    }
+}
+
+/// Reserve tokens paid out for burning `pool_token_amount` out of
+/// `pool_mint_supply`, rounded down so the remaining liquidity providers are
+/// never shortchanged, or up when `round_up` is set
+fn withdraw_token_amount(
+    reserve_amount: u64,
+    pool_token_amount: u128,
+    pool_mint_supply: u128,
+    round_up: bool,
+) -> Result<u128, SwapError> {
+    let numerator = to_u128(reserve_amount)?
+        .checked_mul(pool_token_amount)
+        .ok_or(SwapError::CalculationFailure)?;
+    let token_amount = numerator
+        .checked_div(pool_mint_supply)
+        .ok_or(SwapError::CalculationFailure)?;
+    if round_up && numerator % pool_mint_supply != 0 {
+        token_amount
+            .checked_add(1)
+            .ok_or(SwapError::CalculationFailure)
+    } else {
+        Ok(token_amount)
+    }
+}
+
+/// Pool tokens worth `token_amount` out of `reserve_amount`, rounded down so
+/// existing liquidity providers are never diluted.
+fn deposit_pool_token_amount(
+    token_amount: u64,
+    reserve_amount: u64,
+    pool_mint_supply: u128,
+) -> Result<u128, SwapError> {
+    to_u128(token_amount)?
+        .checked_mul(pool_mint_supply)
+        .ok_or(SwapError::CalculationFailure)?
+        .checked_div(to_u128(reserve_amount)?)
+        .ok_or(SwapError::CalculationFailure)
+}
+
+/// Asserts the constant product of the reserves did not shrink across a swap
+#[cfg(feature = "invariant-check")]
+fn check_swap_invariant(
+    swap_source_before: u128,
+    swap_destination_before: u128,
+    swap_source_after: u128,
+    swap_destination_after: u128,
+) {
+    let invariant_before = swap_source_before.checked_mul(swap_destination_before);
+    let invariant_after = swap_source_after.checked_mul(swap_destination_after);
+    assert!(
+        invariant_after >= invariant_before,
+        "Swap invariant shrank from {:?} to {:?}",
+        invariant_before,
+        invariant_after
+    );
+}
+
+fn to_u128(val: u64) -> Result<u128, SwapError> {
+    val.try_into().map_err(|_| SwapError::ConversionFailure)
+}
+
+fn to_u64(val: u128) -> Result<u64, SwapError> {
+    val.try_into().map_err(|_| SwapError::ConversionFailure)
+}
+
+/// `to_u64`, logging the calculation step that overflowed
+// `Result::inspect_err` needs a newer toolchain than the program builds with
+#[allow(clippy::manual_inspect)]
+fn to_u64_for(step: &str, val: u128) -> Result<u64, SwapError> {
+    to_u64(val).map_err(|error| {
+        msg!("{} overflows a u64: {}", step, val);
+        error
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constraints::{MAX_INITIAL_POOL_AMOUNT, MAX_RESERVE},
+        instruction::initialize_accounts,
+        quote::simulate_swap,
+    };
+    use solana_program::{
+        instruction::AccountMeta,
+        program::get_return_data,
+        program_stubs,
+        rent::Rent,
+        sysvar::{
+            self,
+            instructions::{
+                construct_instructions_data, store_current_index, BorrowedAccountMeta,
+                BorrowedInstruction,
+            },
+        },
+    };
+    use solana_sdk::account::{
+        create_account_for_test, create_is_signer_account_infos, Account as SolanaAccount,
+    };
+    use spl_token::{
+        instruction::{approve, initialize_account, initialize_mint, mint_to, transfer},
+        processor::Processor as SplProcessor,
+        state::{Account as SplAccount, AccountState, Mint as SplMint},
+    };
+
+    const SWAP_PROGRAM_ID: Pubkey = Pubkey::new_from_array([2u8; 32]);
+    /// A second token program sharing the spl-token layout
+    const OTHER_TOKEN_PROGRAM_ID: Pubkey = TOKEN_2022_PROGRAM_ID;
+
+    const TEST_FEES: Fees = Fees {
+        trade_fee_numerator: 1,
+        trade_fee_denominator: 100,
+        owner_trade_fee_numerator: 0,
+        owner_trade_fee_denominator: 0,
+        flash_loan_fee_numerator: 3,
+        flash_loan_fee_denominator: 1000,
+    };
+
+    struct TestSyscallStubs {}
+    static LOGS: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+    /// Runs before the token program on every cross-program invocation
+    type InvokeHook = Box<dyn FnMut(&Instruction, &[AccountInfo])>;
+    thread_local! {
+        static RETURN_DATA: std::cell::RefCell<Option<(Pubkey, Vec<u8>)>> =
+            const { std::cell::RefCell::new(None) };
+        static INVOKE_HOOK: std::cell::RefCell<Option<InvokeHook>> =
+            const { std::cell::RefCell::new(None) };
+        static CLOCK_SLOT: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+    }
+
+    impl program_stubs::SyscallStubs for TestSyscallStubs {
+        fn sol_log(&self, message: &str) {
+            println!("{}", message);
+            LOGS.lock().unwrap().push(message.to_string());
+        }
+
+        fn sol_set_return_data(&self, data: &[u8]) {
+            RETURN_DATA.with(|return_data| {
+                *return_data.borrow_mut() = Some((SWAP_PROGRAM_ID, data.to_vec()));
+            });
+        }
+
+        fn sol_get_return_data(&self) -> Option<(Pubkey, Vec<u8>)> {
+            RETURN_DATA.with(|return_data| return_data.borrow().clone())
+        }
+
+        fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+            unsafe { *(var_addr as *mut Rent) = Rent::default() };
+            solana_program::entrypoint::SUCCESS
+        }
+
+        fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+            let clock = Clock {
+                slot: CLOCK_SLOT.with(|slot| slot.get()),
+                ..Clock::default()
+            };
+            unsafe { *(var_addr as *mut Clock) = clock };
+            solana_program::entrypoint::SUCCESS
+        }
+
+        fn sol_invoke_signed(
+            &self,
+            instruction: &Instruction,
+            account_infos: &[AccountInfo],
+            signers_seeds: &[&[&[u8]]],
+        ) -> ProgramResult {
+            msg!("TestSyscallStubs::sol_invoke_signed()");
+            if instruction.program_id != spl_token::id()
+                && instruction.program_id != OTHER_TOKEN_PROGRAM_ID
+            {
+                return Err(ProgramError::IncorrectProgramId);
+            }
+            if !account_infos
+                .iter()
+                .any(|account_info| *account_info.key == instruction.program_id)
+            {
+                return Err(ProgramError::NotEnoughAccountKeys);
+            }
+
+            let signers = signers_seeds
+                .iter()
+                .map(|seeds| Pubkey::create_program_address(seeds, &SWAP_PROGRAM_ID))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| ProgramError::InvalidSeeds)?;
+            let mut new_account_infos = vec![];
+            for meta in instruction.accounts.iter() {
+                let account_info = account_infos
+                    .iter()
+                    .find(|account_info| *account_info.key == meta.pubkey)
+                    .ok_or(ProgramError::NotEnoughAccountKeys)?;
+                let mut new_account_info = account_info.clone();
+                if signers.contains(account_info.key) {
+                    new_account_info.is_signer = true;
+                }
+                new_account_infos.push(new_account_info);
+            }
+
+            INVOKE_HOOK.with(|hook| {
+                if let Some(hook) = hook.borrow_mut().as_mut() {
+                    hook(instruction, &new_account_infos);
+                }
+            });
+            SplProcessor::process(
+                &instruction.program_id,
+                &new_account_infos,
+                &instruction.data,
+            )
+        }
+    }
+
+    fn test_syscall_stubs() {
+        use std::sync::Once;
+        static ONCE: Once = Once::new();
+
+        ONCE.call_once(|| {
+            program_stubs::set_syscall_stubs(Box::new(TestSyscallStubs {}));
+        });
+    }
+
+    fn do_process_instruction(
+        instruction: Instruction,
+        accounts: Vec<&mut SolanaAccount>,
+    ) -> ProgramResult {
+        do_process_instruction_with_constraints(instruction, accounts, &None)
+    }
+
+    fn do_process_instruction_with_constraints(
+        instruction: Instruction,
+        accounts: Vec<&mut SolanaAccount>,
+        swap_constraints: &Option<SwapConstraints>,
+    ) -> ProgramResult {
+        test_syscall_stubs();
+
+        // approximate the logic in the actual runtime which runs the instruction
+        // and only updates accounts if the instruction is successful
+        let mut account_clones = accounts.iter().map(|x| (*x).clone()).collect::<Vec<_>>();
+        let mut meta = instruction
+            .accounts
+            .iter()
+            .zip(account_clones.iter_mut())
+            .map(|(account_meta, account)| (&account_meta.pubkey, account_meta.is_signer, account))
+            .collect::<Vec<_>>();
+        let mut account_infos = create_is_signer_account_infos(&mut meta);
+        let res = if instruction.program_id == SWAP_PROGRAM_ID {
+            Processor::process_with_constraints(
+                &instruction.program_id,
+                &account_infos,
+                &instruction.data,
+                swap_constraints,
+            )
+        } else {
+            SplProcessor::process(&instruction.program_id, &account_infos, &instruction.data)
+        };
+
+        if res.is_ok() {
+            let mut account_metas = instruction
+                .accounts
+                .iter()
+                .zip(accounts)
+                .map(|(account_meta, account)| (&account_meta.pubkey, account))
+                .collect::<Vec<_>>();
+            for account_info in account_infos.iter_mut() {
+                for account_meta in account_metas.iter_mut() {
+                    if account_info.key == account_meta.0 {
+                        let account = &mut account_meta.1;
+                        account.owner = *account_info.owner;
+                        account.lamports = **account_info.lamports.borrow();
+                        account.data = account_info.data.borrow().to_vec();
+                    }
+                }
+            }
+        }
+        res
+    }
+
+    fn create_mint(
+        program_id: &Pubkey,
+        authority_key: &Pubkey,
+        freeze_authority: Option<&Pubkey>,
+    ) -> (Pubkey, SolanaAccount) {
+        let mint_key = Pubkey::new_unique();
+        let mut mint_account = SolanaAccount::new(
+            Rent::default().minimum_balance(SplMint::get_packed_len()),
+            SplMint::get_packed_len(),
+            program_id,
+        );
+        let mut rent_sysvar_account = create_account_for_test(&Rent::free());
+
+        do_process_instruction(
+            token_instruction(
+                initialize_mint(
+                    &spl_token::id(),
+                    &mint_key,
+                    authority_key,
+                    freeze_authority,
+                    2,
+                )
+                .unwrap(),
+                program_id,
+            ),
+            vec![&mut mint_account, &mut rent_sysvar_account],
+        )
+        .unwrap();
+
+        (mint_key, mint_account)
+    }
+
+    fn mint_token(
+        program_id: &Pubkey,
+        mint_key: &Pubkey,
+        mint_account: &mut SolanaAccount,
+        mint_authority_key: &Pubkey,
+        account_owner_key: &Pubkey,
+        amount: u64,
+    ) -> (Pubkey, SolanaAccount) {
+        let account_key = Pubkey::new_unique();
+        let mut account_account = SolanaAccount::new(
+            Rent::default().minimum_balance(SplAccount::get_packed_len()),
+            SplAccount::get_packed_len(),
+            program_id,
+        );
+        let mut mint_authority_account = SolanaAccount::default();
+        let mut rent_sysvar_account = create_account_for_test(&Rent::free());
+
+        do_process_instruction(
+            token_instruction(
+                initialize_account(&spl_token::id(), &account_key, mint_key, account_owner_key)
+                    .unwrap(),
+                program_id,
+            ),
+            vec![
+                &mut account_account,
+                mint_account,
+                &mut mint_authority_account,
+                &mut rent_sysvar_account,
+            ],
+        )
+        .unwrap();
+
+        if amount > 0 {
+            do_process_instruction(
+                token_instruction(
+                    mint_to(
+                        &spl_token::id(),
+                        mint_key,
+                        &account_key,
+                        mint_authority_key,
+                        &[],
+                        amount,
+                    )
+                    .unwrap(),
+                    program_id,
+                ),
+                vec![
+                    mint_account,
+                    &mut account_account,
+                    &mut mint_authority_account,
+                ],
+            )
+            .unwrap();
+        }
+
+        (account_key, account_account)
+    }
+
+    fn approve_delegate(
+        program_id: &Pubkey,
+        source_key: &Pubkey,
+        source_account: &mut SolanaAccount,
+        delegate_key: &Pubkey,
+        owner_key: &Pubkey,
+        amount: u64,
+    ) {
+        do_process_instruction(
+            token_instruction(
+                approve(
+                    &spl_token::id(),
+                    source_key,
+                    delegate_key,
+                    owner_key,
+                    &[],
+                    amount,
+                )
+                .unwrap(),
+                program_id,
+            ),
+            vec![
+                source_account,
+                &mut SolanaAccount::default(),
+                &mut SolanaAccount::default(),
+            ],
+        )
+        .unwrap();
+    }
+
+    fn token_amount(account: &SolanaAccount) -> u64 {
+        SplAccount::unpack(&account.data).unwrap().amount
+    }
+
+    /// Instructions sysvar of a transaction made of the given instructions,
+    /// executing the one at `current_index`
+    fn instructions_sysvar_account(
+        instructions: &[&Instruction],
+        current_index: u16,
+    ) -> SolanaAccount {
+        let instructions = instructions
+            .iter()
+            .map(|instruction| BorrowedInstruction {
+                program_id: &instruction.program_id,
+                accounts: instruction
+                    .accounts
+                    .iter()
+                    .map(|meta| BorrowedAccountMeta {
+                        pubkey: &meta.pubkey,
+                        is_signer: meta.is_signer,
+                        is_writable: meta.is_writable,
+                    })
+                    .collect(),
+                data: &instruction.data,
+            })
+            .collect::<Vec<_>>();
+        let mut data = construct_instructions_data(&instructions);
+        store_current_index(&mut data, current_index);
+        let mut account = SolanaAccount::new(0, 0, &sysvar::id());
+        account.data = data;
+        account
+    }
+
+    struct SwapAccountInfo {
+        bump_seed: u8,
+        authority_key: Pubkey,
+        owner_key: Pubkey,
+        fees: Fees,
+        swap_key: Pubkey,
+        swap_account: SolanaAccount,
+        pool_mint_key: Pubkey,
+        pool_mint_account: SolanaAccount,
+        pool_token_key: Pubkey,
+        pool_token_account: SolanaAccount,
+        token_a_key: Pubkey,
+        token_a_account: SolanaAccount,
+        token_a_mint_key: Pubkey,
+        token_a_mint_account: SolanaAccount,
+        token_b_key: Pubkey,
+        token_b_account: SolanaAccount,
+        token_b_mint_key: Pubkey,
+        token_b_mint_account: SolanaAccount,
+        token_a_fee_key: Pubkey,
+        token_a_fee_account: SolanaAccount,
+        token_b_fee_key: Pubkey,
+        token_b_fee_account: SolanaAccount,
+        token_program_id: Pubkey,
+        pool_token_program_id: Pubkey,
+        curve_type: CurveType,
+        curve_parameter: u64,
+        reward_cap_numerator: u64,
+        reward_cap_denominator: u64,
+        price_band_bps: u16,
+        initial_pool_amount: u64,
+        minimum_pool_token_amount: u64,
+        round_up_deposits: bool,
+        max_price_impact_bps: u16,
+    }
+
+    impl SwapAccountInfo {
+        fn new(
+            user_key: &Pubkey,
+            fees: Fees,
+            token_a_amount: u64,
+            token_b_amount: u64,
+            token_program_id: &Pubkey,
+            pool_token_program_id: &Pubkey,
+        ) -> Self {
+            let swap_key = Pubkey::new_unique();
+            let swap_account = SolanaAccount::new(
+                Rent::default().minimum_balance(SwapState::get_packed_len()),
+                SwapState::get_packed_len(),
+                &SWAP_PROGRAM_ID,
+            );
+            let (authority_key, bump_seed) =
+                Pubkey::find_program_address(&[&swap_key.to_bytes()[..]], &SWAP_PROGRAM_ID);
+
+            let (pool_mint_key, mut pool_mint_account) =
+                create_mint(pool_token_program_id, &authority_key, None);
+            let (pool_token_key, pool_token_account) = mint_token(
+                pool_token_program_id,
+                &pool_mint_key,
+                &mut pool_mint_account,
+                &authority_key,
+                user_key,
+                0,
+            );
+            let (token_a_mint_key, mut token_a_mint_account) =
+                create_mint(token_program_id, user_key, None);
+            let (token_a_key, token_a_account) = mint_token(
+                token_program_id,
+                &token_a_mint_key,
+                &mut token_a_mint_account,
+                user_key,
+                &authority_key,
+                token_a_amount,
+            );
+            let (token_a_fee_key, token_a_fee_account) = mint_token(
+                token_program_id,
+                &token_a_mint_key,
+                &mut token_a_mint_account,
+                user_key,
+                user_key,
+                0,
+            );
+            let (token_b_mint_key, mut token_b_mint_account) =
+                create_mint(token_program_id, user_key, None);
+            let (token_b_key, token_b_account) = mint_token(
+                token_program_id,
+                &token_b_mint_key,
+                &mut token_b_mint_account,
+                user_key,
+                &authority_key,
+                token_b_amount,
+            );
+            let (token_b_fee_key, token_b_fee_account) = mint_token(
+                token_program_id,
+                &token_b_mint_key,
+                &mut token_b_mint_account,
+                user_key,
+                user_key,
+                0,
+            );
+
+            SwapAccountInfo {
+                bump_seed,
+                authority_key,
+                owner_key: *user_key,
+                fees,
+                swap_key,
+                swap_account,
+                pool_mint_key,
+                pool_mint_account,
+                pool_token_key,
+                pool_token_account,
+                token_a_key,
+                token_a_account,
+                token_a_mint_key,
+                token_a_mint_account,
+                token_b_key,
+                token_b_account,
+                token_b_mint_key,
+                token_b_mint_account,
+                token_a_fee_key,
+                token_a_fee_account,
+                token_b_fee_key,
+                token_b_fee_account,
+                token_program_id: *token_program_id,
+                pool_token_program_id: *pool_token_program_id,
+                curve_type: CurveType::ConstantProduct,
+                curve_parameter: 0,
+                reward_cap_numerator: 0,
+                reward_cap_denominator: 0,
+                price_band_bps: 0,
+                initial_pool_amount: 0,
+                minimum_pool_token_amount: 0,
+                round_up_deposits: true,
+                max_price_impact_bps: 0,
+            }
+        }
+
+        fn initialize_swap(&mut self) -> ProgramResult {
+            self.initialize_swap_with_constraints(&None)
+        }
+
+        fn initialize_swap_with_constraints(
+            &mut self,
+            swap_constraints: &Option<SwapConstraints>,
+        ) -> ProgramResult {
+            do_process_instruction_with_constraints(
+                Instruction {
+                    program_id: SWAP_PROGRAM_ID,
+                    accounts: initialize_accounts(
+                        &self.swap_key,
+                        &self.authority_key,
+                        &self.token_a_key,
+                        &self.token_b_key,
+                        &self.pool_mint_key,
+                        &self.token_a_fee_key,
+                        &self.token_b_fee_key,
+                        &self.pool_token_key,
+                        &self.token_program_id,
+                        &self.pool_token_program_id,
+                        &self.token_a_mint_key,
+                        &self.token_b_mint_key,
+                    ),
+                    data: SwapInstruction::Initialize(Initialize {
+                        fees: self.fees.clone(),
+                        owner: self.owner_key,
+                        curve_type: self.curve_type,
+                        curve_parameter: self.curve_parameter,
+                        reward_cap_numerator: self.reward_cap_numerator,
+                        reward_cap_denominator: self.reward_cap_denominator,
+                        price_band_bps: self.price_band_bps,
+                        initial_pool_amount: self.initial_pool_amount,
+                        minimum_pool_token_amount: self.minimum_pool_token_amount,
+                        round_up_deposits: self.round_up_deposits,
+                        max_price_impact_bps: self.max_price_impact_bps,
+                    })
+                    .pack(),
+                },
+                vec![
+                    &mut self.swap_account,
+                    &mut SolanaAccount::default(),
+                    &mut self.token_a_account,
+                    &mut self.token_b_account,
+                    &mut self.pool_mint_account,
+                    &mut self.token_a_fee_account,
+                    &mut self.token_b_fee_account,
+                    &mut self.pool_token_account,
+                    &mut SolanaAccount::default(),
+                    &mut SolanaAccount::default(),
+                    &mut self.token_a_mint_account,
+                    &mut self.token_b_mint_account,
+                ],
+                swap_constraints,
+            )
+        }
+
+        fn validate_initialize(&mut self) -> ProgramResult {
+            do_process_instruction(
+                Instruction {
+                    program_id: SWAP_PROGRAM_ID,
+                    accounts: initialize_accounts(
+                        &self.swap_key,
+                        &self.authority_key,
+                        &self.token_a_key,
+                        &self.token_b_key,
+                        &self.pool_mint_key,
+                        &self.token_a_fee_key,
+                        &self.token_b_fee_key,
+                        &self.pool_token_key,
+                        &self.token_program_id,
+                        &self.pool_token_program_id,
+                        &self.token_a_mint_key,
+                        &self.token_b_mint_key,
+                    ),
+                    data: SwapInstruction::ValidateInitialize(Initialize {
+                        fees: self.fees.clone(),
+                        owner: self.owner_key,
+                        curve_type: self.curve_type,
+                        curve_parameter: self.curve_parameter,
+                        reward_cap_numerator: self.reward_cap_numerator,
+                        reward_cap_denominator: self.reward_cap_denominator,
+                        price_band_bps: self.price_band_bps,
+                        initial_pool_amount: self.initial_pool_amount,
+                        minimum_pool_token_amount: self.minimum_pool_token_amount,
+                        round_up_deposits: self.round_up_deposits,
+                        max_price_impact_bps: self.max_price_impact_bps,
+                    })
+                    .pack(),
+                },
+                vec![
+                    &mut self.swap_account,
+                    &mut SolanaAccount::default(),
+                    &mut self.token_a_account,
+                    &mut self.token_b_account,
+                    &mut self.pool_mint_account,
+                    &mut self.token_a_fee_account,
+                    &mut self.token_b_fee_account,
+                    &mut self.pool_token_account,
+                    &mut SolanaAccount::default(),
+                    &mut SolanaAccount::default(),
+                    &mut self.token_a_mint_account,
+                    &mut self.token_b_mint_account,
+                ],
+            )
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        fn initialize_swap_with_deposit(
+            &mut self,
+            user_key: &Pubkey,
+            source_a_key: &Pubkey,
+            source_a_account: &mut SolanaAccount,
+            source_b_key: &Pubkey,
+            source_b_account: &mut SolanaAccount,
+            token_a_amount: u64,
+            token_b_amount: u64,
+        ) -> ProgramResult {
+            let mut account_metas = initialize_accounts(
+                &self.swap_key,
+                &self.authority_key,
+                &self.token_a_key,
+                &self.token_b_key,
+                &self.pool_mint_key,
+                &self.token_a_fee_key,
+                &self.token_b_fee_key,
+                &self.pool_token_key,
+                &self.token_program_id,
+                &self.pool_token_program_id,
+                &self.token_a_mint_key,
+                &self.token_b_mint_key,
+            );
+            account_metas[2].is_writable = true;
+            account_metas[3].is_writable = true;
+            account_metas.extend([
+                AccountMeta::new_readonly(*user_key, true),
+                AccountMeta::new(*source_a_key, false),
+                AccountMeta::new(*source_b_key, false),
+            ]);
+            do_process_instruction(
+                Instruction {
+                    program_id: SWAP_PROGRAM_ID,
+                    accounts: account_metas,
+                    data: SwapInstruction::InitializeWithDeposit(InitializeWithDeposit {
+                        initialize: Initialize {
+                            fees: self.fees.clone(),
+                            owner: self.owner_key,
+                            curve_type: self.curve_type,
+                            curve_parameter: self.curve_parameter,
+                            reward_cap_numerator: self.reward_cap_numerator,
+                            reward_cap_denominator: self.reward_cap_denominator,
+                            price_band_bps: self.price_band_bps,
+                            initial_pool_amount: self.initial_pool_amount,
+                            minimum_pool_token_amount: self.minimum_pool_token_amount,
+                            round_up_deposits: self.round_up_deposits,
+                            max_price_impact_bps: self.max_price_impact_bps,
+                        },
+                        token_a_amount,
+                        token_b_amount,
+                    })
+                    .pack(),
+                },
+                vec![
+                    &mut self.swap_account,
+                    &mut SolanaAccount::default(),
+                    &mut self.token_a_account,
+                    &mut self.token_b_account,
+                    &mut self.pool_mint_account,
+                    &mut self.token_a_fee_account,
+                    &mut self.token_b_fee_account,
+                    &mut self.pool_token_account,
+                    &mut SolanaAccount::default(),
+                    &mut SolanaAccount::default(),
+                    &mut self.token_a_mint_account,
+                    &mut self.token_b_mint_account,
+                    &mut SolanaAccount::default(),
+                    source_a_account,
+                    source_b_account,
+                ],
+            )
+        }
+
+        fn flash_loan_instruction(
+            &self,
+            borrower_key: &Pubkey,
+            token: &Pubkey,
+            amount: u64,
+        ) -> Instruction {
+            let reserve_key = if *token == self.token_a_mint_key {
+                self.token_a_key
+            } else {
+                self.token_b_key
+            };
+            Instruction {
+                program_id: SWAP_PROGRAM_ID,
+                accounts: vec![
+                    AccountMeta::new(self.swap_key, false),
+                    AccountMeta::new_readonly(self.authority_key, false),
+                    AccountMeta::new(reserve_key, false),
+                    AccountMeta::new(*borrower_key, false),
+                    AccountMeta::new_readonly(self.token_program_id, false),
+                    AccountMeta::new_readonly(sysvar::instructions::id(), false),
+                ],
+                data: SwapInstruction::FlashLoan(FlashLoan {
+                    amount,
+                    token: *token,
+                })
+                .pack(),
+            }
+        }
+
+        fn flash_loan_repay_instruction(&self, reserve_key: &Pubkey) -> Instruction {
+            Instruction {
+                program_id: SWAP_PROGRAM_ID,
+                accounts: vec![
+                    AccountMeta::new(self.swap_key, false),
+                    AccountMeta::new_readonly(*reserve_key, false),
+                ],
+                data: SwapInstruction::FlashLoanRepay.pack(),
+            }
+        }
+
+        /// Runs a transaction borrowing `amount` of `token`, transferring
+        /// `repay_amount` back and settling the loan, reverting all accounts
+        /// if any instruction fails
+        #[allow(clippy::too_many_arguments)]
+        fn flash_loan(
+            &mut self,
+            user_key: &Pubkey,
+            borrower_key: &Pubkey,
+            borrower_account: &mut SolanaAccount,
+            token: &Pubkey,
+            amount: u64,
+            repay_amount: u64,
+        ) -> ProgramResult {
+            let flash_loan = self.flash_loan_instruction(borrower_key, token, amount);
+            let reserve_key = flash_loan.accounts[2].pubkey;
+            let repay = token_instruction(
+                transfer(
+                    &spl_token::id(),
+                    borrower_key,
+                    &reserve_key,
+                    user_key,
+                    &[],
+                    repay_amount,
+                )
+                .unwrap(),
+                &self.token_program_id,
+            );
+            let flash_loan_repay = self.flash_loan_repay_instruction(&reserve_key);
+            let transaction = [&flash_loan, &repay, &flash_loan_repay];
+
+            let mut swap_account = self.swap_account.clone();
+            let mut reserve_account = self.get_token_account(&reserve_key).clone();
+            let mut new_borrower_account = borrower_account.clone();
+            do_process_instruction(
+                flash_loan.clone(),
+                vec![
+                    &mut swap_account,
+                    &mut SolanaAccount::default(),
+                    &mut reserve_account,
+                    &mut new_borrower_account,
+                    &mut SolanaAccount::default(),
+                    &mut instructions_sysvar_account(&transaction, 0),
+                ],
+            )?;
+            do_process_instruction(
+                repay,
+                vec![
+                    &mut new_borrower_account,
+                    &mut reserve_account,
+                    &mut SolanaAccount::default(),
+                ],
+            )?;
+            do_process_instruction(
+                flash_loan_repay,
+                vec![&mut swap_account, &mut reserve_account],
+            )?;
+
+            self.swap_account = swap_account;
+            self.set_token_account(&reserve_key, reserve_account);
+            *borrower_account = new_borrower_account;
+            Ok(())
+        }
+
+        #[allow(clippy::type_complexity)]
+        fn setup_token_accounts(
+            &mut self,
+            mint_owner: &Pubkey,
+            account_owner: &Pubkey,
+            a_amount: u64,
+            b_amount: u64,
+            pool_amount: u64,
+        ) -> (
+            Pubkey,
+            SolanaAccount,
+            Pubkey,
+            SolanaAccount,
+            Pubkey,
+            SolanaAccount,
+        ) {
+            let (token_a_key, token_a_account) = mint_token(
+                &self.token_program_id,
+                &self.token_a_mint_key,
+                &mut self.token_a_mint_account,
+                mint_owner,
+                account_owner,
+                a_amount,
+            );
+            let (token_b_key, token_b_account) = mint_token(
+                &self.token_program_id,
+                &self.token_b_mint_key,
+                &mut self.token_b_mint_account,
+                mint_owner,
+                account_owner,
+                b_amount,
+            );
+            let (pool_key, pool_account) = mint_token(
+                &self.pool_token_program_id,
+                &self.pool_mint_key,
+                &mut self.pool_mint_account,
+                &self.authority_key,
+                account_owner,
+                pool_amount,
+            );
+            (
+                token_a_key,
+                token_a_account,
+                token_b_key,
+                token_b_account,
+                pool_key,
+                pool_account,
+            )
+        }
+
+        fn get_token_account(&self, account_key: &Pubkey) -> &SolanaAccount {
+            if *account_key == self.token_a_key {
+                return &self.token_a_account;
+            } else if *account_key == self.token_b_key {
+                return &self.token_b_account;
+            } else if *account_key == self.token_a_fee_key {
+                return &self.token_a_fee_account;
+            } else if *account_key == self.token_b_fee_key {
+                return &self.token_b_fee_account;
+            }
+            panic!("Could not find matching swap token account");
+        }
+
+        fn set_token_account(&mut self, account_key: &Pubkey, account: SolanaAccount) {
+            if *account_key == self.token_a_key {
+                self.token_a_account = account;
+                return;
+            } else if *account_key == self.token_b_key {
+                self.token_b_account = account;
+                return;
+            } else if *account_key == self.token_a_fee_key {
+                self.token_a_fee_account = account;
+                return;
+            } else if *account_key == self.token_b_fee_key {
+                self.token_b_fee_account = account;
+                return;
+            }
+            panic!("Could not find matching swap token account");
+        }
+
+        fn close_pool(
+            &mut self,
+            owner_key: &Pubkey,
+            destination_key: &Pubkey,
+            destination_account: &mut SolanaAccount,
+        ) -> ProgramResult {
+            do_process_instruction(
+                Instruction {
+                    program_id: SWAP_PROGRAM_ID,
+                    accounts: vec![
+                        AccountMeta::new(self.swap_key, false),
+                        AccountMeta::new_readonly(self.authority_key, false),
+                        AccountMeta::new_readonly(*owner_key, true),
+                        AccountMeta::new(self.token_a_key, false),
+                        AccountMeta::new(self.token_b_key, false),
+                        AccountMeta::new_readonly(self.pool_mint_key, false),
+                        AccountMeta::new(*destination_key, false),
+                        AccountMeta::new_readonly(self.token_program_id, false),
+                        AccountMeta::new_readonly(self.pool_token_program_id, false),
+                    ],
+                    data: SwapInstruction::ClosePool.pack(),
+                },
+                vec![
+                    &mut self.swap_account,
+                    &mut SolanaAccount::default(),
+                    &mut SolanaAccount::default(),
+                    &mut self.token_a_account,
+                    &mut self.token_b_account,
+                    &mut self.pool_mint_account,
+                    destination_account,
+                    &mut SolanaAccount::default(),
+                    &mut SolanaAccount::default(),
+                ],
+            )
+        }
+
+        fn rebalance(
+            &mut self,
+            owner_key: &Pubkey,
+            owner_token_b_key: &Pubkey,
+            owner_token_b_account: &mut SolanaAccount,
+            target_ratio: u64,
+            maximum_amount: u64,
+        ) -> ProgramResult {
+            do_process_instruction(
+                Instruction {
+                    program_id: SWAP_PROGRAM_ID,
+                    accounts: vec![
+                        AccountMeta::new(self.swap_key, false),
+                        AccountMeta::new_readonly(self.authority_key, false),
+                        AccountMeta::new_readonly(*owner_key, true),
+                        AccountMeta::new_readonly(self.token_a_key, false),
+                        AccountMeta::new(self.token_b_key, false),
+                        AccountMeta::new(*owner_token_b_key, false),
+                        AccountMeta::new_readonly(self.token_program_id, false),
+                    ],
+                    data: SwapInstruction::Rebalance(Rebalance {
+                        target_ratio,
+                        maximum_amount,
+                    })
+                    .pack(),
+                },
+                vec![
+                    &mut self.swap_account,
+                    &mut SolanaAccount::default(),
+                    &mut SolanaAccount::default(),
+                    &mut self.token_a_account,
+                    &mut self.token_b_account,
+                    owner_token_b_account,
+                    &mut SolanaAccount::default(),
+                ],
+            )
+        }
+
+        fn set_curve_parameter(&mut self, owner_key: &Pubkey, parameter: u64) -> ProgramResult {
+            do_process_instruction(
+                Instruction {
+                    program_id: SWAP_PROGRAM_ID,
+                    accounts: vec![
+                        AccountMeta::new(self.swap_key, false),
+                        AccountMeta::new_readonly(*owner_key, true),
+                        AccountMeta::new_readonly(self.token_a_key, false),
+                        AccountMeta::new_readonly(self.token_b_key, false),
+                        AccountMeta::new_readonly(self.pool_mint_key, false),
+                        AccountMeta::new_readonly(self.pool_token_key, false),
+                    ],
+                    data: SwapInstruction::SetCurveParameter(SetCurveParameter { parameter })
+                        .pack(),
+                },
+                vec![
+                    &mut self.swap_account,
+                    &mut SolanaAccount::default(),
+                    &mut self.token_a_account,
+                    &mut self.token_b_account,
+                    &mut self.pool_mint_account,
+                    &mut self.pool_token_account,
+                ],
+            )
+        }
+
+        fn collect_owner_fees(&mut self, owner_key: &Pubkey) -> ProgramResult {
+            do_process_instruction(
+                Instruction {
+                    program_id: SWAP_PROGRAM_ID,
+                    accounts: vec![
+                        AccountMeta::new(self.swap_key, false),
+                        AccountMeta::new_readonly(self.authority_key, false),
+                        AccountMeta::new_readonly(*owner_key, true),
+                        AccountMeta::new(self.pool_mint_key, false),
+                        AccountMeta::new(self.pool_token_key, false),
+                        AccountMeta::new_readonly(self.pool_token_program_id, false),
+                    ],
+                    data: SwapInstruction::CollectOwnerFees.pack(),
+                },
+                vec![
+                    &mut self.swap_account,
+                    &mut SolanaAccount::default(),
+                    &mut SolanaAccount::default(),
+                    &mut self.pool_mint_account,
+                    &mut self.pool_token_account,
+                    &mut SolanaAccount::default(),
+                ],
+            )
+        }
+
+        fn set_fee_accounts(
+            &mut self,
+            owner_key: &Pubkey,
+            token_a_fee_key: &Pubkey,
+            token_a_fee_account: &mut SolanaAccount,
+            token_b_fee_key: &Pubkey,
+            token_b_fee_account: &mut SolanaAccount,
+        ) -> ProgramResult {
+            do_process_instruction(
+                Instruction {
+                    program_id: SWAP_PROGRAM_ID,
+                    accounts: vec![
+                        AccountMeta::new(self.swap_key, false),
+                        AccountMeta::new_readonly(self.authority_key, false),
+                        AccountMeta::new_readonly(*owner_key, true),
+                        AccountMeta::new_readonly(*token_a_fee_key, false),
+                        AccountMeta::new_readonly(*token_b_fee_key, false),
+                    ],
+                    data: SwapInstruction::SetFeeAccounts.pack(),
+                },
+                vec![
+                    &mut self.swap_account,
+                    &mut SolanaAccount::default(),
+                    &mut SolanaAccount::default(),
+                    token_a_fee_account,
+                    token_b_fee_account,
+                ],
+            )
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        fn swap(
+            &mut self,
+            user_key: &Pubkey,
+            user_source_key: &Pubkey,
+            user_source_account: &mut SolanaAccount,
+            swap_source_key: &Pubkey,
+            swap_destination_key: &Pubkey,
+            user_destination_key: &Pubkey,
+            user_destination_account: &mut SolanaAccount,
+            amount_in: u64,
+            minimum_amount_out: u64,
+        ) -> ProgramResult {
+            self.swap_with_data(
+                user_key,
+                user_source_key,
+                user_source_account,
+                swap_source_key,
+                swap_destination_key,
+                user_destination_key,
+                user_destination_account,
+                amount_in,
+                SwapInstruction::Swap(Swap {
+                    amount_in,
+                    minimum_amount_out,
+                    deadline_slot: 0,
+                }),
+            )
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        fn swap_with_data(
+            &mut self,
+            user_key: &Pubkey,
+            user_source_key: &Pubkey,
+            user_source_account: &mut SolanaAccount,
+            swap_source_key: &Pubkey,
+            swap_destination_key: &Pubkey,
+            user_destination_key: &Pubkey,
+            user_destination_account: &mut SolanaAccount,
+            amount_in: u64,
+            data: SwapInstruction,
+        ) -> ProgramResult {
+            let user_transfer_key = Pubkey::new_unique();
+            approve_delegate(
+                &self.token_program_id,
+                user_source_key,
+                user_source_account,
+                &user_transfer_key,
+                user_key,
+                amount_in,
+            );
+
+            let fee_key = if *swap_source_key == self.token_a_key {
+                self.token_a_fee_key
+            } else {
+                self.token_b_fee_key
+            };
+            let reward_key = if *swap_destination_key == self.token_a_key {
+                self.token_a_fee_key
+            } else {
+                self.token_b_fee_key
+            };
+            let mut swap_source_account = self.get_token_account(swap_source_key).clone();
+            let mut swap_destination_account = self.get_token_account(swap_destination_key).clone();
+            let mut fee_account = self.get_token_account(&fee_key).clone();
+            let mut reward_account = self.get_token_account(&reward_key).clone();
+
+            do_process_instruction(
+                Instruction {
+                    program_id: SWAP_PROGRAM_ID,
+                    accounts: vec![
+                        AccountMeta::new(self.swap_key, false),
+                        AccountMeta::new_readonly(self.authority_key, false),
+                        AccountMeta::new_readonly(user_transfer_key, true),
+                        AccountMeta::new(*user_source_key, false),
+                        AccountMeta::new(*swap_source_key, false),
+                        AccountMeta::new(*swap_destination_key, false),
+                        AccountMeta::new(*user_destination_key, false),
+                        AccountMeta::new(fee_key, false),
+                        AccountMeta::new_readonly(self.token_program_id, false),
+                        AccountMeta::new_readonly(reward_key, false),
+                    ],
+                    data: data.pack(),
+                },
+                vec![
+                    &mut self.swap_account,
+                    &mut SolanaAccount::default(),
+                    &mut SolanaAccount::default(),
+                    user_source_account,
+                    &mut swap_source_account,
+                    &mut swap_destination_account,
+                    user_destination_account,
+                    &mut fee_account,
+                    &mut SolanaAccount::default(),
+                    &mut reward_account,
+                ],
+            )?;
+
+            self.set_token_account(swap_source_key, swap_source_account);
+            self.set_token_account(swap_destination_key, swap_destination_account);
+            self.set_token_account(&fee_key, fee_account);
+
+            Ok(())
+        }
+
+        fn batch_quote(
+            &mut self,
+            swap_source_key: &Pubkey,
+            swap_destination_key: &Pubkey,
+            amounts_in: Vec<u64>,
+        ) -> Result<Vec<u64>, ProgramError> {
+            let mut swap_source_account = self.get_token_account(swap_source_key).clone();
+            let mut swap_destination_account = self.get_token_account(swap_destination_key).clone();
+
+            do_process_instruction(
+                Instruction {
+                    program_id: SWAP_PROGRAM_ID,
+                    accounts: vec![
+                        AccountMeta::new_readonly(self.swap_key, false),
+                        AccountMeta::new_readonly(*swap_source_key, false),
+                        AccountMeta::new_readonly(*swap_destination_key, false),
+                    ],
+                    data: SwapInstruction::BatchQuote(BatchQuote { amounts_in }).pack(),
+                },
+                vec![
+                    &mut self.swap_account,
+                    &mut swap_source_account,
+                    &mut swap_destination_account,
+                ],
+            )?;
+
+            let (program_id, data) = get_return_data().unwrap();
+            assert_eq!(program_id, SWAP_PROGRAM_ID);
+            Ok(data
+                .chunks_exact(8)
+                .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+                .collect())
+        }
+
+        fn query_reserves(&mut self) -> Result<PoolReserves, ProgramError> {
+            do_process_instruction(
+                Instruction {
+                    program_id: SWAP_PROGRAM_ID,
+                    accounts: vec![
+                        AccountMeta::new_readonly(self.swap_key, false),
+                        AccountMeta::new_readonly(self.token_a_key, false),
+                        AccountMeta::new_readonly(self.token_b_key, false),
+                        AccountMeta::new_readonly(self.pool_mint_key, false),
+                    ],
+                    data: SwapInstruction::QueryReserves.pack(),
+                },
+                vec![
+                    &mut self.swap_account,
+                    &mut self.token_a_account,
+                    &mut self.token_b_account,
+                    &mut self.pool_mint_account,
+                ],
+            )?;
+
+            let (program_id, data) = get_return_data().unwrap();
+            assert_eq!(program_id, SWAP_PROGRAM_ID);
+            PoolReserves::from_return_data(&data)
+        }
+
+        fn verify_authority(&mut self, authority_key: &Pubkey) -> Result<bool, ProgramError> {
+            do_process_instruction(
+                Instruction {
+                    program_id: SWAP_PROGRAM_ID,
+                    accounts: vec![
+                        AccountMeta::new_readonly(self.swap_key, false),
+                        AccountMeta::new_readonly(*authority_key, false),
+                    ],
+                    data: SwapInstruction::VerifyAuthority.pack(),
+                },
+                vec![&mut self.swap_account, &mut SolanaAccount::default()],
+            )?;
+
+            let (program_id, data) = get_return_data().unwrap();
+            assert_eq!(program_id, SWAP_PROGRAM_ID);
+            Ok(data == [1])
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        fn deposit(
+            &mut self,
+            depositor_key: &Pubkey,
+            depositor_token_a_key: &Pubkey,
+            depositor_token_a_account: &mut SolanaAccount,
+            depositor_token_b_key: &Pubkey,
+            depositor_token_b_account: &mut SolanaAccount,
+            depositor_pool_key: &Pubkey,
+            depositor_pool_account: &mut SolanaAccount,
+            pool_token_amount: u64,
+            maximum_token_a_amount: u64,
+            maximum_token_b_amount: u64,
+        ) -> ProgramResult {
+            self.deposit_with_data(
+                depositor_key,
+                depositor_token_a_key,
+                depositor_token_a_account,
+                depositor_token_b_key,
+                depositor_token_b_account,
+                depositor_pool_key,
+                depositor_pool_account,
+                maximum_token_a_amount,
+                maximum_token_b_amount,
+                SwapInstruction::DepositTokens(DepositTokens {
+                    pool_token_amount,
+                    maximum_token_a_amount,
+                    maximum_token_b_amount,
+                    expected_ratio_numerator: 0,
+                    expected_ratio_denominator: 0,
+                }),
+            )
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        fn deposit_with_data(
+            &mut self,
+            depositor_key: &Pubkey,
+            depositor_token_a_key: &Pubkey,
+            depositor_token_a_account: &mut SolanaAccount,
+            depositor_token_b_key: &Pubkey,
+            depositor_token_b_account: &mut SolanaAccount,
+            depositor_pool_key: &Pubkey,
+            depositor_pool_account: &mut SolanaAccount,
+            maximum_token_a_amount: u64,
+            maximum_token_b_amount: u64,
+            data: SwapInstruction,
+        ) -> ProgramResult {
+            let user_transfer_authority = Pubkey::new_unique();
+            approve_delegate(
+                &self.token_program_id,
+                depositor_token_a_key,
+                depositor_token_a_account,
+                &user_transfer_authority,
+                depositor_key,
+                maximum_token_a_amount,
+            );
+            approve_delegate(
+                &self.token_program_id,
+                depositor_token_b_key,
+                depositor_token_b_account,
+                &user_transfer_authority,
+                depositor_key,
+                maximum_token_b_amount,
+            );
+
+            do_process_instruction(
+                Instruction {
+                    program_id: SWAP_PROGRAM_ID,
+                    accounts: vec![
+                        AccountMeta::new(self.swap_key, false),
+                        AccountMeta::new_readonly(self.authority_key, false),
+                        AccountMeta::new_readonly(user_transfer_authority, true),
+                        AccountMeta::new(*depositor_token_a_key, false),
+                        AccountMeta::new(*depositor_token_b_key, false),
+                        AccountMeta::new(self.token_a_key, false),
+                        AccountMeta::new(self.token_b_key, false),
+                        AccountMeta::new(self.pool_mint_key, false),
+                        AccountMeta::new(*depositor_pool_key, false),
+                        AccountMeta::new_readonly(self.token_program_id, false),
+                        AccountMeta::new_readonly(self.pool_token_program_id, false),
+                    ],
+                    data: data.pack(),
+                },
+                vec![
+                    &mut self.swap_account,
+                    &mut SolanaAccount::default(),
+                    &mut SolanaAccount::default(),
+                    depositor_token_a_account,
+                    depositor_token_b_account,
+                    &mut self.token_a_account,
+                    &mut self.token_b_account,
+                    &mut self.pool_mint_account,
+                    depositor_pool_account,
+                    &mut SolanaAccount::default(),
+                    &mut SolanaAccount::default(),
+                ],
+            )
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        fn withdraw(
+            &mut self,
+            user_key: &Pubkey,
+            pool_key: &Pubkey,
+            pool_account: &mut SolanaAccount,
+            token_a_key: &Pubkey,
+            token_a_account: &mut SolanaAccount,
+            token_b_key: &Pubkey,
+            token_b_account: &mut SolanaAccount,
+            pool_token_amount: u64,
+            minimum_token_a_amount: u64,
+            minimum_token_b_amount: u64,
+        ) -> ProgramResult {
+            self.withdraw_with_data(
+                user_key,
+                pool_key,
+                pool_account,
+                token_a_key,
+                token_a_account,
+                token_b_key,
+                token_b_account,
+                WithdrawTokens {
+                    pool_token_amount,
+                    minimum_token_a_amount,
+                    minimum_token_b_amount,
+                    preferred_mint: None,
+                    minimum_preferred_amount: 0,
+                },
+            )
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        fn withdraw_with_data(
+            &mut self,
+            user_key: &Pubkey,
+            pool_key: &Pubkey,
+            pool_account: &mut SolanaAccount,
+            token_a_key: &Pubkey,
+            token_a_account: &mut SolanaAccount,
+            token_b_key: &Pubkey,
+            token_b_account: &mut SolanaAccount,
+            data: WithdrawTokens,
+        ) -> ProgramResult {
+            let pool_token_amount = data.pool_token_amount;
+            let user_transfer_authority_key = Pubkey::new_unique();
+            approve_delegate(
+                &self.pool_token_program_id,
+                pool_key,
+                pool_account,
+                &user_transfer_authority_key,
+                user_key,
+                pool_token_amount,
+            );
+
+            do_process_instruction(
+                Instruction {
+                    program_id: SWAP_PROGRAM_ID,
+                    accounts: vec![
+                        AccountMeta::new(self.swap_key, false),
+                        AccountMeta::new_readonly(self.authority_key, false),
+                        AccountMeta::new_readonly(user_transfer_authority_key, true),
+                        AccountMeta::new(self.pool_mint_key, false),
+                        AccountMeta::new(*pool_key, false),
+                        AccountMeta::new(self.token_a_key, false),
+                        AccountMeta::new(self.token_b_key, false),
+                        AccountMeta::new(*token_a_key, false),
+                        AccountMeta::new(*token_b_key, false),
+                        AccountMeta::new_readonly(self.token_program_id, false),
+                        AccountMeta::new_readonly(self.pool_token_program_id, false),
+                    ],
+                    data: SwapInstruction::WithdrawTokens(data).pack(),
+                },
+                vec![
+                    &mut self.swap_account,
+                    &mut SolanaAccount::default(),
+                    &mut SolanaAccount::default(),
+                    &mut self.pool_mint_account,
+                    pool_account,
+                    &mut self.token_a_account,
+                    &mut self.token_b_account,
+                    token_a_account,
+                    token_b_account,
+                    &mut SolanaAccount::default(),
+                    &mut SolanaAccount::default(),
+                ],
+            )
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        fn withdraw_all(
+            &mut self,
+            user_key: &Pubkey,
+            pool_key: &Pubkey,
+            pool_account: &mut SolanaAccount,
+            token_a_key: &Pubkey,
+            token_a_account: &mut SolanaAccount,
+            token_b_key: &Pubkey,
+            token_b_account: &mut SolanaAccount,
+            minimum_token_a_amount: u64,
+            minimum_token_b_amount: u64,
+        ) -> ProgramResult {
+            let user_transfer_authority_key = Pubkey::new_unique();
+            approve_delegate(
+                &self.pool_token_program_id,
+                pool_key,
+                pool_account,
+                &user_transfer_authority_key,
+                user_key,
+                u64::MAX,
+            );
+
+            do_process_instruction(
+                Instruction {
+                    program_id: SWAP_PROGRAM_ID,
+                    accounts: vec![
+                        AccountMeta::new(self.swap_key, false),
+                        AccountMeta::new_readonly(self.authority_key, false),
+                        AccountMeta::new_readonly(user_transfer_authority_key, true),
+                        AccountMeta::new(self.pool_mint_key, false),
+                        AccountMeta::new(*pool_key, false),
+                        AccountMeta::new(self.token_a_key, false),
+                        AccountMeta::new(self.token_b_key, false),
+                        AccountMeta::new(*token_a_key, false),
+                        AccountMeta::new(*token_b_key, false),
+                        AccountMeta::new_readonly(self.token_program_id, false),
+                        AccountMeta::new_readonly(self.pool_token_program_id, false),
+                    ],
+                    data: SwapInstruction::WithdrawAllTokenTypes(WithdrawAllTokenTypes {
+                        minimum_token_a_amount,
+                        minimum_token_b_amount,
+                    })
+                    .pack(),
+                },
+                vec![
+                    &mut self.swap_account,
+                    &mut SolanaAccount::default(),
+                    &mut SolanaAccount::default(),
+                    &mut self.pool_mint_account,
+                    pool_account,
+                    &mut self.token_a_account,
+                    &mut self.token_b_account,
+                    token_a_account,
+                    token_b_account,
+                    &mut SolanaAccount::default(),
+                    &mut SolanaAccount::default(),
+                ],
+            )
+        }
+    }
+
+    #[test]
+    fn test_close_pool() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let destination_key = Pubkey::new_unique();
+        let mut destination_account = SolanaAccount::default();
+
+        // pool tokens outstanding
+        assert_eq!(
+            Err(SwapError::NonEmptyPool.into()),
+            accounts.close_pool(&user_key, &destination_key, &mut destination_account)
+        );
+
+        // withdraw everything
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 0, 0, 0);
+        let pool_key = accounts.pool_token_key;
+        let mut pool_account = accounts.pool_token_account.clone();
+        accounts
+            .withdraw(
+                &user_key,
+                &pool_key,
+                &mut pool_account,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                to_u64(INITIAL_SWAP_POOL_AMOUNT).unwrap(),
+                1000,
+                2000,
+            )
+            .unwrap();
+
+        // only the owner may close
+        let stranger_key = Pubkey::new_unique();
+        assert_eq!(
+            Err(SwapError::InvalidOwner.into()),
+            accounts.close_pool(&stranger_key, &destination_key, &mut destination_account)
+        );
+
+        let reclaimed = accounts.swap_account.lamports
+            + accounts.token_a_account.lamports
+            + accounts.token_b_account.lamports;
+        accounts
+            .close_pool(&user_key, &destination_key, &mut destination_account)
+            .unwrap();
+        assert_eq!(destination_account.lamports, reclaimed);
+        assert_eq!(accounts.swap_account.lamports, 0);
+        assert!(accounts.swap_account.data.iter().all(|byte| *byte == 0));
+        assert_eq!(accounts.token_a_account.lamports, 0);
+        assert_eq!(accounts.token_b_account.lamports, 0);
+    }
+
+    #[test]
+    fn test_initialize_bogus_token_program() {
+        let user_key = Pubkey::new_unique();
+        let bogus_program_id = Pubkey::new_unique();
+        let new_accounts = |token_program_id: &Pubkey, pool_token_program_id: &Pubkey| {
+            SwapAccountInfo::new(
+                &user_key,
+                TEST_FEES,
+                1000,
+                2000,
+                token_program_id,
+                pool_token_program_id,
+            )
+        };
+
+        // every account is consistent with the bogus program, only its id is wrong
+        let mut accounts = new_accounts(&bogus_program_id, &bogus_program_id);
+        assert_eq!(
+            Err(SwapError::IncorrectTokenProgramId.into()),
+            accounts.validate_initialize()
+        );
+        assert_eq!(
+            Err(SwapError::IncorrectTokenProgramId.into()),
+            accounts.initialize_swap()
+        );
+
+        let mut accounts = new_accounts(&spl_token::id(), &bogus_program_id);
+        assert_eq!(
+            Err(SwapError::IncorrectTokenProgramId.into()),
+            accounts.initialize_swap()
+        );
+
+        let mut accounts = new_accounts(&bogus_program_id, &spl_token::id());
+        assert_eq!(
+            Err(SwapError::IncorrectTokenProgramId.into()),
+            accounts.initialize_swap()
+        );
+
+        let mut accounts = new_accounts(&TOKEN_2022_PROGRAM_ID, &spl_token::id());
+        accounts.initialize_swap().unwrap();
+    }
+
+    #[test]
+    fn test_distinct_pool_token_program() {
+        let user_key = Pubkey::new_unique();
+        let token_a_amount = 1000;
+        let token_b_amount = 2000;
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            token_a_amount,
+            token_b_amount,
+            &spl_token::id(),
+            &OTHER_TOKEN_PROGRAM_ID,
+        );
+
+        // pool mint checked against the pool token program
+        accounts.pool_token_program_id = spl_token::id();
+        assert_eq!(
+            Err(SwapError::IncorrectTokenProgramId.into()),
+            accounts.initialize_swap()
+        );
+        accounts.pool_token_program_id = OTHER_TOKEN_PROGRAM_ID;
+
+        accounts.initialize_swap().unwrap();
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert_eq!(swap_state.bump_seed(), accounts.bump_seed);
+        assert_eq!(*swap_state.token_program_id(), spl_token::id());
+        assert_eq!(*swap_state.pool_token_program_id(), OTHER_TOKEN_PROGRAM_ID);
+        assert_eq!(accounts.pool_token_account.owner, OTHER_TOKEN_PROGRAM_ID);
+        assert_eq!(
+            token_amount(&accounts.pool_token_account),
+            to_u64(INITIAL_SWAP_POOL_AMOUNT).unwrap()
+        );
+
+        let pool_token_amount = to_u64(INITIAL_SWAP_POOL_AMOUNT / 10).unwrap();
+        let (
+            token_a_key,
+            mut token_a_account,
+            token_b_key,
+            mut token_b_account,
+            pool_key,
+            mut pool_account,
+        ) = accounts.setup_token_accounts(&user_key, &user_key, 1000, 1000, 0);
+
+        // deposit and withdraw reject the wrong pool token program
+        accounts.pool_token_program_id = spl_token::id();
+        assert_eq!(
+            Err(SwapError::IncorrectTokenProgramId.into()),
+            accounts.deposit(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                pool_token_amount,
+                1000,
+                1000,
+            )
+        );
+        accounts.pool_token_program_id = OTHER_TOKEN_PROGRAM_ID;
+
+        accounts
+            .deposit(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                pool_token_amount,
+                1000,
+                1000,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&pool_account), pool_token_amount);
+        assert_eq!(token_amount(&accounts.token_a_account), 1100);
+        assert_eq!(token_amount(&accounts.token_b_account), 2200);
+
+        accounts.pool_token_program_id = spl_token::id();
+        assert_eq!(
+            Err(SwapError::IncorrectTokenProgramId.into()),
+            accounts.withdraw(
+                &user_key,
+                &pool_key,
+                &mut pool_account,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                pool_token_amount,
+                0,
+                0,
+            )
+        );
+        accounts.pool_token_program_id = OTHER_TOKEN_PROGRAM_ID;
+
+        accounts
+            .withdraw(
+                &user_key,
+                &pool_key,
+                &mut pool_account,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                pool_token_amount,
+                0,
+                0,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&pool_account), 0);
+        assert_eq!(token_amount(&token_a_account), 1000);
+        assert_eq!(token_amount(&token_b_account), 1000);
+
+        // swaps only involve the trading token program
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+        accounts
+            .swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                100,
+                1,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&token_a_account), 900);
+        assert!(token_amount(&token_b_account) > 1000);
+    }
+
+    #[test]
+    fn test_reward_cap() {
+        let user_key = Pubkey::new_unique();
+        let token_a_amount = 1000;
+        let token_b_amount = 2000;
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            token_a_amount,
+            token_b_amount,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+
+        // cap larger than the whole fee account balance
+        accounts.reward_cap_numerator = 3;
+        accounts.reward_cap_denominator = 2;
+        assert_eq!(
+            Err(SwapError::InvalidInput.into()),
+            accounts.initialize_swap()
+        );
+
+        accounts.reward_cap_numerator = 1;
+        accounts.initialize_swap().unwrap();
+
+        // fund the token B fee account, allowing outputs up to 50
+        let token_b_mint_key = accounts.token_b_mint_key;
+        let token_b_fee_key = accounts.token_b_fee_key;
+        do_process_instruction(
+            mint_to(
+                &spl_token::id(),
+                &token_b_mint_key,
+                &token_b_fee_key,
+                &user_key,
+                &[],
+                100,
+            )
+            .unwrap(),
+            vec![
+                &mut accounts.token_b_mint_account,
+                &mut accounts.token_b_fee_account,
+                &mut SolanaAccount::default(),
+            ],
+        )
+        .unwrap();
+
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 1000, 0, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+
+        accounts
+            .swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                10,
+                1,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&token_b_account), 17);
+
+        assert_eq!(
+            Err(SwapError::RewardBudgetExceeded.into()),
+            accounts.swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                100,
+                1,
+            )
+        );
+    }
+
+    #[test]
+    fn test_offset_curve() {
+        let user_key = Pubkey::new_unique();
+        let token_a_amount = 1000;
+        let token_b_amount = 0;
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            token_a_amount,
+            token_b_amount,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+
+        // zero token B is only accepted with an offset
+        assert_eq!(
+            Err(SwapError::EmptySupply.into()),
+            accounts.initialize_swap()
+        );
+        accounts.curve_type = CurveType::Offset;
+        assert_eq!(
+            Err(SwapError::InvalidCurve.into()),
+            accounts.initialize_swap()
+        );
+        accounts.curve_parameter = 1_000_000;
+        accounts.initialize_swap().unwrap();
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert_eq!(swap_state.curve_type, CurveType::Offset);
+        assert_eq!(swap_state.curve_parameter, 1_000_000);
+
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 100, 100_000, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+
+        // no real token B to pay out yet
+        assert_eq!(
+            Err(SwapError::ZeroTradingTokens.into()),
+            accounts.swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                10,
+                1,
+            )
+        );
+
+        // 9_900 token B traded after fees, priced against the 1_000_000
+        // offset: 1000 - 1000 * 1_000_000 / 1_009_900, with the 100 token B
+        // fee kept in the pool
+        accounts
+            .swap(
+                &user_key,
+                &token_b_key,
+                &mut token_b_account,
+                &swap_token_b_key,
+                &swap_token_a_key,
+                &token_a_key,
+                &mut token_a_account,
+                10_000,
+                1,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&token_a_account), 109);
+        assert_eq!(token_amount(&accounts.token_a_account), 991);
+        assert_eq!(token_amount(&accounts.token_b_account), 10_000);
+
+        // token A in now prices against 10_000 real plus the offset:
+        // 1_010_000 - 991 * 1_010_000 / 995
+        accounts
+            .swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                5,
+                1,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&token_b_account), 90_000 + 4_060);
+        assert_eq!(token_amount(&accounts.token_b_account), 10_000 - 4_060);
+    }
+
+    #[test]
+    fn test_price_band() {
+        let user_key = Pubkey::new_unique();
+        let token_a_amount = 1000;
+        let token_b_amount = 2000;
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            token_a_amount,
+            token_b_amount,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.price_band_bps = 10_001;
+        assert_eq!(
+            Err(SwapError::InvalidInput.into()),
+            accounts.initialize_swap()
+        );
+        accounts.price_band_bps = 500;
+        accounts.initialize_swap().unwrap();
+
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 1000, 0, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+
+        // first swap sets the reference price: 17 token B for 9 token A
+        accounts
+            .swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                10,
+                1,
+            )
+            .unwrap();
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert_eq!(swap_state.last_swap_price, 17 * PRICE_SCALE / 9);
+
+        // within 5% of the last price
+        accounts
+            .swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                10,
+                1,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&token_b_account), 34);
+
+        // 173 token B out for 99 token A is below the 176 the band requires
+        assert_eq!(
+            Err(SwapError::PriceDeviationTooHigh.into()),
+            accounts.swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                100,
+                1,
+            )
+        );
+    }
+
+    #[test]
+    fn test_max_price_impact() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.max_price_impact_bps = 300;
+        accounts.initialize_swap().unwrap();
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert_eq!(swap_state.max_price_impact_bps, 300);
+
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 1000, 0, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+
+        // 10 token A in moves the price of 2 token B by about 2%
+        accounts
+            .swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                10,
+                1,
+            )
+            .unwrap();
+
+        // 100 more moves it by about 17%
+        assert_eq!(
+            Err(SwapError::ExcessivePriceImpact.into()),
+            accounts.swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                100,
+                1,
+            )
+        );
+        assert_eq!(token_amount(&token_a_account), 990);
+    }
+
+    #[test]
+    fn test_withdraw_preferred_mint() {
+        let user_key = Pubkey::new_unique();
+        let token_a_amount = 1000;
+        let token_b_amount = 2000;
+        let pool_token_amount = to_u64(INITIAL_SWAP_POOL_AMOUNT / 10).unwrap();
+        let fees = Fees {
+            owner_trade_fee_numerator: 1,
+            owner_trade_fee_denominator: 100,
+            ..TEST_FEES
+        };
+
+        for trade_direction in [TradeDirection::AtoB, TradeDirection::BtoA] {
+            // withdraw directly into the preferred mint
+            let mut accounts = SwapAccountInfo::new(
+                &user_key,
+                fees.clone(),
+                token_a_amount,
+                token_b_amount,
+                &spl_token::id(),
+                &spl_token::id(),
+            );
+            accounts.initialize_swap().unwrap();
+            let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+                accounts.setup_token_accounts(&user_key, &user_key, 0, 0, 0);
+            let pool_key = accounts.pool_token_key;
+            let mut pool_account = accounts.pool_token_account.clone();
+            let preferred_mint = match trade_direction {
+                TradeDirection::AtoB => accounts.token_b_mint_key,
+                TradeDirection::BtoA => accounts.token_a_mint_key,
+            };
+
+            assert_eq!(
+                Err(SwapError::InvalidInput.into()),
+                accounts.withdraw_with_data(
+                    &user_key,
+                    &pool_key,
+                    &mut pool_account,
+                    &token_a_key,
+                    &mut token_a_account,
+                    &token_b_key,
+                    &mut token_b_account,
+                    WithdrawTokens {
+                        pool_token_amount,
+                        minimum_token_a_amount: 0,
+                        minimum_token_b_amount: 0,
+                        preferred_mint: Some(accounts.pool_mint_key),
+                        minimum_preferred_amount: 0,
+                    },
+                )
+            );
+            assert_eq!(
+                Err(SwapError::ExceededSlippage.into()),
+                accounts.withdraw_with_data(
+                    &user_key,
+                    &pool_key,
+                    &mut pool_account,
+                    &token_a_key,
+                    &mut token_a_account,
+                    &token_b_key,
+                    &mut token_b_account,
+                    WithdrawTokens {
+                        pool_token_amount,
+                        minimum_token_a_amount: 0,
+                        minimum_token_b_amount: 0,
+                        preferred_mint: Some(preferred_mint),
+                        minimum_preferred_amount: u64::MAX,
+                    },
+                )
+            );
+            accounts
+                .withdraw_with_data(
+                    &user_key,
+                    &pool_key,
+                    &mut pool_account,
+                    &token_a_key,
+                    &mut token_a_account,
+                    &token_b_key,
+                    &mut token_b_account,
+                    WithdrawTokens {
+                        pool_token_amount,
+                        minimum_token_a_amount: 0,
+                        minimum_token_b_amount: 0,
+                        preferred_mint: Some(preferred_mint),
+                        minimum_preferred_amount: 1,
+                    },
+                )
+                .unwrap();
+            let owner_fees_owed = SwapState::unpack(&accounts.swap_account.data)
+                .unwrap()
+                .owner_fees_owed;
+            let preferred_amount = match trade_direction {
+                TradeDirection::AtoB => {
+                    assert_eq!(token_amount(&token_a_account), 0);
+                    token_amount(&token_b_account)
+                }
+                TradeDirection::BtoA => {
+                    assert_eq!(token_amount(&token_b_account), 0);
+                    token_amount(&token_a_account)
+                }
+            };
+
+            // same pool, withdrawing both tokens then swapping one of them
+            let mut accounts = SwapAccountInfo::new(
+                &user_key,
+                fees.clone(),
+                token_a_amount,
+                token_b_amount,
+                &spl_token::id(),
+                &spl_token::id(),
+            );
+            accounts.initialize_swap().unwrap();
+            let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+                accounts.setup_token_accounts(&user_key, &user_key, 0, 0, 0);
+            let pool_key = accounts.pool_token_key;
+            let mut pool_account = accounts.pool_token_account.clone();
+            accounts
+                .withdraw(
+                    &user_key,
+                    &pool_key,
+                    &mut pool_account,
+                    &token_a_key,
+                    &mut token_a_account,
+                    &token_b_key,
+                    &mut token_b_account,
+                    pool_token_amount,
+                    0,
+                    0,
+                )
+                .unwrap();
+            let swap_token_a_key = accounts.token_a_key;
+            let swap_token_b_key = accounts.token_b_key;
+            let withdrawn_and_swapped = match trade_direction {
+                TradeDirection::AtoB => {
+                    let amount_in = token_amount(&token_a_account);
+                    accounts
+                        .swap(
+                            &user_key,
+                            &token_a_key,
+                            &mut token_a_account,
+                            &swap_token_a_key,
+                            &swap_token_b_key,
+                            &token_b_key,
+                            &mut token_b_account,
+                            amount_in,
+                            0,
+                        )
+                        .unwrap();
+                    token_amount(&token_b_account)
+                }
+                TradeDirection::BtoA => {
+                    let amount_in = token_amount(&token_b_account);
+                    accounts
+                        .swap(
+                            &user_key,
+                            &token_b_key,
+                            &mut token_b_account,
+                            &swap_token_b_key,
+                            &swap_token_a_key,
+                            &token_a_key,
+                            &mut token_a_account,
+                            amount_in,
+                            0,
+                        )
+                        .unwrap();
+                    token_amount(&token_a_account)
+                }
+            };
+            assert!(preferred_amount.abs_diff(withdrawn_and_swapped) <= 1);
+            // the owner is owed the same fee either way
+            let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+            assert_ne!(owner_fees_owed, 0);
+            assert_eq!(swap_state.owner_fees_owed, owner_fees_owed);
+        }
+    }
+
+    #[test]
+    fn test_withdraw_preferred_mint_price_band() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.price_band_bps = 500;
+        accounts.initialize_swap().unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 10, 0, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+
+        // a small swap sets the reference price
+        accounts
+            .swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                10,
+                1,
+            )
+            .unwrap();
+
+        // swapping half the pool's token A back in moves the price far
+        // outside the band, so the withdrawal is refused like the swap
+        let pool_key = accounts.pool_token_key;
+        let mut pool_account = accounts.pool_token_account.clone();
+        let pool_token_amount = to_u64(INITIAL_SWAP_POOL_AMOUNT / 2).unwrap();
+        let preferred_mint = accounts.token_b_mint_key;
+        assert_eq!(
+            Err(SwapError::PriceDeviationTooHigh.into()),
+            accounts.withdraw_with_data(
+                &user_key,
+                &pool_key,
+                &mut pool_account,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                WithdrawTokens {
+                    pool_token_amount,
+                    minimum_token_a_amount: 0,
+                    minimum_token_b_amount: 0,
+                    preferred_mint: Some(preferred_mint),
+                    minimum_preferred_amount: 0,
+                },
+            )
+        );
+
+        // both tokens leave without a swap, so the band does not apply
+        accounts
+            .withdraw(
+                &user_key,
+                &pool_key,
+                &mut pool_account,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                pool_token_amount,
+                0,
+                0,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_cached_pool_token_supply() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let assert_supply_in_sync = |accounts: &SwapAccountInfo| {
+            let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+            let pool_mint = SplMint::unpack(&accounts.pool_mint_account.data).unwrap();
+            assert_eq!(swap_state.pool_token_supply, pool_mint.supply);
+        };
+        assert_supply_in_sync(&accounts);
+
+        let pool_token_amount = to_u64(INITIAL_SWAP_POOL_AMOUNT / 10).unwrap();
+        let (
+            token_a_key,
+            mut token_a_account,
+            token_b_key,
+            mut token_b_account,
+            pool_key,
+            mut pool_account,
+        ) = accounts.setup_token_accounts(&user_key, &user_key, 1000, 1000, 0);
+        accounts
+            .deposit(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                pool_token_amount,
+                1000,
+                1000,
+            )
+            .unwrap();
+        assert_supply_in_sync(&accounts);
+
+        accounts
+            .withdraw(
+                &user_key,
+                &pool_key,
+                &mut pool_account,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                pool_token_amount / 2,
+                0,
+                0,
+            )
+            .unwrap();
+        assert_supply_in_sync(&accounts);
+
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+        accounts
+            .swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                100,
+                1,
+            )
+            .unwrap();
+        assert_supply_in_sync(&accounts);
+
+        accounts
+            .withdraw(
+                &user_key,
+                &pool_key,
+                &mut pool_account,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                pool_token_amount / 2,
+                0,
+                0,
+            )
+            .unwrap();
+        assert_supply_in_sync(&accounts);
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert_eq!(
+            swap_state.pool_token_supply,
+            to_u64(INITIAL_SWAP_POOL_AMOUNT).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_pool_tokens_burned_outside_program() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 0, 0, 0);
+        let pool_key = accounts.pool_token_key;
+        let mut pool_account = accounts.pool_token_account.clone();
+
+        // the holder burns half the pool tokens without the swap program
+        let burned = to_u64(INITIAL_SWAP_POOL_AMOUNT / 2).unwrap();
+        do_process_instruction(
+            spl_token::instruction::burn(
+                &spl_token::id(),
+                &pool_key,
+                &accounts.pool_mint_key,
+                &user_key,
+                &[],
+                burned,
+            )
+            .unwrap(),
+            vec![
+                &mut pool_account,
+                &mut accounts.pool_mint_account,
+                &mut SolanaAccount::default(),
+            ],
+        )
+        .unwrap();
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert_eq!(
+            swap_state.pool_token_supply,
+            to_u64(INITIAL_SWAP_POOL_AMOUNT).unwrap()
+        );
+
+        // the remaining pool tokens are the whole supply, so they withdraw
+        // the whole pool
+        let remaining = token_amount(&pool_account);
+        accounts
+            .withdraw(
+                &user_key,
+                &pool_key,
+                &mut pool_account,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                remaining,
+                1000,
+                2000,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&token_a_account), 1000);
+        assert_eq!(token_amount(&token_b_account), 2000);
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert_eq!(swap_state.pool_token_supply, 0);
+    }
+
+    #[test]
+    fn test_signed_invoke_failure_context() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+
+        // a valid program address, but not the swap authority
+        let wrong_bump_seed = (0..accounts.bump_seed)
+            .rev()
+            .find(|bump_seed| {
+                Processor::authority_id(&SWAP_PROGRAM_ID, &accounts.swap_key, *bump_seed).is_ok()
+            })
+            .unwrap();
+
+        test_syscall_stubs();
+        let token_program_id = spl_token::id();
+        let mut pool_mint_account = accounts.pool_mint_account.clone();
+        let mut pool_token_account = accounts.pool_token_account.clone();
+        let mut authority_account = SolanaAccount::default();
+        let mut token_program_account = SolanaAccount::default();
+        let mut meta = vec![
+            (&accounts.pool_mint_key, false, &mut pool_mint_account),
+            (&accounts.pool_token_key, false, &mut pool_token_account),
+            (&accounts.authority_key, false, &mut authority_account),
+            (&token_program_id, false, &mut token_program_account),
+        ];
+        let account_infos = create_is_signer_account_infos(&mut meta);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            Processor::token_mint_to(
+                &accounts.swap_key,
+                account_infos[3].clone(),
+                account_infos[0].clone(),
+                account_infos[1].clone(),
+                account_infos[2].clone(),
+                wrong_bump_seed,
+                1,
+            )
+        );
+
+        let expected_log = format!(
+            "MintTo failed: {}, authority seeds: swap {}, bump seed {}",
+            ProgramError::MissingRequiredSignature,
+            accounts.swap_key,
+            wrong_bump_seed
+        );
+        assert!(LOGS.lock().unwrap().contains(&expected_log));
+    }
+
+    #[test]
+    fn test_batch_quote() {
+        let user_key = Pubkey::new_unique();
+        let token_a_amount = 1000;
+        let token_b_amount = 2000;
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            token_a_amount,
+            token_b_amount,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+
+        let token_a_key = accounts.token_a_key;
+        let token_b_key = accounts.token_b_key;
+        let amounts_in = vec![0, 1, 10, 100, 1000, u64::MAX];
+        let quotes = accounts
+            .batch_quote(&token_a_key, &token_b_key, amounts_in.clone())
+            .unwrap();
+        let expected = amounts_in
+            .iter()
+            .map(|amount_in| {
+                swap_amount_out(
+                    &swap_state,
+                    *amount_in,
+                    token_a_amount,
+                    token_b_amount,
+                    TradeDirection::AtoB,
+                )
+                .unwrap_or(FAILED_QUOTE)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(quotes, expected);
+        assert_eq!(quotes[0], 0);
+        assert_eq!(quotes[3], 180);
+
+        let quotes = accounts
+            .batch_quote(&token_b_key, &token_a_key, vec![200])
+            .unwrap();
+        assert_eq!(
+            quotes,
+            vec![swap_amount_out(
+                &swap_state,
+                200,
+                token_b_amount,
+                token_a_amount,
+                TradeDirection::BtoA
+            )
+            .unwrap()]
+        );
+
+        assert_eq!(
+            Err(SwapError::IncorrectSwapAccount.into()),
+            accounts.batch_quote(&token_a_key, &token_a_key, vec![1])
+        );
+
+        // nothing can be quoted from an empty source reserve, which is told
+        // apart from a zero quote
+        let mut token_a = SplAccount::unpack(&accounts.token_a_account.data).unwrap();
+        token_a.amount = 0;
+        SplAccount::pack(token_a, &mut accounts.token_a_account.data).unwrap();
+        assert_eq!(
+            accounts.batch_quote(&token_a_key, &token_b_key, vec![0, 1]),
+            Ok(vec![FAILED_QUOTE, FAILED_QUOTE])
+        );
+    }
+
+    #[test]
+    fn test_initialize_destination_mint() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+
+        // destination of the initial supply holds another mint
+        let (other_mint_key, mut other_mint_account) =
+            create_mint(&spl_token::id(), &user_key, None);
+        let (other_token_key, other_token_account) = mint_token(
+            &spl_token::id(),
+            &other_mint_key,
+            &mut other_mint_account,
+            &user_key,
+            &user_key,
+            0,
+        );
+        let pool_token_key = accounts.pool_token_key;
+        let pool_token_account = accounts.pool_token_account.clone();
+        accounts.pool_token_key = other_token_key;
+        accounts.pool_token_account = other_token_account;
+        assert_eq!(
+            Err(SwapError::IncorrectPoolMint.into()),
+            accounts.initialize_swap()
+        );
+        assert_eq!(token_amount(&accounts.pool_token_account), 0);
+
+        accounts.pool_token_key = pool_token_key;
+        accounts.pool_token_account = pool_token_account;
+        accounts.initialize_swap().unwrap();
+        assert_eq!(
+            SplAccount::unpack(&accounts.pool_token_account.data)
+                .unwrap()
+                .mint,
+            accounts.pool_mint_key
+        );
+    }
+
+    #[test]
+    fn test_deposit_rounding() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (
+            token_a_key,
+            mut token_a_account,
+            token_b_key,
+            mut token_b_account,
+            pool_key,
+            mut pool_account,
+        ) = accounts.setup_token_accounts(&user_key, &user_key, 1000, 1000, 0);
+
+        // 1000 * 1 / 1e9 rounds to zero token A, no free pool tokens
+        assert_eq!(
+            Err(SwapError::ZeroTradingTokens.into()),
+            accounts.deposit(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                1,
+                1000,
+                1000,
+            )
+        );
+        assert_eq!(token_amount(&pool_account), 0);
+
+        // 1.5 token A rounds up against the depositor, 3 token B is exact
+        let pool_token_amount = to_u64(INITIAL_SWAP_POOL_AMOUNT / 1000 * 3 / 2).unwrap();
+        assert_eq!(
+            Err(SwapError::ExceededSlippage.into()),
+            accounts.deposit(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                pool_token_amount,
+                1,
+                3,
+            )
+        );
+        accounts
+            .deposit(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                pool_token_amount,
+                2,
+                3,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&pool_account), pool_token_amount);
+        assert_eq!(token_amount(&token_a_account), 998);
+        assert_eq!(token_amount(&token_b_account), 997);
+    }
+
+    #[test]
+    fn test_withdraw_zero_supply() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 0, 0, 0);
+        let pool_key = accounts.pool_token_key;
+        let mut pool_account = accounts.pool_token_account.clone();
+
+        // a pool whose every pool token was burned
+        let mut swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        swap_state.pool_token_supply = 0;
+        SwapState::pack(swap_state, &mut accounts.swap_account.data).unwrap();
+        let mut pool_mint = SplMint::unpack(&accounts.pool_mint_account.data).unwrap();
+        pool_mint.supply = 0;
+        SplMint::pack(pool_mint, &mut accounts.pool_mint_account.data).unwrap();
+
+        assert_eq!(
+            Err(SwapError::ZeroTradingTokens.into()),
+            accounts.withdraw(
+                &user_key,
+                &pool_key,
+                &mut pool_account,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                1,
+                0,
+                0,
+            )
+        );
+    }
+
+    #[test]
+    fn test_withdraw_destination_owners() {
+        let user_key = Pubkey::new_unique();
+        let other_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 0, 0, 0);
+        let (_, _, other_token_b_key, mut other_token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &other_key, 0, 0, 0);
+        let pool_key = accounts.pool_token_key;
+        let mut pool_account = accounts.pool_token_account.clone();
+        let pool_token_amount = to_u64(INITIAL_SWAP_POOL_AMOUNT / 10).unwrap();
+
+        // token B sent to another owner
+        assert_eq!(
+            Err(SwapError::DestinationOwnerMismatch.into()),
+            accounts.withdraw(
+                &user_key,
+                &pool_key,
+                &mut pool_account,
+                &token_a_key,
+                &mut token_a_account,
+                &other_token_b_key,
+                &mut other_token_b_account,
+                pool_token_amount,
+                0,
+                0,
+            )
+        );
+
+        // token A destination holding token B
+        let (_, _, second_token_b_key, mut second_token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 0, 0, 0);
+        assert_eq!(
+            Err(SwapError::IncorrectSwapAccount.into()),
+            accounts.withdraw(
+                &user_key,
+                &pool_key,
+                &mut pool_account,
+                &second_token_b_key,
+                &mut second_token_b_account,
+                &token_b_key,
+                &mut token_b_account,
+                pool_token_amount,
+                0,
+                0,
+            )
+        );
+
+        accounts
+            .withdraw(
+                &user_key,
+                &pool_key,
+                &mut pool_account,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                pool_token_amount,
+                0,
+                0,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&token_a_account), 100);
+        assert_eq!(token_amount(&token_b_account), 200);
+    }
+
+    #[test]
+    fn test_deposit_reserve_limit() {
+        fn set_amount(account: &mut SolanaAccount, amount: u64) {
+            let mut token_account = SplAccount::unpack(&account.data).unwrap();
+            token_account.amount = amount;
+            SplAccount::pack(token_account, &mut account.data).unwrap();
+        }
+
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+
+        // a tenth of the pool tokens costs a tenth of the token A reserve, so
+        // a reserve of 10 * share grows to 11 * share
+        let share = MAX_RESERVE / 11;
+        let (
+            token_a_key,
+            mut token_a_account,
+            token_b_key,
+            mut token_b_account,
+            pool_key,
+            mut pool_account,
+        ) = accounts.setup_token_accounts(&user_key, &user_key, share + 1, 1000, 0);
+        let pool_token_amount = to_u64(INITIAL_SWAP_POOL_AMOUNT / 10).unwrap();
+
+        // just over the limit
+        set_amount(&mut accounts.token_a_account, 10 * (share + 1));
+        assert_eq!(
+            Err(SwapError::ReserveLimitExceeded.into()),
+            accounts.deposit(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                pool_token_amount,
+                share + 1,
+                200,
+            )
+        );
+
+        // just under the limit
+        set_amount(&mut accounts.token_a_account, 10 * share);
+        accounts
+            .deposit(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                pool_token_amount,
+                share,
+                200,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&accounts.token_a_account), 11 * share);
+        assert!(MAX_RESERVE - 11 * share < 11);
+    }
+
+    #[test]
+    fn test_deposit_expected_ratio() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (
+            token_a_key,
+            mut token_a_account,
+            token_b_key,
+            mut token_b_account,
+            pool_key,
+            mut pool_account,
+        ) = accounts.setup_token_accounts(&user_key, &user_key, 1000, 2000, 0);
+        let pool_token_amount = to_u64(INITIAL_SWAP_POOL_AMOUNT / 10).unwrap();
+        let deposit = |expected_ratio_numerator, expected_ratio_denominator| {
+            SwapInstruction::DepositTokens(DepositTokens {
+                pool_token_amount,
+                maximum_token_a_amount: 100,
+                maximum_token_b_amount: 200,
+                expected_ratio_numerator,
+                expected_ratio_denominator,
+            })
+        };
+
+        // 100 A for 200 B is over 1% off 97 A for 200 B
+        assert_eq!(
+            Err(SwapError::ExceededSlippage.into()),
+            accounts.deposit_with_data(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                100,
+                200,
+                deposit(97, 200),
+            )
+        );
+
+        // and within 1% of 101 A for 200 B
+        accounts
+            .deposit_with_data(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                100,
+                200,
+                deposit(101, 200),
+            )
+            .unwrap();
+        assert_eq!(token_amount(&pool_account), pool_token_amount);
+    }
+
+    #[test]
+    fn test_withdraw_all_token_types() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (
+            token_a_key,
+            mut token_a_account,
+            token_b_key,
+            mut token_b_account,
+            pool_key,
+            mut pool_account,
+        ) = accounts.setup_token_accounts(&user_key, &user_key, 500, 1000, 0);
+        let pool_token_amount = to_u64(INITIAL_SWAP_POOL_AMOUNT / 2).unwrap();
+        accounts
+            .deposit(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                pool_token_amount,
+                500,
+                1000,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&token_a_account), 0);
+        assert_eq!(token_amount(&token_b_account), 0);
+
+        assert_eq!(
+            Err(SwapError::ExceededSlippage.into()),
+            accounts.withdraw_all(
+                &user_key,
+                &pool_key,
+                &mut pool_account,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                501,
+                1000,
+            )
+        );
+
+        // the whole position comes back, without the client reading it
+        accounts
+            .withdraw_all(
+                &user_key,
+                &pool_key,
+                &mut pool_account,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                500,
+                1000,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&pool_account), 0);
+        assert_eq!(token_amount(&token_a_account), 500);
+        assert_eq!(token_amount(&token_b_account), 1000);
+        let (_, data) = get_return_data().unwrap();
+        assert_eq!(
+            LiquidityDeltas::from_return_data(&data)
+                .unwrap()
+                .pool_token_delta,
+            pool_token_amount
+        );
+    }
+
+    #[test]
+    fn test_deposit_syncs_native_reserve() {
+        // turns a token A account into a wrapped SOL account backed by
+        // lamports
+        fn wrap_native(account: &mut SolanaAccount) {
+            let rent_exempt_reserve = Rent::default().minimum_balance(SplAccount::LEN);
+            let mut token_account = SplAccount::unpack(&account.data).unwrap();
+            token_account.mint = spl_token::native_mint::id();
+            token_account.is_native = COption::Some(rent_exempt_reserve);
+            account.lamports = rent_exempt_reserve + token_account.amount;
+            SplAccount::pack(token_account, &mut account.data).unwrap();
+        }
+
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        let (
+            token_a_key,
+            mut token_a_account,
+            token_b_key,
+            mut token_b_account,
+            pool_key,
+            mut pool_account,
+        ) = accounts.setup_token_accounts(&user_key, &user_key, 1000, 1000, 0);
+        accounts.token_a_mint_key = spl_token::native_mint::id();
+        wrap_native(&mut accounts.token_a_account);
+        wrap_native(&mut accounts.token_a_fee_account);
+        wrap_native(&mut token_a_account);
+        accounts.initialize_swap().unwrap();
+
+        // SOL sent to the reserve without a token transfer
+        accounts.token_a_account.lamports += 500;
+        assert_eq!(token_amount(&accounts.token_a_account), 1000);
+
+        // a tenth of the pool is a tenth of the synced 1500 token A
+        accounts
+            .deposit(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                to_u64(INITIAL_SWAP_POOL_AMOUNT / 10).unwrap(),
+                150,
+                200,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&token_a_account), 850);
+        assert_eq!(token_amount(&accounts.token_a_account), 1650);
+        assert_eq!(token_amount(&accounts.token_b_account), 2200);
+    }
+
+    #[test]
+    fn test_conversion_failure_logs_step() {
+        test_syscall_stubs();
+        let amount_out = u128::from(u64::MAX) + 1;
+        assert_eq!(to_u64_for("amount out", u128::from(u64::MAX)), Ok(u64::MAX));
+        assert_eq!(
+            to_u64_for("amount out", amount_out),
+            Err(SwapError::ConversionFailure)
+        );
+        let expected_log = format!("amount out overflows a u64: {}", amount_out);
+        assert!(LOGS.lock().unwrap().contains(&expected_log));
+    }
+
+    #[test]
+    fn test_deposit_into_empty_pool() {
+        fn set_amount(account: &mut SolanaAccount, amount: u64) {
+            let mut token_account = SplAccount::unpack(&account.data).unwrap();
+            token_account.amount = amount;
+            SplAccount::pack(token_account, &mut account.data).unwrap();
+        }
+
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+
+        // withdraw everything, leaving a zero pool token supply
+        let (
+            token_a_key,
+            mut token_a_account,
+            token_b_key,
+            mut token_b_account,
+            pool_key,
+            mut pool_account,
+        ) = accounts.setup_token_accounts(&user_key, &user_key, 0, 0, 0);
+        let initial_pool_key = accounts.pool_token_key;
+        let mut initial_pool_account = accounts.pool_token_account.clone();
+        accounts
+            .withdraw(
+                &user_key,
+                &initial_pool_key,
+                &mut initial_pool_account,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                to_u64(INITIAL_SWAP_POOL_AMOUNT).unwrap(),
+                1000,
+                2000,
+            )
+            .unwrap();
+
+        // tokens sent to the empty reserves belong to the next depositor
+        set_amount(&mut accounts.token_a_account, 500);
+        set_amount(&mut accounts.token_b_account, 700);
+
+        // the whole reserves are priced, whatever pool token amount is asked
+        // for, and must still fit the maxima
+        assert_eq!(
+            Err(SwapError::ExceededSlippage.into()),
+            accounts.deposit(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                1,
+                499,
+                700,
+            )
+        );
+        assert_eq!(
+            Err(SwapError::ExceededSlippage.into()),
+            accounts.deposit(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                1,
+                500,
+                699,
+            )
+        );
+        accounts
+            .deposit(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                1,
+                500,
+                700,
+            )
+            .unwrap();
+        assert_eq!(
+            token_amount(&pool_account),
+            to_u64(INITIAL_SWAP_POOL_AMOUNT).unwrap()
+        );
+        assert_eq!(token_amount(&token_a_account), 500);
+        assert_eq!(token_amount(&token_b_account), 1300);
+        assert_eq!(token_amount(&accounts.token_a_account), 1000);
+        assert_eq!(token_amount(&accounts.token_b_account), 1400);
+    }
+
+    #[test]
+    fn test_deposit_token_amount_never_shortchanges_pool() {
+        for pool_mint_supply in [7u128, 1000, INITIAL_SWAP_POOL_AMOUNT] {
+            for reserve_amount in [1u64, 3, 999, 1000, 2001, u64::from(u32::MAX)] {
+                for pool_token_amount in [1u128, 2, 7, 333, 1000, pool_mint_supply] {
+                    let proportional = u128::from(reserve_amount) * pool_token_amount;
+                    match deposit_token_amount(
+                        reserve_amount,
+                        pool_token_amount,
+                        pool_mint_supply,
+                        true,
+                    ) {
+                        Ok(token_amount) => {
+                            // at least the proportional share, and less than
+                            // one token more
+                            let token_amount = u128::from(token_amount);
+                            assert!(token_amount * pool_mint_supply >= proportional);
+                            assert!((token_amount - 1) * pool_mint_supply < proportional);
+                        }
+                        Err(error) => {
+                            assert_eq!(error, SwapError::ZeroTradingTokens);
+                            assert!(proportional < pool_mint_supply);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_proportional_math_near_u64_max() {
+        let max = u128::from(u64::MAX);
+        // the whole pool, and all but one pool token of it
+        assert_eq!(withdraw_token_amount(u64::MAX, max, max, false), Ok(max));
+        assert_eq!(
+            withdraw_token_amount(u64::MAX, max - 1, max, false),
+            Ok(max - 1)
+        );
+        assert_eq!(deposit_token_amount(u64::MAX, max, max, true), Ok(u64::MAX));
+        assert_eq!(deposit_pool_token_amount(u64::MAX, u64::MAX, max), Ok(max));
+
+        // products past u128 fail cleanly
+        assert_eq!(
+            withdraw_token_amount(u64::MAX, u128::MAX, max, false),
+            Err(SwapError::CalculationFailure)
+        );
+        assert_eq!(
+            deposit_token_amount(u64::MAX, u128::MAX, max, true),
+            Err(SwapError::CalculationFailure)
+        );
+        assert_eq!(
+            deposit_pool_token_amount(u64::MAX, u64::MAX, u128::MAX),
+            Err(SwapError::CalculationFailure)
+        );
+
+        // as does an empty pool
+        assert_eq!(
+            withdraw_token_amount(u64::MAX, max, 0, false),
+            Err(SwapError::CalculationFailure)
+        );
+        assert_eq!(
+            deposit_pool_token_amount(u64::MAX, 0, max),
+            Err(SwapError::CalculationFailure)
+        );
+    }
+
+    #[test]
+    fn test_constant_price_curve() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.curve_type = CurveType::ConstantPrice;
+        accounts.curve_parameter = 3;
+
+        // the mints must be the ones of the swap token accounts
+        let token_a_mint_key = accounts.token_a_mint_key;
+        accounts.token_a_mint_key = accounts.token_b_mint_key;
+        assert_eq!(
+            Err(SwapError::IncorrectMint.into()),
+            accounts.initialize_swap()
+        );
+        accounts.token_a_mint_key = token_a_mint_key;
+
+        accounts.initialize_swap().unwrap();
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert_eq!(swap_state.curve_type, CurveType::ConstantPrice);
+        assert_eq!(swap_state.token_a_decimals, 2);
+        assert_eq!(swap_state.token_b_decimals, 2);
+
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 100, 0, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+
+        // 99 token A in after fees, for 3 token B each
+        accounts
+            .swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                100,
+                297,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&token_b_account), 297);
+        assert_eq!(token_amount(&accounts.token_b_account), 2000 - 297);
+    }
+
+    #[test]
+    fn test_flash_loan() {
+        let user_key = Pubkey::new_unique();
+        let token_a_amount = 1000;
+        let token_b_amount = 2000;
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            token_a_amount,
+            token_b_amount,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 10, 10, 0);
+        let token_a_mint_key = accounts.token_a_mint_key;
+
+        // 500 * 3 / 1000 rounds to a fee of one
+        let borrowed = 500;
+        let fee = 1;
+        assert_eq!(
+            Err(SwapError::FlashLoanNotRepaid.into()),
+            accounts.flash_loan(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_a_mint_key,
+                borrowed,
+                borrowed,
+            )
+        );
+        assert_eq!(token_amount(&accounts.token_a_account), token_a_amount);
+        assert_eq!(token_amount(&token_a_account), 10);
+
+        accounts
+            .flash_loan(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_a_mint_key,
+                borrowed,
+                borrowed + fee,
+            )
+            .unwrap();
+        assert_eq!(
+            token_amount(&accounts.token_a_account),
+            token_a_amount + fee
+        );
+        assert_eq!(token_amount(&token_a_account), 10 - fee);
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert!(!swap_state.has_flash_loan());
+
+        // the loan is refused unless it is settled later in the transaction
+        let flash_loan = accounts.flash_loan_instruction(&token_a_key, &token_a_mint_key, borrowed);
+        let mut reserve_account = accounts.token_a_account.clone();
+        assert_eq!(
+            Err(SwapError::FlashLoanNotRepaid.into()),
+            do_process_instruction(
+                flash_loan.clone(),
+                vec![
+                    &mut accounts.swap_account,
+                    &mut SolanaAccount::default(),
+                    &mut reserve_account,
+                    &mut token_a_account,
+                    &mut SolanaAccount::default(),
+                    &mut instructions_sysvar_account(&[&flash_loan], 0),
+                ],
+            )
+        );
+
+        // the pool cannot be traded against while the loan is outstanding
+        let flash_loan_repay = accounts.flash_loan_repay_instruction(&accounts.token_a_key);
+        do_process_instruction(
+            flash_loan.clone(),
+            vec![
+                &mut accounts.swap_account,
+                &mut SolanaAccount::default(),
+                &mut reserve_account,
+                &mut token_a_account,
+                &mut SolanaAccount::default(),
+                &mut instructions_sysvar_account(&[&flash_loan, &flash_loan_repay], 0),
+            ],
+        )
+        .unwrap();
+        accounts.token_a_account = reserve_account;
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+        assert_eq!(
+            Err(SwapError::FlashLoanOutstanding.into()),
+            accounts.swap(
+                &user_key,
+                &token_b_key,
+                &mut token_b_account,
+                &swap_token_b_key,
+                &swap_token_a_key,
+                &token_a_key,
+                &mut token_a_account,
+                10,
+                1,
+            )
+        );
+    }
+
+    #[test]
+    fn test_rebalance() {
+        let user_key = Pubkey::new_unique();
+        let token_a_amount = 1000;
+        let token_b_amount = 2000;
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            token_a_amount,
+            token_b_amount,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (_, _, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 0, 1000, 0);
+        let pool_mint_account = accounts.pool_mint_account.clone();
+
+        let other_key = Pubkey::new_unique();
+        assert_eq!(
+            Err(SwapError::InvalidOwner.into()),
+            accounts.rebalance(&other_key, &token_b_key, &mut token_b_account, 0, 0)
+        );
+        assert_eq!(
+            Err(SwapError::InvalidInput.into()),
+            accounts.rebalance(&user_key, &token_b_key, &mut token_b_account, 0, 0)
+        );
+
+        // up from 2 to 3 token B per token A
+        let target_ratio = to_u64(3 * PRICE_SCALE).unwrap();
+        assert_eq!(
+            Err(SwapError::ExceededSlippage.into()),
+            accounts.rebalance(
+                &user_key,
+                &token_b_key,
+                &mut token_b_account,
+                target_ratio,
+                999,
+            )
+        );
+        accounts
+            .rebalance(
+                &user_key,
+                &token_b_key,
+                &mut token_b_account,
+                target_ratio,
+                1000,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&accounts.token_a_account), 1000);
+        assert_eq!(token_amount(&accounts.token_b_account), 3000);
+        assert_eq!(token_amount(&token_b_account), 0);
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert_eq!(swap_state.last_swap_price, 3 * PRICE_SCALE);
+
+        assert_eq!(swap_state.owner_rebalance_amount, 1000);
+
+        // down to 2.5 token B per token A
+        let target_ratio = to_u64(5 * PRICE_SCALE / 2).unwrap();
+        accounts
+            .rebalance(
+                &user_key,
+                &token_b_key,
+                &mut token_b_account,
+                target_ratio,
+                500,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&accounts.token_b_account), 2500);
+        assert_eq!(token_amount(&token_b_account), 500);
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert_eq!(swap_state.owner_rebalance_amount, 500);
+
+        // taking 1000 back would dip into the liquidity providers' 2000
+        let target_ratio = to_u64(3 * PRICE_SCALE / 2).unwrap();
+        assert_eq!(
+            Err(SwapError::RebalanceExceedsOwnerFunds.into()),
+            accounts.rebalance(
+                &user_key,
+                &token_b_key,
+                &mut token_b_account,
+                target_ratio,
+                u64::MAX,
+            )
+        );
+        assert_eq!(token_amount(&accounts.token_b_account), 2500);
+
+        // down to 2 token B per token A returns exactly what was added
+        let target_ratio = to_u64(2 * PRICE_SCALE).unwrap();
+        accounts
+            .rebalance(
+                &user_key,
+                &token_b_key,
+                &mut token_b_account,
+                target_ratio,
+                500,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&accounts.token_b_account), 2000);
+        assert_eq!(token_amount(&token_b_account), 1000);
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert_eq!(swap_state.owner_rebalance_amount, 0);
+
+        // the owner bears the difference, not the pool token holders
+        assert_eq!(accounts.pool_mint_account, pool_mint_account);
+    }
+
+    #[test]
+    fn test_initialize_with_constraints() {
+        let owner_key = Pubkey::new_unique();
+        let owner_key_str = owner_key.to_string();
+        let swap_constraints = Some(SwapConstraints {
+            owner_key: &owner_key_str,
+            max_trade_fee_basis_points: 100,
+            max_owner_fee_basis_points: 0,
+        });
+
+        // an unapproved owner
+        let stranger_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &stranger_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        assert_eq!(
+            Err(SwapError::InvalidOwner.into()),
+            accounts.initialize_swap_with_constraints(&swap_constraints)
+        );
+
+        // fees above the approved maxima
+        let mut accounts = SwapAccountInfo::new(
+            &owner_key,
+            Fees {
+                owner_trade_fee_numerator: 1,
+                owner_trade_fee_denominator: 100,
+                ..TEST_FEES
+            },
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        assert_eq!(
+            Err(SwapError::InvalidFee.into()),
+            accounts.initialize_swap_with_constraints(&swap_constraints)
+        );
+
+        let mut accounts = SwapAccountInfo::new(
+            &owner_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts
+            .initialize_swap_with_constraints(&swap_constraints)
+            .unwrap();
+    }
+
+    #[cfg(feature = "production")]
+    #[test]
+    fn test_initialize_production_constraints() {
+        let mut accounts = SwapAccountInfo::new(
+            &Pubkey::new_unique(),
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        assert_eq!(
+            Err(SwapError::InvalidOwner.into()),
+            accounts.initialize_swap_with_constraints(&SWAP_CONSTRAINTS)
+        );
+    }
+
+    #[test]
+    fn test_initialize_not_rent_exempt() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        let rent_exempt_lamports = accounts.token_b_fee_account.lamports;
+        accounts.token_b_fee_account.lamports -= 1;
+        assert_eq!(
+            Err(SwapError::NotRentExempt.into()),
+            accounts.initialize_swap()
+        );
+
+        accounts.token_b_fee_account.lamports = rent_exempt_lamports;
+        accounts.initialize_swap().unwrap();
+    }
+
+    #[test]
+    fn test_initialize_pool_mint_freeze_authority() {
+        fn set_freeze_authority(account: &mut SolanaAccount, freeze_authority: Pubkey) {
+            let mut mint = SplMint::unpack(&account.data).unwrap();
+            mint.freeze_authority = COption::Some(freeze_authority);
+            SplMint::pack(mint, &mut account.data).unwrap();
+        }
+
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+
+        // a stranger could freeze every liquidity provider
+        let pool_mint_account = accounts.pool_mint_account.clone();
+        set_freeze_authority(&mut accounts.pool_mint_account, Pubkey::new_unique());
+        assert_eq!(
+            Err(SwapError::InvalidFreezeAuthority.into()),
+            accounts.initialize_swap()
+        );
+
+        // the owner may keep the pool token freezable
+        accounts.pool_mint_account = pool_mint_account;
+        set_freeze_authority(&mut accounts.pool_mint_account, user_key);
+        accounts.initialize_swap().unwrap();
+        let pool_mint = SplMint::unpack(&accounts.pool_mint_account.data).unwrap();
+        assert_eq!(pool_mint.freeze_authority, COption::Some(user_key));
+    }
+
+    #[test]
+    fn test_initialize_frozen_accounts() {
+        fn freeze(account: &mut SolanaAccount) {
+            let mut token_account = SplAccount::unpack(&account.data).unwrap();
+            token_account.state = AccountState::Frozen;
+            SplAccount::pack(token_account, &mut account.data).unwrap();
+        }
+
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+
+        let token_b_fee_account = accounts.token_b_fee_account.clone();
+        freeze(&mut accounts.token_b_fee_account);
+        assert_eq!(
+            Err(SwapError::InvalidFeeAccount.into()),
+            accounts.initialize_swap()
+        );
+        accounts.token_b_fee_account = token_b_fee_account;
+
+        let token_a_account = accounts.token_a_account.clone();
+        freeze(&mut accounts.token_a_account);
+        assert_eq!(
+            Err(SwapError::FrozenAccount.into()),
+            accounts.initialize_swap()
+        );
+        accounts.token_a_account = token_a_account;
+
+        accounts.initialize_swap().unwrap();
+    }
+
+    #[test]
+    fn test_deposit_exceeding_reserves() {
+        let user_key = Pubkey::new_unique();
+        let token_a_amount = 1_000_000_000_000;
+        let token_b_amount = 2_000_000_000_000;
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            token_a_amount,
+            token_b_amount,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (
+            token_a_key,
+            mut token_a_account,
+            token_b_key,
+            mut token_b_account,
+            pool_key,
+            mut pool_account,
+        ) = accounts.setup_token_accounts(&user_key, &user_key, 1000, 1000, 0);
+
+        // u64::MAX / 1e9 of the pool is more token A than can exist
+        assert_eq!(
+            Err(SwapError::CalculationFailure.into()),
+            accounts.deposit(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                u64::MAX,
+                u64::MAX,
+                u64::MAX,
+            )
+        );
+        let expected_log = format!(
+            "Deposit of {} pool tokens requires {} reserve tokens, more than the reserves support",
+            u64::MAX,
+            u128::from(u64::MAX) * u128::from(token_a_amount) / INITIAL_SWAP_POOL_AMOUNT
+        );
+        assert!(LOGS.lock().unwrap().contains(&expected_log));
+        assert_eq!(token_amount(&pool_account), 0);
+    }
+
+    #[test]
+    fn test_initialize_repeated_mint() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+
+        // token B account holding token A
+        let (token_b_key, token_b_account) = mint_token(
+            &spl_token::id(),
+            &accounts.token_a_mint_key,
+            &mut accounts.token_a_mint_account,
+            &user_key,
+            &accounts.authority_key,
+            2000,
+        );
+        let old_token_b_key = accounts.token_b_key;
+        let old_token_b_account = accounts.token_b_account.clone();
+        accounts.token_b_key = token_b_key;
+        accounts.token_b_account = token_b_account;
+        assert_eq!(
+            Err(SwapError::RepeatedMint.into()),
+            accounts.initialize_swap()
+        );
+        accounts.token_b_key = old_token_b_key;
+        accounts.token_b_account = old_token_b_account;
+
+        // pool token mint is the token A mint
+        let old_pool_mint_key = accounts.pool_mint_key;
+        let old_pool_mint_account = accounts.pool_mint_account.clone();
+        accounts.pool_mint_key = accounts.token_a_mint_key;
+        accounts.pool_mint_account = accounts.token_a_mint_account.clone();
+        assert_eq!(
+            Err(SwapError::RepeatedMint.into()),
+            accounts.initialize_swap()
+        );
+        accounts.pool_mint_key = old_pool_mint_key;
+        accounts.pool_mint_account = old_pool_mint_account;
+
+        accounts.initialize_swap().unwrap();
+    }
+
+    #[test]
+    fn test_deposit_exact_amounts() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (
+            token_a_key,
+            mut token_a_account,
+            token_b_key,
+            mut token_b_account,
+            pool_key,
+            mut pool_account,
+        ) = accounts.setup_token_accounts(&user_key, &user_key, 1000, 1000, 0);
+        let mut deposit = |accounts: &mut SwapAccountInfo,
+                           token_a_amount: u64,
+                           token_b_amount: u64,
+                           minimum_pool_token_amount: u64| {
+            accounts.deposit_with_data(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                token_a_amount,
+                token_b_amount,
+                SwapInstruction::DepositTokensExactAmounts(DepositTokensExactAmounts {
+                    token_a_amount,
+                    token_b_amount,
+                    minimum_pool_token_amount,
+                }),
+            )
+        };
+
+        // token B is limiting: 100 of 2000 is worth 5% of the supply
+        assert_eq!(
+            Err(SwapError::ExceededSlippage.into()),
+            deposit(&mut accounts, 100, 100, 50_000_001)
+        );
+        deposit(&mut accounts, 100, 100, 50_000_000).unwrap();
+        assert_eq!(
+            SwapState::unpack(&accounts.swap_account.data)
+                .unwrap()
+                .pool_token_supply,
+            1_050_000_000
+        );
+
+        // token A is limiting: 11 of 1100 is worth 1% of the supply
+        deposit(&mut accounts, 11, 500, 0).unwrap();
+        assert_eq!(
+            SwapState::unpack(&accounts.swap_account.data)
+                .unwrap()
+                .pool_token_supply,
+            1_060_500_000
+        );
+
+        // nothing is refunded on the excess side
+        assert_eq!(token_amount(&accounts.token_a_account), 1111);
+        assert_eq!(token_amount(&accounts.token_b_account), 2600);
+
+        assert_eq!(
+            Err(SwapError::ZeroTradingTokens.into()),
+            deposit(&mut accounts, 0, 100, 0)
+        );
+        let pool_mint = SplMint::unpack(&accounts.pool_mint_account.data).unwrap();
+        assert_eq!(pool_mint.supply, 1_060_500_000);
+    }
+
+    #[cfg(feature = "invariant-check")]
+    #[test]
+    fn test_swap_invariant_check() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 1000, 1000, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+
+        // 253 token A, 250 traded after a 3 token fee kept in the pool, takes
+        // the reserves to 1253 * 1600
+        accounts
+            .swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                253,
+                1,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&accounts.token_a_account), 1253);
+        assert_eq!(token_amount(&accounts.token_b_account), 1600);
+    }
+
+    #[cfg(feature = "invariant-check")]
+    #[test]
+    #[should_panic(expected = "Swap invariant shrank")]
+    fn test_swap_invariant_check_broken_math() {
+        // paying out 200 token B for 100 token A ignores the curve entirely
+        check_swap_invariant(1000, 2000, 1100, 1800);
+    }
+
+    #[test]
+    fn test_set_curve_parameter() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.curve_type = CurveType::ConstantPrice;
+        accounts.curve_parameter = 2;
+        accounts.initialize_swap().unwrap();
+
+        let other_key = Pubkey::new_unique();
+        assert_eq!(
+            Err(SwapError::InvalidOwner.into()),
+            accounts.set_curve_parameter(&other_key, 3)
+        );
+        assert_eq!(
+            Err(SwapError::InvalidCurve.into()),
+            accounts.set_curve_parameter(&user_key, 0)
+        );
+
+        // no liquidity beyond the initial supply yet
+        accounts.set_curve_parameter(&user_key, 3).unwrap();
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert_eq!(swap_state.curve_parameter, 3);
+
+        let (
+            token_a_key,
+            mut token_a_account,
+            token_b_key,
+            mut token_b_account,
+            pool_key,
+            mut pool_account,
+        ) = accounts.setup_token_accounts(&user_key, &user_key, 1000, 1000, 0);
+        accounts
+            .deposit(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                to_u64(INITIAL_SWAP_POOL_AMOUNT / 10).unwrap(),
+                100,
+                200,
+            )
+            .unwrap();
+        assert_eq!(
+            Err(SwapError::OperationNotAllowed.into()),
+            accounts.set_curve_parameter(&user_key, 4)
+        );
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert_eq!(swap_state.curve_parameter, 3);
+    }
+
+    #[test]
+    fn test_set_curve_parameter_outside_liquidity() {
+        let user_key = Pubkey::new_unique();
+        let depositor_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.curve_type = CurveType::ConstantPrice;
+        accounts.curve_parameter = 2;
+        accounts.initialize_swap().unwrap();
+
+        // another provider deposits, then the owner withdraws as many pool
+        // tokens, bringing the supply back to the initial amount
+        let pool_token_amount = to_u64(INITIAL_SWAP_POOL_AMOUNT / 10).unwrap();
+        let (
+            token_a_key,
+            mut token_a_account,
+            token_b_key,
+            mut token_b_account,
+            pool_key,
+            mut pool_account,
+        ) = accounts.setup_token_accounts(&user_key, &depositor_key, 100, 200, 0);
+        accounts
+            .deposit(
+                &depositor_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                pool_token_amount,
+                100,
+                200,
+            )
+            .unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 0, 0, 0);
+        let owner_pool_key = accounts.pool_token_key;
+        let mut owner_pool_account = accounts.pool_token_account.clone();
+        accounts
+            .withdraw(
+                &user_key,
+                &owner_pool_key,
+                &mut owner_pool_account,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                pool_token_amount,
+                0,
+                0,
+            )
+            .unwrap();
+        accounts.pool_token_account = owner_pool_account;
+        let pool_mint = SplMint::unpack(&accounts.pool_mint_account.data).unwrap();
+        assert_eq!(u128::from(pool_mint.supply), INITIAL_SWAP_POOL_AMOUNT);
+        assert_eq!(
+            Err(SwapError::OperationNotAllowed.into()),
+            accounts.set_curve_parameter(&user_key, 3)
+        );
+
+        // not while the reserves are lent out either
+        let mut swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        swap_state.flash_loan_balance = 1;
+        SwapState::pack(swap_state, &mut accounts.swap_account.data).unwrap();
+        assert_eq!(
+            Err(SwapError::FlashLoanOutstanding.into()),
+            accounts.set_curve_parameter(&user_key, 3)
+        );
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert_eq!(swap_state.curve_parameter, 2);
+    }
+
+    #[test]
+    fn test_authority_signer_seeds() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+
+        // the authority found on initialization is recreated from its seeds
+        let (authority_key, bump_seed) =
+            Pubkey::find_program_address(&[&accounts.swap_key.to_bytes()], &SWAP_PROGRAM_ID);
+        assert_eq!(swap_state.bump_seed(), bump_seed);
+        assert_eq!(
+            Processor::authority_id(&SWAP_PROGRAM_ID, &accounts.swap_key, swap_state.bump_seed()),
+            Ok(authority_key)
+        );
+        assert_eq!(
+            authority_signer_seeds(&accounts.swap_key, &bump_seed),
+            [&accounts.swap_key.to_bytes()[..], &[bump_seed][..]]
+        );
+    }
+
+    #[test]
+    fn test_swap_authority_matches_initialize() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert_eq!(
+            crate::swap_authority(&SWAP_PROGRAM_ID, &accounts.swap_key),
+            (accounts.authority_key, swap_state.bump_seed())
+        );
+    }
+
+    #[test]
+    fn test_non_canonical_bump_seed() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 100, 0, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+
+        // a lower bump seed that still derives a valid program address, with
+        // the authority passed to match it
+        let mut swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        let wrong_bump_seed = (0..swap_state.bump_seed())
+            .rev()
+            .find(|bump_seed| {
+                Processor::authority_id(&SWAP_PROGRAM_ID, &accounts.swap_key, *bump_seed).is_ok()
+            })
+            .unwrap();
+        swap_state.bump_seed = wrong_bump_seed;
+        SwapState::pack(swap_state, &mut accounts.swap_account.data).unwrap();
+        accounts.authority_key =
+            Processor::authority_id(&SWAP_PROGRAM_ID, &accounts.swap_key, wrong_bump_seed).unwrap();
+
+        assert_eq!(
+            Err(SwapError::InvalidProgramAddress.into()),
+            accounts.swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                100,
+                1,
+            )
+        );
+        assert_eq!(token_amount(&token_a_account), 100);
+    }
+
+    #[test]
+    fn test_stable_curve() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1_000_000,
+            1_000_000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.curve_type = CurveType::Stable;
+        assert_eq!(
+            Err(SwapError::InvalidCurve.into()),
+            accounts.initialize_swap()
+        );
+        accounts.curve_parameter = 100;
+        accounts.initialize_swap().unwrap();
+
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 100_000, 0, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+
+        // 99_000 token A in after fees, close to one for one
+        accounts
+            .swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                100_000,
+                98_900,
+            )
+            .unwrap();
+        let amount_out = token_amount(&token_b_account);
+        assert!(amount_out < 99_000);
+        assert_eq!(
+            token_amount(&accounts.token_b_account),
+            1_000_000 - amount_out
+        );
+    }
+
+    #[test]
+    fn test_swap_fee_account_aliasing() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 100, 0, 0);
+        let user_transfer_key = Pubkey::new_unique();
+        approve_delegate(
+            &accounts.token_program_id,
+            &token_a_key,
+            &mut token_a_account,
+            &user_transfer_key,
+            &user_key,
+            100,
+        );
+
+        // either reserve passed as the fee account
+        for fee_key in [accounts.token_a_key, accounts.token_b_key] {
+            let mut token_a_reserve = accounts.token_a_account.clone();
+            let mut token_b_reserve = accounts.token_b_account.clone();
+            let mut fee_account = accounts.get_token_account(&fee_key).clone();
+            assert_eq!(
+                Err(SwapError::InvalidFeeAccount.into()),
+                do_process_instruction(
+                    Instruction {
+                        program_id: SWAP_PROGRAM_ID,
+                        accounts: vec![
+                            AccountMeta::new(accounts.swap_key, false),
+                            AccountMeta::new_readonly(accounts.authority_key, false),
+                            AccountMeta::new_readonly(user_transfer_key, true),
+                            AccountMeta::new(token_a_key, false),
+                            AccountMeta::new(accounts.token_a_key, false),
+                            AccountMeta::new(accounts.token_b_key, false),
+                            AccountMeta::new(token_b_key, false),
+                            AccountMeta::new(fee_key, false),
+                            AccountMeta::new_readonly(accounts.token_program_id, false),
+                        ],
+                        data: SwapInstruction::Swap(Swap {
+                            amount_in: 100,
+                            minimum_amount_out: 1,
+                            deadline_slot: 0,
+                        })
+                        .pack(),
+                    },
+                    vec![
+                        &mut accounts.swap_account,
+                        &mut SolanaAccount::default(),
+                        &mut SolanaAccount::default(),
+                        &mut token_a_account,
+                        &mut token_a_reserve,
+                        &mut token_b_reserve,
+                        &mut token_b_account,
+                        &mut fee_account,
+                        &mut SolanaAccount::default(),
+                    ],
+                )
+            );
+        }
+        assert_eq!(token_amount(&token_a_account), 100);
+    }
+
+    #[test]
+    fn test_swap_fee_account_direction() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 100, 100, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+
+        // the fee account of the destination token is refused in either
+        // direction
+        for (source_key, swap_source_key, swap_destination_key, destination_key, wrong_fee_key) in [
+            (
+                token_a_key,
+                swap_token_a_key,
+                swap_token_b_key,
+                token_b_key,
+                accounts.token_b_fee_key,
+            ),
+            (
+                token_b_key,
+                swap_token_b_key,
+                swap_token_a_key,
+                token_a_key,
+                accounts.token_a_fee_key,
+            ),
+        ] {
+            let user_transfer_key = Pubkey::new_unique();
+            let (source_account, destination_account) = if source_key == token_a_key {
+                (&mut token_a_account, &mut token_b_account)
+            } else {
+                (&mut token_b_account, &mut token_a_account)
+            };
+            approve_delegate(
+                &accounts.token_program_id,
+                &source_key,
+                source_account,
+                &user_transfer_key,
+                &user_key,
+                10,
+            );
+            let mut swap_source_account = accounts.get_token_account(&swap_source_key).clone();
+            let mut swap_destination_account =
+                accounts.get_token_account(&swap_destination_key).clone();
+            let mut fee_account = accounts.get_token_account(&wrong_fee_key).clone();
+            assert_eq!(
+                Err(SwapError::IncorrectFeeAccount.into()),
+                do_process_instruction(
+                    Instruction {
+                        program_id: SWAP_PROGRAM_ID,
+                        accounts: vec![
+                            AccountMeta::new(accounts.swap_key, false),
+                            AccountMeta::new_readonly(accounts.authority_key, false),
+                            AccountMeta::new_readonly(user_transfer_key, true),
+                            AccountMeta::new(source_key, false),
+                            AccountMeta::new(swap_source_key, false),
+                            AccountMeta::new(swap_destination_key, false),
+                            AccountMeta::new(destination_key, false),
+                            AccountMeta::new(wrong_fee_key, false),
+                            AccountMeta::new_readonly(accounts.token_program_id, false),
+                        ],
+                        data: SwapInstruction::Swap(Swap {
+                            amount_in: 10,
+                            minimum_amount_out: 1,
+                            deadline_slot: 0,
+                        })
+                        .pack(),
+                    },
+                    vec![
+                        &mut accounts.swap_account,
+                        &mut SolanaAccount::default(),
+                        &mut SolanaAccount::default(),
+                        source_account,
+                        &mut swap_source_account,
+                        &mut swap_destination_account,
+                        destination_account,
+                        &mut fee_account,
+                        &mut SolanaAccount::default(),
+                    ],
+                )
+            );
+        }
+
+        // the fee account of the source token is accepted in either direction
+        accounts
+            .swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                10,
+                1,
+            )
+            .unwrap();
+        accounts
+            .swap(
+                &user_key,
+                &token_b_key,
+                &mut token_b_account,
+                &swap_token_b_key,
+                &swap_token_a_key,
+                &token_a_key,
+                &mut token_a_account,
+                10,
+                1,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_swap_insufficient_funds() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 99, 0, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+
+        assert_eq!(
+            Err(SwapError::InsufficientFunds.into()),
+            accounts.swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                100,
+                1,
+            )
+        );
+
+        accounts
+            .swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                99,
+                1,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&token_a_account), 0);
+    }
+
+    #[test]
+    fn test_swap_fee_consumes_input() {
+        let user_key = Pubkey::new_unique();
+        // the fees round up to the whole unit, or to more than it
+        for fees in [
+            TEST_FEES,
+            Fees {
+                owner_trade_fee_numerator: 1,
+                owner_trade_fee_denominator: 50,
+                ..TEST_FEES
+            },
+        ] {
+            let mut accounts = SwapAccountInfo::new(
+                &user_key,
+                fees,
+                1000,
+                2000,
+                &spl_token::id(),
+                &spl_token::id(),
+            );
+            accounts.initialize_swap().unwrap();
+            let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+                accounts.setup_token_accounts(&user_key, &user_key, 1, 0, 0);
+            let swap_token_a_key = accounts.token_a_key;
+            let swap_token_b_key = accounts.token_b_key;
+
+            assert_eq!(
+                Err(SwapError::ZeroTradingTokens.into()),
+                accounts.swap(
+                    &user_key,
+                    &token_a_key,
+                    &mut token_a_account,
+                    &swap_token_a_key,
+                    &swap_token_b_key,
+                    &token_b_key,
+                    &mut token_b_account,
+                    1,
+                    0,
+                )
+            );
+            assert_eq!(token_amount(&token_a_account), 1);
+        }
+    }
+
+    #[test]
+    fn test_swap_reserve_mint_substituted() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 100, 0, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+
+        // the token B reserve now claims to hold token A
+        let mut reserve_b = SplAccount::unpack(&accounts.token_b_account.data).unwrap();
+        reserve_b.mint = accounts.token_a_mint_key;
+        SplAccount::pack(reserve_b, &mut accounts.token_b_account.data).unwrap();
+        assert_eq!(
+            Err(SwapError::IncorrectMint.into()),
+            accounts.swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                100,
+                0,
+            )
+        );
+        assert_eq!(token_amount(&token_a_account), 100);
+    }
+
+    #[test]
+    fn test_simulate_swap_matches_swap() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.curve_type = CurveType::Offset;
+        accounts.curve_parameter = 500;
+        assert_eq!(
+            Err(SwapError::InvalidInput),
+            simulate_swap(
+                &accounts.swap_account.data,
+                1000,
+                2000,
+                100,
+                TradeDirection::AtoB
+            )
+        );
+        accounts.initialize_swap().unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 100, 100, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+
+        let amount_out = simulate_swap(
+            &accounts.swap_account.data,
+            1000,
+            2000,
+            100,
+            TradeDirection::AtoB,
+        )
+        .unwrap();
+        accounts
+            .swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                100,
+                0,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&token_b_account), 100 + amount_out);
+
+        // the offset curve prices the way back differently
+        let amount_out = simulate_swap(
+            &accounts.swap_account.data,
+            token_amount(&accounts.token_b_account),
+            token_amount(&accounts.token_a_account),
+            100,
+            TradeDirection::BtoA,
+        )
+        .unwrap();
+        accounts
+            .swap(
+                &user_key,
+                &token_b_key,
+                &mut token_b_account,
+                &swap_token_b_key,
+                &swap_token_a_key,
+                &token_a_key,
+                &mut token_a_account,
+                100,
+                0,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&token_a_account), amount_out);
+    }
+
+    #[test]
+    fn test_swap_deadline() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 100, 0, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+        CLOCK_SLOT.with(|slot| slot.set(50));
+
+        assert_eq!(
+            Err(SwapError::DeadlineExceeded.into()),
+            accounts.swap_with_data(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                10,
+                SwapInstruction::Swap(Swap {
+                    amount_in: 10,
+                    minimum_amount_out: 1,
+                    deadline_slot: 49,
+                }),
+            )
+        );
+
+        // the deadline slot itself is still in time
+        for deadline_slot in [50, 60] {
+            accounts
+                .swap_with_data(
+                    &user_key,
+                    &token_a_key,
+                    &mut token_a_account,
+                    &swap_token_a_key,
+                    &swap_token_b_key,
+                    &token_b_key,
+                    &mut token_b_account,
+                    10,
+                    SwapInstruction::Swap(Swap {
+                        amount_in: 10,
+                        minimum_amount_out: 1,
+                        deadline_slot,
+                    }),
+                )
+                .unwrap();
+        }
+        assert_eq!(token_amount(&token_a_account), 80);
+    }
+
+    #[test]
+    fn test_swap_with_slippage_bps() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1_000_000,
+            2_000_000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 101_000, 0, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+        let mut swap = |accounts: &mut SwapAccountInfo, amount_in, slippage_bps| {
+            accounts.swap_with_data(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                amount_in,
+                SwapInstruction::SwapWithSlippageBps(SwapWithSlippageBps {
+                    amount_in,
+                    slippage_bps,
+                }),
+            )
+        };
+
+        // a tenth of the pool moves the price about 9% past the spot output
+        assert_eq!(
+            swap(&mut accounts, 100_000, 100),
+            Err(SwapError::ExceededSlippage.into())
+        );
+        assert_eq!(token_amount(&accounts.token_a_account), 1_000_000);
+
+        // a thousandth of the pool moves it about 0.1%
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        let amount_out = swap_amount_out(
+            &swap_state,
+            1_000,
+            1_000_000,
+            2_000_000,
+            TradeDirection::AtoB,
+        )
+        .unwrap();
+        swap(&mut accounts, 1_000, 100).unwrap();
+        assert_eq!(token_amount(&token_b_account), amount_out);
+        assert_eq!(token_amount(&token_a_account), 100_000);
+    }
+
+    #[test]
+    fn test_swap_exact_out_or_partial() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 5000, 0, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+        let swap_exact_out = |accounts: &mut SwapAccountInfo,
+                              token_a_account: &mut SolanaAccount,
+                              token_b_account: &mut SolanaAccount,
+                              amount_out,
+                              maximum_amount_in,
+                              allow_partial| {
+            accounts.swap_with_data(
+                &user_key,
+                &token_a_key,
+                token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                token_b_account,
+                maximum_amount_in,
+                SwapInstruction::SwapExactOutOrPartial(SwapExactOutOrPartial {
+                    amount_out,
+                    maximum_amount_in,
+                    allow_partial,
+                }),
+            )
+        };
+        let filled = || {
+            let (program_id, data) = get_return_data().unwrap();
+            assert_eq!(program_id, SWAP_PROGRAM_ID);
+            u64::from_le_bytes(data.try_into().unwrap())
+        };
+
+        // full fill spends only what the output needs
+        swap_exact_out(
+            &mut accounts,
+            &mut token_a_account,
+            &mut token_b_account,
+            100,
+            500,
+            false,
+        )
+        .unwrap();
+        let received = token_amount(&token_b_account);
+        assert_eq!(filled(), received);
+        assert!(received >= 100);
+        let spent = 5000 - token_amount(&token_a_account);
+        assert!(spent < 500);
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert!(
+            swap_amount_out(
+                &swap_state,
+                spent - 1,
+                token_amount(&accounts.token_a_account) - spent,
+                token_amount(&accounts.token_b_account) + received,
+                TradeDirection::AtoB,
+            )
+            .unwrap()
+                < 100
+        );
+
+        // the pool cannot give out its whole reserve
+        let reserve_b = token_amount(&accounts.token_b_account);
+        assert_eq!(
+            Err(SwapError::ExceededSlippage.into()),
+            swap_exact_out(
+                &mut accounts,
+                &mut token_a_account,
+                &mut token_b_account,
+                reserve_b,
+                1000,
+                false,
+            )
+        );
+
+        // partial fill swaps the whole maximum for what the pool can give
+        let a_before = token_amount(&token_a_account);
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        let expected = swap_amount_out(
+            &swap_state,
+            1000,
+            token_amount(&accounts.token_a_account),
+            reserve_b,
+            TradeDirection::AtoB,
+        )
+        .unwrap();
+        swap_exact_out(
+            &mut accounts,
+            &mut token_a_account,
+            &mut token_b_account,
+            reserve_b,
+            1000,
+            true,
+        )
+        .unwrap();
+        assert_eq!(filled(), expected);
+        assert!(expected < reserve_b);
+        assert_eq!(token_amount(&token_a_account), a_before - 1000);
+        assert_eq!(token_amount(&token_b_account), received + expected);
+    }
+
+    #[test]
+    fn test_route_swap() {
+        let user_key = Pubkey::new_unique();
+        let mut first_pool = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        first_pool.initialize_swap().unwrap();
+
+        // the second pool trades the first pool's token B for a new token
+        let mut second_pool = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            3000,
+            1000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        let second_authority_key = second_pool.authority_key;
+        (second_pool.token_a_key, second_pool.token_a_account) = mint_token(
+            &spl_token::id(),
+            &first_pool.token_b_mint_key,
+            &mut first_pool.token_b_mint_account,
+            &user_key,
+            &second_authority_key,
+            3000,
+        );
+        (second_pool.token_a_fee_key, second_pool.token_a_fee_account) = mint_token(
+            &spl_token::id(),
+            &first_pool.token_b_mint_key,
+            &mut first_pool.token_b_mint_account,
+            &user_key,
+            &user_key,
+            0,
+        );
+        second_pool.token_a_mint_key = first_pool.token_b_mint_key;
+        second_pool.token_a_mint_account = first_pool.token_b_mint_account.clone();
+        second_pool.initialize_swap().unwrap();
+
+        let (source_key, mut source_account, intermediate_key, mut intermediate_account, _, _) =
+            first_pool.setup_token_accounts(&user_key, &user_key, 100, 0, 0);
+        let (_, _, destination_key, mut destination_account, _, _) =
+            second_pool.setup_token_accounts(&user_key, &user_key, 0, 0, 0);
+
+        let first_state = SwapState::unpack(&first_pool.swap_account.data).unwrap();
+        let intermediate_amount =
+            swap_amount_out(&first_state, 100, 1000, 2000, TradeDirection::AtoB).unwrap();
+        let second_state = SwapState::unpack(&second_pool.swap_account.data).unwrap();
+        let amount_out = swap_amount_out(
+            &second_state,
+            intermediate_amount,
+            3000,
+            1000,
+            TradeDirection::AtoB,
+        )
+        .unwrap();
+
+        let mut route_swap = |minimum_amount_out: u64| {
+            do_process_instruction(
+                Instruction {
+                    program_id: SWAP_PROGRAM_ID,
+                    accounts: vec![
+                        AccountMeta::new_readonly(user_key, true),
+                        AccountMeta::new(source_key, false),
+                        AccountMeta::new(intermediate_key, false),
+                        AccountMeta::new(destination_key, false),
+                        AccountMeta::new_readonly(spl_token::id(), false),
+                        AccountMeta::new(first_pool.swap_key, false),
+                        AccountMeta::new_readonly(first_pool.authority_key, false),
+                        AccountMeta::new(first_pool.token_a_key, false),
+                        AccountMeta::new(first_pool.token_b_key, false),
+                        AccountMeta::new_readonly(first_pool.token_a_fee_key, false),
+                        AccountMeta::new_readonly(first_pool.token_b_fee_key, false),
+                        AccountMeta::new(second_pool.swap_key, false),
+                        AccountMeta::new_readonly(second_pool.authority_key, false),
+                        AccountMeta::new(second_pool.token_a_key, false),
+                        AccountMeta::new(second_pool.token_b_key, false),
+                        AccountMeta::new_readonly(second_pool.token_a_fee_key, false),
+                        AccountMeta::new_readonly(second_pool.token_b_fee_key, false),
+                    ],
+                    data: SwapInstruction::RouteSwap(RouteSwap {
+                        amount_in: 100,
+                        minimum_amount_out,
+                    })
+                    .pack(),
+                },
+                vec![
+                    &mut SolanaAccount::default(),
+                    &mut source_account,
+                    &mut intermediate_account,
+                    &mut destination_account,
+                    &mut SolanaAccount::default(),
+                    &mut first_pool.swap_account,
+                    &mut SolanaAccount::default(),
+                    &mut first_pool.token_a_account,
+                    &mut first_pool.token_b_account,
+                    &mut first_pool.token_a_fee_account,
+                    &mut first_pool.token_b_fee_account,
+                    &mut second_pool.swap_account,
+                    &mut SolanaAccount::default(),
+                    &mut second_pool.token_a_account,
+                    &mut second_pool.token_b_account,
+                    &mut second_pool.token_a_fee_account,
+                    &mut second_pool.token_b_fee_account,
+                ],
+            )
+        };
+
+        assert_eq!(
+            Err(SwapError::ExceededSlippage.into()),
+            route_swap(amount_out + 1)
+        );
+        route_swap(amount_out).unwrap();
+        assert_eq!(token_amount(&source_account), 0);
+        assert_eq!(token_amount(&intermediate_account), 0);
+        assert_eq!(token_amount(&destination_account), amount_out);
+        assert_eq!(token_amount(&first_pool.token_a_account), 1100);
+        assert_eq!(
+            token_amount(&first_pool.token_b_account),
+            2000 - intermediate_amount
+        );
+        assert_eq!(
+            token_amount(&second_pool.token_a_account),
+            3000 + intermediate_amount
+        );
+        assert_eq!(
+            token_amount(&second_pool.token_b_account),
+            1000 - amount_out
+        );
+    }
+
+    #[test]
+    fn test_swap_user_account_mints() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 100, 100, 0);
+        let (other_token_a_key, mut other_token_a_account, _, _, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 100, 0, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+
+        // source account holding the destination token
+        assert_eq!(
+            Err(SwapError::IncorrectSwapAccount.into()),
+            accounts.swap(
+                &user_key,
+                &token_b_key,
+                &mut token_b_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &other_token_a_key,
+                &mut other_token_a_account,
+                10,
+                1,
+            )
+        );
+
+        // destination account holding the source token
+        assert_eq!(
+            Err(SwapError::IncorrectSwapAccount.into()),
+            accounts.swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &other_token_a_key,
+                &mut other_token_a_account,
+                10,
+                1,
+            )
+        );
+
+        accounts
+            .swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                10,
+                1,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_swap_reserve_close_authority() {
+        fn set_close_authority(account: &mut SolanaAccount, close_authority: COption<Pubkey>) {
+            let mut token_account = SplAccount::unpack(&account.data).unwrap();
+            token_account.close_authority = close_authority;
+            SplAccount::pack(token_account, &mut account.data).unwrap();
+        }
+
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 100, 100, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+
+        // close authority on the source reserve
+        set_close_authority(
+            &mut accounts.token_a_account,
+            COption::Some(Pubkey::new_unique()),
+        );
+        assert_eq!(
+            Err(SwapError::InvalidCloseAuthority.into()),
+            accounts.swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                10,
+                1,
+            )
+        );
+
+        // close authority on the destination reserve
+        assert_eq!(
+            Err(SwapError::InvalidCloseAuthority.into()),
+            accounts.swap(
+                &user_key,
+                &token_b_key,
+                &mut token_b_account,
+                &swap_token_b_key,
+                &swap_token_a_key,
+                &token_a_key,
+                &mut token_a_account,
+                10,
+                1,
+            )
+        );
+
+        set_close_authority(&mut accounts.token_a_account, COption::None);
+        accounts
+            .swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                10,
+                1,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_swap_transfers_use_amounts_computed_up_front() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 100, 0, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        let amount_out =
+            swap_amount_out(&swap_state, 100, 1000, 2000, TradeDirection::AtoB).unwrap();
+
+        // a token program re-entering the pool during the first transfer,
+        // here doubling the source reserve, must not change what the second
+        // transfer pays out
+        let transfers = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let recorded = transfers.clone();
+        INVOKE_HOOK.with(|hook| {
+            *hook.borrow_mut() = Some(Box::new(move |instruction, account_infos| {
+                if let Ok(spl_token::instruction::TokenInstruction::Transfer { amount }) =
+                    spl_token::instruction::TokenInstruction::unpack(&instruction.data)
+                {
+                    recorded.borrow_mut().push(amount);
+                }
+                if let Some(reserve_info) = account_infos
+                    .iter()
+                    .find(|account_info| *account_info.key == swap_token_a_key)
+                {
+                    let mut reserve = SplAccount::unpack(&reserve_info.data.borrow()).unwrap();
+                    reserve.amount *= 2;
+                    SplAccount::pack(reserve, &mut reserve_info.data.borrow_mut()).unwrap();
+                }
+            }));
+        });
+        let result = accounts.swap(
+            &user_key,
+            &token_a_key,
+            &mut token_a_account,
+            &swap_token_a_key,
+            &swap_token_b_key,
+            &token_b_key,
+            &mut token_b_account,
+            100,
+            0,
+        );
+        INVOKE_HOOK.with(|hook| hook.borrow_mut().take());
+        result.unwrap();
+
+        assert_eq!(*transfers.borrow(), vec![100, amount_out]);
+        assert_eq!(token_amount(&token_b_account), amount_out);
+        assert_ne!(
+            swap_amount_out(&swap_state, 100, 2000, 2000, TradeDirection::AtoB).unwrap(),
+            amount_out
+        );
+    }
+
+    #[test]
+    fn test_swap_rounding_keeps_invariant() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        // a migrated pool may charge no fee at all
+        let mut swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        swap_state.fees = Fees {
+            trade_fee_numerator: 0,
+            trade_fee_denominator: 1,
+            ..TEST_FEES
+        };
+        SwapState::pack(swap_state, &mut accounts.swap_account.data).unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 10, 0, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+
+        // with no fee to absorb it, paying out the floored 20 token B would
+        // leave 1010 * 1980 below 1000 * 2000, so only 19 are paid out
+        accounts
+            .swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                10,
+                1,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&token_b_account), 19);
+        assert!(
+            u128::from(token_amount(&accounts.token_a_account))
+                * u128::from(token_amount(&accounts.token_b_account))
+                >= 1000 * 2000
+        );
+    }
+
+    #[test]
+    fn test_swap_fee_split() {
+        let user_key = Pubkey::new_unique();
+        let fees = Fees {
+            trade_fee_numerator: 1,
+            trade_fee_denominator: 100,
+            owner_trade_fee_numerator: 1,
+            owner_trade_fee_denominator: 50,
+            ..TEST_FEES
+        };
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            fees,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 110, 0, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+        let invariant = |accounts: &SwapAccountInfo| {
+            u128::from(token_amount(&accounts.token_a_account))
+                * u128::from(token_amount(&accounts.token_b_account))
+        };
+        let invariant_before = invariant(&accounts);
+
+        // 110 token A in: a 4 token fee, 2 of it trade fee and 2 owner fee,
+        // stays in the pool and 106 are traded for 191
+        accounts
+            .swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                110,
+                1,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&token_a_account), 0);
+        assert_eq!(token_amount(&token_b_account), 191);
+        assert_eq!(token_amount(&accounts.token_a_account), 1110);
+        assert_eq!(token_amount(&accounts.token_b_account), 1809);
+        let fee_key = accounts.token_a_fee_key;
+        assert_eq!(token_amount(accounts.get_token_account(&fee_key)), 0);
+
+        // the pool token supply is unchanged, so the fees raise the invariant
+        // behind each pool token
+        let pool_mint = SplMint::unpack(&accounts.pool_mint_account.data).unwrap();
+        assert_eq!(u128::from(pool_mint.supply), INITIAL_SWAP_POOL_AMOUNT);
+        assert!(invariant(&accounts) > invariant_before);
+
+        // the owner fee is owed as 2 / 2220 of the pool
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert_eq!(swap_state.owner_fees_owed, 900_900);
+    }
+
+    #[test]
+    fn test_validate_initialize() {
+        fn edit_token_account(account: &mut SolanaAccount, edit: impl FnOnce(&mut SplAccount)) {
+            let mut token_account = SplAccount::unpack(&account.data).unwrap();
+            edit(&mut token_account);
+            SplAccount::pack(token_account, &mut account.data).unwrap();
+        }
+
+        let user_key = Pubkey::new_unique();
+        let new_accounts = || {
+            SwapAccountInfo::new(
+                &user_key,
+                TEST_FEES,
+                1000,
+                2000,
+                &spl_token::id(),
+                &spl_token::id(),
+            )
+        };
+        type BreakAccounts = Box<dyn Fn(&mut SwapAccountInfo)>;
+        let failures: Vec<(SwapError, BreakAccounts)> = vec![
+            (
+                SwapError::InvalidProgramAddress,
+                Box::new(|accounts| accounts.authority_key = Pubkey::new_unique()),
+            ),
+            (
+                SwapError::InvalidOwner,
+                Box::new(|accounts| {
+                    edit_token_account(&mut accounts.token_a_account, |account| {
+                        account.owner = Pubkey::new_unique()
+                    })
+                }),
+            ),
+            (
+                SwapError::InvalidOutputOwner,
+                Box::new(|accounts| {
+                    let authority_key = accounts.authority_key;
+                    edit_token_account(&mut accounts.token_a_fee_account, |account| {
+                        account.owner = authority_key
+                    })
+                }),
+            ),
+            (
+                SwapError::IncorrectMint,
+                Box::new(|accounts| accounts.token_a_mint_key = accounts.token_b_mint_key),
+            ),
+            (
+                SwapError::EmptySupply,
+                Box::new(|accounts| {
+                    edit_token_account(&mut accounts.token_a_account, |account| account.amount = 0)
+                }),
+            ),
+            (
+                SwapError::InvalidDelegate,
+                Box::new(|accounts| {
+                    edit_token_account(&mut accounts.token_b_account, |account| {
+                        account.delegate = COption::Some(Pubkey::new_unique())
+                    })
+                }),
+            ),
+            (
+                SwapError::IncorrectFeeAccount,
+                Box::new(|accounts| {
+                    let token_b_mint_key = accounts.token_b_mint_key;
+                    edit_token_account(&mut accounts.token_a_fee_account, |account| {
+                        account.mint = token_b_mint_key
+                    })
+                }),
+            ),
+            (
+                SwapError::NotRentExempt,
+                Box::new(|accounts| accounts.token_b_fee_account.lamports = 0),
+            ),
+            (
+                SwapError::InvalidSupply,
+                Box::new(|accounts| {
+                    let mut pool_mint = SplMint::unpack(&accounts.pool_mint_account.data).unwrap();
+                    pool_mint.supply = 1;
+                    SplMint::pack(pool_mint, &mut accounts.pool_mint_account.data).unwrap();
+                }),
+            ),
+            (
+                SwapError::InvalidFee,
+                Box::new(|accounts| {
+                    accounts.fees.trade_fee_numerator = accounts.fees.trade_fee_denominator
+                }),
+            ),
+            (
+                SwapError::ExceededSlippage,
+                Box::new(|accounts| {
+                    accounts.minimum_pool_token_amount =
+                        to_u64(INITIAL_SWAP_POOL_AMOUNT).unwrap() + 1
+                }),
+            ),
+        ];
+        // the dry run fails exactly as the initialization would
+        for (error, break_accounts) in failures {
+            let mut accounts = new_accounts();
+            break_accounts(&mut accounts);
+            assert_eq!(Err(error.clone().into()), accounts.validate_initialize());
+            assert_eq!(Err(error.into()), accounts.initialize_swap());
+        }
+
+        // and on success leaves everything untouched
+        let mut accounts = new_accounts();
+        let swap_data = accounts.swap_account.data.clone();
+        accounts.validate_initialize().unwrap();
+        assert_eq!(accounts.swap_account.data, swap_data);
+        let pool_mint = SplMint::unpack(&accounts.pool_mint_account.data).unwrap();
+        assert_eq!(pool_mint.supply, 0);
+        assert_eq!(token_amount(&accounts.pool_token_account), 0);
+
+        accounts.initialize_swap().unwrap();
+        assert_eq!(
+            Err(SwapError::AlreadyInUse.into()),
+            accounts.validate_initialize()
+        );
+    }
+
+    #[test]
+    fn test_initialize_with_deposit() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            0,
+            0,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        let (source_a_key, mut source_a_account, source_b_key, mut source_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 1000, 2000, 0);
+
+        // more pool tokens than the initial supply
+        accounts.minimum_pool_token_amount = to_u64(INITIAL_SWAP_POOL_AMOUNT).unwrap() + 1;
+        assert_eq!(
+            Err(SwapError::ExceededSlippage.into()),
+            accounts.initialize_swap_with_deposit(
+                &user_key,
+                &source_a_key,
+                &mut source_a_account.clone(),
+                &source_b_key,
+                &mut source_b_account.clone(),
+                1000,
+                2000,
+            )
+        );
+
+        // the curve needs both reserves funded
+        accounts.minimum_pool_token_amount = to_u64(INITIAL_SWAP_POOL_AMOUNT).unwrap();
+        assert_eq!(
+            Err(SwapError::EmptySupply.into()),
+            accounts.initialize_swap_with_deposit(
+                &user_key,
+                &source_a_key,
+                &mut source_a_account.clone(),
+                &source_b_key,
+                &mut source_b_account.clone(),
+                1000,
+                0,
+            )
+        );
+
+        accounts
+            .initialize_swap_with_deposit(
+                &user_key,
+                &source_a_key,
+                &mut source_a_account,
+                &source_b_key,
+                &mut source_b_account,
+                1000,
+                2000,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&source_a_account), 0);
+        assert_eq!(token_amount(&source_b_account), 0);
+        assert_eq!(token_amount(&accounts.token_a_account), 1000);
+        assert_eq!(token_amount(&accounts.token_b_account), 2000);
+        assert_eq!(
+            token_amount(&accounts.pool_token_account),
+            to_u64(INITIAL_SWAP_POOL_AMOUNT).unwrap()
+        );
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert!(swap_state.is_initialized());
+    }
+
+    #[test]
+    fn test_set_fee_accounts() {
+        let owner_key = Pubkey::new_unique();
+        let other_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &owner_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (token_a_fee_key, mut token_a_fee_account) = mint_token(
+            &spl_token::id(),
+            &accounts.token_a_mint_key,
+            &mut accounts.token_a_mint_account,
+            &owner_key,
+            &other_key,
+            0,
+        );
+        let (token_b_fee_key, mut token_b_fee_account) = mint_token(
+            &spl_token::id(),
+            &accounts.token_b_mint_key,
+            &mut accounts.token_b_mint_account,
+            &owner_key,
+            &other_key,
+            0,
+        );
+
+        assert_eq!(
+            Err(SwapError::InvalidOwner.into()),
+            accounts.set_fee_accounts(
+                &other_key,
+                &token_a_fee_key,
+                &mut token_a_fee_account,
+                &token_b_fee_key,
+                &mut token_b_fee_account,
+            )
+        );
+
+        // fee accounts swapped around hold the wrong mints
+        assert_eq!(
+            Err(SwapError::IncorrectFeeAccount.into()),
+            accounts.set_fee_accounts(
+                &owner_key,
+                &token_b_fee_key,
+                &mut token_b_fee_account.clone(),
+                &token_a_fee_key,
+                &mut token_a_fee_account.clone(),
+            )
+        );
+
+        // the swap authority may not collect the fees itself
+        let authority_key = accounts.authority_key;
+        let (authority_fee_key, mut authority_fee_account) = mint_token(
+            &spl_token::id(),
+            &accounts.token_a_mint_key,
+            &mut accounts.token_a_mint_account,
+            &owner_key,
+            &authority_key,
+            0,
+        );
+        assert_eq!(
+            Err(SwapError::InvalidOutputOwner.into()),
+            accounts.set_fee_accounts(
+                &owner_key,
+                &authority_fee_key,
+                &mut authority_fee_account,
+                &token_b_fee_key,
+                &mut token_b_fee_account,
+            )
+        );
+
+        accounts
+            .set_fee_accounts(
+                &owner_key,
+                &token_a_fee_key,
+                &mut token_a_fee_account,
+                &token_b_fee_key,
+                &mut token_b_fee_account,
+            )
+            .unwrap();
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert_eq!(*swap_state.token_a_fee_account(), token_a_fee_key);
+        assert_eq!(*swap_state.token_b_fee_account(), token_b_fee_key);
+    }
+
+    #[test]
+    fn test_collect_owner_fees() {
+        let user_key = Pubkey::new_unique();
+        let owner_key = Pubkey::new_unique();
+        let fees = Fees {
+            owner_trade_fee_numerator: 1,
+            owner_trade_fee_denominator: 50,
+            ..TEST_FEES
+        };
+        let mut accounts = SwapAccountInfo::new(
+            &owner_key,
+            fees,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&owner_key, &user_key, 300, 300, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+
+        // nothing owed yet
+        accounts.collect_owner_fees(&owner_key).unwrap();
+        assert_eq!(
+            token_amount(&accounts.pool_token_account),
+            to_u64(INITIAL_SWAP_POOL_AMOUNT).unwrap()
+        );
+
+        // owner fees accrue over swaps in both directions
+        let mut owed = 0;
+        for _ in 0..3 {
+            accounts
+                .swap(
+                    &user_key,
+                    &token_a_key,
+                    &mut token_a_account,
+                    &swap_token_a_key,
+                    &swap_token_b_key,
+                    &token_b_key,
+                    &mut token_b_account,
+                    50,
+                    1,
+                )
+                .unwrap();
+            accounts
+                .swap(
+                    &user_key,
+                    &token_b_key,
+                    &mut token_b_account,
+                    &swap_token_b_key,
+                    &swap_token_a_key,
+                    &token_a_key,
+                    &mut token_a_account,
+                    50,
+                    1,
+                )
+                .unwrap();
+            let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+            assert!(swap_state.owner_fees_owed > owed);
+            owed = swap_state.owner_fees_owed;
+        }
+        let fee_a_key = accounts.token_a_fee_key;
+        let fee_b_key = accounts.token_b_fee_key;
+        assert_eq!(token_amount(accounts.get_token_account(&fee_a_key)), 0);
+        assert_eq!(token_amount(accounts.get_token_account(&fee_b_key)), 0);
+
+        // only the owner may collect
+        assert_eq!(
+            Err(SwapError::InvalidOwner.into()),
+            accounts.collect_owner_fees(&user_key)
+        );
+
+        accounts.collect_owner_fees(&owner_key).unwrap();
+        let expected = to_u64(INITIAL_SWAP_POOL_AMOUNT).unwrap() + owed;
+        assert_eq!(token_amount(&accounts.pool_token_account), expected);
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert_eq!(swap_state.owner_fees_owed, 0);
+        assert_eq!(swap_state.pool_token_supply, expected);
+        let pool_mint = SplMint::unpack(&accounts.pool_mint_account.data).unwrap();
+        assert_eq!(pool_mint.supply, expected);
+
+        // and only once
+        accounts.collect_owner_fees(&owner_key).unwrap();
+        assert_eq!(token_amount(&accounts.pool_token_account), expected);
+    }
+
+    #[test]
+    fn test_pool_token_decimals() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let pool_mint = SplMint::unpack(&accounts.pool_mint_account.data).unwrap();
+        let mut swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert_eq!(swap_state.pool_token_decimals, pool_mint.decimals);
+
+        // a stored value out of line with the mint is rejected
+        let (
+            token_a_key,
+            mut token_a_account,
+            token_b_key,
+            mut token_b_account,
+            pool_key,
+            mut pool_account,
+        ) = accounts.setup_token_accounts(&user_key, &user_key, 1000, 1000, 0);
+        swap_state.pool_token_decimals = pool_mint.decimals + 1;
+        SwapState::pack(swap_state, &mut accounts.swap_account.data).unwrap();
+        assert_eq!(
+            Err(SwapError::IncorrectPoolMint.into()),
+            accounts.deposit(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                to_u64(INITIAL_SWAP_POOL_AMOUNT / 10).unwrap(),
+                1000,
+                1000,
+            )
+        );
+    }
+
+    #[test]
+    fn test_zero_amount_swap() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 100, 0, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+
+        assert_eq!(
+            Err(SwapError::ZeroTradingTokens.into()),
+            accounts.swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                0,
+                0,
+            )
+        );
+        assert_eq!(token_amount(&token_a_account), 100);
+        assert_eq!(token_amount(&token_b_account), 0);
+        assert_eq!(
+            SwapState::unpack(&accounts.swap_account.data).unwrap(),
+            swap_state
+        );
+    }
+
+    #[test]
+    fn test_zero_amount_out_swap() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1_000_000,
+            1000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let (token_a_key, mut token_a_account, token_b_key, mut token_b_account, _, _) =
+            accounts.setup_token_accounts(&user_key, &user_key, 2000, 0, 0);
+        let swap_token_a_key = accounts.token_a_key;
+        let swap_token_b_key = accounts.token_b_key;
+
+        // 9 token A traded after fees: 1000 - 1_000_000 * 1000 / 1_000_009
+        // rounds to no token B, even with no minimum out
+        assert_eq!(
+            Err(SwapError::ZeroTradingTokens.into()),
+            accounts.swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                10,
+                0,
+            )
+        );
+        assert_eq!(token_amount(&token_a_account), 2000);
+        assert_eq!(token_amount(&accounts.token_a_account), 1_000_000);
+
+        // enough token A for one token B goes through
+        accounts
+            .swap(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &swap_token_a_key,
+                &swap_token_b_key,
+                &token_b_key,
+                &mut token_b_account,
+                2000,
+                0,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&token_b_account), 1);
+    }
+
+    #[test]
+    fn test_liquidity_return_data() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        let supply = INITIAL_SWAP_POOL_AMOUNT;
+
+        let (
+            token_a_key,
+            mut token_a_account,
+            token_b_key,
+            mut token_b_account,
+            pool_key,
+            mut pool_account,
+        ) = accounts.setup_token_accounts(&user_key, &user_key, 1000, 2000, 0);
+        let pool_token_amount = to_u64(supply / 3).unwrap();
+        accounts
+            .deposit(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                pool_token_amount,
+                1000,
+                2000,
+            )
+            .unwrap();
+        let (program_id, data) = get_return_data().unwrap();
+        assert_eq!(program_id, SWAP_PROGRAM_ID);
+        let deposited = LiquidityDeltas::from_return_data(&data).unwrap();
+        assert_eq!(
+            deposited,
+            LiquidityDeltas {
+                pool_token_delta: pool_token_amount,
+                token_a_delta: deposit_token_amount(
+                    1000,
+                    u128::from(pool_token_amount),
+                    supply,
+                    true
+                )
+                .unwrap(),
+                token_b_delta: deposit_token_amount(
+                    2000,
+                    u128::from(pool_token_amount),
+                    supply,
+                    true
+                )
+                .unwrap(),
+            }
+        );
+        assert_eq!(token_amount(&pool_account), deposited.pool_token_delta);
+        assert_eq!(
+            token_amount(&token_a_account),
+            1000 - deposited.token_a_delta
+        );
+        assert_eq!(
+            token_amount(&token_b_account),
+            2000 - deposited.token_b_delta
+        );
+
+        let reserve_a = token_amount(&accounts.token_a_account);
+        let reserve_b = token_amount(&accounts.token_b_account);
+        let supply = supply + u128::from(pool_token_amount);
+        let token_a_before = token_amount(&token_a_account);
+        let token_b_before = token_amount(&token_b_account);
+        accounts
+            .withdraw(
+                &user_key,
+                &pool_key,
+                &mut pool_account,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                pool_token_amount,
+                0,
+                0,
+            )
+            .unwrap();
+        let (program_id, data) = get_return_data().unwrap();
+        assert_eq!(program_id, SWAP_PROGRAM_ID);
+        let withdrawn = LiquidityDeltas::from_return_data(&data).unwrap();
+        assert_eq!(
+            withdrawn,
+            LiquidityDeltas {
+                pool_token_delta: pool_token_amount,
+                token_a_delta: to_u64(
+                    withdraw_token_amount(reserve_a, u128::from(pool_token_amount), supply, false)
+                        .unwrap()
+                )
+                .unwrap(),
+                token_b_delta: to_u64(
+                    withdraw_token_amount(reserve_b, u128::from(pool_token_amount), supply, false)
+                        .unwrap()
+                )
+                .unwrap(),
+            }
+        );
+        assert_eq!(token_amount(&pool_account), 0);
+        assert_eq!(
+            token_amount(&token_a_account),
+            token_a_before + withdrawn.token_a_delta
+        );
+        assert_eq!(
+            token_amount(&token_b_account),
+            token_b_before + withdrawn.token_b_delta
+        );
+    }
+
+    #[test]
+    fn test_unequal_decimals_deposit_precision() {
+        // one token A at 6 decimals against three token B at 9 decimals
+        let user_key = Pubkey::new_unique();
+        let reserve_a = 1_000_000;
+        let reserve_b = 3_000_000_000;
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            reserve_a,
+            reserve_b,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        for (mint_account, decimals) in [
+            (&mut accounts.token_a_mint_account, 6),
+            (&mut accounts.token_b_mint_account, 9),
+        ] {
+            let mut mint = SplMint::unpack(&mint_account.data).unwrap();
+            mint.decimals = decimals;
+            SplMint::pack(mint, &mut mint_account.data).unwrap();
+        }
+        accounts.initialize_swap().unwrap();
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert_eq!(swap_state.token_a_decimals, 6);
+        assert_eq!(swap_state.token_b_decimals, 9);
+
+        let (
+            token_a_key,
+            mut token_a_account,
+            token_b_key,
+            mut token_b_account,
+            pool_key,
+            mut pool_account,
+        ) = accounts.setup_token_accounts(&user_key, &user_key, reserve_a, reserve_b, 0);
+        // each side is computed in its own smallest unit, so the deposit is
+        // within one unit of the exact share on both, whatever the decimals
+        let supply = INITIAL_SWAP_POOL_AMOUNT;
+        let pool_token_amount = 333_333_333;
+        let deposit = |expected_ratio_numerator, expected_ratio_denominator| {
+            SwapInstruction::DepositTokens(DepositTokens {
+                pool_token_amount,
+                maximum_token_a_amount: reserve_a,
+                maximum_token_b_amount: reserve_b,
+                expected_ratio_numerator,
+                expected_ratio_denominator,
+            })
+        };
+        // the expected ratio is in whole tokens, so the raw 1 / 3_000 is
+        // rejected and the whole 1 / 3 accepted
+        assert_eq!(
+            Err(SwapError::ExceededSlippage.into()),
+            accounts.deposit_with_data(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                reserve_a,
+                reserve_b,
+                deposit(reserve_a, reserve_b),
+            )
+        );
+        accounts
+            .deposit_with_data(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                reserve_a,
+                reserve_b,
+                deposit(1, 3),
+            )
+            .unwrap();
+        for (reserve, deposited) in [
+            (reserve_a, reserve_a - token_amount(&token_a_account)),
+            (reserve_b, reserve_b - token_amount(&token_b_account)),
+        ] {
+            let exact = u128::from(reserve) * u128::from(pool_token_amount);
+            assert!(u128::from(deposited) * supply >= exact);
+            assert!(u128::from(deposited - 1) * supply < exact);
+        }
+        // 333_333.333 token A rounds up, 999_999_999 token B is exact
+        assert_eq!(token_amount(&token_a_account), reserve_a - 333_334);
+        assert_eq!(token_amount(&token_b_account), reserve_b - 999_999_999);
+    }
+
+    #[test]
+    fn test_round_up_deposits() {
+        // 1.5 token A per 1_500_000 pool tokens, 3 token B exactly
+        let pool_token_amount = 1_500_000;
+        for (round_up_deposits, deposited_a, withdrawn_a) in [(true, 2, 1), (false, 1, 2)] {
+            let user_key = Pubkey::new_unique();
+            let mut accounts = SwapAccountInfo::new(
+                &user_key,
+                TEST_FEES,
+                1000,
+                2000,
+                &spl_token::id(),
+                &spl_token::id(),
+            );
+            accounts.round_up_deposits = round_up_deposits;
+            accounts.initialize_swap().unwrap();
+            let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+            assert_eq!(swap_state.round_up_deposits, round_up_deposits);
+
+            let (
+                token_a_key,
+                mut token_a_account,
+                token_b_key,
+                mut token_b_account,
+                pool_key,
+                mut pool_account,
+            ) = accounts.setup_token_accounts(&user_key, &user_key, 1000, 2000, 0);
+            accounts
+                .deposit(
+                    &user_key,
+                    &token_a_key,
+                    &mut token_a_account,
+                    &token_b_key,
+                    &mut token_b_account,
+                    &pool_key,
+                    &mut pool_account,
+                    pool_token_amount,
+                    1000,
+                    2000,
+                )
+                .unwrap();
+            assert_eq!(token_amount(&token_a_account), 1000 - deposited_a);
+            assert_eq!(token_amount(&token_b_account), 2000 - 3);
+
+            // burning the same amount is again worth between one and two
+            // token A
+            let withdraw_amount = pool_token_amount;
+            let reserve_a = token_amount(&accounts.token_a_account);
+            let supply = INITIAL_SWAP_POOL_AMOUNT + u128::from(pool_token_amount);
+            let exact_a = u128::from(reserve_a) * u128::from(withdraw_amount);
+            assert_ne!(exact_a % supply, 0);
+            accounts
+                .withdraw(
+                    &user_key,
+                    &pool_key,
+                    &mut pool_account,
+                    &token_a_key,
+                    &mut token_a_account,
+                    &token_b_key,
+                    &mut token_b_account,
+                    withdraw_amount,
+                    0,
+                    0,
+                )
+                .unwrap();
+            assert_eq!(
+                token_amount(&token_a_account),
+                1000 - deposited_a + withdrawn_a
+            );
+        }
+    }
+
+    #[test]
+    fn test_query_reserves() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+        assert_eq!(
+            accounts.query_reserves(),
+            Ok(PoolReserves {
+                reserve_a: 1000,
+                reserve_b: 2000,
+                pool_supply: to_u64(INITIAL_SWAP_POOL_AMOUNT).unwrap(),
+            })
+        );
+
+        let (
+            token_a_key,
+            mut token_a_account,
+            token_b_key,
+            mut token_b_account,
+            pool_key,
+            mut pool_account,
+        ) = accounts.setup_token_accounts(&user_key, &user_key, 1000, 1000, 0);
+        accounts
+            .deposit(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                to_u64(INITIAL_SWAP_POOL_AMOUNT / 10).unwrap(),
+                100,
+                200,
+            )
+            .unwrap();
+        assert_eq!(
+            accounts.query_reserves(),
+            Ok(PoolReserves {
+                reserve_a: 1100,
+                reserve_b: 2200,
+                pool_supply: to_u64(INITIAL_SWAP_POOL_AMOUNT / 10 * 11).unwrap(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_verify_authority() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initialize_swap().unwrap();
+
+        let authority_key = accounts.authority_key;
+        assert_eq!(accounts.verify_authority(&authority_key), Ok(true));
+        assert_eq!(accounts.verify_authority(&Pubkey::new_unique()), Ok(false));
+
+        // a corrupted bump no longer derives the cached authority
+        let mut swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        swap_state.bump_seed = swap_state.bump_seed.wrapping_sub(1);
+        SwapState::pack(swap_state, &mut accounts.swap_account.data).unwrap();
+        assert_eq!(accounts.verify_authority(&authority_key), Ok(false));
+    }
+
+    #[test]
+    fn test_initial_pool_amount() {
+        let user_key = Pubkey::new_unique();
+        let mut accounts = SwapAccountInfo::new(
+            &user_key,
+            TEST_FEES,
+            1000,
+            2000,
+            &spl_token::id(),
+            &spl_token::id(),
+        );
+        accounts.initial_pool_amount = MAX_INITIAL_POOL_AMOUNT + 1;
+        assert_eq!(
+            Err(SwapError::InvalidInput.into()),
+            accounts.initialize_swap()
+        );
+        accounts.initial_pool_amount = 1_000_000;
+        // fewer pool tokens than the initializer expects
+        accounts.minimum_pool_token_amount = 1_000_001;
+        assert_eq!(
+            Err(SwapError::ExceededSlippage.into()),
+            accounts.initialize_swap()
+        );
+        assert_eq!(token_amount(&accounts.pool_token_account), 0);
+        accounts.minimum_pool_token_amount = 1_000_000;
+        accounts.initialize_swap().unwrap();
+        assert_eq!(token_amount(&accounts.pool_token_account), 1_000_000);
+        let swap_state = SwapState::unpack(&accounts.swap_account.data).unwrap();
+        assert_eq!(swap_state.pool_token_supply, 1_000_000);
+        assert_eq!(swap_state.initial_pool_amount, 1_000_000);
+
+        // deposits are priced against the custom supply
+        let (
+            token_a_key,
+            mut token_a_account,
+            token_b_key,
+            mut token_b_account,
+            pool_key,
+            mut pool_account,
+        ) = accounts.setup_token_accounts(&user_key, &user_key, 1000, 1000, 0);
+        accounts
+            .deposit(
+                &user_key,
+                &token_a_key,
+                &mut token_a_account,
+                &token_b_key,
+                &mut token_b_account,
+                &pool_key,
+                &mut pool_account,
+                100_000,
+                100,
+                200,
+            )
+            .unwrap();
+        assert_eq!(token_amount(&pool_account), 100_000);
+        assert_eq!(token_amount(&accounts.token_a_account), 1100);
+        assert_eq!(token_amount(&accounts.token_b_account), 2200);
+    }
+
+    /// Runs `Migrate` against a swap account laid out the way the runtime
+    /// serializes it: the data length sits in the 8 bytes before the data,
+    /// followed by room to grow
+    fn migrate_swap_account(
+        swap_buffer: &mut [u8],
+        swap_lamports: u64,
+        upgrade_authority_key: &Pubkey,
+        program_data: &[u8],
+        mints: &[(Pubkey, SplMint)],
+    ) -> ProgramResult {
+        let swap_key = Pubkey::new_unique();
+        let mut swap_lamports = swap_lamports;
+        let data_len = u64::from_le_bytes(swap_buffer[..8].try_into().unwrap()) as usize;
+        let swap_data = &mut swap_buffer[8..8 + data_len];
+
+        let (program_data_key, _) = Pubkey::find_program_address(
+            &[SWAP_PROGRAM_ID.as_ref()],
+            &bpf_loader_upgradeable::id(),
+        );
+        let mut program_data_lamports = 0;
+        let mut program_data = program_data.to_vec();
+        let mut upgrade_authority_lamports = 0;
+        let mut upgrade_authority_data = vec![];
+        let mut mint_lamports = vec![0; mints.len()];
+        let mut mint_data = mints
+            .iter()
+            .map(|(_, mint)| {
+                let mut data = vec![0; SplMint::get_packed_len()];
+                SplMint::pack(*mint, &mut data).unwrap();
+                data
+            })
+            .collect::<Vec<_>>();
+        let mut rent_account = create_account_for_test(&Rent::default());
+        let rent_key = sysvar::rent::id();
+        let bpf_loader_upgradeable_id = bpf_loader_upgradeable::id();
+        let system_program_id = Pubkey::default();
+        let token_program_id = spl_token::id();
+
+        let mut account_infos = vec![
+            AccountInfo::new(
+                &swap_key,
+                false,
+                true,
+                &mut swap_lamports,
+                swap_data,
+                &SWAP_PROGRAM_ID,
+                false,
+                0,
+            ),
+            AccountInfo::new(
+                upgrade_authority_key,
+                true,
+                false,
+                &mut upgrade_authority_lamports,
+                &mut upgrade_authority_data,
+                &system_program_id,
+                false,
+                0,
+            ),
+            AccountInfo::new(
+                &program_data_key,
+                false,
+                false,
+                &mut program_data_lamports,
+                &mut program_data,
+                &bpf_loader_upgradeable_id,
+                false,
+                0,
+            ),
+        ];
+        for (((key, _), lamports), data) in mints
+            .iter()
+            .zip(mint_lamports.iter_mut())
+            .zip(mint_data.iter_mut())
+        {
+            account_infos.push(AccountInfo::new(
+                key,
+                false,
+                false,
+                lamports,
+                data,
+                &token_program_id,
+                false,
+                0,
+            ));
+        }
+        account_infos.push(AccountInfo::new(
+            &rent_key,
+            false,
+            false,
+            &mut rent_account.lamports,
+            &mut rent_account.data,
+            &rent_account.owner,
+            false,
+            0,
+        ));
+
+        Processor::process(
+            &SWAP_PROGRAM_ID,
+            &account_infos,
+            &SwapInstruction::Migrate.pack(),
+        )
+    }
+
+    #[test]
+    fn test_migrate() {
+        let upgrade_authority_key = Pubkey::new_unique();
+        let mut program_data = vec![];
+        program_data.extend_from_slice(&3u32.to_le_bytes());
+        program_data.extend_from_slice(&42u64.to_le_bytes());
+        program_data.push(1);
+        program_data.extend_from_slice(upgrade_authority_key.as_ref());
+
+        let pool_mint_key = Pubkey::new_unique();
+        let token_a_mint_key = Pubkey::new_unique();
+        let token_b_mint_key = Pubkey::new_unique();
+        let mint = |supply, decimals| SplMint {
+            supply,
+            decimals,
+            is_initialized: true,
+            ..SplMint::default()
+        };
+        let mints = [
+            (pool_mint_key, mint(5_000, 2)),
+            (token_a_mint_key, mint(1_000, 6)),
+            (token_b_mint_key, mint(2_000, 9)),
+        ];
+
+        let swap_state_v1 = SwapStateV1 {
+            is_initialized: true,
+            bump_seed: 255,
+            token_program_id: spl_token::id(),
+            token_a: Pubkey::new_unique(),
+            token_b: Pubkey::new_unique(),
+            pool_mint: pool_mint_key,
+            token_a_mint: token_a_mint_key,
+            token_b_mint: token_b_mint_key,
+            token_a_fee_account: Pubkey::new_unique(),
+            token_b_fee_account: Pubkey::new_unique(),
+            trade_fee_numerator: 1,
+            trade_fee_denominator: 100,
+        };
+        let mut swap_buffer = vec![0; 8 + SwapState::LEN];
+        swap_buffer[..8].copy_from_slice(&(SwapStateV1::LEN as u64).to_le_bytes());
+        SwapStateV1::pack(
+            swap_state_v1.clone(),
+            &mut swap_buffer[8..8 + SwapStateV1::LEN],
+        )
+        .unwrap();
+        let rent_exempt_lamports = Rent::default().minimum_balance(SwapState::LEN);
+
+        // only the upgrade authority may migrate
+        assert_eq!(
+            Err(SwapError::InvalidOwner.into()),
+            migrate_swap_account(
+                &mut swap_buffer,
+                rent_exempt_lamports,
+                &Pubkey::new_unique(),
+                &program_data,
+                &mints,
+            )
+        );
+
+        // the mints must match the pool
+        assert_eq!(
+            Err(SwapError::IncorrectMint.into()),
+            migrate_swap_account(
+                &mut swap_buffer,
+                rent_exempt_lamports,
+                &upgrade_authority_key,
+                &program_data,
+                &[mints[0], mints[2], mints[1]],
+            )
+        );
+
+        // the larger account must already be rent exempt
+        assert_eq!(
+            Err(ProgramError::AccountNotRentExempt),
+            migrate_swap_account(
+                &mut swap_buffer,
+                rent_exempt_lamports - 1,
+                &upgrade_authority_key,
+                &program_data,
+                &mints,
+            )
+        );
+
+        migrate_swap_account(
+            &mut swap_buffer,
+            rent_exempt_lamports,
+            &upgrade_authority_key,
+            &program_data,
+            &mints,
+        )
+        .unwrap();
+        assert_eq!(
+            u64::from_le_bytes(swap_buffer[..8].try_into().unwrap()),
+            SwapState::LEN as u64
+        );
+        let swap_state = SwapState::unpack(&swap_buffer[8..]).unwrap();
+        assert_eq!(swap_state.owner, upgrade_authority_key);
+        assert_eq!(swap_state.bump_seed, swap_state_v1.bump_seed);
+        assert_eq!(swap_state.token_program_id, spl_token::id());
+        assert_eq!(swap_state.pool_token_program_id, spl_token::id());
+        assert_eq!(swap_state.token_a, swap_state_v1.token_a);
+        assert_eq!(swap_state.token_b, swap_state_v1.token_b);
+        assert_eq!(swap_state.pool_mint, pool_mint_key);
+        assert_eq!(
+            swap_state.token_a_fee_account,
+            swap_state_v1.token_a_fee_account
+        );
+        assert_eq!(
+            swap_state.token_b_fee_account,
+            swap_state_v1.token_b_fee_account
+        );
+        assert_eq!(swap_state.fees.trade_fee_numerator, 1);
+        assert_eq!(swap_state.fees.trade_fee_denominator, 100);
+        assert_eq!(swap_state.fees.owner_trade_fee_numerator, 0);
+        assert_eq!(swap_state.curve_type, CurveType::ConstantProduct);
+        assert_eq!(swap_state.pool_token_supply, 5_000);
+        assert_eq!(swap_state.pool_token_decimals, 2);
+        assert_eq!(swap_state.token_a_decimals, 6);
+        assert_eq!(swap_state.token_b_decimals, 9);
+        assert_eq!(swap_state.owner_fees_owed, 0);
+
+        // a migrated pool cannot be migrated again
+        assert_eq!(
+            Err(SwapError::AlreadyInUse.into()),
+            migrate_swap_account(
+                &mut swap_buffer,
+                rent_exempt_lamports,
+                &upgrade_authority_key,
+                &program_data,
+                &mints,
+            )
+        );
+    }
 }
@@ -0,0 +1,664 @@
+//! Read-only swap quotes, computed the same way as the swap itself
+
+use crate::{
+    curve::TradeDirection,
+    error::SwapError,
+    fees::FeeRounding,
+    state::{SwapState, BPS_DENOMINATOR, PRICE_SCALE},
+};
+use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
+use solana_program::{
+    program_error::ProgramError,
+    program_pack::{IsInitialized, Pack, Sealed},
+};
+
+/// Maximum number of amounts quoted by a single `BatchQuote`, bounding its
+/// compute usage
+pub const MAX_QUOTE_AMOUNTS: usize = 16;
+
+/// Quote returned by `BatchQuote` for an amount that cannot be swapped. No
+/// swap outputs `u64::MAX`, which would drain a full reserve, so it cannot
+/// be mistaken for a real quote, unlike zero.
+pub const FAILED_QUOTE: u64 = u64::MAX;
+
+/// Amount of destination token a swap of `amount_in` source token would
+/// output from the given reserves, after trading fees. Non-decreasing in
+/// `amount_in`, so a larger input is never quoted a smaller output.
+pub fn swap_amount_out(
+    swap_state: &SwapState,
+    amount_in: u64,
+    swap_source_amount: u64,
+    swap_destination_amount: u64,
+    trade_direction: TradeDirection,
+) -> Result<u64, SwapError> {
+    let swap_result = swap_state
+        .swap_curve()?
+        .swap(
+            u128::from(amount_in),
+            u128::from(swap_source_amount),
+            u128::from(swap_destination_amount),
+            trade_direction,
+            swap_state.fees(),
+        )
+        .ok_or(SwapError::ZeroTradingTokens)?;
+    u64::try_from(swap_result.destination_amount_swapped).map_err(|_| SwapError::ConversionFailure)
+}
+
+/// Amount of destination token `amount_in` source token would output at the
+/// marginal price of the given reserves, after trading fees, as if the swap
+/// did not move the price. The benchmark a swap's price impact and slippage
+/// are measured against.
+pub fn spot_amount_out(
+    swap_state: &SwapState,
+    amount_in: u64,
+    swap_source_amount: u64,
+    swap_destination_amount: u64,
+    trade_direction: TradeDirection,
+) -> Result<u64, SwapError> {
+    let (reserve_a, reserve_b) = match trade_direction {
+        TradeDirection::AtoB => (swap_source_amount, swap_destination_amount),
+        TradeDirection::BtoA => (swap_destination_amount, swap_source_amount),
+    };
+    let price = swap_state
+        .swap_curve()?
+        .normalized_value(u128::from(reserve_a), u128::from(reserve_b))
+        .ok_or(SwapError::CalculationFailure)?;
+    let fees = swap_state
+        .fees()
+        .total_trading_fee_rounded(u128::from(amount_in), FeeRounding::Floor)
+        .ok_or(SwapError::FeeCalculationFailure)?;
+    let amount_in = u128::from(amount_in).saturating_sub(fees);
+    let amount_out = match trade_direction {
+        TradeDirection::AtoB => amount_in
+            .checked_mul(price)
+            .map(|amount_out| amount_out / PRICE_SCALE),
+        TradeDirection::BtoA => amount_in
+            .checked_mul(PRICE_SCALE)
+            .and_then(|amount_out| amount_out.checked_div(price)),
+    }
+    .ok_or(SwapError::CalculationFailure)?;
+    u64::try_from(amount_out).map_err(|_| SwapError::ConversionFailure)
+}
+
+/// Least `amount_in`, at most `maximum_amount_in`, whose swap outputs at
+/// least `amount_out` from the given reserves, or `None` if even
+/// `maximum_amount_in` outputs less. Searches on `swap_amount_out` never
+/// decreasing in `amount_in`, so it works for every curve.
+pub fn swap_amount_in(
+    swap_state: &SwapState,
+    amount_out: u64,
+    maximum_amount_in: u64,
+    swap_source_amount: u64,
+    swap_destination_amount: u64,
+    trade_direction: TradeDirection,
+) -> Option<u64> {
+    // an input too small to output anything outputs nothing
+    let fills = |amount_in| {
+        matches!(
+            swap_amount_out(
+                swap_state,
+                amount_in,
+                swap_source_amount,
+                swap_destination_amount,
+                trade_direction,
+            ),
+            Ok(out) if out >= amount_out
+        )
+    };
+    if !fills(maximum_amount_in) {
+        return None;
+    }
+    let (mut low, mut high) = (0, maximum_amount_in);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if fills(middle) {
+            high = middle;
+        } else {
+            low = middle + 1;
+        }
+    }
+    Some(high)
+}
+
+/// Amount of destination token a swap of `amount_in` would output, from a
+/// packed `SwapState` and the reserves alone, for quoting off-chain without
+/// account infos. Fails with `InvalidInput` if the bytes are not an
+/// initialized swap.
+pub fn simulate_swap(
+    swap_state_bytes: &[u8],
+    source_reserve: u64,
+    dest_reserve: u64,
+    amount_in: u64,
+    trade_direction: TradeDirection,
+) -> Result<u64, SwapError> {
+    let swap_state = SwapState::unpack(swap_state_bytes).map_err(|_| SwapError::InvalidInput)?;
+    swap_amount_out(
+        &swap_state,
+        amount_in,
+        source_reserve,
+        dest_reserve,
+        trade_direction,
+    )
+}
+
+/// Least acceptable output for a quoted `amount_out`, given a slippage
+/// tolerance in basis points, rounded down, for use as `minimum_amount_out`.
+/// A tolerance above 10_000 basis points accepts any output.
+pub fn minimum_out_with_slippage(amount_out: u64, slippage_bps: u16) -> u64 {
+    let kept_bps = BPS_DENOMINATOR.saturating_sub(u128::from(slippage_bps));
+    // at most amount_out, so the conversion back cannot fail
+    u64::try_from(u128::from(amount_out) * kept_bps / BPS_DENOMINATOR).unwrap_or(0)
+}
+
+/// Marginal price of token A in token B between the given reserves,
+/// `reserve_b / reserve_a` scaled by `PRICE_SCALE`, or `None` if either
+/// reserve is empty
+pub fn pool_price(reserve_a: u64, reserve_b: u64) -> Option<u128> {
+    if reserve_a == 0 || reserve_b == 0 {
+        return None;
+    }
+    u128::from(reserve_b)
+        .checked_mul(PRICE_SCALE)?
+        .checked_div(u128::from(reserve_a))
+}
+
+/// Token A and B a position of `pool_tokens` out of `pool_supply` is worth
+/// at the given reserves, rounded down as a withdrawal would be. A position
+/// larger than the supply is valued as the whole pool, and an empty supply
+/// values every position at nothing.
+pub fn pool_token_value(
+    pool_tokens: u64,
+    pool_supply: u64,
+    reserve_a: u64,
+    reserve_b: u64,
+) -> (u64, u64) {
+    if pool_supply == 0 {
+        return (0, 0);
+    }
+    let pool_tokens = u128::from(pool_tokens.min(pool_supply));
+    let pool_supply = u128::from(pool_supply);
+    // at most the reserve, so the conversion back cannot fail
+    let share = |reserve: u64| {
+        u64::try_from(u128::from(reserve) * pool_tokens / pool_supply).unwrap_or(reserve)
+    };
+    (share(reserve_a), share(reserve_b))
+}
+
+/// Reserves and pool token supply of a pool, as returned by `QueryReserves`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PoolReserves {
+    /// Token A reserve
+    pub reserve_a: u64,
+    /// Token B reserve
+    pub reserve_b: u64,
+    /// Pool token supply
+    pub pool_supply: u64,
+}
+
+impl PoolReserves {
+    /// Decodes the return data of a `QueryReserves` instruction
+    pub fn from_return_data(data: &[u8]) -> Result<Self, ProgramError> {
+        Self::unpack_unchecked(data)
+    }
+}
+
+/// Pool tokens minted or burned and token amounts moved by a deposit or a
+/// withdrawal, as returned by `DepositTokens`, `DepositTokensExactAmounts`
+/// and `WithdrawTokens`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LiquidityDeltas {
+    /// Pool tokens minted by a deposit or burned by a withdrawal
+    pub pool_token_delta: u64,
+    /// Token A moved into or out of the pool
+    pub token_a_delta: u64,
+    /// Token B moved into or out of the pool
+    pub token_b_delta: u64,
+}
+
+impl LiquidityDeltas {
+    /// Decodes the return data of a deposit or withdrawal
+    pub fn from_return_data(data: &[u8]) -> Result<Self, ProgramError> {
+        Self::unpack_unchecked(data)
+    }
+}
+
+impl IsInitialized for LiquidityDeltas {
+    fn is_initialized(&self) -> bool {
+        true
+    }
+}
+
+impl Sealed for LiquidityDeltas {}
+impl Pack for LiquidityDeltas {
+    const LEN: usize = 24;
+    fn pack_into_slice(&self, output: &mut [u8]) {
+        let output = array_mut_ref![output, 0, 24];
+        let (pool_token_delta, token_a_delta, token_b_delta) = mut_array_refs![output, 8, 8, 8];
+        *pool_token_delta = self.pool_token_delta.to_le_bytes();
+        *token_a_delta = self.token_a_delta.to_le_bytes();
+        *token_b_delta = self.token_b_delta.to_le_bytes();
+    }
+
+    fn unpack_from_slice(input: &[u8]) -> Result<Self, ProgramError> {
+        let input = array_ref![input, 0, 24];
+        #[allow(clippy::ptr_offset_with_cast)]
+        let (pool_token_delta, token_a_delta, token_b_delta) = array_refs![input, 8, 8, 8];
+        Ok(Self {
+            pool_token_delta: u64::from_le_bytes(*pool_token_delta),
+            token_a_delta: u64::from_le_bytes(*token_a_delta),
+            token_b_delta: u64::from_le_bytes(*token_b_delta),
+        })
+    }
+}
+
+impl IsInitialized for PoolReserves {
+    fn is_initialized(&self) -> bool {
+        true
+    }
+}
+
+impl Sealed for PoolReserves {}
+impl Pack for PoolReserves {
+    const LEN: usize = 24;
+    fn pack_into_slice(&self, output: &mut [u8]) {
+        let output = array_mut_ref![output, 0, 24];
+        let (reserve_a, reserve_b, pool_supply) = mut_array_refs![output, 8, 8, 8];
+        *reserve_a = self.reserve_a.to_le_bytes();
+        *reserve_b = self.reserve_b.to_le_bytes();
+        *pool_supply = self.pool_supply.to_le_bytes();
+    }
+
+    fn unpack_from_slice(input: &[u8]) -> Result<Self, ProgramError> {
+        let input = array_ref![input, 0, 24];
+        #[allow(clippy::ptr_offset_with_cast)]
+        let (reserve_a, reserve_b, pool_supply) = array_refs![input, 8, 8, 8];
+        Ok(Self {
+            reserve_a: u64::from_le_bytes(*reserve_a),
+            reserve_b: u64::from_le_bytes(*reserve_b),
+            pool_supply: u64::from_le_bytes(*pool_supply),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fees::Fees;
+    use proptest::{
+        prelude::*,
+        test_runner::{RngAlgorithm, TestRng, TestRunner},
+    };
+
+    /// Seed of the property tests, fixed so any failure reproduces on rerun
+    const PROPTEST_SEED: [u8; 32] = [42; 32];
+
+    fn seeded_runner() -> TestRunner {
+        TestRunner::new_with_rng(
+            ProptestConfig {
+                cases: 2_000,
+                failure_persistence: None,
+                ..ProptestConfig::default()
+            },
+            TestRng::from_seed(RngAlgorithm::ChaCha, &PROPTEST_SEED),
+        )
+    }
+
+    /// Swap state with a random trade and owner fee, each below a quarter
+    fn fees_strategy() -> impl Strategy<Value = SwapState> {
+        (1..=10_000u64, 1..=10_000u64).prop_flat_map(|(trade_denominator, owner_denominator)| {
+            (0..=trade_denominator / 4, 0..=owner_denominator / 4).prop_map(
+                move |(trade_numerator, owner_numerator)| SwapState {
+                    fees: Fees {
+                        trade_fee_numerator: trade_numerator,
+                        trade_fee_denominator: trade_denominator,
+                        owner_trade_fee_numerator: owner_numerator,
+                        owner_trade_fee_denominator: owner_denominator,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+        })
+    }
+
+    /// Reserve or amount at small, medium or large scale, below 2^63 so new
+    /// reserves fit a u64 and their product a u128
+    fn amount_strategy() -> impl Strategy<Value = u64> {
+        prop_oneof![1..=1_000u64, 1..=1_000_000_000u64, 1..=u64::MAX >> 1]
+    }
+
+    #[test]
+    fn swap_amount_out_charges_fees() {
+        let swap_state = SwapState {
+            fees: Fees {
+                trade_fee_numerator: 1,
+                trade_fee_denominator: 100,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        // 100 in, 1 fee: 2000 - 1000 * 2000 / 1099 = 2000 - 1819.8
+        assert_eq!(
+            swap_amount_out(&swap_state, 100, 1000, 2000, TradeDirection::AtoB),
+            Ok(180)
+        );
+        assert_eq!(
+            swap_amount_out(&swap_state, 0, 1000, 2000, TradeDirection::AtoB),
+            Ok(0)
+        );
+    }
+
+    #[test]
+    fn spot_amount_out_ignores_price_impact() {
+        let swap_state = SwapState {
+            fees: Fees {
+                trade_fee_numerator: 1,
+                trade_fee_denominator: 100,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        // 99 after fees at 2 B per A, whatever the trade size
+        assert_eq!(
+            spot_amount_out(&swap_state, 100, 1000, 2000, TradeDirection::AtoB),
+            Ok(198)
+        );
+        assert_eq!(
+            spot_amount_out(&swap_state, 100, 1000, 2000, TradeDirection::BtoA),
+            Ok(198)
+        );
+        assert_eq!(
+            spot_amount_out(&swap_state, 100, 2000, 1000, TradeDirection::AtoB),
+            Ok(49)
+        );
+        // the swap itself moves the price against the trader
+        assert_eq!(
+            swap_amount_out(&swap_state, 100, 1000, 2000, TradeDirection::AtoB),
+            Ok(180)
+        );
+        assert_eq!(
+            spot_amount_out(&swap_state, 100, 0, 2000, TradeDirection::AtoB),
+            Err(SwapError::CalculationFailure)
+        );
+    }
+
+    #[test]
+    fn swap_never_decreases_invariant() {
+        seeded_runner()
+            .run(
+                &(
+                    fees_strategy(),
+                    amount_strategy(),
+                    amount_strategy(),
+                    amount_strategy(),
+                ),
+                |(swap_state, amount_in, reserve_a, reserve_b)| {
+                    let amount_out = match swap_amount_out(
+                        &swap_state,
+                        amount_in,
+                        reserve_a,
+                        reserve_b,
+                        TradeDirection::AtoB,
+                    ) {
+                        Ok(amount_out) => amount_out,
+                        Err(_) => return Ok(()),
+                    };
+                    prop_assert!(amount_out < reserve_b);
+                    // fees stay in the pool with the rest of the input
+                    let new_reserve_a = reserve_a + amount_in;
+                    let new_reserve_b = reserve_b - amount_out;
+                    prop_assert!(
+                        u128::from(new_reserve_a) * u128::from(new_reserve_b)
+                            >= u128::from(reserve_a) * u128::from(reserve_b)
+                    );
+                    Ok(())
+                },
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn swap_round_trip_never_profits() {
+        seeded_runner()
+            .run(
+                &(
+                    fees_strategy(),
+                    amount_strategy(),
+                    amount_strategy(),
+                    amount_strategy(),
+                ),
+                |(swap_state, amount_in, reserve_a, reserve_b)| {
+                    let amount_out = match swap_amount_out(
+                        &swap_state,
+                        amount_in,
+                        reserve_a,
+                        reserve_b,
+                        TradeDirection::AtoB,
+                    ) {
+                        Ok(amount_out) if amount_out > 0 => amount_out,
+                        _ => return Ok(()),
+                    };
+                    let amount_back = match swap_amount_out(
+                        &swap_state,
+                        amount_out,
+                        reserve_b - amount_out,
+                        reserve_a + amount_in,
+                        TradeDirection::BtoA,
+                    ) {
+                        Ok(amount_back) => amount_back,
+                        Err(_) => return Ok(()),
+                    };
+                    prop_assert!(amount_back <= amount_in);
+                    Ok(())
+                },
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn swap_amount_out_never_decreases() {
+        for fees in [
+            Fees::default(),
+            Fees {
+                trade_fee_numerator: 1,
+                trade_fee_denominator: 4,
+                owner_trade_fee_numerator: 1,
+                owner_trade_fee_denominator: 4,
+                ..Default::default()
+            },
+            Fees {
+                trade_fee_numerator: 25,
+                trade_fee_denominator: 10_000,
+                owner_trade_fee_numerator: 5,
+                owner_trade_fee_denominator: 10_000,
+                ..Default::default()
+            },
+        ] {
+            let swap_state = SwapState {
+                fees,
+                ..Default::default()
+            };
+            for (reserve_a, reserve_b) in [(1_000, 1_000), (1_000, 3), (7, 1_000_000)] {
+                let mut last_amount_out = 0;
+                for amount_in in 0..=2_000 {
+                    let amount_out = match swap_amount_out(
+                        &swap_state,
+                        amount_in,
+                        reserve_a,
+                        reserve_b,
+                        TradeDirection::AtoB,
+                    ) {
+                        Ok(amount_out) => amount_out,
+                        Err(_) => continue,
+                    };
+                    assert!(
+                        amount_out >= last_amount_out,
+                        "{} in gave {}, less than {}",
+                        amount_in,
+                        amount_out,
+                        last_amount_out
+                    );
+                    last_amount_out = amount_out;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn swap_amount_out_monotonic() {
+        seeded_runner()
+            .run(
+                &(
+                    fees_strategy(),
+                    amount_strategy(),
+                    amount_strategy(),
+                    amount_strategy(),
+                ),
+                |(swap_state, amount_in, reserve_a, reserve_b)| {
+                    let quote = |amount_in| {
+                        swap_amount_out(
+                            &swap_state,
+                            amount_in,
+                            reserve_a,
+                            reserve_b,
+                            TradeDirection::AtoB,
+                        )
+                    };
+                    if let (Ok(amount_out), Ok(next_amount_out)) =
+                        (quote(amount_in), quote(amount_in + 1))
+                    {
+                        prop_assert!(next_amount_out >= amount_out);
+                    }
+                    Ok(())
+                },
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn swap_amount_in_inverts_amount_out() {
+        let swap_state = SwapState {
+            fees: Fees {
+                trade_fee_numerator: 25,
+                trade_fee_denominator: 10_000,
+                owner_trade_fee_numerator: 5,
+                owner_trade_fee_denominator: 10_000,
+                ..Fees::default()
+            },
+            ..SwapState::default()
+        };
+        let quote_in = |amount_out, maximum_amount_in| {
+            swap_amount_in(
+                &swap_state,
+                amount_out,
+                maximum_amount_in,
+                1_000_000,
+                2_000_000,
+                TradeDirection::AtoB,
+            )
+        };
+        let quote_out = |amount_in| {
+            swap_amount_out(
+                &swap_state,
+                amount_in,
+                1_000_000,
+                2_000_000,
+                TradeDirection::AtoB,
+            )
+            .unwrap_or(0)
+        };
+        for amount_out in [1, 100, 12_345, 1_000_000] {
+            let amount_in = quote_in(amount_out, u64::MAX).unwrap();
+            assert!(quote_out(amount_in) >= amount_out);
+            assert!(quote_out(amount_in - 1) < amount_out);
+            // exactly enough input is enough
+            assert_eq!(quote_in(amount_out, amount_in), Some(amount_in));
+            assert_eq!(quote_in(amount_out, amount_in - 1), None);
+        }
+        // no input drains the whole reserve
+        assert_eq!(quote_in(2_000_000, u64::MAX), None);
+    }
+
+    #[test]
+    fn minimum_out_applies_slippage() {
+        assert_eq!(minimum_out_with_slippage(1000, 0), 1000);
+        // 0.5% of 1000 is 5, of 999 is 4.995 rounded against the trader
+        assert_eq!(minimum_out_with_slippage(1000, 50), 995);
+        assert_eq!(minimum_out_with_slippage(999, 50), 994);
+        assert_eq!(minimum_out_with_slippage(1000, 10_000), 0);
+        assert_eq!(minimum_out_with_slippage(1000, u16::MAX), 0);
+        assert_eq!(minimum_out_with_slippage(u64::MAX, 0), u64::MAX);
+        assert_eq!(minimum_out_with_slippage(0, 50), 0);
+    }
+
+    #[test]
+    fn pool_reserves_from_return_data() {
+        let reserves = PoolReserves {
+            reserve_a: 1000,
+            reserve_b: 2000,
+            pool_supply: 1_000_000_000,
+        };
+        let mut packed = vec![];
+        packed.extend_from_slice(&1000u64.to_le_bytes());
+        packed.extend_from_slice(&2000u64.to_le_bytes());
+        packed.extend_from_slice(&1_000_000_000u64.to_le_bytes());
+        assert_eq!(
+            PoolReserves::from_return_data(&packed),
+            Ok(reserves.clone())
+        );
+
+        let mut data = [0u8; PoolReserves::LEN];
+        reserves.pack_into_slice(&mut data);
+        assert_eq!(data.to_vec(), packed);
+
+        assert_eq!(
+            PoolReserves::from_return_data(&packed[..16]),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn liquidity_deltas_from_return_data() {
+        let deltas = LiquidityDeltas {
+            pool_token_delta: 100_000_000,
+            token_a_delta: 100,
+            token_b_delta: 200,
+        };
+        let mut data = [0u8; LiquidityDeltas::LEN];
+        deltas.pack_into_slice(&mut data);
+        assert_eq!(LiquidityDeltas::from_return_data(&data), Ok(deltas));
+        assert_eq!(
+            LiquidityDeltas::from_return_data(&data[..16]),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn pool_price_scales_reserve_ratio() {
+        assert_eq!(pool_price(1000, 1000), Some(PRICE_SCALE));
+        assert_eq!(pool_price(1000, 2000), Some(2 * PRICE_SCALE));
+        assert_eq!(pool_price(3000, 1000), Some(333_333_333));
+        assert_eq!(
+            pool_price(1, u64::MAX),
+            Some(u128::from(u64::MAX) * PRICE_SCALE)
+        );
+        assert_eq!(pool_price(0, 1000), None);
+        assert_eq!(pool_price(1000, 0), None);
+    }
+
+    #[test]
+    fn pool_token_value_shares_reserves() {
+        // full position
+        assert_eq!(pool_token_value(1_000, 1_000, 1000, 2001), (1000, 2001));
+        assert_eq!(pool_token_value(2_000, 1_000, 1000, 2001), (1000, 2001));
+        // half position, rounded down
+        assert_eq!(pool_token_value(500, 1_000, 1000, 2001), (500, 1000));
+        assert_eq!(
+            pool_token_value(u64::MAX / 2, u64::MAX, u64::MAX, u64::MAX),
+            (u64::MAX / 2, u64::MAX / 2)
+        );
+        // zero position or empty pool
+        assert_eq!(pool_token_value(0, 1_000, 1000, 2001), (0, 0));
+        assert_eq!(pool_token_value(1_000, 0, 1000, 2001), (0, 0));
+    }
+}
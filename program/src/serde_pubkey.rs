@@ -0,0 +1,42 @@
+//! Serde helpers writing pubkeys as base58 strings, as shown by explorers and
+//! the CLI, instead of raw byte arrays
+
+use serde::{de::Error, Deserialize, Deserializer, Serializer};
+use solana_program::pubkey::Pubkey;
+use std::str::FromStr;
+
+/// Serializes a pubkey as a base58 string
+pub fn serialize<S: Serializer>(pubkey: &Pubkey, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(pubkey)
+}
+
+/// Deserializes a pubkey from a base58 string
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Pubkey, D::Error> {
+    let pubkey = String::deserialize(deserializer)?;
+    Pubkey::from_str(&pubkey).map_err(Error::custom)
+}
+
+/// Same as the parent module, for optional pubkeys
+pub mod option {
+    use super::*;
+
+    /// Serializes an optional pubkey as a base58 string or null
+    pub fn serialize<S: Serializer>(
+        pubkey: &Option<Pubkey>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match pubkey {
+            Some(pubkey) => serializer.collect_str(pubkey),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    /// Deserializes an optional pubkey from a base58 string or null
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Pubkey>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|pubkey| Pubkey::from_str(&pubkey).map_err(Error::custom))
+            .transpose()
+    }
+}
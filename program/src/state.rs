@@ -1,16 +1,29 @@
 //! State transition types
 
-use crate::fees::Fees;
+use crate::{
+    curve::{build_swap_curve, CurveType, SwapCurve, TradeDirection},
+    error::SwapError,
+    fees::Fees,
+    pda::swap_authority,
+};
 use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
+use num_enum::TryFromPrimitive;
 use solana_program::{
+    account_info::AccountInfo,
     program_error::ProgramError,
     program_pack::{IsInitialized, Pack, Sealed},
     pubkey::Pubkey,
+    rent::Rent,
 };
 
 /// Program states.
 #[repr(C)]
-#[derive(Debug, Default, PartialEq)]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SwapState {
     /// Initialized state.
     pub is_initialized: bool,
@@ -21,30 +34,146 @@ pub struct SwapState {
     /// token mint.
     pub bump_seed: u8,
 
+    /// Owner of the pool, allowed to perform privileged operations such as
+    /// closing it.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_pubkey"))]
+    pub owner: Pubkey,
+
     /// Program ID of the tokens being exchanged.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_pubkey"))]
     pub token_program_id: Pubkey,
+    /// Program ID of the pool token mint, which may differ from the program
+    /// of the tokens being exchanged.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_pubkey"))]
+    pub pool_token_program_id: Pubkey,
 
     /// Token A
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_pubkey"))]
     pub token_a: Pubkey,
     /// Token B
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_pubkey"))]
     pub token_b: Pubkey,
 
     /// Pool tokens are issued when A or B tokens are deposited.
     /// Pool tokens can be withdrawn back to the original A or B token.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_pubkey"))]
     pub pool_mint: Pubkey,
 
     /// Mint information for token A
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_pubkey"))]
     pub token_a_mint: Pubkey,
     /// Mint information for token B
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_pubkey"))]
     pub token_b_mint: Pubkey,
 
     /// token a account to receive trading and / or withdrawal fees
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_pubkey"))]
     pub token_a_fee_account: Pubkey,
     /// token b account to receive trading and / or withdrawal fees
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_pubkey"))]
     pub token_b_fee_account: Pubkey,
 
     /// All fee information
     pub fees: Fees,
+
+    /// Curve used to calculate swaps
+    pub curve_type: CurveType,
+    /// Curve-specific parameter, the token B offset for `CurveType::Offset`,
+    /// the whole token B per whole token A for `CurveType::ConstantPrice`
+    /// or the amplification coefficient for `CurveType::Stable`
+    pub curve_parameter: u64,
+
+    /// Numerator of the largest share of the destination side's fee account
+    /// balance a single swap may output, for reward pools subsidizing swaps
+    /// from their fee accounts
+    pub reward_cap_numerator: u64,
+    /// Denominator of the reward cap, zero when swaps are uncapped
+    pub reward_cap_denominator: u64,
+
+    /// Token B per token A price of the last swap, scaled by `PRICE_SCALE`
+    pub last_swap_price: u128,
+    /// Largest deviation from the last swap price allowed for a swap, in
+    /// basis points, zero when swaps are not bound to the last price
+    pub price_band_bps: u16,
+
+    /// Pool token supply, updated on every mint and burn by the program so
+    /// swaps need not read the pool mint. Pool tokens burned directly through
+    /// the token program are picked up by the next instruction given the
+    /// pool mint.
+    pub pool_token_supply: u64,
+
+    /// Decimals of the token A mint
+    pub token_a_decimals: u8,
+    /// Decimals of the token B mint
+    pub token_b_decimals: u8,
+    /// Decimals of the pool mint, so pool token amounts can be displayed
+    /// without reading the mint
+    pub pool_token_decimals: u8,
+
+    /// Token account a flash loan is outstanding from, only meaningful
+    /// while `flash_loan_balance` is non-zero
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_pubkey"))]
+    pub flash_loan_reserve: Pubkey,
+    /// Balance the flash loan reserve must be restored to, the balance before
+    /// the loan plus the flash loan fee, zero when no loan is outstanding
+    pub flash_loan_balance: u64,
+
+    /// Pool token amount minted on initialization, and again when the pool
+    /// is refilled after every pool token was burned
+    pub initial_pool_amount: u64,
+
+    /// Pool tokens owed to the owner for the owner fees kept in the reserves,
+    /// minted and reset by `CollectOwnerFees`
+    pub owner_fees_owed: u64,
+
+    /// Round proportional deposits up and withdrawals down, favoring the
+    /// liquidity providers. When unset deposits round down and withdrawals
+    /// up, favoring the user, which lets repeated tiny withdrawals drain the
+    /// pool one unit at a time
+    pub round_up_deposits: bool,
+
+    /// Largest move of the pool price a single swap may cause, in basis
+    /// points of the price before it, zero to leave swaps unbounded
+    pub max_price_impact_bps: u16,
+
+    /// Token B the owner has added through `Rebalance` and not taken back,
+    /// the most a later `Rebalance` may remove, so the owner cannot take
+    /// the liquidity providers' tokens
+    pub owner_rebalance_amount: u64,
+}
+
+/// Scale of the fixed-point prices stored in the swap state
+pub const PRICE_SCALE: u128 = 1_000_000_000;
+
+/// Denominator of basis point amounts
+pub const BPS_DENOMINATOR: u128 = 10_000;
+
+/// Token B per token A price of a swap, scaled by `PRICE_SCALE`
+pub fn swap_price(
+    amount_in: u128,
+    amount_out: u128,
+    trade_direction: TradeDirection,
+) -> Option<u128> {
+    match trade_direction {
+        TradeDirection::AtoB => amount_out.checked_mul(PRICE_SCALE)?.checked_div(amount_in),
+        TradeDirection::BtoA => amount_in.checked_mul(PRICE_SCALE)?.checked_div(amount_out),
+    }
+}
+
+/// Seeds of the swap authority, a program address derived from the swap
+/// account and its bump seed
+pub fn authority_signer_seeds<'a>(swap_key: &'a Pubkey, bump_seed: &'a u8) -> [&'a [u8]; 2] {
+    [swap_key.as_ref(), std::slice::from_ref(bump_seed)]
+}
+
+/// Lamports needed to make every account created for a new pool rent
+/// exempt: the swap account, both reserves, the pool mint, both fee
+/// accounts and the account receiving the initial pool tokens
+pub fn initialization_rent(rent: &Rent) -> u64 {
+    let token_account = rent.minimum_balance(spl_token::state::Account::LEN);
+    rent.minimum_balance(SwapState::LEN)
+        + rent.minimum_balance(spl_token::state::Mint::LEN)
+        + 5 * token_account
 }
 
 /// SwapState representing access to program state
@@ -59,11 +188,21 @@ impl SwapState {
         self.bump_seed
     }
 
+    /// Owner of the pool
+    pub fn owner(&self) -> &Pubkey {
+        &self.owner
+    }
+
     /// Token program ID associated with the swap
     pub fn token_program_id(&self) -> &Pubkey {
         &self.token_program_id
     }
 
+    /// Token program ID associated with the pool token mint
+    pub fn pool_token_program_id(&self) -> &Pubkey {
+        &self.pool_token_program_id
+    }
+
     /// Address of token A liquidity account
     pub fn token_a_account(&self) -> &Pubkey {
         &self.token_a
@@ -99,10 +238,199 @@ impl SwapState {
         &self.token_b_fee_account
     }
 
+    /// Owner of the pool, by value
+    pub fn owner_key(&self) -> Pubkey {
+        self.owner
+    }
+
+    /// Token program ID associated with the swap, by value
+    pub fn token_program_key(&self) -> Pubkey {
+        self.token_program_id
+    }
+
+    /// Token program ID associated with the pool token mint, by value
+    pub fn pool_token_program_key(&self) -> Pubkey {
+        self.pool_token_program_id
+    }
+
+    /// Address of token A liquidity account, by value. Unlike
+    /// `token_a_account`, the result outlives the state:
+    ///
+    /// ```
+    /// use solana_program::pubkey::Pubkey;
+    /// use token_swap::state::SwapState;
+    ///
+    /// let swap_state = SwapState::default();
+    /// let borrowed: &Pubkey = swap_state.token_a_account();
+    /// assert_eq!(*borrowed, Pubkey::default());
+    /// let keys: Vec<Pubkey> = vec![swap_state.token_a_key(), swap_state.pool_mint_key()];
+    /// drop(swap_state);
+    /// assert_eq!(keys, vec![Pubkey::default(); 2]);
+    /// ```
+    pub fn token_a_key(&self) -> Pubkey {
+        self.token_a
+    }
+
+    /// Address of token B liquidity account, by value
+    pub fn token_b_key(&self) -> Pubkey {
+        self.token_b
+    }
+
+    /// Address of pool token mint, by value
+    pub fn pool_mint_key(&self) -> Pubkey {
+        self.pool_mint
+    }
+
+    /// Address of token A mint, by value
+    pub fn token_a_mint_key(&self) -> Pubkey {
+        self.token_a_mint
+    }
+
+    /// Address of token B mint, by value
+    pub fn token_b_mint_key(&self) -> Pubkey {
+        self.token_b_mint
+    }
+
+    /// Address of token a fee account, by value
+    pub fn token_a_fee_account_key(&self) -> Pubkey {
+        self.token_a_fee_account
+    }
+
+    /// Address of token b fee account, by value
+    pub fn token_b_fee_account_key(&self) -> Pubkey {
+        self.token_b_fee_account
+    }
+
     /// Fees associated with swap
     pub fn fees(&self) -> &Fees {
         &self.fees
     }
+
+    /// Token A and B reserves held by the given accounts, after checking
+    /// they are the pool's reserve accounts owned by its token program and
+    /// still hold the pool's mints
+    pub fn reserves_from_accounts(
+        &self,
+        token_a_info: &AccountInfo,
+        token_b_info: &AccountInfo,
+    ) -> Result<(u64, u64), SwapError> {
+        let (token_a, token_b) = self.reserve_accounts(token_a_info, token_b_info)?;
+        Ok((token_a.amount, token_b.amount))
+    }
+
+    /// Token A and B reserve accounts, unpacked once with the same checks as
+    /// `reserves_from_accounts`, for callers needing more than the amounts
+    pub fn reserve_accounts(
+        &self,
+        token_a_info: &AccountInfo,
+        token_b_info: &AccountInfo,
+    ) -> Result<(spl_token::state::Account, spl_token::state::Account), SwapError> {
+        if *token_a_info.key != self.token_a || *token_b_info.key != self.token_b {
+            return Err(SwapError::IncorrectSwapAccount);
+        }
+        let reserve = |account_info: &AccountInfo, mint: &Pubkey| {
+            if *account_info.owner != self.token_program_id {
+                return Err(SwapError::IncorrectTokenProgramId);
+            }
+            let account = spl_token::state::Account::unpack(&account_info.data.borrow())
+                .map_err(|_| SwapError::ExpectedAccount)?;
+            if account.mint != *mint {
+                return Err(SwapError::IncorrectMint);
+            }
+            Ok(account)
+        };
+        Ok((
+            reserve(token_a_info, &self.token_a_mint)?,
+            reserve(token_b_info, &self.token_b_mint)?,
+        ))
+    }
+
+    /// Curve type and parameter associated with swap
+    pub fn swap_curve(&self) -> Result<Box<dyn SwapCurve>, SwapError> {
+        build_swap_curve(
+            self.curve_type,
+            self.curve_parameter,
+            self.token_a_decimals,
+            self.token_b_decimals,
+        )
+    }
+
+    /// Whether a flash loan is waiting to be repaid
+    pub fn has_flash_loan(&self) -> bool {
+        self.flash_loan_balance != 0
+    }
+
+    /// Whether swap outputs are capped by the reward account balance
+    pub fn has_reward_cap(&self) -> bool {
+        self.reward_cap_denominator != 0
+    }
+
+    /// Largest swap output allowed given the reward account balance, or
+    /// `None` if swaps are uncapped
+    pub fn reward_budget(&self, reward_balance: u64) -> Option<u128> {
+        (u128::from(reward_balance) * u128::from(self.reward_cap_numerator))
+            .checked_div(u128::from(self.reward_cap_denominator))
+    }
+
+    /// Whether swap outputs must stay within the price band around the last
+    /// swap price
+    pub fn has_price_band(&self) -> bool {
+        self.price_band_bps != 0 && self.last_swap_price != 0
+    }
+
+    /// Smallest output accepted for `amount_in` by the price band around the
+    /// last swap price
+    pub fn price_band_minimum_amount_out(
+        &self,
+        amount_in: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<u128> {
+        let expected_amount_out = match trade_direction {
+            TradeDirection::AtoB => amount_in
+                .checked_mul(self.last_swap_price)?
+                .checked_div(PRICE_SCALE)?,
+            TradeDirection::BtoA => amount_in
+                .checked_mul(PRICE_SCALE)?
+                .checked_div(self.last_swap_price)?,
+        };
+        expected_amount_out
+            .checked_mul(BPS_DENOMINATOR.checked_sub(u128::from(self.price_band_bps))?)?
+            .checked_div(BPS_DENOMINATOR)
+    }
+
+    /// Check the internal invariants of a loaded swap state, returning the
+    /// first violation found. Useful to fail fast on a corrupted account.
+    pub fn validate_self_consistency(
+        &self,
+        program_id: &Pubkey,
+        swap_key: &Pubkey,
+    ) -> Result<(), SwapError> {
+        if self.token_a == self.token_b {
+            return Err(SwapError::IncorrectSwapAccount);
+        }
+        if self.token_a_mint == self.token_b_mint {
+            return Err(SwapError::RepeatedMint);
+        }
+        if self.token_a_fee_account == self.token_b_fee_account {
+            return Err(SwapError::IncorrectFeeAccount);
+        }
+        self.fees.validate()?;
+        self.validate_bump_seed(program_id, swap_key)
+    }
+
+    /// Checks that the stored bump seed is the canonical one for the swap
+    /// account, as unpacking alone cannot without the program id
+    pub fn validate_bump_seed(
+        &self,
+        program_id: &Pubkey,
+        swap_key: &Pubkey,
+    ) -> Result<(), SwapError> {
+        let (_, bump_seed) = swap_authority(program_id, swap_key);
+        if self.bump_seed != bump_seed {
+            return Err(SwapError::InvalidProgramAddress);
+        }
+        Ok(())
+    }
 }
 
 impl Sealed for SwapState {}
@@ -112,15 +440,42 @@ impl IsInitialized for SwapState {
     }
 }
 
+// the field sizes of the layout add up to the packed length
+const _: () = assert!(
+    1 + 1
+        + 10 * 32
+        + Fees::LEN
+        + 1
+        + 8
+        + 8
+        + 8
+        + 16
+        + 2
+        + 8
+        + 1
+        + 1
+        + 1
+        + 32
+        + 8
+        + 8
+        + 8
+        + 1
+        + 2
+        + 8
+        == SwapState::LEN
+);
+
 impl Pack for SwapState {
-    const LEN: usize = 274;
+    const LEN: usize = 491;
 
     fn pack_into_slice(&self, output: &mut [u8]) {
-        let output = array_mut_ref![output, 0, 274];
+        let output = array_mut_ref![output, 0, SwapState::LEN];
         let (
             is_initialized,
             bump_seed,
+            owner,
             token_program_id,
+            pool_token_program_id,
             token_a,
             token_b,
             pool_mint,
@@ -129,10 +484,32 @@ impl Pack for SwapState {
             token_a_fee_account,
             token_b_fee_account,
             fees,
-        ) = mut_array_refs![output, 1, 1, 32, 32, 32, 32, 32, 32, 32, 32, 16];
+            curve_type,
+            curve_parameter,
+            reward_cap_numerator,
+            reward_cap_denominator,
+            last_swap_price,
+            price_band_bps,
+            pool_token_supply,
+            token_a_decimals,
+            token_b_decimals,
+            pool_token_decimals,
+            flash_loan_reserve,
+            flash_loan_balance,
+            initial_pool_amount,
+            owner_fees_owed,
+            round_up_deposits,
+            max_price_impact_bps,
+            owner_rebalance_amount,
+        ) = mut_array_refs![
+            output, 1, 1, 32, 32, 32, 32, 32, 32, 32, 32, 32, 32, 48, 1, 8, 8, 8, 16, 2, 8, 1, 1,
+            1, 32, 8, 8, 8, 1, 2, 8
+        ];
         is_initialized[0] = self.is_initialized as u8;
         bump_seed[0] = self.bump_seed;
+        owner.copy_from_slice(self.owner.as_ref());
         token_program_id.copy_from_slice(self.token_program_id.as_ref());
+        pool_token_program_id.copy_from_slice(self.pool_token_program_id.as_ref());
         token_a.copy_from_slice(self.token_a.as_ref());
         token_b.copy_from_slice(self.token_b.as_ref());
         pool_mint.copy_from_slice(self.pool_mint.as_ref());
@@ -141,16 +518,39 @@ impl Pack for SwapState {
         token_a_fee_account.copy_from_slice(self.token_a_fee_account.as_ref());
         token_b_fee_account.copy_from_slice(self.token_b_fee_account.as_ref());
         self.fees.pack_into_slice(&mut fees[..]);
+        curve_type[0] = self.curve_type as u8;
+        *curve_parameter = self.curve_parameter.to_le_bytes();
+        *reward_cap_numerator = self.reward_cap_numerator.to_le_bytes();
+        *reward_cap_denominator = self.reward_cap_denominator.to_le_bytes();
+        *last_swap_price = self.last_swap_price.to_le_bytes();
+        *price_band_bps = self.price_band_bps.to_le_bytes();
+        *pool_token_supply = self.pool_token_supply.to_le_bytes();
+        token_a_decimals[0] = self.token_a_decimals;
+        token_b_decimals[0] = self.token_b_decimals;
+        pool_token_decimals[0] = self.pool_token_decimals;
+        flash_loan_reserve.copy_from_slice(self.flash_loan_reserve.as_ref());
+        *flash_loan_balance = self.flash_loan_balance.to_le_bytes();
+        *initial_pool_amount = self.initial_pool_amount.to_le_bytes();
+        *owner_fees_owed = self.owner_fees_owed.to_le_bytes();
+        round_up_deposits[0] = self.round_up_deposits as u8;
+        *max_price_impact_bps = self.max_price_impact_bps.to_le_bytes();
+        *owner_rebalance_amount = self.owner_rebalance_amount.to_le_bytes();
     }
 
     /// Unpacks a byte buffer into a [SwapState](struct.SwapState.html).
     fn unpack_from_slice(input: &[u8]) -> Result<Self, ProgramError> {
-        let input = array_ref![input, 0, 274];
+        // a truncated account fails cleanly instead of panicking below
+        let input = input
+            .get(..SwapState::LEN)
+            .ok_or(ProgramError::InvalidAccountData)?;
+        let input = array_ref![input, 0, SwapState::LEN];
         #[allow(clippy::ptr_offset_with_cast)]
         let (
             is_initialized,
             bump_seed,
+            owner,
             token_program_id,
+            pool_token_program_id,
             token_a,
             token_b,
             pool_mint,
@@ -159,7 +559,27 @@ impl Pack for SwapState {
             token_a_fee_account,
             token_b_fee_account,
             fees,
-        ) = array_refs![input, 1, 1, 32, 32, 32, 32, 32, 32, 32, 32, 16];
+            curve_type,
+            curve_parameter,
+            reward_cap_numerator,
+            reward_cap_denominator,
+            last_swap_price,
+            price_band_bps,
+            pool_token_supply,
+            token_a_decimals,
+            token_b_decimals,
+            pool_token_decimals,
+            flash_loan_reserve,
+            flash_loan_balance,
+            initial_pool_amount,
+            owner_fees_owed,
+            round_up_deposits,
+            max_price_impact_bps,
+            owner_rebalance_amount,
+        ) = array_refs![
+            input, 1, 1, 32, 32, 32, 32, 32, 32, 32, 32, 32, 32, 48, 1, 8, 8, 8, 16, 2, 8, 1, 1, 1,
+            32, 8, 8, 8, 1, 2, 8
+        ];
         Ok(Self {
             is_initialized: match is_initialized {
                 [0] => false,
@@ -167,7 +587,9 @@ impl Pack for SwapState {
                 _ => return Err(ProgramError::InvalidAccountData),
             },
             bump_seed: bump_seed[0],
+            owner: Pubkey::new_from_array(*owner),
             token_program_id: Pubkey::new_from_array(*token_program_id),
+            pool_token_program_id: Pubkey::new_from_array(*pool_token_program_id),
             token_a: Pubkey::new_from_array(*token_a),
             token_b: Pubkey::new_from_array(*token_b),
             pool_mint: Pubkey::new_from_array(*pool_mint),
@@ -176,6 +598,143 @@ impl Pack for SwapState {
             token_a_fee_account: Pubkey::new_from_array(*token_a_fee_account),
             token_b_fee_account: Pubkey::new_from_array(*token_b_fee_account),
             fees: Fees::unpack_from_slice(fees)?,
+            curve_type: CurveType::try_from_primitive(curve_type[0])
+                .or(Err(ProgramError::InvalidAccountData))?,
+            curve_parameter: u64::from_le_bytes(*curve_parameter),
+            reward_cap_numerator: u64::from_le_bytes(*reward_cap_numerator),
+            reward_cap_denominator: u64::from_le_bytes(*reward_cap_denominator),
+            last_swap_price: u128::from_le_bytes(*last_swap_price),
+            price_band_bps: u16::from_le_bytes(*price_band_bps),
+            pool_token_supply: u64::from_le_bytes(*pool_token_supply),
+            token_a_decimals: token_a_decimals[0],
+            token_b_decimals: token_b_decimals[0],
+            pool_token_decimals: pool_token_decimals[0],
+            flash_loan_reserve: Pubkey::new_from_array(*flash_loan_reserve),
+            flash_loan_balance: u64::from_le_bytes(*flash_loan_balance),
+            initial_pool_amount: u64::from_le_bytes(*initial_pool_amount),
+            owner_fees_owed: u64::from_le_bytes(*owner_fees_owed),
+            round_up_deposits: match round_up_deposits {
+                [0] => false,
+                [1] => true,
+                _ => return Err(ProgramError::InvalidAccountData),
+            },
+            max_price_impact_bps: u16::from_le_bytes(*max_price_impact_bps),
+            owner_rebalance_amount: u64::from_le_bytes(*owner_rebalance_amount),
+        })
+    }
+}
+
+/// Swap state as laid out before the owner, curve and later fields were
+/// added, read by `Migrate` to bring deployed pools to the current layout
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SwapStateV1 {
+    /// Initialized state.
+    pub is_initialized: bool,
+    /// Bump seed used in program address.
+    pub bump_seed: u8,
+    /// Program ID of the tokens being exchanged.
+    pub token_program_id: Pubkey,
+    /// Token A
+    pub token_a: Pubkey,
+    /// Token B
+    pub token_b: Pubkey,
+    /// Pool tokens are issued when A or B tokens are deposited.
+    pub pool_mint: Pubkey,
+    /// Mint information for token A
+    pub token_a_mint: Pubkey,
+    /// Mint information for token B
+    pub token_b_mint: Pubkey,
+    /// token a account to receive trading and / or withdrawal fees
+    pub token_a_fee_account: Pubkey,
+    /// token b account to receive trading and / or withdrawal fees
+    pub token_b_fee_account: Pubkey,
+    /// Trade fee numerator
+    pub trade_fee_numerator: u64,
+    /// Trade fee denominator
+    pub trade_fee_denominator: u64,
+}
+
+impl Sealed for SwapStateV1 {}
+impl IsInitialized for SwapStateV1 {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+// the field sizes of the original layout add up to its packed length
+const _: () = assert!(1 + 1 + 8 * 32 + 8 + 8 == SwapStateV1::LEN);
+
+impl Pack for SwapStateV1 {
+    const LEN: usize = 274;
+
+    fn pack_into_slice(&self, output: &mut [u8]) {
+        let output = array_mut_ref![output, 0, SwapStateV1::LEN];
+        let (
+            is_initialized,
+            bump_seed,
+            token_program_id,
+            token_a,
+            token_b,
+            pool_mint,
+            token_a_mint,
+            token_b_mint,
+            token_a_fee_account,
+            token_b_fee_account,
+            trade_fee_numerator,
+            trade_fee_denominator,
+        ) = mut_array_refs![output, 1, 1, 32, 32, 32, 32, 32, 32, 32, 32, 8, 8];
+        is_initialized[0] = self.is_initialized as u8;
+        bump_seed[0] = self.bump_seed;
+        token_program_id.copy_from_slice(self.token_program_id.as_ref());
+        token_a.copy_from_slice(self.token_a.as_ref());
+        token_b.copy_from_slice(self.token_b.as_ref());
+        pool_mint.copy_from_slice(self.pool_mint.as_ref());
+        token_a_mint.copy_from_slice(self.token_a_mint.as_ref());
+        token_b_mint.copy_from_slice(self.token_b_mint.as_ref());
+        token_a_fee_account.copy_from_slice(self.token_a_fee_account.as_ref());
+        token_b_fee_account.copy_from_slice(self.token_b_fee_account.as_ref());
+        *trade_fee_numerator = self.trade_fee_numerator.to_le_bytes();
+        *trade_fee_denominator = self.trade_fee_denominator.to_le_bytes();
+    }
+
+    fn unpack_from_slice(input: &[u8]) -> Result<Self, ProgramError> {
+        // a truncated account fails cleanly instead of panicking below
+        let input = input
+            .get(..SwapStateV1::LEN)
+            .ok_or(ProgramError::InvalidAccountData)?;
+        let input = array_ref![input, 0, SwapStateV1::LEN];
+        #[allow(clippy::ptr_offset_with_cast)]
+        let (
+            is_initialized,
+            bump_seed,
+            token_program_id,
+            token_a,
+            token_b,
+            pool_mint,
+            token_a_mint,
+            token_b_mint,
+            token_a_fee_account,
+            token_b_fee_account,
+            trade_fee_numerator,
+            trade_fee_denominator,
+        ) = array_refs![input, 1, 1, 32, 32, 32, 32, 32, 32, 32, 32, 8, 8];
+        Ok(Self {
+            is_initialized: match is_initialized {
+                [0] => false,
+                [1] => true,
+                _ => return Err(ProgramError::InvalidAccountData),
+            },
+            bump_seed: bump_seed[0],
+            token_program_id: Pubkey::new_from_array(*token_program_id),
+            token_a: Pubkey::new_from_array(*token_a),
+            token_b: Pubkey::new_from_array(*token_b),
+            pool_mint: Pubkey::new_from_array(*pool_mint),
+            token_a_mint: Pubkey::new_from_array(*token_a_mint),
+            token_b_mint: Pubkey::new_from_array(*token_b_mint),
+            token_a_fee_account: Pubkey::new_from_array(*token_a_fee_account),
+            token_b_fee_account: Pubkey::new_from_array(*token_b_fee_account),
+            trade_fee_numerator: u64::from_le_bytes(*trade_fee_numerator),
+            trade_fee_denominator: u64::from_le_bytes(*trade_fee_denominator),
         })
     }
 }
@@ -187,10 +746,29 @@ mod tests {
     const TEST_FEES: Fees = Fees {
         trade_fee_numerator: 1,
         trade_fee_denominator: 4,
+        owner_trade_fee_numerator: 2,
+        owner_trade_fee_denominator: 5,
+        flash_loan_fee_numerator: 3,
+        flash_loan_fee_denominator: 1000,
     };
 
     const TEST_BUMP_SEED: u8 = 255;
+    const TEST_CURVE_TYPE: CurveType = CurveType::Offset;
+    const TEST_CURVE_PARAMETER: u64 = 1_000;
+    const TEST_REWARD_CAP_NUMERATOR: u64 = 1;
+    const TEST_REWARD_CAP_DENOMINATOR: u64 = 2;
+    const TEST_LAST_SWAP_PRICE: u128 = 2 * PRICE_SCALE;
+    const TEST_PRICE_BAND_BPS: u16 = 500;
+    const TEST_POOL_TOKEN_SUPPLY: u64 = 1_000_000_000;
+    const TEST_TOKEN_A_DECIMALS: u8 = 6;
+    const TEST_TOKEN_B_DECIMALS: u8 = 9;
+    const TEST_POOL_TOKEN_DECIMALS: u8 = 2;
+    const TEST_FLASH_LOAN_BALANCE: u64 = 1_003;
+    const TEST_INITIAL_POOL_AMOUNT: u64 = 1_000_000_000;
+    const TEST_OWNER_FEES_OWED: u64 = 12_345;
+    const TEST_OWNER: Pubkey = Pubkey::new_from_array([10u8; 32]);
     const TEST_TOKEN_PROGRAM_ID: Pubkey = Pubkey::new_from_array([1u8; 32]);
+    const TEST_POOL_TOKEN_PROGRAM_ID: Pubkey = Pubkey::new_from_array([9u8; 32]);
     const TEST_TOKEN_A: Pubkey = Pubkey::new_from_array([2u8; 32]);
     const TEST_TOKEN_B: Pubkey = Pubkey::new_from_array([3u8; 32]);
     const TEST_POOL_MINT: Pubkey = Pubkey::new_from_array([4u8; 32]);
@@ -198,13 +776,17 @@ mod tests {
     const TEST_TOKEN_B_MINT: Pubkey = Pubkey::new_from_array([6u8; 32]);
     const TEST_TOKEN_A_FEE_ACCOUNT: Pubkey = Pubkey::new_from_array([7u8; 32]);
     const TEST_TOKEN_B_FEE_ACCOUNT: Pubkey = Pubkey::new_from_array([8u8; 32]);
+    const TEST_FLASH_LOAN_RESERVE: Pubkey = TEST_TOKEN_A;
+    const TEST_MAX_PRICE_IMPACT_BPS: u16 = 300;
+    const TEST_OWNER_REBALANCE_AMOUNT: u64 = 6_789;
 
-    #[test]
-    fn swap_state_pack() {
-        let swap_info = SwapState {
+    fn test_swap_state() -> SwapState {
+        SwapState {
             is_initialized: true,
             bump_seed: TEST_BUMP_SEED,
+            owner: TEST_OWNER,
             token_program_id: TEST_TOKEN_PROGRAM_ID,
+            pool_token_program_id: TEST_POOL_TOKEN_PROGRAM_ID,
             token_a: TEST_TOKEN_A,
             token_b: TEST_TOKEN_B,
             pool_mint: TEST_POOL_MINT,
@@ -213,7 +795,29 @@ mod tests {
             token_a_fee_account: TEST_TOKEN_A_FEE_ACCOUNT,
             token_b_fee_account: TEST_TOKEN_B_FEE_ACCOUNT,
             fees: TEST_FEES,
-        };
+            curve_type: TEST_CURVE_TYPE,
+            curve_parameter: TEST_CURVE_PARAMETER,
+            reward_cap_numerator: TEST_REWARD_CAP_NUMERATOR,
+            reward_cap_denominator: TEST_REWARD_CAP_DENOMINATOR,
+            last_swap_price: TEST_LAST_SWAP_PRICE,
+            price_band_bps: TEST_PRICE_BAND_BPS,
+            pool_token_supply: TEST_POOL_TOKEN_SUPPLY,
+            token_a_decimals: TEST_TOKEN_A_DECIMALS,
+            token_b_decimals: TEST_TOKEN_B_DECIMALS,
+            pool_token_decimals: TEST_POOL_TOKEN_DECIMALS,
+            flash_loan_reserve: TEST_FLASH_LOAN_RESERVE,
+            flash_loan_balance: TEST_FLASH_LOAN_BALANCE,
+            initial_pool_amount: TEST_INITIAL_POOL_AMOUNT,
+            owner_fees_owed: TEST_OWNER_FEES_OWED,
+            round_up_deposits: true,
+            max_price_impact_bps: TEST_MAX_PRICE_IMPACT_BPS,
+            owner_rebalance_amount: TEST_OWNER_REBALANCE_AMOUNT,
+        }
+    }
+
+    #[test]
+    fn swap_state_pack() {
+        let swap_info = test_swap_state();
 
         let mut packed = [0u8; SwapState::LEN];
         SwapState::pack_into_slice(&swap_info, &mut packed);
@@ -221,7 +825,9 @@ mod tests {
         assert_eq!(swap_info, unpacked);
 
         let mut packed = vec![1u8, TEST_BUMP_SEED];
+        packed.extend_from_slice(&TEST_OWNER.to_bytes());
         packed.extend_from_slice(&TEST_TOKEN_PROGRAM_ID.to_bytes());
+        packed.extend_from_slice(&TEST_POOL_TOKEN_PROGRAM_ID.to_bytes());
         packed.extend_from_slice(&TEST_TOKEN_A.to_bytes());
         packed.extend_from_slice(&TEST_TOKEN_B.to_bytes());
         packed.extend_from_slice(&TEST_POOL_MINT.to_bytes());
@@ -231,6 +837,27 @@ mod tests {
         packed.extend_from_slice(&TEST_TOKEN_B_FEE_ACCOUNT.to_bytes());
         packed.extend_from_slice(&TEST_FEES.trade_fee_numerator.to_le_bytes());
         packed.extend_from_slice(&TEST_FEES.trade_fee_denominator.to_le_bytes());
+        packed.extend_from_slice(&TEST_FEES.owner_trade_fee_numerator.to_le_bytes());
+        packed.extend_from_slice(&TEST_FEES.owner_trade_fee_denominator.to_le_bytes());
+        packed.extend_from_slice(&TEST_FEES.flash_loan_fee_numerator.to_le_bytes());
+        packed.extend_from_slice(&TEST_FEES.flash_loan_fee_denominator.to_le_bytes());
+        packed.push(TEST_CURVE_TYPE as u8);
+        packed.extend_from_slice(&TEST_CURVE_PARAMETER.to_le_bytes());
+        packed.extend_from_slice(&TEST_REWARD_CAP_NUMERATOR.to_le_bytes());
+        packed.extend_from_slice(&TEST_REWARD_CAP_DENOMINATOR.to_le_bytes());
+        packed.extend_from_slice(&TEST_LAST_SWAP_PRICE.to_le_bytes());
+        packed.extend_from_slice(&TEST_PRICE_BAND_BPS.to_le_bytes());
+        packed.extend_from_slice(&TEST_POOL_TOKEN_SUPPLY.to_le_bytes());
+        packed.push(TEST_TOKEN_A_DECIMALS);
+        packed.push(TEST_TOKEN_B_DECIMALS);
+        packed.push(TEST_POOL_TOKEN_DECIMALS);
+        packed.extend_from_slice(&TEST_FLASH_LOAN_RESERVE.to_bytes());
+        packed.extend_from_slice(&TEST_FLASH_LOAN_BALANCE.to_le_bytes());
+        packed.extend_from_slice(&TEST_INITIAL_POOL_AMOUNT.to_le_bytes());
+        packed.extend_from_slice(&TEST_OWNER_FEES_OWED.to_le_bytes());
+        packed.push(1);
+        packed.extend_from_slice(&TEST_MAX_PRICE_IMPACT_BPS.to_le_bytes());
+        packed.extend_from_slice(&TEST_OWNER_REBALANCE_AMOUNT.to_le_bytes());
         let unpacked = SwapState::unpack(&packed).unwrap();
         assert_eq!(swap_info, unpacked);
 
@@ -240,5 +867,248 @@ mod tests {
         assert_eq!(unpack_unchecked, swap_info);
         let err = SwapState::unpack(&packed).unwrap_err();
         assert_eq!(err, ProgramError::UninitializedAccount);
+
+        let truncated = [1u8; 100];
+        assert_eq!(
+            SwapState::unpack_from_slice(&truncated),
+            Err(ProgramError::InvalidAccountData)
+        );
+        assert_eq!(
+            SwapStateV1::unpack_from_slice(&truncated),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn initialization_rent_sums_accounts() {
+        let rent = Rent::default();
+        let expected = rent.minimum_balance(SwapState::LEN)
+            + rent.minimum_balance(spl_token::state::Account::LEN) * 2
+            + rent.minimum_balance(spl_token::state::Mint::LEN)
+            + rent.minimum_balance(spl_token::state::Account::LEN) * 3;
+        assert_eq!(initialization_rent(&rent), expected);
+        assert_eq!(initialization_rent(&Rent::free()), 0);
+    }
+
+    #[test]
+    fn swap_state_reserves_from_accounts() {
+        fn token_account_data(mint: Pubkey, amount: u64) -> Vec<u8> {
+            let mut data = vec![0; spl_token::state::Account::LEN];
+            spl_token::state::Account::pack(
+                spl_token::state::Account {
+                    mint,
+                    amount,
+                    state: spl_token::state::AccountState::Initialized,
+                    ..Default::default()
+                },
+                &mut data,
+            )
+            .unwrap();
+            data
+        }
+
+        let swap_state = test_swap_state();
+        let (mut lamports_a, mut lamports_b) = (0, 0);
+        let mut data_a = token_account_data(TEST_TOKEN_A_MINT, 1000);
+        let mut data_b = token_account_data(TEST_TOKEN_B_MINT, 2000);
+        let token_a_info = AccountInfo::new(
+            &TEST_TOKEN_A,
+            false,
+            false,
+            &mut lamports_a,
+            &mut data_a,
+            &TEST_TOKEN_PROGRAM_ID,
+            false,
+            0,
+        );
+        let token_b_info = AccountInfo::new(
+            &TEST_TOKEN_B,
+            false,
+            false,
+            &mut lamports_b,
+            &mut data_b,
+            &TEST_TOKEN_PROGRAM_ID,
+            false,
+            0,
+        );
+        assert_eq!(
+            swap_state.reserves_from_accounts(&token_a_info, &token_b_info),
+            Ok((1000, 2000))
+        );
+        assert_eq!(
+            swap_state.reserves_from_accounts(&token_b_info, &token_a_info),
+            Err(SwapError::IncorrectSwapAccount)
+        );
+
+        let other_token_program_id = TEST_POOL_TOKEN_PROGRAM_ID;
+        let mut other_token_b_info = token_b_info.clone();
+        other_token_b_info.owner = &other_token_program_id;
+        assert_eq!(
+            swap_state.reserves_from_accounts(&token_a_info, &other_token_b_info),
+            Err(SwapError::IncorrectTokenProgramId)
+        );
+
+        // a reserve holding another mint than the pool's
+        let mut other_lamports_b = 0;
+        let mut other_data_b = token_account_data(TEST_TOKEN_A_MINT, 2000);
+        let other_token_b_info = AccountInfo::new(
+            &TEST_TOKEN_B,
+            false,
+            false,
+            &mut other_lamports_b,
+            &mut other_data_b,
+            &TEST_TOKEN_PROGRAM_ID,
+            false,
+            0,
+        );
+        assert_eq!(
+            swap_state.reserves_from_accounts(&token_a_info, &other_token_b_info),
+            Err(SwapError::IncorrectMint)
+        );
+
+        token_b_info.data.borrow_mut()[..].fill(0);
+        assert_eq!(
+            swap_state.reserves_from_accounts(&token_a_info, &token_b_info),
+            Err(SwapError::ExpectedAccount)
+        );
+    }
+
+    #[cfg(feature = "borsh")]
+    #[test]
+    fn swap_state_borsh() {
+        use borsh::{BorshDeserialize, BorshSerialize};
+
+        let swap_info = test_swap_state();
+        let serialized = swap_info.try_to_vec().unwrap();
+        assert_eq!(serialized.len(), SwapState::LEN);
+        assert_eq!(SwapState::try_from_slice(&serialized).unwrap(), swap_info);
+
+        // same layout as the on-chain packing
+        let mut packed = [0u8; SwapState::LEN];
+        SwapState::pack_into_slice(&swap_info, &mut packed);
+        assert_eq!(serialized, packed);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn swap_state_serde() {
+        let swap_info = test_swap_state();
+        let serialized = serde_json::to_string(&swap_info).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(value["owner"], swap_info.owner.to_string());
+        assert_eq!(value["curve_type"], "Offset");
+        assert_eq!(
+            serde_json::from_str::<SwapState>(&serialized).unwrap(),
+            swap_info
+        );
+    }
+
+    #[test]
+    fn swap_state_reward_budget() {
+        let swap_info = SwapState {
+            reward_cap_numerator: TEST_REWARD_CAP_NUMERATOR,
+            reward_cap_denominator: TEST_REWARD_CAP_DENOMINATOR,
+            ..Default::default()
+        };
+        assert!(swap_info.has_reward_cap());
+        assert_eq!(swap_info.reward_budget(101), Some(50));
+        assert_eq!(swap_info.reward_budget(0), Some(0));
+
+        let swap_info = SwapState::default();
+        assert!(!swap_info.has_reward_cap());
+        assert_eq!(swap_info.reward_budget(101), None);
+    }
+
+    #[test]
+    fn swap_state_price_band() {
+        let mut swap_info = SwapState {
+            price_band_bps: TEST_PRICE_BAND_BPS,
+            ..Default::default()
+        };
+        assert!(!swap_info.has_price_band());
+
+        swap_info.last_swap_price = swap_price(100, 200, TradeDirection::AtoB).unwrap();
+        assert_eq!(swap_info.last_swap_price, TEST_LAST_SWAP_PRICE);
+        assert_eq!(
+            swap_price(200, 100, TradeDirection::BtoA),
+            Some(TEST_LAST_SWAP_PRICE)
+        );
+        assert!(swap_info.has_price_band());
+        assert_eq!(
+            swap_info.price_band_minimum_amount_out(100, TradeDirection::AtoB),
+            Some(190)
+        );
+        assert_eq!(
+            swap_info.price_band_minimum_amount_out(200, TradeDirection::BtoA),
+            Some(95)
+        );
+    }
+
+    #[test]
+    fn swap_state_self_consistency() {
+        let program_id = Pubkey::new_unique();
+        let swap_key = Pubkey::new_unique();
+        let (_, bump_seed) = Pubkey::find_program_address(&[&swap_key.to_bytes()], &program_id);
+        let swap_info = SwapState {
+            bump_seed,
+            ..test_swap_state()
+        };
+        swap_info
+            .validate_self_consistency(&program_id, &swap_key)
+            .unwrap();
+
+        // same token account on both sides
+        let corrupted = SwapState {
+            token_b: TEST_TOKEN_A,
+            ..swap_info.clone()
+        };
+        assert_eq!(
+            corrupted.validate_self_consistency(&program_id, &swap_key),
+            Err(SwapError::IncorrectSwapAccount)
+        );
+
+        // same mint on both sides
+        let corrupted = SwapState {
+            token_b_mint: TEST_TOKEN_A_MINT,
+            ..swap_info.clone()
+        };
+        assert_eq!(
+            corrupted.validate_self_consistency(&program_id, &swap_key),
+            Err(SwapError::RepeatedMint)
+        );
+
+        // shared fee account
+        let corrupted = SwapState {
+            token_b_fee_account: TEST_TOKEN_A_FEE_ACCOUNT,
+            ..swap_info.clone()
+        };
+        assert_eq!(
+            corrupted.validate_self_consistency(&program_id, &swap_key),
+            Err(SwapError::IncorrectFeeAccount)
+        );
+
+        // invalid fees
+        let corrupted = SwapState {
+            fees: Fees {
+                trade_fee_numerator: 4,
+                trade_fee_denominator: 1,
+                ..TEST_FEES
+            },
+            ..swap_info.clone()
+        };
+        assert_eq!(
+            corrupted.validate_self_consistency(&program_id, &swap_key),
+            Err(SwapError::InvalidFee)
+        );
+
+        // non-canonical bump seed
+        let corrupted = SwapState {
+            bump_seed: bump_seed.wrapping_sub(1),
+            ..swap_info.clone()
+        };
+        assert_eq!(
+            corrupted.validate_self_consistency(&program_id, &swap_key),
+            Err(SwapError::InvalidProgramAddress)
+        );
     }
 }